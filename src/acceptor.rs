@@ -0,0 +1,636 @@
+use hyper::Request;
+use hyper::header::HeaderValue;
+use log::{ info };
+use rustls::{ RootCertStore, ServerConfig };
+use rustls::crypto::{ ring, CryptoProvider };
+use rustls::pki_types::{ CertificateDer, PrivateKeyDer };
+use rustls::server::{ ClientHello, ResolvesServerCert, WebPkiClientVerifier };
+use rustls::sign::CertifiedKey;
+use std::collections::{ BTreeMap, HashMap };
+use std::fmt::Debug;
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::server::TlsStream;
+use url::Host;
+use x509_parser::prelude::{ FromDer, X509Certificate };
+use crate::acme::{ self, Challenge };
+use crate::errors::{ Error };
+use crate::listeners::{ ListenAddr };
+use crate::matcher;
+use crate::options::{ ClientAuth, ListenerProtocol, ListenerTlsOptions };
+use crate::routes::{ Route };
+use crate::settings::{ Settings };
+use crate::tls::read;
+
+const CLIENT_VERIFY: &str = "x-client-verify";
+const CLIENT_SUBJECT: &str = "x-client-subject";
+
+/// How long a client has to finish the TLS handshake once it's connected:
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a listener accepts TLS connections, and the certificates it
+/// presents to them.
+#[derive(Debug)]
+pub struct TlsConfig {
+    pub server: Arc<ServerConfig>,
+    certs: Arc<Certificates>
+}
+
+impl TlsConfig {
+    /// The hosts that we get certificates for with `--acme`:
+    pub fn acme_hosts(&self) -> impl Iterator<Item=&Arc<acme::Host>> {
+        self.certs.acme.values()
+    }
+}
+
+/// The certificates for the hosts on a listener. Clients are presented
+/// with the certificate for the host they ask for with SNI, or with the
+/// listener's default one if that host doesn't have one of its own yet
+/// (or they don't say which host they're after).
+#[derive(Debug,Default)]
+struct Certificates {
+    by_host: HashMap<String, Arc<CertifiedKey>>,
+    /// Hosts that we get certificates for with `--acme`:
+    acme: HashMap<String, Arc<acme::Host>>,
+    default: Option<Arc<CertifiedKey>>
+}
+
+impl Certificates {
+    fn for_host(&self, host: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let host = host.map(|host| host.to_lowercase());
+        let host = host.as_ref();
+        host.and_then(|host| self.by_host.get(host).cloned())
+            .or_else(|| host.and_then(|host| self.acme.get(host)?.certificate()))
+            .or_else(|| self.default.clone())
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        // The CA checking a TLS-ALPN-01 challenge:
+        if client_hello.alpn().is_some_and(|mut protocols| protocols.any(|protocol| protocol == acme::ACME_TLS_ALPN)) {
+            return acme::challenge_certificate(client_hello.server_name());
+        }
+        self.for_host(client_hello.server_name())
+    }
+}
+
+/// What we know about the TLS connection that a request arrived on, kept
+/// in the request's extensions. Requests over plain HTTP don't have one.
+#[derive(Debug,Clone,Default)]
+pub struct TlsConnection {
+    /// The subject of the certificate the client presented, if it was
+    /// asked for one and presented one we trust (eg `CN=alice, O=Example`):
+    pub client_subject: Option<String>,
+    /// The host the client asked for with SNI as it connected, if it said:
+    pub server_name: Option<String>,
+    /// The certificates the listener had when the client connected:
+    certs: Arc<Certificates>
+}
+
+impl TlsConnection {
+    pub fn of<IO>(stream: &TlsStream<IO>, config: &TlsConfig) -> TlsConnection {
+        let (_, conn) = stream.get_ref();
+        let client_cert = conn.peer_certificates().and_then(|certs| certs.first());
+        TlsConnection {
+            client_subject: client_cert.and_then(|cert| subject(cert)),
+            server_name: conn.server_name().map(|name| name.to_lowercase()),
+            certs: Arc::clone(&config.certs)
+        }
+    }
+
+    /// Would a client asking for this host have been presented with the
+    /// certificate that this connection was made with?
+    fn serves(&self, host: &str) -> bool {
+        let presented = self.certs.for_host(self.server_name.as_deref());
+        presented.as_ref().map(Arc::as_ptr) == self.certs.for_host(Some(host)).as_ref().map(Arc::as_ptr)
+    }
+}
+
+/// Is a request for a host that the TLS connection it arrived on wasn't
+/// made for? Clients can send requests for any host the certificate they
+/// were presented with covers down one connection (browsers do, over
+/// HTTP/2), but hosts with certificates of their own need connections of
+/// their own. We answer these with a 421, to have the client connect again:
+pub fn is_misdirected<T>(req: &Request<T>) -> bool {
+    match (req.extensions().get::<TlsConnection>(), matcher::request_host(req)) {
+        (Some(tls), Some(host)) => !tls.serves(&host),
+        _ => false
+    }
+}
+
+/// Tell destinations whether the client presented a certificate we trust
+/// (`X-Client-Verify: SUCCESS` or `NONE`) and whose it is (`X-Client-Subject`),
+/// for requests over TLS. Clients can't say so themselves, so we remove any
+/// of these headers they send:
+pub fn set_client_cert_headers<T>(req: &mut Request<T>) {
+    let subject = req.extensions().get::<TlsConnection>().map(|tls| tls.client_subject.clone());
+    let headers = req.headers_mut();
+    headers.remove(CLIENT_VERIFY);
+    headers.remove(CLIENT_SUBJECT);
+    match subject {
+        Some(Some(subject)) => {
+            headers.insert(CLIENT_VERIFY, HeaderValue::from_static("SUCCESS"));
+            if let Ok(subject) = HeaderValue::from_bytes(subject.as_bytes()) {
+                headers.insert(CLIENT_SUBJECT, subject);
+            }
+        },
+        Some(None) => { headers.insert(CLIENT_VERIFY, HeaderValue::from_static("NONE")); },
+        None => {}
+    }
+}
+
+/// The subject of a certificate, as a distinguished name:
+fn subject(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    Some(cert.subject().to_string())
+}
+
+/// Where we keep the certificates we make or get (`~/.cache/weave`):
+pub fn cache_dir() -> PathBuf {
+    let cache = std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    cache.join("weave")
+}
+
+/// Where `--self-signed` keeps the certificates it makes, unless told
+/// otherwise. They're kept so that browsers told to trust one carry on
+/// trusting it after a restart:
+pub fn default_self_signed_dir() -> PathBuf {
+    cache_dir().join("self-signed")
+}
+
+/// Are the routes on a listener for `https://` sources? Either all of
+/// them are, or none are:
+pub fn is_https(listen_addr: &ListenAddr, routes: &[Route]) -> Result<bool, Error> {
+    let https = routes.iter().filter(|route| route.src.url.scheme() == "https").count();
+    if https > 0 && https < routes.len() {
+        return Err(err!("Routes on {} mix http:// and https:// sources", listen_addr));
+    }
+    if let (0, Some(route)) = (https, routes.iter().find(|route| route.options.listener_tls != Default::default())) {
+        return Err(err!("The cert, key, client-ca and client-auth options of {} are only for https:// sources", route.src));
+    }
+    Ok(https > 0)
+}
+
+/// How to accept TLS connections on a listener whose routes are for
+/// `https://` sources, or None if they're for plain HTTP. We present the
+/// certificates that the routes give with their `cert` and `key` options,
+/// or else one we make ourselves if we're asked to with `--self-signed`,
+/// and ask clients for certificates if the routes give a `client-ca`.
+pub fn server_config(listen_addr: &ListenAddr, routes: &[Route], protocol: ListenerProtocol, settings: &Settings) -> Result<Option<Arc<TlsConfig>>, Error> {
+    if !is_https(listen_addr, routes)? {
+        return Ok(None);
+    }
+    let options = listener_options(listen_addr, routes)?;
+    let certs = Arc::new(certificates(listen_addr, routes, settings)?);
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &options.client_ca {
+        Some(ca_file) => builder.with_client_cert_verifier(client_verifier(ca_file, options.client_auth)?),
+        None => builder.with_no_client_auth()
+    };
+    let mut config = builder.with_cert_resolver(Arc::clone(&certs) as Arc<dyn ResolvesServerCert>);
+    config.alpn_protocols = alpn_protocols(protocol);
+    if settings.acme.as_ref().is_some_and(|acme| acme.challenge == Challenge::TlsAlpn01) {
+        config.alpn_protocols.push(acme::ACME_TLS_ALPN.to_vec());
+    }
+    Ok(Some(Arc::new(TlsConfig { server: Arc::new(config), certs })))
+}
+
+/// Check that the certificates given to routes on a listener can be used,
+/// for `weave check`. Certificates that we'd make ourselves aren't:
+pub fn check(listen_addr: &ListenAddr, routes: &[Route]) -> Result<(), Error> {
+    if !is_https(listen_addr, routes)? {
+        return Ok(());
+    }
+    let options = listener_options(listen_addr, routes)?;
+    for (cert_path, key_path) in cert_paths(listen_addr, routes)?.values().flatten() {
+        load_certified_key(cert_path, key_path)?;
+    }
+    if let Some(ca_file) = &options.client_ca {
+        client_verifier(ca_file, options.client_auth)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Tell clients which versions of HTTP we speak as they connect:
+fn alpn_protocols(protocol: ListenerProtocol) -> Vec<Vec<u8>> {
+    match protocol {
+        ListenerProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        ListenerProtocol::Http1 => vec![b"http/1.1".to_vec()],
+        ListenerProtocol::Http2 => vec![b"h2".to_vec()]
+    }
+}
+
+/// The client-ca and client-auth options given to the routes on a
+/// listener. Clients are asked for certificates before they say which
+/// host they're after, so routes that give an option need to agree on it:
+fn listener_options(listen_addr: &ListenAddr, routes: &[Route]) -> Result<ListenerTlsOptions, Error> {
+    let mut options = ListenerTlsOptions::default();
+    let on = format!("Routes on {}", listen_addr);
+    for route in routes {
+        let given = &route.options.listener_tls;
+        agree(&on, "client-ca", &mut options.client_ca, &given.client_ca)?;
+        agree(&on, "client-auth", &mut options.client_auth, &given.client_auth)?;
+    }
+    if options.client_auth.is_some() && options.client_ca.is_none() {
+        return Err(err!("Routes on {} give client-auth without a client-ca to check certificates with", listen_addr));
+    }
+    Ok(options)
+}
+
+/// The paths of a certificate and its key:
+type CertPaths = (PathBuf, PathBuf);
+
+/// The certificate and key that the routes on a listener give for each
+/// virtual host they're for (or None for routes that aren't for one), if
+/// they give one. There's one certificate per host, so routes for the same
+/// host that give one need to agree on it:
+fn cert_paths(listen_addr: &ListenAddr, routes: &[Route]) -> Result<BTreeMap<Option<String>, Option<CertPaths>>, Error> {
+    let mut given_by_host = BTreeMap::new();
+    for route in routes {
+        let given = &route.options.listener_tls;
+        if given.cert.is_some() != given.key.is_some() {
+            return Err(err!("Both cert and key must be given to serve {}", route.src));
+        }
+        let on = match &route.src.host {
+            Some(host) => format!("Routes for {} on {}", host, listen_addr),
+            None => format!("Routes on {}", listen_addr)
+        };
+        let (cert, key) = given_by_host.entry(route.src.host.clone()).or_insert((None, None));
+        agree(&on, "cert", cert, &given.cert)?;
+        agree(&on, "key", key, &given.key)?;
+    }
+    Ok(given_by_host.into_iter()
+        .map(|(host, (cert, key))| (host, cert.zip(key)))
+        .collect())
+}
+
+fn agree<T: PartialEq + Clone + Debug>(on: &str, name: &str, current: &mut Option<T>, given: &Option<T>) -> Result<(), Error> {
+    match (&*current, given) {
+        (Some(a), Some(b)) if a != b => Err(err!("{} give different {} options ({:?} and {:?})", on, name, a, b)),
+        (None, Some(_)) => { *current = given.clone(); Ok(()) },
+        _ => Ok(())
+    }
+}
+
+/// Check the certificates that clients present against the CA certificates
+/// in a file. Clients that don't present one can connect too, if that's
+/// optional:
+fn client_verifier(ca_file: &Path, client_auth: Option<ClientAuth>) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_file)? {
+        roots.add(cert).map_err(|e| err!("Cannot use CA file '{}': {}", ca_file.display(), e))?;
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+    let verifier = match client_auth.unwrap_or(ClientAuth::Required) {
+        ClientAuth::Required => verifier,
+        ClientAuth::Optional => verifier.allow_unauthenticated()
+    };
+    verifier.build().map_err(|e| err!("Cannot use CA file '{}': {}", ca_file.display(), e))
+}
+
+/// The certificates to present for the routes on a listener. Routes for
+/// a virtual host can give it a certificate of its own (or we get one for
+/// it with `--acme`), and routes that aren't for one give the default
+/// certificate, which hosts without their own are presented with too. We
+/// make that ourselves if it's needed but not given and we're asked to
+/// with `--self-signed`.
+fn certificates(listen_addr: &ListenAddr, routes: &[Route], settings: &Settings) -> Result<Certificates, Error> {
+    let paths = cert_paths(listen_addr, routes)?;
+    let mut certs = Certificates::default();
+    // Hosts given the same certificate share it, so that clients can send
+    // requests for either down one connection:
+    let mut loaded: HashMap<&CertPaths, Arc<CertifiedKey>> = HashMap::new();
+    for (host, given) in &paths {
+        let given = match given {
+            Some(given) => given,
+            None => continue
+        };
+        let cert = match loaded.get(given) {
+            Some(cert) => Arc::clone(cert),
+            None => Arc::new(load_certified_key(&given.0, &given.1)?)
+        };
+        loaded.insert(given, Arc::clone(&cert));
+        match host {
+            Some(host) => { certs.by_host.insert(host.clone(), cert); },
+            None => certs.default = Some(cert)
+        }
+    }
+    let mut uncovered: Vec<&Route> = routes.iter().filter(|route| paths[&route.src.host].is_none()).collect();
+    if let Some(options) = &settings.acme {
+        for host in uncovered.iter().filter_map(|route| route.src.host.as_ref()) {
+            certs.acme.insert(host.clone(), acme::Host::of(host, options));
+        }
+        uncovered.retain(|route| route.src.host.is_none());
+    }
+    if certs.default.is_none() && !uncovered.is_empty() {
+        let (cert_path, key_path) = match &settings.self_signed {
+            Some(dir) => self_signed(dir, &host_names(uncovered))?,
+            None => return Err(err!("Routes on {} need the cert and key options (or --self-signed) to serve https:// sources", listen_addr))
+        };
+        certs.default = Some(Arc::new(load_certified_key(&cert_path, &key_path)?));
+    }
+    Ok(certs)
+}
+
+/// Read a PEM certificate chain and its private key, checking that they
+/// go together:
+pub(crate) fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, Error> {
+    CertifiedKey::from_der(load_certs(cert_path)?, load_key(key_path)?, &provider())
+        .map_err(|e| err!("Cannot use certificate '{}' with key '{}': {}", cert_path.display(), key_path.display(), e))
+}
+
+/// Read a PEM certificate chain:
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = rustls_pemfile::certs(&mut &*read(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| err!("Cannot read certificates from '{}': {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(err!("No PEM certificates found in '{}'", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Read a PEM private key (PKCS#8, PKCS#1 or SEC1):
+pub(crate) fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    rustls_pemfile::private_key(&mut &*read(path)?)
+        .map_err(|e| err!("Cannot read a private key from '{}': {}", path.display(), e))?
+        .ok_or_else(|| err!("No PEM private key found in '{}'", path.display()))
+}
+
+/// The names that the routes on a listener are for, to make a certificate
+/// for. Routes listening on every interface are taken to be for `localhost`:
+fn host_names<'a>(routes: impl IntoIterator<Item=&'a Route>) -> Vec<String> {
+    let mut names = vec![];
+    for route in routes {
+        let name = match (&route.src.host, route.src.url.host()) {
+            (Some(vhost), _) => vhost.clone(),
+            (None, Some(Host::Domain(domain))) => domain.to_lowercase(),
+            (None, Some(Host::Ipv4(ip))) if !ip.is_unspecified() => ip.to_string(),
+            (None, Some(Host::Ipv6(ip))) if !ip.is_unspecified() => ip.to_string(),
+            _ => "localhost".to_owned()
+        };
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names.sort();
+    names
+}
+
+/// A self-signed certificate for some names, made the first time they're
+/// asked for and kept in `dir` after that. Hands back the paths of the
+/// certificate and its key:
+fn self_signed(dir: &Path, names: &[String]) -> Result<(PathBuf, PathBuf), Error> {
+    // Colons (from IPv6 addresses) can't go in file names everywhere:
+    let stem = names.join("+").replace(':', "-");
+    let cert_path = dir.join(format!("{}.pem", stem));
+    let key_path = dir.join(format!("{}-key.pem", stem));
+    if !cert_path.exists() || !key_path.exists() {
+        let certified = rcgen::generate_simple_self_signed(names.to_vec())
+            .map_err(|e| err!("Cannot make a self-signed certificate for {}: {}", names.join(", "), e))?;
+        std::fs::create_dir_all(dir).map_err(|e| err!("Cannot create '{}': {}", dir.display(), e))?;
+        write_key(&key_path, &certified.key_pair.serialize_pem())?;
+        std::fs::write(&cert_path, certified.cert.pem())
+            .map_err(|e| err!("Cannot write '{}': {}", cert_path.display(), e))?;
+    }
+    info!("Serving {} with a self-signed certificate. Trust {} to avoid certificate warnings", names.join(", "), cert_path.display());
+    Ok((cert_path, key_path))
+}
+
+/// Write a private key that only we can read (on Unix, at least):
+pub(crate) fn write_key(path: &Path, pem: &str) -> Result<(), Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .map_err(|e| err!("Cannot write '{}': {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use rcgen::{ BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair };
+    use rustls::ClientConfig;
+    use rustls::pki_types::ServerName;
+    use std::convert::TryFrom;
+    use std::io;
+    use std::net::SocketAddr;
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+    use tokio::net::{ TcpListener, TcpStream };
+    use tokio_rustls::TlsConnector;
+    use crate::routes;
+    use crate::server::WeaveServer;
+
+    fn routes(s: &str) -> Vec<Route> {
+        routes::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn needs_one_certificate_for_https_sources() {
+        let addr = ListenAddr::Tcp("127.0.0.1:8443".parse().unwrap());
+        assert!(!is_https(&addr, &routes("8080 to 9000")).unwrap());
+        assert!(is_https(&addr, &routes("https://localhost:8443 to 9000")).unwrap());
+        assert!(is_https(&addr, &routes("https://localhost:8443/a to 9000 and 8443/b to 9001")).is_err());
+        assert!(is_https(&addr, &routes("8080 to 9000 with cert=cert.pem key=key.pem")).is_err());
+
+        let given = cert_paths(&addr, &routes("https://localhost:8443/a to 9000 with cert=cert.pem key=key.pem and https://localhost:8443/b to 9001")).unwrap();
+        assert_eq!(given.into_iter().collect::<Vec<_>>(), vec![(None, Some(("cert.pem".into(), "key.pem".into())))]);
+        assert!(cert_paths(&addr, &routes("https://localhost:8443 to 9000 with cert=cert.pem")).is_err());
+        assert!(cert_paths(&addr, &routes("https://localhost:8443/a to 9000 with cert=a.pem key=a-key.pem and https://localhost:8443/b to 9001 with cert=b.pem key=b-key.pem")).is_err());
+        assert!(cert_paths(&addr, &routes("https://a.local:8443/a to 9000 with cert=a.pem key=a-key.pem and https://A.local:8443/b to 9001 with cert=b.pem key=b-key.pem")).is_err());
+
+        // Each virtual host can have a certificate of its own:
+        let given = cert_paths(&addr, &routes("https://a.local:8443 to 9000 with cert=a.pem key=a-key.pem and https://b.local:8443 to 9001 and https://localhost:8443 to 9002")).unwrap();
+        assert_eq!(given.into_iter().collect::<Vec<_>>(), vec![
+            (None, None),
+            (Some("a.local".to_owned()), Some(("a.pem".into(), "a-key.pem".into()))),
+            (Some("b.local".to_owned()), None)
+        ]);
+        assert!(listener_options(&addr, &routes("https://localhost:8443 to 9000 with client-auth=optional")).is_err());
+        assert!(listener_options(&addr, &routes("https://localhost:8443/a to 9000 with client-ca=ca.pem and https://localhost:8443/b to 9001 with client-ca=ca.pem client-auth=optional")).is_ok());
+
+        let err = server_config(&addr, &routes("https://localhost:8443 to 9000"), ListenerProtocol::Auto, &Settings::default()).unwrap_err();
+        assert!(err.to_string().contains("--self-signed"));
+    }
+
+    #[test]
+    fn names_self_signed_certificates_after_hosts() {
+        assert_eq!(host_names(&routes("https://localhost:8443 to 9000")), vec!["localhost"]);
+        assert_eq!(host_names(&routes("https://0.0.0.0:8443 to 9000")), vec!["localhost"]);
+        assert_eq!(
+            host_names(&routes("https://api.local:8443 to 9000 and https://127.0.0.1:8443 to 9001 and https://API.local:8443/v2 to 9002")),
+            vec!["127.0.0.1", "api.local"]
+        );
+    }
+
+    /// The response to a GET over TLS for the host `name` (going by SNI),
+    /// trusting only the certificate in `cert_path`, and presenting the
+    /// client certificate given if any:
+    async fn get(addr: SocketAddr, name: &str, cert_path: &Path, client_cert: Option<&(rcgen::Certificate, KeyPair)>, headers: &str) -> io::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(load_certs(cert_path).unwrap());
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some((cert, key)) => config.with_client_auth_cert(vec![cert.der().clone()], PrivateKeyDer::Pkcs8(key.serialize_der().into())).unwrap(),
+            None => config.with_no_client_auth()
+        };
+        let tcp = TcpStream::connect(addr).await?;
+        let name = ServerName::try_from(name.to_owned()).unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config)).connect(name, tcp).await?;
+        stream.write_all(format!("GET / HTTP/1.1\r\nconnection: close\r\n{}\r\n", headers).as_bytes()).await?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await?;
+        Ok(resp)
+    }
+
+    /// A destination that responds with the head of each request it's sent:
+    async fn echo() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut tcp, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut head = vec![];
+                    let mut buf = [0; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match tcp.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n])
+                        }
+                    }
+                    let resp = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n", head.len());
+                    let _ = tcp.write_all(resp.as_bytes()).await;
+                    let _ = tcp.write_all(&head).await;
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn serves_https_with_self_signed_certificates() {
+        let dir = std::env::temp_dir().join(format!("weave-self-signed-test-{}", std::process::id()));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let settings = Settings { self_signed: Some(dir.clone()), ..Settings::default() };
+            let spawned = WeaveServer::builder()
+                .settings(settings)
+                .parse_routes("https://localhost:8443 to status://204").unwrap()
+                .spawn()
+                .unwrap();
+            let resp = get(spawned.addrs[0], "localhost", &dir.join("localhost.pem"), None, "host: localhost\r\n").await.unwrap();
+            assert_eq!(resp.lines().next(), Some("HTTP/1.1 204 No Content"));
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn verifies_client_certificates() {
+        let dir = std::env::temp_dir().join(format!("weave-client-ca-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // A CA, and a certificate it signed for a client:
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "Weave Test CA");
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, "alice");
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = (params.signed_by(&client_key, &ca, &ca_key).unwrap(), client_key);
+        // And one that it didn't:
+        let stranger = rcgen::generate_simple_self_signed(vec!["mallory".to_owned()]).unwrap();
+        let stranger = (stranger.cert, stranger.key_pair);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let port = echo().await;
+            let spawned = WeaveServer::builder()
+                .settings(Settings { self_signed: Some(dir.clone()), ..Settings::default() })
+                .parse_routes(&format!("https://localhost:8443 to http://127.0.0.1:{} with client-ca={}", port, dir.join("ca.pem").display())).unwrap()
+                .parse_routes(&format!("https://localhost:8444 to http://127.0.0.1:{} with client-ca={} client-auth=optional", port, dir.join("ca.pem").display())).unwrap()
+                .spawn()
+                .unwrap();
+            let (required, optional) = (spawned.addrs[0], spawned.addrs[1]);
+            let cert_path = dir.join("localhost.pem");
+
+            let resp = get(required, "localhost", &cert_path, Some(&client), "host: localhost\r\nx-client-subject: CN=mallory\r\n").await.unwrap().to_lowercase();
+            assert!(resp.contains("x-client-verify: success\r\n"), "{}", resp);
+            assert!(resp.contains("x-client-subject: cn=alice\r\n"), "{}", resp);
+            assert!(!resp.contains("mallory"), "{}", resp);
+            assert!(get(required, "localhost", &cert_path, None, "host: localhost\r\n").await.map(|resp| resp.is_empty()).unwrap_or(true));
+            assert!(get(required, "localhost", &cert_path, Some(&stranger), "host: localhost\r\n").await.map(|resp| resp.is_empty()).unwrap_or(true));
+
+            let resp = get(optional, "localhost", &cert_path, None, "host: localhost\r\nx-client-subject: CN=mallory\r\n").await.unwrap().to_lowercase();
+            assert!(resp.contains("x-client-verify: none\r\n"), "{}", resp);
+            assert!(!resp.contains("x-client-subject"), "{}", resp);
+            assert!(get(optional, "localhost", &cert_path, Some(&stranger), "host: localhost\r\n").await.map(|resp| resp.is_empty()).unwrap_or(true));
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn presents_certificates_by_sni() {
+        let dir = std::env::temp_dir().join(format!("weave-sni-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for host in &["api.local", "web.local"] {
+            let certified = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", host)), certified.cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{}-key.pem", host)), certified.key_pair.serialize_pem()).unwrap();
+        }
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let port = echo().await;
+            let with_cert = |host: &str| format!("with cert={} key={}", dir.join(format!("{}.pem", host)).display(), dir.join(format!("{}-key.pem", host)).display());
+            let spawned = WeaveServer::builder()
+                .settings(Settings { self_signed: Some(dir.clone()), ..Settings::default() })
+                .parse_routes(&format!("https://api.local:8443 to http://127.0.0.1:{}/api {}", port, with_cert("api.local"))).unwrap()
+                .parse_routes(&format!("https://web.local:8443 to http://127.0.0.1:{}/web {}", port, with_cert("web.local"))).unwrap()
+                .parse_routes(&format!("https://localhost:8443 to http://127.0.0.1:{}/default", port)).unwrap()
+                .spawn()
+                .unwrap();
+            let addr = SocketAddr::from(([127, 0, 0, 1], spawned.addrs[0].port()));
+
+            // Each host is presented with its own certificate, and the
+            // rest with one made for them:
+            let resp = get(addr, "api.local", &dir.join("api.local.pem"), None, "host: api.local\r\n").await.unwrap();
+            assert!(resp.contains("GET /api HTTP/1.1"), "{}", resp);
+            let resp = get(addr, "web.local", &dir.join("web.local.pem"), None, "host: web.local\r\n").await.unwrap();
+            assert!(resp.contains("GET /web HTTP/1.1"), "{}", resp);
+            let resp = get(addr, "localhost", &dir.join("localhost.pem"), None, "host: localhost\r\n").await.unwrap();
+            assert!(resp.contains("GET /default HTTP/1.1"), "{}", resp);
+            assert!(get(addr, "web.local", &dir.join("api.local.pem"), None, "host: web.local\r\n").await.is_err());
+
+            // Requests that don't say which host they're for go by SNI:
+            let resp = get(addr, "web.local", &dir.join("web.local.pem"), None, "").await.unwrap();
+            assert!(resp.contains("GET /web HTTP/1.1"), "{}", resp);
+
+            // But they can't be for a host with a different certificate:
+            let resp = get(addr, "api.local", &dir.join("api.local.pem"), None, "host: web.local\r\n").await.unwrap();
+            assert_eq!(resp.lines().next(), Some("HTTP/1.1 421 Misdirected Request"));
+            let resp = get(addr, "localhost", &dir.join("localhost.pem"), None, "host: api.local\r\n").await.unwrap();
+            assert_eq!(resp.lines().next(), Some("HTTP/1.1 421 Misdirected Request"));
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,860 @@
+use ansi_term::Color::{ Green, Red };
+use clap::ArgMatches;
+use hyper::{ Request, Response };
+use hyper::header::{ HeaderMap, CONTENT_TYPE, LOCATION };
+use lazy_static::lazy_static;
+use log::{ info, warn };
+use rcgen::{ CertificateParams, CustomExtension, DistinguishedName, KeyPair };
+use ring::digest::{ digest, SHA256 };
+use ring::rand::SystemRandom;
+use ring::signature::{ self, EcdsaKeyPair, KeyPair as _ };
+use rustls::pki_types::PrivateKeyDer;
+use rustls::sign::CertifiedKey;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{ json, Value };
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, RwLock, Weak };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::time::{ self, sleep };
+use url::Url;
+use x509_parser::prelude::{ FromDer, X509Certificate };
+use crate::acceptor;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::options::{ TlsOptions };
+use crate::proxy;
+use crate::HttpsClient;
+
+/// Let's Encrypt's, unless `--acme-directory` says otherwise:
+const DEFAULT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// Certificates are renewed once they have this long left:
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// How often we check whether certificates need renewing:
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// How long we wait to try again after failing to get a certificate:
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long we wait for the CA to answer each request:
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often, and how many times, we ask the CA whether it's done checking
+/// challenges or issuing a certificate:
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const POLL_ATTEMPTS: usize = 240;
+/// The protocol that clients checking TLS-ALPN-01 challenges ask for:
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+/// Where clients checking HTTP-01 challenges ask for them:
+const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+lazy_static! {
+    /// The hosts we get certificates for, kept by name so that they carry
+    /// on across reloads for as long as some route still wants them:
+    static ref HOSTS: Mutex<HashMap<String, Weak<Host>>> = Mutex::new(HashMap::new());
+    /// The key authorizations for HTTP-01 challenges being checked, by token:
+    static ref HTTP_CHALLENGES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    /// The certificates for TLS-ALPN-01 challenges being checked, by host:
+    static ref TLS_ALPN_CHALLENGES: Mutex<HashMap<String, Arc<CertifiedKey>>> = Mutex::new(HashMap::new());
+}
+
+/// How we prove to the CA that we serve a host.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Challenge {
+    /// Present a certificate made for the challenge to clients asking for
+    /// `acme-tls/1`, on port 443:
+    TlsAlpn01,
+    /// Respond to requests for `/.well-known/acme-challenge/TOKEN`, on port 80:
+    Http01
+}
+
+impl Challenge {
+    pub fn parse(input: &str) -> Result<Challenge, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "tls-alpn-01" => Ok(Challenge::TlsAlpn01),
+            "http-01" => Ok(Challenge::Http01),
+            _ => Err(err!("'{}' is not an ACME challenge; expecting 'tls-alpn-01' or 'http-01'", input))
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Challenge::TlsAlpn01 => "tls-alpn-01",
+            Challenge::Http01 => "http-01"
+        }
+    }
+}
+
+/// Where to get certificates for `https://` sources that aren't given one,
+/// with `--acme`.
+#[derive(Debug,Clone)]
+pub struct AcmeOptions {
+    /// The CA's ACME directory:
+    pub directory: Url,
+    /// Who the CA can tell about problems with our certificates:
+    pub email: Option<String>,
+    pub challenge: Challenge,
+    /// Where we keep our account key and the certificates we get:
+    pub cache: PathBuf
+}
+
+impl AcmeOptions {
+    pub fn new(directory: Url, cache: PathBuf) -> AcmeOptions {
+        AcmeOptions { directory, email: None, challenge: Challenge::TlsAlpn01, cache }
+    }
+
+    pub fn from_matches(matches: &ArgMatches) -> Result<Option<AcmeOptions>, Error> {
+        if !matches.is_present("acme") {
+            return Ok(None);
+        }
+        let mut options = AcmeOptions::new(Url::parse(DEFAULT_DIRECTORY).unwrap(), acceptor::cache_dir().join("acme"));
+        if let Some(s) = matches.value_of("acme-directory") {
+            options.directory = Url::parse(s).map_err(|e| err!("Invalid --acme-directory '{}': {}", s, e))?;
+        }
+        options.email = matches.value_of("acme-email").map(|s| s.to_owned());
+        if let Some(s) = matches.value_of("acme-challenge") {
+            options.challenge = Challenge::parse(s)?;
+        }
+        Ok(Some(options))
+    }
+
+    /// Accounts and certificates are kept apart for each CA, so that
+    /// trying out a staging CA doesn't get in the way of the real one:
+    fn dir(&self) -> PathBuf {
+        let ca = &self.directory[url::Position::BeforeHost..url::Position::AfterPort];
+        self.cache.join(ca.replace(':', "-"))
+    }
+}
+
+/// A host that we get certificates for, and the one we have, if any.
+#[derive(Debug)]
+pub struct Host {
+    name: String,
+    cert: RwLock<Option<Issued>>,
+    /// Is something keeping its certificate renewed?
+    renewing: AtomicBool
+}
+
+#[derive(Debug,Clone)]
+struct Issued {
+    cert: Arc<CertifiedKey>,
+    expires: SystemTime
+}
+
+impl Host {
+    /// A host we get certificates for, shared with any other listeners for
+    /// it. The certificate we got last time is picked up from the cache:
+    pub fn of(name: &str, options: &AcmeOptions) -> Arc<Host> {
+        let mut hosts = HOSTS.lock().unwrap();
+        if let Some(host) = hosts.get(name).and_then(Weak::upgrade) {
+            return host;
+        }
+        hosts.retain(|_, host| host.strong_count() > 0);
+        let (cert_path, key_path) = cert_paths(&options.dir(), name);
+        let cert = if cert_path.exists() && key_path.exists() {
+            match load(&cert_path, &key_path) {
+                Ok(issued) => Some(issued),
+                Err(e) => {
+                    warn!("{}", Red.paint(format!("[acme] Can't use the certificate for {} in the cache ({})", name, e)));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let host = Arc::new(Host {
+            name: name.to_owned(),
+            cert: RwLock::new(cert),
+            renewing: AtomicBool::new(false)
+        });
+        hosts.insert(name.to_owned(), Arc::downgrade(&host));
+        host
+    }
+
+    pub fn certificate(&self) -> Option<Arc<CertifiedKey>> {
+        self.cert.read().unwrap().as_ref().map(|issued| Arc::clone(&issued.cert))
+    }
+
+    /// How long to wait before renewing the certificate, if it doesn't
+    /// need renewing yet:
+    fn renew_in(&self) -> Option<Duration> {
+        let expires = self.cert.read().unwrap().as_ref()?.expires;
+        expires.duration_since(SystemTime::now() + RENEW_BEFORE).ok()
+    }
+}
+
+fn cert_paths(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+    (dir.join(format!("{}.pem", name)), dir.join(format!("{}-key.pem", name)))
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<Issued, Error> {
+    let cert = acceptor::load_certified_key(cert_path, key_path)?;
+    let (_, parsed) = X509Certificate::from_der(cert.end_entity_cert()?)
+        .map_err(|e| err!("Cannot read '{}': {}", cert_path.display(), e))?;
+    let expires = UNIX_EPOCH + Duration::from_secs(parsed.validity().not_after.timestamp().max(0) as u64);
+    Ok(Issued { cert: Arc::new(cert), expires })
+}
+
+/// The certificate for a TLS-ALPN-01 challenge for a host, if the CA is
+/// checking one:
+pub fn challenge_certificate(name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+    TLS_ALPN_CHALLENGES.lock().unwrap().get(&name?.to_lowercase()).cloned()
+}
+
+/// Respond to the CA checking an HTTP-01 challenge, if that's what a
+/// request is for:
+pub fn http_challenge<T>(req: &Request<T>) -> Option<Response<Body>> {
+    let token = req.uri().path().strip_prefix(HTTP_CHALLENGE_PATH)?;
+    let key_authorization = HTTP_CHALLENGES.lock().unwrap().get(token).cloned()?;
+    Some(Response::builder()
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .body(Body::from(key_authorization))
+        .unwrap())
+}
+
+/// Start getting and renewing certificates for the hosts given, unless we
+/// already are. We stop once no routes want them.
+pub fn spawn_orders<'a>(hosts: impl Iterator<Item=&'a Arc<Host>>, client: &HttpsClient, options: &AcmeOptions) {
+    for host in hosts {
+        if host.renewing.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+        tokio::spawn(keep_renewed(client.clone(), options.clone(), Arc::downgrade(host)));
+    }
+}
+
+async fn keep_renewed(client: HttpsClient, options: AcmeOptions, host: Weak<Host>) {
+    loop {
+        // The routes we were getting certificates for are gone, so stop:
+        let host = match host.upgrade() {
+            Some(host) => host,
+            None => return
+        };
+        let wait = match host.renew_in() {
+            Some(renew_in) => renew_in.min(CHECK_INTERVAL),
+            None => match order(&client, &options, &host.name).await {
+                Ok(issued) => {
+                    info!("{}", Green.paint(format!("[acme] Got a certificate for {} from {}", host.name, options.directory)));
+                    *host.cert.write().unwrap() = Some(issued);
+                    CHECK_INTERVAL
+                },
+                Err(e) => {
+                    warn!("{}", Red.paint(format!("[acme] Can't get a certificate for {} ({}); trying again in {:#?}", host.name, e, RETRY_INTERVAL)));
+                    RETRY_INTERVAL
+                }
+            }
+        };
+        drop(host);
+        sleep(wait).await;
+    }
+}
+
+/// Get a certificate for a host from the CA, and keep it in the cache:
+async fn order(client: &HttpsClient, options: &AcmeOptions, name: &str) -> Result<Issued, Error> {
+    let dir = options.dir();
+    std::fs::create_dir_all(&dir).map_err(|e| err!("Cannot create '{}': {}", dir.display(), e))?;
+    let mut account = Account::new(client, options).await?;
+
+    let identifiers = json!({ "identifiers": [{ "type": "dns", "value": name }] });
+    let (headers, mut order): (_, Order) = account.post(&account.directory.new_order.clone(), Some(identifiers)).await?;
+    let order_url = location(&headers)?;
+
+    for authorization_url in &order.authorizations {
+        let (_, authorization): (_, Authorization) = account.post(authorization_url, None).await?;
+        if authorization.status == "valid" {
+            continue;
+        }
+        let challenge = authorization.challenges.iter()
+            .find(|challenge| challenge.kind == options.challenge.name())
+            .ok_or_else(|| err!("The CA doesn't offer a {} challenge", options.challenge.name()))?;
+        let key_authorization = format!("{}.{}", challenge.token, account.thumbprint());
+        let _answering = Answering::start(options.challenge, name, &challenge.token, &key_authorization)?;
+        account.post::<Value>(&challenge.url, Some(json!({}))).await?;
+        let authorization: Authorization = account.poll(authorization_url, |status| status != "pending" && status != "processing").await?;
+        if authorization.status != "valid" {
+            let problem = authorization.challenges.iter().find_map(|challenge| challenge.error.as_ref());
+            return Err(err!("The CA couldn't check the {} challenge: {}", options.challenge.name(), problem.map(problem_detail).unwrap_or_else(|| authorization.status.clone())));
+        }
+    }
+
+    // Ask for a certificate for a key of our own:
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![name.to_owned()])?;
+    params.distinguished_name = DistinguishedName::new();
+    let csr = params.serialize_request(&key)?;
+    if order.status == "pending" {
+        order = account.poll(&order_url, |status| status != "pending").await?;
+    }
+    if order.status == "ready" {
+        account.post::<Value>(&order.finalize, Some(json!({ "csr": base64url(csr.der()) }))).await?;
+        order = account.poll(&order_url, |status| status != "ready" && status != "processing").await?;
+    }
+    let certificate_url = match (order.status.as_str(), &order.certificate) {
+        ("valid", Some(url)) => url.clone(),
+        (status, _) => return Err(err!("The order is {}: {}", status, order.error.as_ref().map(problem_detail).unwrap_or_default()))
+    };
+    let chain = account.post_for_bytes(&certificate_url, None).await?.1;
+
+    let (cert_path, key_path) = cert_paths(&dir, name);
+    acceptor::write_key(&key_path, &key.serialize_pem())?;
+    std::fs::write(&cert_path, chain).map_err(|e| err!("Cannot write '{}': {}", cert_path.display(), e))?;
+    load(&cert_path, &key_path)
+}
+
+/// Answers a challenge while the CA checks it, until dropped:
+struct Answering {
+    challenge: Challenge,
+    name: String,
+    token: String
+}
+
+impl Answering {
+    fn start(challenge: Challenge, name: &str, token: &str, key_authorization: &str) -> Result<Answering, Error> {
+        match challenge {
+            Challenge::Http01 => {
+                HTTP_CHALLENGES.lock().unwrap().insert(token.to_owned(), key_authorization.to_owned());
+            },
+            Challenge::TlsAlpn01 => {
+                let cert = tls_alpn_certificate(name, key_authorization)?;
+                TLS_ALPN_CHALLENGES.lock().unwrap().insert(name.to_owned(), Arc::new(cert));
+            }
+        }
+        Ok(Answering { challenge, name: name.to_owned(), token: token.to_owned() })
+    }
+}
+
+impl Drop for Answering {
+    fn drop(&mut self) {
+        match self.challenge {
+            Challenge::Http01 => { HTTP_CHALLENGES.lock().unwrap().remove(&self.token); },
+            Challenge::TlsAlpn01 => { TLS_ALPN_CHALLENGES.lock().unwrap().remove(&self.name); }
+        }
+    }
+}
+
+/// A certificate for a TLS-ALPN-01 challenge: one for the host, carrying a
+/// digest of the key authorization (RFC 8737):
+fn tls_alpn_certificate(name: &str, key_authorization: &str) -> Result<CertifiedKey, Error> {
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![name.to_owned()])?;
+    let key_digest = digest(&SHA256, key_authorization.as_bytes());
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(key_digest.as_ref())];
+    let cert = params.self_signed(&key)?;
+    // Not `CertifiedKey::from_der`, whose check that the key goes with the
+    // certificate can't parse certificates with this extension:
+    let key = acceptor::provider().key_provider.load_private_key(PrivateKeyDer::Pkcs8(key.serialize_der().into()))?;
+    Ok(CertifiedKey::new(vec![cert.der().clone()], key))
+}
+
+/// The URLs the CA wants us to use, from its directory:
+#[derive(Debug,Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String
+}
+
+#[derive(Debug,Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Value>
+}
+
+#[derive(Debug,Deserialize)]
+struct Authorization {
+    status: String,
+    #[serde(default)]
+    challenges: Vec<ChallengeEntry>
+}
+
+#[derive(Debug,Deserialize)]
+struct ChallengeEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Value>
+}
+
+/// The status of an order or authorization, to poll on:
+trait Status {
+    fn status(&self) -> &str;
+}
+
+impl Status for Order {
+    fn status(&self) -> &str { &self.status }
+}
+
+impl Status for Authorization {
+    fn status(&self) -> &str { &self.status }
+}
+
+/// Our account with the CA, which signs each request we make to it.
+struct Account<'a> {
+    client: &'a HttpsClient,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    /// Our account's URL, once we have one:
+    kid: Option<String>,
+    nonce: Option<String>
+}
+
+impl<'a> Account<'a> {
+    /// Sign in to the CA with the account key in the cache (making one the
+    /// first time), registering the account if the CA doesn't know it:
+    async fn new(client: &'a HttpsClient, options: &AcmeOptions) -> Result<Account<'a>, Error> {
+        let key_path = options.dir().join("account-key.pem");
+        if !key_path.exists() {
+            acceptor::write_key(&key_path, &KeyPair::generate()?.serialize_pem())?;
+        }
+        let key = match acceptor::load_key(&key_path)? {
+            PrivateKeyDer::Pkcs8(der) => EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, der.secret_pkcs8_der())
+                .map_err(|e| err!("Cannot use the account key in '{}': {}", key_path.display(), e))?,
+            _ => return Err(err!("The account key in '{}' isn't a PKCS#8 P-256 key", key_path.display()))
+        };
+        let resp = time::timeout(REQUEST_TIMEOUT, client.get(options.directory.as_str().parse()?, &TlsOptions::default()))
+            .await
+            .map_err(|_| err!("Timed out asking {} for its directory", options.directory))??;
+        if !resp.status().is_success() {
+            return Err(err!("{} responded with {}", options.directory, resp.status()));
+        }
+        let directory = serde_json::from_slice(&proxy::read_body(resp.into_body()).await?)
+            .map_err(|e| err!("Can't understand the directory at {}: {}", options.directory, e))?;
+
+        let mut account = Account { client, directory, key, kid: None, nonce: None };
+        let mut registration = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &options.email {
+            registration["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let (headers, _) = account.post::<Value>(&account.directory.new_account.clone(), Some(registration)).await?;
+        account.kid = Some(location(&headers)?);
+        Ok(account)
+    }
+
+    /// Our public key, as a JSON Web Key:
+    fn jwk(&self) -> Value {
+        // An uncompressed point: 0x04, then x and y:
+        let point = self.key.public_key().as_ref();
+        json!({ "crv": "P-256", "kty": "EC", "x": base64url(&point[1..33]), "y": base64url(&point[33..]) })
+    }
+
+    /// The thumbprint of our key (RFC 7638), which key authorizations end with:
+    fn thumbprint(&self) -> String {
+        // The members are in order, without whitespace, as the RFC asks:
+        let jwk = serde_json::to_string(&self.jwk()).unwrap();
+        base64url(digest(&SHA256, jwk.as_bytes()).as_ref())
+    }
+
+    /// Send something to the CA, or ask it for something (a "POST-as-GET")
+    /// with no payload:
+    async fn post<T: DeserializeOwned>(&mut self, url: &str, payload: Option<Value>) -> Result<(HeaderMap, T), Error> {
+        let (headers, body) = self.post_for_bytes(url, payload).await?;
+        let body = serde_json::from_slice(&body).map_err(|e| err!("Can't understand the CA's response from {}: {}", url, e))?;
+        Ok((headers, body))
+    }
+
+    async fn post_for_bytes(&mut self, url: &str, payload: Option<Value>) -> Result<(HeaderMap, Vec<u8>), Error> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?
+            };
+            let req = Request::post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(self.sign(url, &nonce, payload.as_ref())?))?;
+            let resp = time::timeout(REQUEST_TIMEOUT, self.client.request(req, &TlsOptions::default()))
+                .await
+                .map_err(|_| err!("Timed out sending to {}", url))??;
+            let (parts, body) = resp.into_parts();
+            self.nonce = replay_nonce(&parts.headers);
+            let body = proxy::read_body(body).await?;
+            if parts.status.is_success() {
+                return Ok((parts.headers, body));
+            }
+            // Nonces can go stale, in which case the CA gives us another
+            // to try again with:
+            let problem: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(err!("{} responded with {}: {}", url, parts.status, problem_detail(&problem)));
+        }
+    }
+
+    /// Ask for an order or authorization until its status is one we're
+    /// waiting for:
+    async fn poll<T: DeserializeOwned + Status>(&mut self, url: &str, done: impl Fn(&str) -> bool) -> Result<T, Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            let (_, polled): (_, T) = self.post(url, None).await?;
+            if done(polled.status()) {
+                return Ok(polled);
+            }
+            sleep(POLL_INTERVAL).await;
+        }
+        Err(err!("Gave up waiting on {}", url))
+    }
+
+    async fn new_nonce(&mut self) -> Result<String, Error> {
+        let req = Request::head(self.directory.new_nonce.as_str()).body(Body::empty())?;
+        let resp = time::timeout(REQUEST_TIMEOUT, self.client.request(req, &TlsOptions::default()))
+            .await
+            .map_err(|_| err!("Timed out asking {} for a nonce", self.directory.new_nonce))??;
+        replay_nonce(resp.headers()).ok_or_else(|| err!("{} didn't give us a nonce", self.directory.new_nonce))
+    }
+
+    /// A request to the CA, as a JWS signed with our key. We identify
+    /// ourselves by our account's URL once we have one, and by our key
+    /// until then:
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<Vec<u8>, Error> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk()
+        }
+        let protected = base64url(&serde_json::to_vec(&protected)?);
+        let payload = match payload {
+            Some(payload) => base64url(&serde_json::to_vec(payload)?),
+            None => String::new()
+        };
+        let signature = self.key.sign(&SystemRandom::new(), format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| err!("Cannot sign a request to the CA"))?;
+        Ok(serde_json::to_vec(&json!({ "protected": protected, "payload": payload, "signature": base64url(signature.as_ref()) }))?)
+    }
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+    headers.get("replay-nonce").and_then(|v| v.to_str().ok()).map(|v| v.to_owned())
+}
+
+fn location(headers: &HeaderMap) -> Result<String, Error> {
+    headers.get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+        .ok_or_else(|| err!("The CA didn't say where to find what it made"))
+}
+
+/// What went wrong, from an ACME problem document:
+fn problem_detail(problem: &Value) -> String {
+    match (problem["type"].as_str(), problem["detail"].as_str()) {
+        (_, Some(detail)) => detail.to_owned(),
+        (Some(kind), None) => kind.to_owned(),
+        (None, None) => "no details given".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use rcgen::{ BasicConstraints, IsCa, PublicKeyData, SignatureAlgorithm };
+    use rustls::{ ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme };
+    use rustls::client::danger::{ HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier };
+    use rustls::pki_types::{ CertificateDer, ServerName, UnixTime };
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+    use x509_parser::certification_request::X509CertificationRequest;
+    use crate::listeners;
+    use crate::server::WeaveServer;
+    use crate::settings::{ Settings };
+
+    #[test]
+    fn answers_http_challenges_while_they_are_checked() {
+        let req = |path: &str| Request::get(path).body(()).unwrap();
+        assert!(http_challenge(&req("/.well-known/acme-challenge/http-test-token")).is_none());
+        let answering = Answering::start(Challenge::Http01, "http-test.local", "http-test-token", "http-test-token.thumbprint").unwrap();
+        let resp = http_challenge(&req("/.well-known/acme-challenge/http-test-token")).unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = futures::executor::block_on(proxy::read_body(resp.into_body())).unwrap();
+        assert_eq!(body, b"http-test-token.thumbprint");
+        assert!(http_challenge(&req("/.well-known/acme-challenge/other-token")).is_none());
+        drop(answering);
+        assert!(http_challenge(&req("/.well-known/acme-challenge/http-test-token")).is_none());
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    /// A CA that checks TLS-ALPN-01 challenges on the listener it's told
+    /// about, and issues certificates signed by its own certificate:
+    struct MockCa {
+        base: String,
+        cert: rcgen::Certificate,
+        key: KeyPair,
+        nonces: Mutex<HashSet<String>>,
+        next_nonce: AtomicUsize,
+        jwk: Mutex<Option<Value>>,
+        host: Mutex<Option<String>>,
+        challenged: tokio::sync::watch::Sender<Option<SocketAddr>>,
+        valid: AtomicBool,
+        chain: Mutex<Option<String>>,
+        issued: AtomicUsize
+    }
+
+    /// The public key in a CSR, for rcgen to sign a certificate for:
+    struct CsrKey(Vec<u8>);
+
+    impl PublicKeyData for CsrKey {
+        fn der_bytes(&self) -> &[u8] { &self.0 }
+        fn algorithm(&self) -> &SignatureAlgorithm { &rcgen::PKCS_ECDSA_P256_SHA256 }
+    }
+
+    /// The CA only wants to see the challenge's certificate, not trust it:
+    #[derive(Debug)]
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(&self, _: &CertificateDer, _: &[CertificateDer], _: &ServerName, _: &[u8], _: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+        fn verify_tls12_signature(&self, _: &[u8], _: &CertificateDer, _: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+        fn verify_tls13_signature(&self, _: &[u8], _: &CertificateDer, _: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            acceptor::provider().signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    impl MockCa {
+        fn start() -> Arc<MockCa> {
+            let port = free_port();
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = Arc::new(MockCa {
+                base: format!("http://127.0.0.1:{}", port),
+                cert: params.self_signed(&key).unwrap(),
+                key,
+                nonces: Mutex::new(HashSet::new()),
+                next_nonce: AtomicUsize::new(0),
+                jwk: Mutex::new(None),
+                host: Mutex::new(None),
+                challenged: tokio::sync::watch::channel(None).0,
+                valid: AtomicBool::new(false),
+                chain: Mutex::new(None),
+                issued: AtomicUsize::new(0)
+            });
+            let serving = Arc::clone(&ca);
+            listeners::serve(&SocketAddr::from(([127, 0, 0, 1], port)), move |req| {
+                let ca = Arc::clone(&serving);
+                async move { ca.respond(req).await }
+            }).unwrap();
+            ca
+        }
+
+        fn directory(&self) -> Url {
+            Url::parse(&format!("{}/directory", self.base)).unwrap()
+        }
+
+        fn nonce(&self) -> String {
+            let nonce = format!("nonce-{}", self.next_nonce.fetch_add(1, Ordering::Relaxed));
+            self.nonces.lock().unwrap().insert(nonce.clone());
+            nonce
+        }
+
+        fn reply(&self, status: u16, location: Option<String>, body: Value) -> Response<Body> {
+            let mut resp = Response::builder().status(status).header("replay-nonce", self.nonce());
+            if let Some(location) = location {
+                resp = resp.header(LOCATION, location);
+            }
+            resp.body(Body::from(body.to_string())).unwrap()
+        }
+
+        /// The protected header and payload of a request, once we've
+        /// checked that it's signed by the key it says, and that its nonce
+        /// and URL are right:
+        async fn open(&self, req: Request<Body>) -> Result<(Value, Option<Value>), Response<Body>> {
+            let url = format!("{}{}", self.base, req.uri().path());
+            let jws: Value = serde_json::from_slice(&proxy::read_body(req.into_body()).await.unwrap()).unwrap();
+            let decode = |field: &str| base64::decode_config(jws[field].as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+            let protected: Value = serde_json::from_slice(&decode("protected")).unwrap();
+            if !self.nonces.lock().unwrap().remove(protected["nonce"].as_str().unwrap()) {
+                return Err(self.reply(400, None, json!({ "type": "urn:ietf:params:acme:error:badNonce" })));
+            }
+            assert_eq!(protected["alg"], "ES256");
+            assert_eq!(protected["url"], url);
+            let jwk = match protected["kid"].as_str() {
+                Some(kid) => {
+                    assert_eq!(kid, format!("{}/account/1", self.base));
+                    self.jwk.lock().unwrap().clone().unwrap()
+                },
+                None => protected["jwk"].clone()
+            };
+            let coordinate = |name: &str| base64::decode_config(jwk[name].as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+            let point = [&[4][..], &coordinate("x"), &coordinate("y")].concat();
+            let signed = format!("{}.{}", jws["protected"].as_str().unwrap(), jws["payload"].as_str().unwrap());
+            signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(signed.as_bytes(), &decode("signature"))
+                .expect("a request signed by the account key");
+            let payload = decode("payload");
+            let payload = if payload.is_empty() { None } else { Some(serde_json::from_slice(&payload).unwrap()) };
+            Ok((protected, payload))
+        }
+
+        async fn respond(&self, req: Request<Body>) -> Response<Body> {
+            let base = &self.base;
+            match req.uri().path() {
+                "/directory" => return Response::new(Body::from(json!({
+                    "newNonce": format!("{}/nonce", base),
+                    "newAccount": format!("{}/account", base),
+                    "newOrder": format!("{}/order", base)
+                }).to_string())),
+                "/nonce" => return Response::builder().header("replay-nonce", self.nonce()).body(Body::empty()).unwrap(),
+                _ => {}
+            }
+            let path = req.uri().path().to_owned();
+            let (protected, payload) = match self.open(req).await {
+                Ok(opened) => opened,
+                Err(resp) => return resp
+            };
+            let order = |status: &str| json!({
+                "status": status,
+                "authorizations": [format!("{}/authz/1", base)],
+                "finalize": format!("{}/finalize/1", base),
+                "certificate": format!("{}/cert/1", base)
+            });
+            let status = |valid: bool| if valid { "valid" } else { "pending" };
+            match path.as_str() {
+                "/account" => {
+                    assert_eq!(payload.unwrap()["termsOfServiceAgreed"], true);
+                    *self.jwk.lock().unwrap() = Some(protected["jwk"].clone());
+                    self.reply(201, Some(format!("{}/account/1", base)), json!({ "status": "valid" }))
+                },
+                "/order" => {
+                    let host = payload.unwrap()["identifiers"][0]["value"].as_str().unwrap().to_owned();
+                    *self.host.lock().unwrap() = Some(host);
+                    self.reply(201, Some(format!("{}/order/1", base)), order("pending"))
+                },
+                "/authz/1" => self.reply(200, None, json!({
+                    "status": status(self.valid.load(Ordering::SeqCst)),
+                    "challenges": [
+                        { "type": "http-01", "url": format!("{}/challenge/2", base), "token": "http-token", "status": "pending" },
+                        { "type": "tls-alpn-01", "url": format!("{}/challenge/1", base), "token": "tls-token", "status": status(self.valid.load(Ordering::SeqCst)) }
+                    ]
+                })),
+                "/challenge/1" => {
+                    assert_eq!(payload, Some(json!({})));
+                    // Wait until we know where to check the challenge:
+                    let mut challenged = self.challenged.subscribe();
+                    let addr = *challenged.wait_for(Option::is_some).await.unwrap();
+                    self.valid.store(self.check_tls_alpn(addr.unwrap()).await, Ordering::SeqCst);
+                    self.reply(200, None, json!({ "type": "tls-alpn-01", "url": format!("{}/challenge/1", base), "token": "tls-token", "status": "processing" }))
+                },
+                "/order/1" => {
+                    let status = if self.chain.lock().unwrap().is_some() { "valid" } else if self.valid.load(Ordering::SeqCst) { "ready" } else { "pending" };
+                    self.reply(200, None, order(status))
+                },
+                "/finalize/1" => {
+                    let csr = base64::decode_config(payload.unwrap()["csr"].as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+                    let (_, csr) = X509CertificationRequest::from_der(&csr).unwrap();
+                    let public_key = CsrKey(csr.certification_request_info.subject_pki.subject_public_key.data.to_vec());
+                    let host = self.host.lock().unwrap().clone().unwrap();
+                    let cert = CertificateParams::new(vec![host]).unwrap().signed_by(&public_key, &self.cert, &self.key).unwrap();
+                    *self.chain.lock().unwrap() = Some(format!("{}{}", cert.pem(), self.cert.pem()));
+                    self.issued.fetch_add(1, Ordering::SeqCst);
+                    self.reply(200, Some(format!("{}/order/1", base)), order("processing"))
+                },
+                "/cert/1" => Response::builder()
+                    .header("replay-nonce", self.nonce())
+                    .header(CONTENT_TYPE, "application/pem-certificate-chain")
+                    .body(Body::from(self.chain.lock().unwrap().clone().unwrap()))
+                    .unwrap(),
+                _ => self.reply(404, None, json!({ "type": "urn:ietf:params:acme:error:malformed" }))
+            }
+        }
+
+        /// Connect asking for `acme-tls/1`, and check that the certificate
+        /// we're presented with is for the host, with the digest of the key
+        /// authorization:
+        async fn check_tls_alpn(&self, addr: SocketAddr) -> bool {
+            let mut config = ClientConfig::builder_with_provider(acceptor::provider())
+                .with_safe_default_protocol_versions().unwrap()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+                .with_no_client_auth();
+            config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+            let host = self.host.lock().unwrap().clone().unwrap();
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let stream = match TlsConnector::from(Arc::new(config)).connect(ServerName::try_from(host.clone()).unwrap(), tcp).await {
+                Ok(stream) => stream,
+                Err(_) => return false
+            };
+            let cert = stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+            let (_, cert) = X509Certificate::from_der(&cert).unwrap();
+            let jwk = serde_json::to_string(&self.jwk.lock().unwrap().clone().unwrap()).unwrap();
+            let thumbprint = base64url(digest(&SHA256, jwk.as_bytes()).as_ref());
+            let expected = [&[0x04, 0x20][..], digest(&SHA256, format!("tls-token.{}", thumbprint).as_bytes()).as_ref()].concat();
+            let names_host = cert.subject_alternative_name().unwrap().unwrap().value.general_names.iter()
+                .any(|name| matches!(name, x509_parser::extensions::GeneralName::DNSName(name) if *name == host));
+            let identifier = cert.extensions().iter().find(|ext| ext.oid.to_id_string() == "1.3.6.1.5.5.7.1.31");
+            names_host && identifier.is_some_and(|ext| ext.critical && ext.value == &expected[..])
+        }
+    }
+
+    #[test]
+    fn gets_certificates_with_tls_alpn_challenges() {
+        let cache = std::env::temp_dir().join(format!("weave-acme-test-{}", std::process::id()));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let ca = MockCa::start();
+            let echo = free_port();
+            listeners::serve(&SocketAddr::from(([127, 0, 0, 1], echo)), |_| async { Response::new(Body::from("hello")) }).unwrap();
+            let settings = Settings { acme: Some(AcmeOptions::new(ca.directory(), cache.clone())), ..Settings::default() };
+            let spawned = WeaveServer::builder()
+                .settings(settings)
+                .parse_routes(&format!("https://acme-test.local:8443 to http://127.0.0.1:{}", echo)).unwrap()
+                .spawn()
+                .unwrap();
+            let addr = SocketAddr::from(([127, 0, 0, 1], spawned.addrs[0].port()));
+            ca.challenged.send_replace(Some(addr));
+
+            // Trusting only the CA, wait for the certificate it issues:
+            let mut roots = RootCertStore::empty();
+            roots.add(ca.cert.der().clone()).unwrap();
+            let config = Arc::new(ClientConfig::builder_with_provider(acceptor::provider())
+                .with_safe_default_protocol_versions().unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth());
+            let mut status_line = None;
+            for _ in 0..100 {
+                let tcp = TcpStream::connect(addr).await.unwrap();
+                if let Ok(mut stream) = TlsConnector::from(Arc::clone(&config)).connect(ServerName::try_from("acme-test.local").unwrap(), tcp).await {
+                    stream.write_all(b"GET / HTTP/1.1\r\nhost: acme-test.local\r\nconnection: close\r\n\r\n").await.unwrap();
+                    let mut resp = String::new();
+                    stream.read_to_string(&mut resp).await.unwrap();
+                    status_line = resp.lines().next().map(|line| line.to_owned());
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+            assert_eq!(status_line.as_deref(), Some("HTTP/1.1 200 OK"));
+            assert_eq!(ca.issued.load(Ordering::SeqCst), 1);
+
+            // It's kept for next time, along with our account key:
+            let dir = cache.join(format!("127.0.0.1-{}", ca.directory().port().unwrap()));
+            assert!(dir.join("acme-test.local.pem").exists());
+            assert!(dir.join("acme-test.local-key.pem").exists());
+            assert!(dir.join("account-key.pem").exists());
+        });
+        let _ = std::fs::remove_dir_all(&cache);
+    }
+}
@@ -0,0 +1,275 @@
+use clap::ArgMatches;
+use hyper::{ Method, Request, Response, StatusCode };
+use hyper::header::{ AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE };
+use log::{ info, warn };
+use serde::{ Deserialize, Serialize };
+use std::net::SocketAddr;
+use std::sync::{ Arc, Mutex };
+use crate::auth;
+use crate::body::{ Body };
+use crate::control::{ Control };
+use crate::errors::{ Error };
+use crate::listeners;
+use crate::maintenance;
+use crate::proxy;
+use crate::routes::{ self, Route };
+use crate::stats::{ self, Snapshot };
+
+/// A page for keeping an eye on things in a browser, which uses the API:
+static DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Where to serve the admin API, and the token clients need to use it.
+#[derive(Debug,Clone,PartialEq)]
+pub struct AdminOptions {
+    pub addr: SocketAddr,
+    pub token: String,
+    /// Serve the dashboard at `/` too:
+    pub dashboard: bool
+}
+
+impl AdminOptions {
+    /// The admin API is only served if asked for, and must have a token:
+    pub fn from_matches(matches: &ArgMatches) -> Result<Option<AdminOptions>, Error> {
+        let addr = match matches.value_of("admin") {
+            Some(s) => s.parse().map_err(|_| err!("Invalid --admin '{}': Not a valid socket address", s))?,
+            None => return Ok(None)
+        };
+        let token = match matches.value_of("admin-token") {
+            Some(s) if !s.is_empty() => s.to_owned(),
+            _ => return Err(err!("--admin needs an --admin-token for clients to authenticate with"))
+        };
+        Ok(Some(AdminOptions { addr, token, dashboard: matches.is_present("admin-dashboard") }))
+    }
+}
+
+/// A route we're serving, as the admin API describes it. Its `id` is its
+/// position in the list of routes, so removing a route changes the ids of
+/// those after it.
+#[derive(Debug,Serialize)]
+struct RouteInfo {
+    id: usize,
+    src: String,
+    dests: String,
+    listen: String
+}
+
+#[derive(Debug,Serialize)]
+struct RouteStatsInfo {
+    #[serde(flatten)]
+    route: RouteInfo,
+    #[serde(flatten)]
+    stats: Snapshot
+}
+
+#[derive(Debug,Serialize,Deserialize)]
+struct MaintenanceInfo {
+    down: bool
+}
+
+/// Serve the admin API until we exit:
+pub fn spawn(options: AdminOptions, control: Arc<Mutex<Control>>) -> Result<(), Error> {
+    let (addr, dashboard) = (options.addr, options.dashboard);
+    let token = Arc::new(options.token);
+    listeners::serve(&addr, move |req| {
+        let control = Arc::clone(&control);
+        let token = Arc::clone(&token);
+        async move {
+            handle(req, &control, &token, dashboard).await
+        }
+    }).map_err(|e| err!("Failed to listen on {} for the admin API: {}", addr, e))?;
+    info!("Serving the admin API on {}", addr);
+    Ok(())
+}
+
+async fn handle(req: Request<Body>, control: &Mutex<Control>, token: &str, dashboard: bool) -> Response<Body> {
+    // The dashboard itself has nothing to hide; it asks for the token so
+    // that it can use the API:
+    if dashboard && req.method() == Method::GET && req.uri().path() == "/" {
+        return Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(DASHBOARD))
+            .unwrap();
+    }
+    if !is_authorized(&req, token) {
+        warn!("[401] admin {} {}", req.method(), req.uri().path());
+        let mut resp = json_error(StatusCode::UNAUTHORIZED, "Authentication required");
+        resp.headers_mut().insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+        return resp;
+    }
+
+    let method = req.method().clone();
+    let path = req.uri().path().trim_end_matches('/').to_owned();
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+    let resp = match (&method, segments.as_slice()) {
+        (&Method::GET, ["routes"]) => {
+            json(StatusCode::OK, &route_infos(control.lock().unwrap().routes()))
+        },
+        (&Method::POST, ["routes"]) => {
+            match read_routes(req.into_body()).await {
+                Ok(new_routes) => add_routes(control, new_routes),
+                Err(e) => json_error(StatusCode::BAD_REQUEST, &e.to_string())
+            }
+        },
+        (&Method::DELETE, ["routes", id]) => {
+            match id.parse() {
+                Ok(id) => remove_route(control, id),
+                Err(_) => json_error(StatusCode::NOT_FOUND, &format!("There is no route {}", id))
+            }
+        },
+        (&Method::GET, ["stats"]) => {
+            let control = control.lock().unwrap();
+            let stats: Vec<RouteStatsInfo> = route_infos(control.routes()).into_iter()
+                .zip(control.routes())
+                .map(|(route, r)| RouteStatsInfo { route, stats: stats::for_route(r).snapshot() })
+                .collect();
+            json(StatusCode::OK, &stats)
+        },
+        (&Method::GET, ["errors"]) => {
+            json(StatusCode::OK, &stats::recent_errors())
+        },
+        (&Method::POST, ["reload"]) => {
+            info!("Reloading routes");
+            let mut control = control.lock().unwrap();
+            match control.reload() {
+                Ok(()) => json(StatusCode::OK, &route_infos(control.routes())),
+                Err(e) => json_error(StatusCode::BAD_REQUEST, &format!("Failed to reload routes: {}", e))
+            }
+        },
+        (&Method::GET, ["maintenance"]) => {
+            json(StatusCode::OK, &MaintenanceInfo { down: maintenance::is_down() })
+        },
+        (&Method::PUT, ["maintenance"]) => {
+            let body = proxy::read_body(req.into_body()).await
+                .map_err(|e| err!("Cannot read request: {}", e))
+                .and_then(|body| serde_json::from_slice::<MaintenanceInfo>(&body).map_err(|e| err!("Expecting {{\"down\": true|false}}: {}", e)));
+            match body {
+                Ok(info) => {
+                    maintenance::set_down(info.down);
+                    info!("{}", if info.down { "Down for maintenance" } else { "Back up after maintenance" });
+                    json(StatusCode::OK, &info)
+                },
+                Err(e) => json_error(StatusCode::BAD_REQUEST, &e.to_string())
+            }
+        },
+        (_, ["routes"]) | (_, ["routes", _]) | (_, ["stats"]) | (_, ["errors"]) | (_, ["reload"]) | (_, ["maintenance"]) => {
+            json_error(StatusCode::METHOD_NOT_ALLOWED, &format!("{} is not allowed here", method))
+        },
+        _ => json_error(StatusCode::NOT_FOUND, "Not found")
+    };
+    info!("[{}] admin {} {}", resp.status().as_str(), method, path);
+    resp
+}
+
+/// Does the request have the admin token, as `Authorization: Bearer TOKEN`?
+fn is_authorized<T>(req: &Request<T>, token: &str) -> bool {
+    let value = match req.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        Some(value) => value.trim(),
+        None => return false
+    };
+    let mut parts = value.splitn(2, ' ');
+    let scheme = parts.next().unwrap_or("");
+    let given = parts.next().unwrap_or("").trim();
+    scheme.eq_ignore_ascii_case("bearer") && auth::constant_time_eq(given.as_bytes(), token.as_bytes())
+}
+
+/// Parse routes from a request body, written as they would be on the
+/// command line (eg `8080/api to 9000 with cache`), or as a JSON array of
+/// arguments if any need to contain spaces:
+async fn read_routes(body: Body) -> Result<Vec<Route>, Error> {
+    let body = proxy::read_body(body).await?;
+    let body = String::from_utf8(body).map_err(|_| err!("Routes must be valid UTF-8"))?;
+    let args: Vec<String> = if body.trim_start().starts_with('[') {
+        serde_json::from_str(&body).map_err(|e| err!("Expecting a JSON array of arguments: {}", e))?
+    } else {
+        body.split_whitespace().map(|s| s.to_owned()).collect()
+    };
+    let (routes, rest) = routes::from_args(args)?;
+    let rest: Vec<String> = rest.collect();
+    if !rest.is_empty() {
+        return Err(err!("Unexpected arguments after the routes: {}", rest.join(" ")));
+    }
+    if routes.is_empty() {
+        return Err(err!("No routes have been provided"));
+    }
+    Ok(routes)
+}
+
+fn add_routes(control: &Mutex<Control>, new_routes: Vec<Route>) -> Response<Body> {
+    let mut control = control.lock().unwrap();
+    let first = control.routes().len();
+    let count = new_routes.len();
+    for route in &new_routes {
+        info!("Routing {} to {}", route.src, route.dests_to_string());
+    }
+    match control.add(new_routes) {
+        Ok(()) => json(StatusCode::CREATED, &route_infos(control.routes())[first..first + count]),
+        Err(e) => json_error(StatusCode::BAD_REQUEST, &format!("Failed to add routes: {}", e))
+    }
+}
+
+fn remove_route(control: &Mutex<Control>, id: usize) -> Response<Body> {
+    let mut control = control.lock().unwrap();
+    if id >= control.routes().len() {
+        return json_error(StatusCode::NOT_FOUND, &format!("There is no route {}", id));
+    }
+    let info = route_info(id, &control.routes()[id]);
+    match control.remove(id) {
+        Ok(route) => {
+            info!("Stopped routing {} to {}", route.src, route.dests_to_string());
+            json(StatusCode::OK, &info)
+        },
+        Err(e) => json_error(StatusCode::BAD_REQUEST, &format!("Failed to remove route: {}", e))
+    }
+}
+
+fn route_infos(routes: &[Route]) -> Vec<RouteInfo> {
+    routes.iter().enumerate().map(|(id, route)| route_info(id, route)).collect()
+}
+
+fn route_info(id: usize, route: &Route) -> RouteInfo {
+    RouteInfo {
+        id,
+        src: route.src.to_string(),
+        dests: route.dests_to_string(),
+        listen: route.listen_addr().map(|addr| addr.to_string()).unwrap_or_default()
+    }
+}
+
+fn json<T: Serialize + ?Sized>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = serde_json::to_string_pretty(value).expect("admin responses can be serialized");
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn json_error(status: StatusCode, message: &str) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn checks_the_admin_token() {
+        let req = |auth: &str| Request::builder().header(AUTHORIZATION, auth).body(()).unwrap();
+        assert!(is_authorized(&req("Bearer secret"), "secret"));
+        assert!(is_authorized(&req("bearer  secret "), "secret"));
+        assert!(!is_authorized(&req("Bearer secre"), "secret"));
+        assert!(!is_authorized(&req("Basic secret"), "secret"));
+        assert!(!is_authorized(&Request::new(()), "secret"));
+    }
+
+    #[test]
+    fn reads_routes() {
+        let routes = futures::executor::block_on(read_routes(Body::from("8080/a to 9000 and 8080/b to 9001"))).unwrap();
+        assert_eq!(routes.len(), 2);
+        let routes = futures::executor::block_on(read_routes(Body::from(r#"["8080/a", "to", "9000"]"#))).unwrap();
+        assert_eq!(routes.len(), 1);
+        assert!(futures::executor::block_on(read_routes(Body::from("8080/a to 9000 --insecure"))).is_err());
+        assert!(futures::executor::block_on(read_routes(Body::from(""))).is_err());
+    }
+}
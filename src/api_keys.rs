@@ -0,0 +1,165 @@
+use hyper::{ HeaderMap, Response };
+use hyper::header::{ AUTHORIZATION, WWW_AUTHENTICATE };
+use lazy_static::lazy_static;
+use log::{ warn };
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::time::SystemTime;
+use crate::auth;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+
+lazy_static! {
+    /// The keys in each key file, and when that file was last modified, so
+    /// that we notice when it changes:
+    static ref KEY_FILES: Mutex<HashMap<PathBuf, KeyFile>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone)]
+struct KeyFile {
+    modified: Option<SystemTime>,
+    keys: Arc<Vec<ApiKey>>
+}
+
+/// Added to the extensions of a response to a request that used an API
+/// key, so that we can log whose key it was (but not the key itself).
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ApiKeyLabel(pub String);
+
+/// Require requests to a route to carry one of the API keys in a file,
+/// either in an `X-Api-Key` header or as a bearer token. The file has a
+/// `label:key` per line, and is read again whenever it changes.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct ApiKeys {
+    pub path: Option<PathBuf>
+}
+
+#[derive(Debug,Clone,PartialEq)]
+struct ApiKey {
+    label: String,
+    key: String
+}
+
+impl ApiKeys {
+    /// Use the keys in a file, complaining now if we can't read them:
+    pub fn from_file(path: &Path) -> Result<ApiKeys, Error> {
+        keys(path)?;
+        Ok(ApiKeys { path: Some(path.to_owned()) })
+    }
+}
+
+/// Check the API key on a request, if the route asks for one, handing back
+/// the label of the key that was used. If the key is missing or wrong, this
+/// hands back a response to send instead.
+pub fn authenticate(headers: &HeaderMap, options: &ApiKeys) -> Result<Result<Option<ApiKeyLabel>, Response<Body>>, Error> {
+    let path = match &options.path {
+        Some(path) => path,
+        None => return Ok(Ok(None))
+    };
+    let keys = keys(path)?;
+    let given = match key_from_headers(headers) {
+        Some(given) => given,
+        None => return Ok(Err(unauthorized("an API key is required")))
+    };
+    match keys.iter().find(|k| auth::constant_time_eq(k.key.as_bytes(), given.as_bytes())) {
+        Some(key) => Ok(Ok(Some(ApiKeyLabel(key.label.clone())))),
+        None => Ok(Err(unauthorized("the API key is not valid")))
+    }
+}
+
+fn unauthorized(reason: &str) -> Response<Body> {
+    Response::builder()
+        .status(401)
+        .header(WWW_AUTHENTICATE, "Bearer realm=\"weave\"")
+        .body(Body::from(format!("Weave: Not authorized ({})", reason)))
+        .unwrap()
+}
+
+/// Keys can be given in an `X-Api-Key` header, or as a bearer token:
+fn key_from_headers(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key") {
+        return key.to_str().ok().map(|k| k.trim());
+    }
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = value.trim().splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(key)) if scheme.eq_ignore_ascii_case("bearer") => Some(key.trim()),
+        _ => None
+    }
+}
+
+/// The keys in a file, reading it again if it's been modified. If it can't
+/// be read any more, we stick with the keys we had.
+fn keys(path: &Path) -> Result<Arc<Vec<ApiKey>>, Error> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut key_files = KEY_FILES.lock().unwrap();
+    let cached = key_files.get(path).cloned();
+    if let Some(cached) = &cached {
+        if cached.modified == modified {
+            return Ok(cached.keys.clone());
+        }
+    }
+
+    let read = fs::read_to_string(path)
+        .map_err(|e| err!("Cannot read API key file '{}': {}", path.display(), e))
+        .and_then(|contents| parse_keys(&contents).map_err(|e| err!("Cannot use API key file '{}': {}", path.display(), e)));
+    match (read, cached) {
+        (Ok(keys), _) => {
+            let keys = Arc::new(keys);
+            key_files.insert(path.to_owned(), KeyFile { modified, keys: keys.clone() });
+            Ok(keys)
+        },
+        (Err(e), Some(cached)) => {
+            warn!("{}; still using the keys it had before", e);
+            key_files.insert(path.to_owned(), KeyFile { modified, keys: cached.keys.clone() });
+            Ok(cached.keys)
+        },
+        (Err(e), None) => Err(e)
+    }
+}
+
+fn parse_keys(contents: &str) -> Result<Vec<ApiKey>, Error> {
+    let lines = contents.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    let mut keys = vec![];
+    for (n, line) in lines.enumerate() {
+        let idx = line.find(':').ok_or_else(|| err!("Entry {} is not like 'label:key'", n + 1))?;
+        let (label, key) = (line[..idx].trim(), line[idx+1..].trim());
+        if label.is_empty() || key.is_empty() {
+            return Err(err!("Entry {} is not like 'label:key'", n + 1));
+        }
+        keys.push(ApiKey { label: label.to_owned(), key: key.to_owned() });
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn finds_keys_in_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(key_from_headers(&headers), None);
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer abc123"));
+        assert_eq!(key_from_headers(&headers), Some("abc123"));
+        headers.insert("x-api-key", HeaderValue::from_static("def456"));
+        assert_eq!(key_from_headers(&headers), Some("def456"));
+    }
+
+    #[test]
+    fn parses_key_files() {
+        let keys = parse_keys("# Comments are ignored\n\nci: abc123\nalice:def:456\n").unwrap();
+        assert_eq!(keys, vec![
+            ApiKey { label: "ci".to_owned(), key: "abc123".to_owned() },
+            ApiKey { label: "alice".to_owned(), key: "def:456".to_owned() }
+        ]);
+        assert!(parse_keys("abc123").is_err());
+        assert!(parse_keys("ci:").is_err());
+    }
+}
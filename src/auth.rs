@@ -0,0 +1,194 @@
+use hyper::{ HeaderMap, Response };
+use hyper::header::{ AUTHORIZATION, WWW_AUTHENTICATE };
+use std::fs;
+use std::path::Path;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+
+/// The users allowed to access a route using HTTP Basic authentication. If
+/// there are none, anybody can access it. Note that bcrypt passwords are
+/// deliberately slow to check, and are checked on every request.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct BasicAuth {
+    users: Vec<User>
+}
+
+#[derive(Debug,Clone,PartialEq)]
+struct User {
+    name: String,
+    password: Password
+}
+
+/// The password formats we understand, as written by `htpasswd`:
+#[derive(Debug,Clone,PartialEq)]
+enum Password {
+    /// `htpasswd -p`:
+    Plain(String),
+    /// `htpasswd -s` (`{SHA}` then a base64 SHA-1 digest):
+    Sha1(Vec<u8>),
+    /// `htpasswd -B`:
+    Bcrypt(String)
+}
+
+impl BasicAuth {
+    /// Add a user from something like `user:pass`:
+    pub fn add_user(&mut self, input: &str) -> Result<(), Error> {
+        let (name, password) = split_user(input)
+            .ok_or_else(|| err!("Expecting a username and password like 'user:pass' but got '{}'", input))?;
+        self.users.push(User { name, password: Password::Plain(password) });
+        Ok(())
+    }
+
+    /// Add each of the users in an htpasswd file:
+    pub fn add_htpasswd_file(&mut self, path: &Path) -> Result<(), Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| err!("Cannot read htpasswd file '{}': {}", path.display(), e))?;
+        self.add_htpasswd(&contents)
+            .map_err(|e| err!("Cannot use htpasswd file '{}': {}", path.display(), e))
+    }
+
+    fn add_htpasswd(&mut self, contents: &str) -> Result<(), Error> {
+        let lines = contents.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        for (n, line) in lines.enumerate() {
+            let (name, hash) = split_user(line)
+                .ok_or_else(|| err!("Entry {} is not like 'user:password'", n + 1))?;
+            let password = Password::parse(&hash)
+                .map_err(|e| err!("Cannot use the password for '{}': {}", name, e))?;
+            self.users.push(User { name, password });
+        }
+        Ok(())
+    }
+
+    /// Does the request carry the credentials of one of our users? Routes
+    /// without users let everything through.
+    pub fn allows(&self, headers: &HeaderMap) -> bool {
+        if self.users.is_empty() {
+            return true;
+        }
+        let (name, password) = match credentials(headers) {
+            Some(creds) => creds,
+            None => return false
+        };
+        self.users.iter().any(|user| user.name == name && user.password.verify(&password))
+    }
+}
+
+impl Password {
+    fn parse(hash: &str) -> Result<Password, Error> {
+        if let Some(digest) = hash.strip_prefix("{SHA}") {
+            let digest = base64::decode(digest).map_err(|_| err!("Invalid SHA-1 digest"))?;
+            Ok(Password::Sha1(digest))
+        } else if hash.starts_with("$2y$") || hash.starts_with("$2b$") || hash.starts_with("$2a$") {
+            Ok(Password::Bcrypt(hash.to_owned()))
+        } else if hash.starts_with("$apr1$") || hash.starts_with("$1$") {
+            Err(err!("MD5 passwords aren't supported; use bcrypt (htpasswd -B) instead"))
+        } else if hash.starts_with('$') {
+            Err(err!("Unknown password format"))
+        } else {
+            Ok(Password::Plain(hash.to_owned()))
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Password::Plain(expected) => constant_time_eq(expected.as_bytes(), password.as_bytes()),
+            Password::Sha1(digest) => constant_time_eq(digest, &sha1::Sha1::from(password).digest().bytes()),
+            Password::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false)
+        }
+    }
+}
+
+/// Respond to a request that hasn't authenticated itself:
+pub fn unauthorized() -> Response<Body> {
+    Response::builder()
+        .status(401)
+        .header(WWW_AUTHENTICATE, "Basic realm=\"weave\", charset=\"UTF-8\"")
+        .body(Body::from("Weave: Authentication required"))
+        .unwrap()
+}
+
+/// The username a request gives in a `Authorization: Basic ...` header,
+/// whether or not the password is right, for logging:
+pub fn username(headers: &HeaderMap) -> Option<String> {
+    credentials(headers).map(|(name, _)| name)
+}
+
+/// The username and password from a `Authorization: Basic ...` header:
+fn credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = value.trim().splitn(2, ' ');
+    if !parts.next()?.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::decode(parts.next()?.trim()).ok()?;
+    split_user(&String::from_utf8(decoded).ok()?)
+}
+
+fn split_user(input: &str) -> Option<(String, String)> {
+    let idx = input.find(':')?;
+    let (name, password) = (&input[..idx], &input[idx+1..]);
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_owned(), password.to_owned()))
+}
+
+/// Compare secrets without giving away how much of them matched:
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn with_auth(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn checks_basic_auth_credentials() {
+        let mut auth = BasicAuth::default();
+        assert!(auth.allows(&HeaderMap::new()));
+
+        auth.add_user("alice:s3cret:ish").unwrap();
+        assert!(!auth.allows(&HeaderMap::new()));
+        // alice:s3cret:ish
+        assert!(auth.allows(&with_auth("Basic YWxpY2U6czNjcmV0OmlzaA==")));
+        assert!(auth.allows(&with_auth("basic YWxpY2U6czNjcmV0OmlzaA==")));
+        // alice:s3cret
+        assert!(!auth.allows(&with_auth("Basic YWxpY2U6czNjcmV0")));
+        assert!(!auth.allows(&with_auth("Bearer YWxpY2U6czNjcmV0OmlzaA==")));
+        assert!(!auth.allows(&with_auth("Basic !!!")));
+
+        assert!(auth.add_user("alice").is_err());
+        assert!(auth.add_user(":pass").is_err());
+    }
+
+    #[test]
+    fn reads_htpasswd_entries() {
+        let mut auth = BasicAuth::default();
+        auth.add_htpasswd("\
+            # Comments and blank lines are ignored\n\
+            \n\
+            plain:password\n\
+            sha:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n\
+            bcrypt:$2y$05$COJ.LC2P2r2SzQPUkODVt.CVwww9zTNCzBnwpvUYP4o8g.sgBNIoi\n\
+        ").unwrap();
+        // plain:password, sha:password and bcrypt:password
+        assert!(auth.allows(&with_auth("Basic cGxhaW46cGFzc3dvcmQ=")));
+        assert!(auth.allows(&with_auth("Basic c2hhOnBhc3N3b3Jk")));
+        assert!(auth.allows(&with_auth("Basic YmNyeXB0OnBhc3N3b3Jk")));
+        // sha:wibble
+        assert!(!auth.allows(&with_auth("Basic c2hhOndpYmJsZQ==")));
+
+        assert!(BasicAuth::default().add_htpasswd("md5:$apr1$abc$def").is_err());
+        assert!(BasicAuth::default().add_htpasswd("nopassword").is_err());
+    }
+}
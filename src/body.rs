@@ -0,0 +1,173 @@
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::{ SinkExt, StreamExt };
+use http_body::{ Frame, SizeHint };
+use http_body_util::{ BodyExt, Empty, Full };
+use http_body_util::combinators::BoxBody;
+use hyper::body::Incoming;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::task::{ Context, Poll };
+use crate::errors::{ Error };
+
+/// The body of a request or response, whether it arrived from a client,
+/// came back from a destination, or is one of our own. Any of these can
+/// be sent on to a client or a destination.
+pub struct Body(BoxBody<Bytes, Error>);
+
+impl Body {
+    pub fn empty() -> Body {
+        Body(Empty::new().map_err(|never| match never {}).boxed())
+    }
+
+    /// A body that's sent as we're given the chunks of it, ending once the
+    /// sender is dropped:
+    pub fn channel() -> (Sender, Body) {
+        let (chunks, rx) = mpsc::channel(0);
+        let aborted = Arc::new(AtomicBool::new(false));
+        let channel = Channel { rx, aborted: Arc::clone(&aborted) };
+        (Sender { chunks, aborted }, Body(channel.boxed()))
+    }
+
+    /// The next chunk of the body, skipping over any trailers:
+    pub async fn next(&mut self) -> Option<Result<Bytes, Error>> {
+        loop {
+            match self.0.frame().await? {
+                Ok(frame) => if let Ok(data) = frame.into_data() {
+                    return Some(Ok(data));
+                },
+                Err(e) => return Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Body {
+        Body::empty()
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Body")
+    }
+}
+
+impl http_body::Body for Body {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        Pin::new(&mut self.0).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.size_hint()
+    }
+}
+
+impl From<Incoming> for Body {
+    fn from(body: Incoming) -> Body {
+        Body(body.map_err(Error::from).boxed())
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(bytes: Bytes) -> Body {
+        Body(Full::new(bytes).map_err(|never| match never {}).boxed())
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::from(Bytes::from(bytes))
+    }
+}
+
+impl From<&'static [u8]> for Body {
+    fn from(bytes: &'static [u8]) -> Body {
+        Body::from(Bytes::from_static(bytes))
+    }
+}
+
+impl From<String> for Body {
+    fn from(s: String) -> Body {
+        Body::from(Bytes::from(s))
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(s: &'static str) -> Body {
+        Body::from(Bytes::from_static(s.as_bytes()))
+    }
+}
+
+/// Sends the chunks of a body made by `Body::channel`.
+pub struct Sender {
+    chunks: mpsc::Sender<Bytes>,
+    aborted: Arc<AtomicBool>
+}
+
+impl Sender {
+    /// Send a chunk, once the body is ready for another. Errors if the
+    /// body has been dropped:
+    pub async fn send_data(&mut self, chunk: Bytes) -> Result<(), Error> {
+        self.chunks.send(chunk).await.map_err(|_| err!("The body was dropped"))
+    }
+
+    /// End the body with an error, so that whoever's reading it knows it
+    /// was cut short:
+    pub fn abort(self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+}
+
+struct Channel {
+    rx: mpsc::Receiver<Bytes>,
+    aborted: Arc<AtomicBool>
+}
+
+impl http_body::Body for Channel {
+    type Data = Bytes;
+    type Error = Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Frame<Bytes>, Error>>> {
+        match self.rx.poll_next_unpin(cx) {
+            Poll::Ready(Some(chunk)) => Poll::Ready(Some(Ok(Frame::data(chunk)))),
+            Poll::Ready(None) if self.aborted.swap(false, Ordering::SeqCst) => Poll::Ready(Some(Err(err!("The body was aborted")))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn sends_chunks_until_aborted() {
+        let (mut sender, mut body) = Body::channel();
+        let sending = async move {
+            sender.send_data(Bytes::from_static(b"hello")).await.unwrap();
+            sender.abort();
+        };
+        let (_, chunks) = block_on(futures::future::join(sending, async {
+            let mut chunks = vec![];
+            while let Some(chunk) = body.next().await {
+                chunks.push(chunk.map_err(|e| e.to_string()));
+            }
+            chunks
+        }));
+        assert_eq!(chunks, vec![Ok(Bytes::from_static(b"hello")), Err("The body was aborted".to_owned())]);
+    }
+}
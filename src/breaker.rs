@@ -0,0 +1,92 @@
+use ansi_term::Color::{ Green, Red };
+use log::{ info, warn };
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+/// A circuit breaker for a single destination. After enough consecutive
+/// failures it trips, and requests are refused until the cooldown has passed.
+/// After that, requests are let through again; the first failure trips the
+/// breaker again, and the first success resets it.
+#[derive(Debug,Default)]
+pub struct Breaker {
+    state: Mutex<BreakerState>
+}
+
+#[derive(Debug,Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>
+}
+
+impl Breaker {
+    pub fn new() -> Breaker {
+        Breaker::default()
+    }
+
+    /// Should a request be let through to the destination?
+    pub fn allow(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) => Instant::now() >= until,
+            None => true
+        }
+    }
+
+    /// Record that a request to the destination succeeded:
+    pub fn record_success(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.take().is_some() {
+            let closed_string = format!("[breaker closed] {}", name);
+            info!("{}", Green.paint(closed_string));
+        }
+        state.consecutive_failures = 0;
+    }
+
+    /// Record that a request to the destination failed, tripping the
+    /// breaker for `cooldown` if we've now seen `threshold` failures in a row:
+    pub fn record_failure(&self, name: &str, threshold: u32, cooldown: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures >= threshold {
+            state.open_until = Some(Instant::now() + cooldown);
+            let open_string = format!("[breaker open] {} after {} failures, cooling down for {:#?}",
+                                      name,
+                                      state.consecutive_failures,
+                                      cooldown);
+            warn!("{}", Red.paint(open_string));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn trips_after_threshold_and_resets_on_success() {
+        let breaker = Breaker::new();
+        let long = Duration::from_secs(60);
+
+        breaker.record_failure("test", 3, long);
+        breaker.record_failure("test", 3, long);
+        assert!(breaker.allow());
+        breaker.record_failure("test", 3, long);
+        assert!(!breaker.allow());
+
+        breaker.record_success("test");
+        assert!(breaker.allow());
+        breaker.record_failure("test", 3, long);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn lets_requests_through_after_cooldown() {
+        let breaker = Breaker::new();
+        breaker.record_failure("test", 1, Duration::from_millis(0));
+        assert!(breaker.allow());
+        // Still failing, so the next failure trips it again straight away:
+        breaker.record_failure("test", 1, Duration::from_secs(60));
+        assert!(!breaker.allow());
+    }
+}
@@ -0,0 +1,581 @@
+use futures::channel::oneshot;
+use hyper::{ HeaderMap, Method, Request, Response, StatusCode };
+use hyper::header::{ HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONNECTION, DATE, EXPIRES, PRAGMA, RANGE, SET_COOKIE, TRANSFER_ENCODING, VARY };
+use lazy_static::lazy_static;
+use log::{ debug, info, warn };
+use std::collections::{ HashMap, HashSet };
+use std::path::Path;
+use std::sync::{ Arc, Mutex, RwLock };
+use std::time::{ Duration, Instant, SystemTime };
+use tokio::time;
+use url::Url;
+use crate::body::{ Body };
+use crate::disk_cache::{ DiskCache, Found };
+use crate::errors::{ Error };
+
+/// How much memory cached responses can take up, if not provided:
+pub const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// How long a request waits for an identical one that's already on its way
+/// to the destination, before going there itself:
+const COALESCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// Responses from the destinations of routes with caching turned on,
+    /// shared by them all:
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache::default());
+    /// Where responses are cached on disk too, if anywhere:
+    static ref DISK: RwLock<Option<Arc<DiskCache>>> = RwLock::new(None);
+    /// The requests waiting for identical ones on their way to destinations
+    /// to come back, by cache key:
+    static ref FETCHING: Mutex<HashMap<String, Vec<oneshot::Sender<bool>>>> = Mutex::new(HashMap::new());
+}
+
+/// Keep responses to GET requests for routes that ask us to, and answer
+/// later requests with them while they're fresh. How long a response is
+/// fresh for is up to its `Cache-Control` or `Expires` headers, unless the
+/// route has a TTL of its own.
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub struct CacheOptions {
+    pub enabled: bool,
+    /// Keep responses for this long regardless of what they say:
+    pub ttl: Option<Duration>
+}
+
+/// Added to the extensions of responses to requests we could have answered
+/// from the cache, so that we can log whether we did.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    /// The total size of the entries:
+    size: usize,
+    /// Counts up each time an entry is used, so that we can
+    /// evict the least recently used ones:
+    tick: u64
+}
+
+struct Entry {
+    head: Head,
+    body: Vec<u8>,
+    last_used: u64
+}
+
+/// Everything about a cached response besides its body.
+#[derive(Debug,Clone)]
+pub struct Head {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// The values of the request headers the response varies on:
+    pub vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    pub stored: SystemTime,
+    /// How old the response already was when we got it:
+    pub initial_age: Duration,
+    pub fresh_for: Duration
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        let headers: usize = self.head.headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+        self.body.len() + headers
+    }
+}
+
+impl Head {
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored.elapsed().unwrap_or_default()
+    }
+
+    /// Can this response be used to answer a request with these headers?
+    pub fn is_fresh_for(&self, req_headers: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, value)| req_headers.get(name) == value.as_ref())
+            && self.age() < self.fresh_for
+    }
+
+    fn into_response(self, body: Body) -> Response<Body> {
+        let age = self.age();
+        let mut resp = Response::builder()
+            .status(self.status)
+            .body(body)
+            .unwrap();
+        *resp.headers_mut() = self.headers;
+        resp.headers_mut().insert(AGE, HeaderValue::from(age.as_secs()));
+        resp.extensions_mut().insert(CacheStatus::Hit);
+        resp
+    }
+}
+
+/// Cache responses on disk as well as in memory, in the directory provided.
+/// Responses already there from last time are picked up.
+pub fn use_disk(dir: &Path, max_size: usize) -> Result<(), Error> {
+    let disk = DiskCache::open(dir, max_size as u64)?;
+    let (count, size) = disk.usage();
+    info!("Caching responses in {} ({} already cached, taking up {} bytes)", dir.display(), count, size);
+    *DISK.write().unwrap() = Some(Arc::new(disk));
+    Ok(())
+}
+
+fn disk() -> Option<Arc<DiskCache>> {
+    DISK.read().unwrap().clone()
+}
+
+/// How big a response can be to keep in memory. With a disk tier, only
+/// responses up to a sixteenth of the memory we have are kept there too,
+/// so that a few large ones don't push everything else out.
+fn memory_limit(max_size: usize, has_disk: bool) -> usize {
+    if has_disk { max_size / 16 } else { max_size }
+}
+
+/// A request that may be answered from the cache, and whose response may
+/// be stored in it.
+#[derive(Debug)]
+pub struct CacheRequest {
+    key: String,
+    headers: HeaderMap,
+    ttl: Option<Duration>,
+    /// The client wants a response from the destination, not the cache:
+    no_cache: bool,
+    /// Set if we're the request others are waiting on:
+    fetching: Option<Fetching>
+}
+
+/// Held while a request that missed the cache is on its way to the
+/// destination, so that identical requests wait for its response rather
+/// than all going to the destination at once.
+#[derive(Debug)]
+struct Fetching {
+    key: String,
+    /// Whether we cached the response we got:
+    cached: bool
+}
+
+impl Fetching {
+    /// Note that we're fetching `key`, unless someone else already is, in
+    /// which case we hand back something that resolves when they're done,
+    /// to `true` if they cached what they got.
+    fn start(key: &str) -> Result<Fetching, oneshot::Receiver<bool>> {
+        let mut fetching = FETCHING.lock().unwrap();
+        match fetching.get_mut(key) {
+            Some(waiting) => {
+                let (sender, receiver) = oneshot::channel();
+                waiting.push(sender);
+                Err(receiver)
+            },
+            None => {
+                fetching.insert(key.to_owned(), vec![]);
+                Ok(Fetching { key: key.to_owned(), cached: false })
+            }
+        }
+    }
+
+    fn finish(mut self, cached: bool) {
+        self.cached = cached;
+    }
+}
+
+impl Drop for Fetching {
+    /// Let those waiting on us know how it went. If we didn't cache the
+    /// response, they go to the destination themselves.
+    fn drop(&mut self) {
+        let waiting = FETCHING.lock().unwrap().remove(&self.key).unwrap_or_default();
+        for sender in waiting {
+            let _ = sender.send(self.cached);
+        }
+    }
+}
+
+impl CacheRequest {
+    /// Requests are cacheable if the route has caching turned on, and they're
+    /// GET requests for a whole resource without credentials.
+    pub fn new(req: &Request<Body>, url: &Url, options: &CacheOptions) -> Option<CacheRequest> {
+        let headers = req.headers();
+        if !options.enabled
+            || req.method() != Method::GET
+            || url.scheme() == "h2c"
+            || headers.contains_key(AUTHORIZATION)
+            || headers.contains_key(RANGE)
+            || has_directive(headers, CACHE_CONTROL, "no-store") {
+            return None;
+        }
+        let no_cache = has_directive(headers, CACHE_CONTROL, "no-cache")
+            || has_directive(headers, PRAGMA, "no-cache");
+        Some(CacheRequest { key: url.to_string(), headers: headers.clone(), ttl: options.ttl, no_cache, fetching: None })
+    }
+
+    /// The cached response to this request, if we have a fresh one. If not,
+    /// but an identical request is already on its way to the destination,
+    /// we wait for its response to be cached and use that instead.
+    pub async fn cached(&mut self, max_size: usize) -> Option<Response<Body>> {
+        if self.no_cache {
+            return None;
+        }
+        if let Some(resp) = self.lookup(max_size).await {
+            return Some(resp);
+        }
+        match Fetching::start(&self.key) {
+            Ok(fetching) => {
+                self.fetching = Some(fetching);
+                None
+            },
+            Err(done) => {
+                let waited_since = Instant::now();
+                match time::timeout(COALESCE_TIMEOUT, done).await {
+                    Ok(Ok(true)) => {
+                        debug!("Waited {:#?} for {} to be cached", waited_since.elapsed(), self.key);
+                        self.lookup(max_size).await
+                    },
+                    _ => None
+                }
+            }
+        }
+    }
+
+    /// Look in memory first, and then on disk, keeping what we find there in
+    /// memory too if it's small enough.
+    async fn lookup(&self, max_size: usize) -> Option<Response<Body>> {
+        if let Some(resp) = self.cached_in_memory() {
+            return Some(resp);
+        }
+
+        let disk = disk()?;
+        let limit = memory_limit(max_size, true);
+        match disk.get(&self.key, &self.headers, limit as u64).await? {
+            Found::Read(head, body) => {
+                let entry = Entry { head: head.clone(), body: body.clone(), last_used: 0 };
+                insert(&mut CACHE.lock().unwrap(), self.key.clone(), entry, max_size);
+                Some(head.into_response(Body::from(body)))
+            },
+            Found::Streamed(head, body) => Some(head.into_response(body))
+        }
+    }
+
+    fn cached_in_memory(&self) -> Option<Response<Body>> {
+        let mut cache = CACHE.lock().unwrap();
+        cache.tick += 1;
+        let tick = cache.tick;
+        let entry = cache.entries.get_mut(&self.key)?;
+        if !entry.head.is_fresh_for(&self.headers) {
+            return None;
+        }
+        entry.last_used = tick;
+        Some(entry.head.clone().into_response(Body::from(entry.body.clone())))
+    }
+
+    /// Pass a response from the destination on, keeping a copy of it
+    /// as it goes by if it can be cached.
+    pub fn store(self, mut resp: Response<Body>, max_size: usize) -> Response<Body> {
+        resp.extensions_mut().insert(CacheStatus::Miss);
+        let fresh_for = match freshness(resp.status(), resp.headers(), self.ttl) {
+            Some(fresh_for) => fresh_for,
+            None => return resp
+        };
+        let vary = match vary(resp.headers(), &self.headers) {
+            Some(vary) => vary,
+            None => return resp
+        };
+
+        let mut headers = resp.headers().clone();
+        for name in &[CONNECTION, TRANSFER_ENCODING] {
+            headers.remove(name);
+        }
+        let initial_age = resp.headers().get(AGE)
+            .and_then(|age| age.to_str().ok())
+            .and_then(|age| age.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        let head = Head { status: resp.status(), headers, vary, stored: SystemTime::now(), initial_age, fresh_for };
+        let key = self.key;
+        let fetching = self.fetching;
+
+        resp.map(|mut body| {
+            let (mut sender, new_body) = Body::channel();
+            tokio::spawn(async move {
+                let disk = disk();
+                let limit = memory_limit(max_size, disk.is_some());
+                let mut writer = match &disk {
+                    Some(disk) => disk.writer(&key).await
+                        .map_err(|e| warn!("Can't cache {} on disk: {}", key, e))
+                        .ok(),
+                    None => None
+                };
+                let mut bytes = Some(vec![]);
+                while let Some(chunk) = body.next().await {
+                    let chunk = match chunk {
+                        Ok(chunk) => chunk,
+                        Err(_) => {
+                            sender.abort();
+                            return;
+                        }
+                    };
+                    bytes = bytes.filter(|bytes| bytes.len() + chunk.len() <= limit);
+                    if let Some(bytes) = &mut bytes {
+                        bytes.extend_from_slice(&chunk);
+                    }
+                    // The client has gone away, so we don't have it all:
+                    if sender.send_data(chunk.clone()).await.is_err() {
+                        return;
+                    }
+                    if let Some(w) = &mut writer {
+                        if let Err(e) = w.write(&chunk).await {
+                            debug!("Not caching {} on disk: {}", key, e);
+                            writer = None;
+                        }
+                    }
+                }
+                let mut cached = false;
+                if let Some(writer) = writer {
+                    match writer.finish(&head).await {
+                        Ok(()) => cached = true,
+                        Err(e) => warn!("Can't cache {} on disk: {}", key, e)
+                    }
+                }
+                match bytes {
+                    Some(body) => cached |= insert(&mut CACHE.lock().unwrap(), key, Entry { head, body, last_used: 0 }, max_size),
+                    None => debug!("Not caching {} in memory (it's too big)", key)
+                }
+                if let Some(fetching) = fetching {
+                    fetching.finish(cached);
+                }
+            });
+            new_body
+        })
+    }
+}
+
+/// Drop the cached responses from these URLs, or from anywhere under them
+/// if `prefix` is set, handing back how many were dropped.
+pub fn purge(urls: &[Url], prefix: bool) -> usize {
+    let mut purged: HashSet<String> = purge_from(&mut CACHE.lock().unwrap(), urls, prefix).into_iter().collect();
+    if let Some(disk) = disk() {
+        purged.extend(disk.purge(|key| matches_purge(key, urls, prefix)));
+    }
+    purged.len()
+}
+
+fn purge_from(cache: &mut Cache, urls: &[Url], prefix: bool) -> Vec<String> {
+    let purged: Vec<String> = cache.entries.keys()
+        .filter(|key| matches_purge(key, urls, prefix))
+        .cloned()
+        .collect();
+    for key in &purged {
+        let old = cache.entries.remove(key).unwrap();
+        cache.size -= old.size();
+    }
+    purged
+}
+
+fn matches_purge(key: &str, urls: &[Url], prefix: bool) -> bool {
+    urls.iter().any(|url| {
+        if prefix { key.starts_with(url.as_str()) } else { key == url.as_str() }
+    })
+}
+
+/// Add an entry to the cache, making room for it by evicting the least
+/// recently used ones. Entries too big for the cache aren't added.
+fn insert(cache: &mut Cache, key: String, mut entry: Entry, max_size: usize) -> bool {
+    let size = entry.size();
+    if size > max_size {
+        debug!("Not caching {} ({} bytes is too big)", key, size);
+        return false;
+    }
+    if let Some(old) = cache.entries.remove(&key) {
+        cache.size -= old.size();
+    }
+    while cache.size + size > max_size {
+        let oldest = cache.entries.iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone())
+            .expect("entries take up space");
+        let old = cache.entries.remove(&oldest).unwrap();
+        cache.size -= old.size();
+    }
+    cache.tick += 1;
+    entry.last_used = cache.tick;
+    cache.size += size;
+    cache.entries.insert(key, entry);
+    true
+}
+
+/// How long a response can be cached for, if it can be. Responses need
+/// an explicit lifetime (or the route's TTL), and none that are private
+/// or set cookies are kept.
+fn freshness(status: StatusCode, headers: &HeaderMap, ttl: Option<Duration>) -> Option<Duration> {
+    let cacheable_status = [200, 203, 204, 300, 301, 404, 405, 410, 414, 501].contains(&status.as_u16());
+    if !cacheable_status
+        || headers.contains_key(SET_COOKIE)
+        || ["no-store", "no-cache", "private"].iter().any(|d| has_directive(headers, CACHE_CONTROL, d)) {
+        return None;
+    }
+    if let Some(ttl) = ttl {
+        return Some(ttl);
+    }
+
+    let max_age = directive_value(headers, "s-maxage").or_else(|| directive_value(headers, "max-age"));
+    let fresh_for = match max_age {
+        Some(max_age) => Duration::from_secs(max_age.parse().ok()?),
+        None => {
+            let expires = parse_date(headers.get(EXPIRES)?)?;
+            let date = headers.get(DATE).and_then(parse_date).unwrap_or_else(SystemTime::now);
+            expires.duration_since(date).ok()?
+        }
+    };
+    if fresh_for == Duration::from_secs(0) { None } else { Some(fresh_for) }
+}
+
+/// The request headers a response varies on, and their values. Responses
+/// that vary on everything can't be cached.
+fn vary(resp_headers: &HeaderMap, req_headers: &HeaderMap) -> Option<Vec<(HeaderName, Option<HeaderValue>)>> {
+    let mut vary = vec![];
+    for value in resp_headers.get_all(VARY) {
+        for name in value.to_str().ok()?.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+            if name == "*" {
+                return None;
+            }
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let value = req_headers.get(&name).cloned();
+            vary.push((name, value));
+        }
+    }
+    Some(vary)
+}
+
+fn directives(headers: &HeaderMap, name: HeaderName) -> impl Iterator<Item=(String, Option<String>)> + '_ {
+    headers.get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| {
+            let d = d.trim();
+            match d.find('=') {
+                Some(idx) => (d[..idx].trim().to_lowercase(), Some(d[idx+1..].trim().trim_matches('"').to_owned())),
+                None => (d.to_lowercase(), None)
+            }
+        })
+}
+
+fn has_directive(headers: &HeaderMap, name: HeaderName, directive: &str) -> bool {
+    directives(headers, name).any(|(d, _)| d == directive)
+}
+
+fn directive_value(headers: &HeaderMap, directive: &str) -> Option<String> {
+    directives(headers, CACHE_CONTROL).find(|(d, _)| d == directive).and_then(|(_, v)| v)
+}
+
+fn parse_date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use futures::executor::block_on;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn entry(body: &str, last_used: u64) -> Entry {
+        Entry { head: head(), body: body.as_bytes().to_vec(), last_used }
+    }
+
+    fn head() -> Head {
+        Head {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            vary: vec![],
+            stored: SystemTime::now(),
+            initial_age: Duration::from_secs(0),
+            fresh_for: Duration::from_secs(60)
+        }
+    }
+
+    #[test]
+    fn works_out_freshness() {
+        let ok = StatusCode::OK;
+        let secs = Duration::from_secs;
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "public, max-age=60")]), None), Some(secs(60)));
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "max-age=60, s-maxage=600")]), None), Some(secs(600)));
+        assert_eq!(freshness(ok, &headers(&[
+            ("date", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            ("expires", "Wed, 21 Oct 2015 08:28:00 GMT")
+        ]), None), Some(secs(3600)));
+        assert_eq!(freshness(ok, &headers(&[]), None), None);
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "max-age=0")]), None), None);
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "private, max-age=60")]), None), None);
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "max-age=60"), ("set-cookie", "a=1")]), None), None);
+        assert_eq!(freshness(StatusCode::INTERNAL_SERVER_ERROR, &headers(&[("cache-control", "max-age=60")]), None), None);
+
+        // The route's TTL wins, unless the response mustn't be cached at all:
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "max-age=60")]), Some(secs(5))), Some(secs(5)));
+        assert_eq!(freshness(ok, &headers(&[]), Some(secs(5))), Some(secs(5)));
+        assert_eq!(freshness(ok, &headers(&[("cache-control", "no-store")]), Some(secs(5))), None);
+    }
+
+    #[test]
+    fn notes_what_responses_vary_on() {
+        let req = headers(&[("accept-encoding", "gzip")]);
+        let vary_on = vary(&headers(&[("vary", "Accept-Encoding, Origin")]), &req).unwrap();
+        assert_eq!(vary_on, vec![
+            (HeaderName::from_static("accept-encoding"), Some(HeaderValue::from_static("gzip"))),
+            (HeaderName::from_static("origin"), None)
+        ]);
+        assert!(vary(&headers(&[("vary", "*")]), &req).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entries() {
+        let mut cache = Cache::default();
+        insert(&mut cache, "a".to_owned(), entry("aaaa", 0), 10);
+        insert(&mut cache, "b".to_owned(), entry("bbbb", 0), 10);
+        cache.entries.get_mut("a").unwrap().last_used = 100;
+        insert(&mut cache, "c".to_owned(), entry("cccc", 0), 10);
+
+        assert!(cache.entries.contains_key("a") && cache.entries.contains_key("c"));
+        assert!(!cache.entries.contains_key("b"));
+        assert_eq!(cache.size, 8);
+
+        // Too big to cache at all:
+        insert(&mut cache, "d".to_owned(), entry("ddddddddddd", 0), 10);
+        assert!(!cache.entries.contains_key("d"));
+    }
+
+    #[test]
+    fn purges_entries() {
+        let mut cache = Cache::default();
+        for key in &["http://a/v1/users", "http://a/v1/users?page=2", "http://a/v1/posts", "http://b/v1/users"] {
+            insert(&mut cache, key.to_string(), entry("x", 0), 1000);
+        }
+        let url = |s: &str| Url::parse(s).unwrap();
+
+        assert_eq!(purge_from(&mut cache, &[url("http://a/v1/users")], false).len(), 1);
+        assert!(cache.entries.contains_key("http://a/v1/users?page=2"));
+        assert_eq!(purge_from(&mut cache, &[url("http://a/v1/")], true).len(), 2);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.size, 1);
+    }
+
+    #[test]
+    fn coalesces_identical_fetches() {
+        let key = "http://a/coalesced";
+        let fetching = Fetching::start(key).unwrap();
+        let waiting = Fetching::start(key).unwrap_err();
+        fetching.finish(true);
+        assert_eq!(block_on(waiting), Ok(true));
+
+        // Those waiting go ahead themselves if nothing was cached:
+        let fetching = Fetching::start(key).unwrap();
+        let waiting = Fetching::start(key).unwrap_err();
+        drop(fetching);
+        assert_eq!(block_on(waiting), Ok(false));
+        assert!(Fetching::start(key).is_ok());
+    }
+}
@@ -0,0 +1,207 @@
+use hyper::{ HeaderMap, Request, Response };
+use http_body::Body as _;
+use hyper::header::{ HeaderName, HeaderValue, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE };
+use log::{ info };
+use regex::Regex;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Instant;
+use url::Url;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::options::{ RouteOptions };
+use crate::proxy;
+use crate::request_id::{ RequestId };
+use crate::settings::{ Settings };
+
+/// The target of what we log about captured requests and responses:
+pub const CAPTURE: &str = "weave::capture";
+/// How much of each body we log, if not provided:
+pub const DEFAULT_CAPTURE_BODY_LIMIT: usize = 4 * 1024;
+/// What we log in place of anything we hide:
+const REDACTED: &str = "[redacted]";
+
+/// Numbers each captured request without an ID, so that its response can
+/// be matched up:
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How much to capture and what to hide when logging the requests we
+/// proxy and the responses we get back.
+#[derive(Debug,Clone)]
+pub struct CaptureOptions {
+    /// How many bytes of each body to log:
+    pub body_limit: usize,
+    /// Headers whose values are never logged, on top of credentials and
+    /// cookies:
+    pub redact_headers: Vec<HeaderName>,
+    /// Matches of this in URLs, header values and bodies are hidden:
+    pub redact: Option<Regex>
+}
+
+impl Default for CaptureOptions {
+    fn default() -> CaptureOptions {
+        CaptureOptions {
+            body_limit: DEFAULT_CAPTURE_BODY_LIMIT,
+            redact_headers: vec![],
+            redact: None
+        }
+    }
+}
+
+impl CaptureOptions {
+    /// Parse a comma separated list of header names like `x-api-key,x-token`:
+    pub fn parse_headers(input: &str) -> Result<Vec<HeaderName>, Error> {
+        input.split(',')
+            .map(|name| name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|_| err!("'{}' is not a valid header name", name)))
+            .collect()
+    }
+
+    pub fn parse_pattern(input: &str) -> Result<Regex, Error> {
+        Regex::new(input).map_err(|e| err!("Invalid regular expression: {}", e))
+    }
+
+    fn is_sensitive(&self, name: &HeaderName) -> bool {
+        [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE].contains(name) || self.redact_headers.contains(name)
+    }
+
+    /// The value of a header, with anything sensitive hidden:
+    pub fn header_value(&self, name: &HeaderName, value: &HeaderValue) -> String {
+        if self.is_sensitive(name) {
+            REDACTED.to_owned()
+        } else {
+            self.redact(&String::from_utf8_lossy(value.as_bytes()))
+        }
+    }
+
+    /// Hide anything matching the redaction pattern:
+    pub fn redact(&self, text: &str) -> String {
+        match &self.redact {
+            Some(pattern) => pattern.replace_all(text, REDACTED).into_owned(),
+            None => text.to_owned()
+        }
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> String {
+        let mut out = String::new();
+        for (name, value) in headers {
+            out.push_str(&format!("\n{}: {}", name, self.header_value(name, value)));
+        }
+        out
+    }
+
+    /// The start of a body, as text if it is, along with how much we left out:
+    fn body(&self, captured: &[u8], total: usize) -> String {
+        if total == 0 {
+            return String::new();
+        }
+        let text = match std::str::from_utf8(captured) {
+            Ok(text) => Some(text),
+            // The limit may have cut a character in half:
+            Err(e) if e.error_len().is_none() => Some(std::str::from_utf8(&captured[..e.valid_up_to()]).unwrap()),
+            Err(_) => None
+        };
+        match text {
+            Some(text) if total > captured.len() => format!("\n\n{}\n(truncated, {} bytes in all)", self.redact(text), total),
+            Some(text) => format!("\n\n{}", self.redact(text)),
+            None => format!("\n\n({} bytes of binary data)", total)
+        }
+    }
+}
+
+/// A request we're capturing, waiting for its response.
+pub struct Capture {
+    /// The request's ID, or a number if it doesn't have one:
+    id: String,
+    options: CaptureOptions,
+    /// We can't pass bodies on ourselves without losing their trailers,
+    /// which gRPC needs, so bodies to `h2c://` destinations aren't logged:
+    bodies: bool,
+    started: Instant
+}
+
+/// Log a request on its way to `url`, if its route is being captured. The
+/// request is logged once its body has been sent, so this hands back a
+/// request to send instead.
+pub fn request(req: Request<Body>, url: &Url, route: &RouteOptions, settings: &Settings) -> (Request<Body>, Option<Capture>) {
+    if !route.capture.unwrap_or(settings.capture) {
+        return (req, None);
+    }
+    let id = match req.extensions().get::<RequestId>() {
+        Some(id) => id.to_string(),
+        None => NEXT_ID.fetch_add(1, Ordering::Relaxed).to_string()
+    };
+    let capture = Capture {
+        id,
+        options: settings.capture_options.clone(),
+        bodies: url.scheme() != "h2c",
+        started: Instant::now()
+    };
+    let head = format!("[capture {}] > {} {} {:?}{}",
+                       capture.id,
+                       req.method(),
+                       capture.options.redact(url.as_str()),
+                       req.version(),
+                       capture.options.headers(req.headers()));
+    let req = req.map(|body| capture.log(body, head));
+    (req, Some(capture))
+}
+
+impl Capture {
+    /// Log the response to a captured request, once its body has arrived:
+    pub fn response(self, resp: Response<Body>) -> Response<Body> {
+        let head = format!("[capture {}] < {} in {:#?}{}",
+                           self.id,
+                           resp.status(),
+                           self.started.elapsed(),
+                           self.options.headers(resp.headers()));
+        resp.map(|body| self.log(body, head))
+    }
+
+    /// Log `head`, along with the body once it's been passed on. Empty
+    /// bodies are left alone, so they aren't sent chunked:
+    fn log(&self, body: Body, head: String) -> Body {
+        if !self.bodies || body.is_end_stream() {
+            info!(target: CAPTURE, "{}", head);
+            return body;
+        }
+        let options = self.options.clone();
+        proxy::tee(body, options.body_limit, move |teed| {
+            let ended = if teed.complete { String::new() } else { format!("\n(incomplete after {} bytes)", teed.total) };
+            info!(target: CAPTURE, "{}{}{}", head, options.body(&teed.start, teed.total), ended);
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn redacts_headers() {
+        let options = CaptureOptions {
+            redact_headers: CaptureOptions::parse_headers("x-api-key").unwrap(),
+            redact: Some(CaptureOptions::parse_pattern("secret-[a-z]+").unwrap()),
+            ..CaptureOptions::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer abc".parse().unwrap());
+        headers.insert("x-api-key", "abc".parse().unwrap());
+        headers.insert("x-note", "has secret-stuff in".parse().unwrap());
+        assert_eq!(options.headers(&headers), "\nauthorization: [redacted]\nx-api-key: [redacted]\nx-note: has [redacted] in");
+    }
+
+    #[test]
+    fn logs_the_start_of_bodies() {
+        let options = CaptureOptions {
+            redact: Some(CaptureOptions::parse_pattern(r#""password":"[^"]*""#).unwrap()),
+            ..CaptureOptions::default()
+        };
+        assert_eq!(options.body(b"", 0), "");
+        assert_eq!(options.body(br#"{"password":"hunter2"}"#, 22), "\n\n{[redacted]}");
+        // A character cut in half by the limit is left out:
+        assert_eq!(options.body("ab\u{e9}".as_bytes()[..3].as_ref(), 10), "\n\nab\n(truncated, 10 bytes in all)");
+        assert_eq!(options.body(&[0xff, 0xfe, 0x00], 3), "\n\n(3 bytes of binary data)");
+    }
+}
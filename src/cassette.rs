@@ -0,0 +1,238 @@
+use hyper::{ Request, Response, StatusCode };
+use hyper::header::{ HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING };
+use log::{ debug };
+use serde::{ Deserialize, Serialize };
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::SystemTime;
+use tokio::fs;
+use url::Url;
+use crate::body::{ Body };
+use crate::error_pages;
+use crate::errors::{ Error };
+use crate::logging;
+use crate::proxy;
+
+/// Counts up for each response we start writing, so that responses to
+/// the same request being written at once don't share a temporary file:
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether we save responses, answer from the ones we've saved, or both.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum CassetteMode {
+    /// Send every request on, saving the responses:
+    Record,
+    /// Answer from saved responses, without sending anything on:
+    Replay,
+    /// Answer from saved responses where we have them, and send the
+    /// rest on, saving their responses:
+    Auto
+}
+
+impl CassetteMode {
+    pub fn parse(input: &str) -> Result<CassetteMode, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "record" => Ok(CassetteMode::Record),
+            "replay" => Ok(CassetteMode::Replay),
+            "auto" => Ok(CassetteMode::Auto),
+            _ => Err(err!("Expecting 'record', 'replay' or 'auto'"))
+        }
+    }
+}
+
+/// Added to the extensions of responses answered from the cassette, so
+/// that we can log them as such.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Replayed;
+
+/// A directory of responses from URL destinations, so that we can answer
+/// requests without them, VCR style. Each response has a JSON file of its
+/// own, named after a hash of the method, URL and body of the request it
+/// was for, which can be read, edited or checked in alongside tests.
+#[derive(Debug)]
+pub struct Cassette {
+    dir: PathBuf,
+    mode: CassetteMode
+}
+
+/// How a response is saved. Bodies that aren't text are base64 encoded.
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+struct Interaction {
+    method: String,
+    url: String,
+    /// The SHA-1 of the request body:
+    request_body_sha1: String,
+    recorded: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_base64: Option<String>
+}
+
+/// What to do with a request, having looked in the cassette.
+pub enum Lookup {
+    /// Respond with this, rather than sending the request on:
+    Answered(Response<Body>),
+    /// Send this on, and save the response with the tape:
+    Send(Request<Body>, Tape)
+}
+
+/// A request whose response is to be saved to the cassette.
+pub struct Tape {
+    cassette: Arc<Cassette>,
+    method: String,
+    url: String,
+    request_body_sha1: String
+}
+
+impl Cassette {
+    pub fn open(dir: &Path, mode: CassetteMode) -> Result<Arc<Cassette>, Error> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| err!("Can't create cassette directory {}: {}", dir.display(), e))?;
+        Ok(Arc::new(Cassette { dir: dir.to_owned(), mode }))
+    }
+
+    /// Look for the response to a request on its way to `url`. The body
+    /// needs reading to tell requests apart, so a request to send on is
+    /// handed back if we don't answer it.
+    pub async fn lookup(self: &Arc<Self>, req: Request<Body>, url: &Url) -> Result<Lookup, Error> {
+        let (parts, body) = req.into_parts();
+        let body = proxy::read_body(body).await?;
+        let request_body_sha1 = sha1::Sha1::from(&body).digest().to_string();
+        let path = self.path(parts.method.as_str(), url.as_str(), &request_body_sha1);
+
+        if self.mode != CassetteMode::Record {
+            match fs::read(&path).await {
+                Ok(bytes) => {
+                    let interaction: Interaction = serde_json::from_slice(&bytes)
+                        .map_err(|e| err!("Can't read {} from the cassette: {}", path.display(), e))?;
+                    let mut resp = interaction.into_response()
+                        .map_err(|e| err!("Can't read {} from the cassette: {}", path.display(), e))?;
+                    resp.extensions_mut().insert(Replayed);
+                    return Ok(Lookup::Answered(resp));
+                },
+                Err(_) if self.mode == CassetteMode::Replay => {
+                    return Ok(Lookup::Answered(error_pages::error(502, format!("No response to {} {} in the cassette", parts.method, url))));
+                },
+                Err(_) => {}
+            }
+        }
+
+        let tape = Tape {
+            cassette: Arc::clone(self),
+            method: parts.method.to_string(),
+            url: url.to_string(),
+            request_body_sha1
+        };
+        Ok(Lookup::Send(Request::from_parts(parts, Body::from(body)), tape))
+    }
+
+    fn path(&self, method: &str, url: &str, request_body_sha1: &str) -> PathBuf {
+        let key = format!("{} {} {}", method, url, request_body_sha1);
+        self.dir.join(format!("{}.json", sha1::Sha1::from(key).digest()))
+    }
+}
+
+impl Tape {
+    /// Save a response to the cassette, handing back one to send on in
+    /// its place:
+    pub async fn record(self, resp: Response<Body>) -> Result<Response<Body>, Error> {
+        let (parts, body) = resp.into_parts();
+        let body = proxy::read_body(body).await?;
+        let (text, base64) = match String::from_utf8(body.clone()) {
+            Ok(text) => (Some(text), None),
+            Err(_) => (None, Some(base64::encode(&body)))
+        };
+        let interaction = Interaction {
+            method: self.method,
+            url: self.url,
+            request_body_sha1: self.request_body_sha1,
+            recorded: logging::rfc3339(SystemTime::now()),
+            status: parts.status.as_u16(),
+            // The body is replayed in one piece, so how it was sent is
+            // no longer true:
+            headers: parts.headers.iter()
+                .filter(|(name, _)| ![CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING].contains(name))
+                .map(|(name, value)| (name.as_str().to_owned(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+                .collect(),
+            body: text,
+            body_base64: base64
+        };
+
+        // Written under a temporary name, so we never replay half a file:
+        let path = self.cassette.path(&interaction.method, &interaction.url, &interaction.request_body_sha1);
+        let tmp = path.with_extension(format!("{}.tmp", WRITES.fetch_add(1, Ordering::Relaxed)));
+        let json = serde_json::to_vec_pretty(&interaction)?;
+        fs::write(tmp.clone(), json).await
+            .map_err(|e| err!("Can't write {} to the cassette: {}", tmp.display(), e))?;
+        fs::rename(tmp, path.clone()).await
+            .map_err(|e| err!("Can't write {} to the cassette: {}", path.display(), e))?;
+        debug!("Recorded {} {} to {}", interaction.method, interaction.url, path.display());
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+impl Interaction {
+    fn into_response(self) -> Result<Response<Body>, Error> {
+        let status = StatusCode::from_u16(self.status).map_err(|_| err!("Invalid status {}", self.status))?;
+        let body = match (self.body, self.body_base64) {
+            (_, Some(base64)) => base64::decode(&base64).map_err(|_| err!("Invalid base64 body"))?,
+            (Some(text), None) => text.into_bytes(),
+            (None, None) => vec![]
+        };
+        let mut resp = Response::new(Body::from(body));
+        *resp.status_mut() = status;
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| err!("Invalid header name '{}'", name))?;
+            let value = HeaderValue::from_str(value).map_err(|_| err!("Invalid value for header '{}'", name))?;
+            resp.headers_mut().append(name, value);
+        }
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!(CassetteMode::parse("Record").unwrap(), CassetteMode::Record);
+        assert_eq!(CassetteMode::parse("auto").unwrap(), CassetteMode::Auto);
+        assert!(CassetteMode::parse("rewind").is_err());
+    }
+
+    #[test]
+    fn tells_requests_apart() {
+        let cassette = Cassette { dir: PathBuf::from("/tapes"), mode: CassetteMode::Auto };
+        let path = cassette.path("GET", "http://a/x", "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(path, cassette.path("GET", "http://a/x", "da39a3ee5e6b4b0d3255bfef95601890afd80709"));
+        assert_ne!(path, cassette.path("POST", "http://a/x", "da39a3ee5e6b4b0d3255bfef95601890afd80709"));
+        assert_ne!(path, cassette.path("GET", "http://a/x", "0000000000000000000000000000000000000000"));
+        assert_eq!(path.extension().unwrap(), "json");
+    }
+
+    #[test]
+    fn reads_interactions_back() {
+        let interaction = Interaction {
+            method: "GET".to_owned(),
+            url: "http://a/x".to_owned(),
+            request_body_sha1: String::new(),
+            recorded: "2019-10-12T07:20:50.520Z".to_owned(),
+            status: 201,
+            headers: vec![("x-a".to_owned(), "1".to_owned()), ("x-a".to_owned(), "2".to_owned())],
+            body: None,
+            body_base64: Some("/wA=".to_owned())
+        };
+        let json = serde_json::to_string(&interaction).unwrap();
+        assert!(!json.contains("\"body\""));
+        let resp = serde_json::from_str::<Interaction>(&json).unwrap().into_response().unwrap();
+        assert_eq!(resp.status(), 201);
+        assert_eq!(resp.headers().get_all("x-a").iter().count(), 2);
+    }
+}
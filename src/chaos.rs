@@ -0,0 +1,165 @@
+use hyper::{ Response, StatusCode };
+use ring::rand::{ SecureRandom, SystemRandom };
+use std::fmt;
+use std::sync::Mutex;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::error_pages;
+
+/// Fail some fraction of the requests to a route on purpose, to see how
+/// clients cope.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Chaos {
+    /// How likely each request is to fail, from 0 to 1:
+    pub rate: f64,
+    /// How requests fail:
+    pub fault: Fault,
+    /// Seed the choice of which requests fail, so that runs of the
+    /// same requests fail in the same way:
+    pub seed: Option<u64>
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Fault {
+    /// Respond with this status:
+    Status(StatusCode),
+    /// Close the connection without responding:
+    Abort
+}
+
+/// Added to the extensions of a response to say that, rather than sending
+/// it, the connection should be closed.
+#[derive(Debug,Clone,Copy)]
+pub struct Abort;
+
+impl Default for Chaos {
+    fn default() -> Chaos {
+        Chaos {
+            rate: 0.0,
+            fault: Fault::Status(StatusCode::INTERNAL_SERVER_ERROR),
+            seed: None
+        }
+    }
+}
+
+impl Chaos {
+    /// Parse a percentage of requests to fail, like `10%`:
+    pub fn parse_rate(input: &str) -> Result<f64, Error> {
+        let invalid = || err!("Expecting a percentage like 10% but got '{}'", input);
+        let percent: f64 = input.trim().trim_end_matches('%').trim().parse().map_err(|_| invalid())?;
+        if !(0.0..=100.0).contains(&percent) {
+            return Err(invalid());
+        }
+        Ok(percent / 100.0)
+    }
+}
+
+impl Fault {
+    /// Parse a status code like `503`, or `abort`:
+    pub fn parse(input: &str) -> Result<Fault, Error> {
+        let input = input.trim();
+        if input.eq_ignore_ascii_case("abort") {
+            return Ok(Fault::Abort);
+        }
+        input.parse::<u16>().ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .map(Fault::Status)
+            .ok_or_else(|| err!("Expecting a status code or 'abort' but got '{}'", input))
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        match self {
+            Fault::Status(status) => {
+                error_pages::error(status.as_u16(), "Failed on purpose")
+            },
+            Fault::Abort => {
+                let mut resp = Response::new(Body::empty());
+                resp.extensions_mut().insert(Abort);
+                resp
+            }
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::Status(status) => write!(f, "{}", status.as_str()),
+            Fault::Abort => write!(f, "abort")
+        }
+    }
+}
+
+/// Decides which requests to a route fail. Each route has its own, so that
+/// seeded routes fail the same way regardless of requests to other routes.
+#[derive(Debug)]
+pub struct FaultInjector {
+    state: Mutex<u64>
+}
+
+impl FaultInjector {
+    pub fn new(seed: Option<u64>) -> FaultInjector {
+        let seed = seed.unwrap_or_else(|| {
+            let mut bytes = [0u8; 8];
+            SystemRandom::new().fill(&mut bytes).expect("random numbers are available");
+            u64::from_le_bytes(bytes)
+        });
+        FaultInjector { state: Mutex::new(seed) }
+    }
+
+    /// Should this request fail, and if so, how?
+    pub fn inject(&self, chaos: &Chaos) -> Option<Fault> {
+        if chaos.rate > 0.0 && self.next() < chaos.rate {
+            Some(chaos.fault)
+        } else {
+            None
+        }
+    }
+
+    /// A number from 0 up to (but not including) 1, using SplitMix64:
+    fn next(&self) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_chaos_options() {
+        assert_eq!(Chaos::parse_rate("10%").unwrap(), 0.1);
+        assert_eq!(Chaos::parse_rate("100").unwrap(), 1.0);
+        assert!(Chaos::parse_rate("150%").is_err());
+        assert!(Chaos::parse_rate("lots").is_err());
+
+        assert_eq!(Fault::parse("503").unwrap(), Fault::Status(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(Fault::parse("abort").unwrap(), Fault::Abort);
+        assert!(Fault::parse("42").is_err());
+    }
+
+    #[test]
+    fn fails_requests_reproducibly() {
+        let chaos = Chaos { rate: 0.3, seed: Some(42), ..Chaos::default() };
+        let failures = |injector: &FaultInjector| -> Vec<bool> {
+            (0..1000).map(|_| injector.inject(&chaos).is_some()).collect()
+        };
+
+        let first = failures(&FaultInjector::new(chaos.seed));
+        let second = failures(&FaultInjector::new(chaos.seed));
+        assert_eq!(first, second);
+        let failed = first.iter().filter(|&&failed| failed).count();
+        assert!(failed > 250 && failed < 350);
+
+        let never = Chaos::default();
+        let injector = FaultInjector::new(None);
+        assert!((0..100).all(|_| injector.inject(&never).is_none()));
+    }
+}
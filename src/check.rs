@@ -0,0 +1,201 @@
+use ansi_term::Color::{ Green, Red };
+use clap::{ App, AppSettings, Arg };
+use futures::future::join_all;
+use hyper::{ Request };
+use log::{ info, warn };
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time;
+use url::Url;
+use crate::acceptor;
+use crate::body::{ Body };
+use crate::client::HttpsClient;
+use crate::config;
+use crate::errors::{ Error };
+use crate::listeners::{ self, ListenAddr };
+use crate::location::{ DestLocation };
+use crate::logging::{ self, LogFormat };
+use crate::proxy;
+use crate::routes::{ self, Route };
+use crate::settings::Settings;
+use crate::syslog::{ LogTarget };
+
+/// How long we wait for a destination to respond when probing it:
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What's wrong with the routes we've been asked to check.
+#[derive(Debug,Default)]
+struct Report {
+    /// The problems with each route, in the same order as the routes:
+    routes: Vec<Vec<String>>,
+    /// Problems that aren't down to one route:
+    general: Vec<String>
+}
+
+/// `weave check [SOURCE to DEST ...] [--config FILE]`: parse routes the way
+/// we would when serving them, and report anything wrong with them (like
+/// addresses that can't be resolved or files that don't exist) without
+/// listening on any ports, so that configs can be checked before they're
+/// deployed. Exits with an error if anything is wrong.
+pub async fn run(args: impl Iterator<Item=String>) -> Result<(), Error> {
+    let (mut cli_routes, other_args) = routes::from_args(args).map_err(|e| {
+        err!("failed to parse routes: {}", e)
+    })?;
+    let matches = App::new("weave check")
+        .about("Check that routes and config files are valid, without listening on any ports.")
+        .usage("weave check [SOURCE to DEST [and SOURCE to DEST ...]] [--config FILE] [--stdin] [--probe]")
+        .setting(AppSettings::NoBinaryName)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .help("Check the routes in this TOML config file too"))
+        .arg(Arg::with_name("stdin")
+            .long("stdin")
+            .help("Check routes read from standard input too, one per line"))
+        .arg(Arg::with_name("probe")
+            .long("probe")
+            .help("Send a request to each URL destination, and report any that can't be reached. Any response counts as reachable"))
+        .arg(Arg::with_name("insecure")
+            .long("insecure")
+            .help("Don't verify the certificates of HTTPS destinations when probing them"))
+        .get_matches_from(other_args);
+    logging::init(LogFormat::Text, &LogTarget::Stderr, None)?;
+
+    cli_routes.extend(routes::from_env()?);
+    if matches.is_present("stdin") {
+        cli_routes.extend(routes::from_stdin()?);
+    }
+    let mut all_routes = cli_routes;
+    if let Some(config_path) = matches.value_of("config") {
+        all_routes.extend(config::from_file(config_path)?);
+    }
+    if all_routes.is_empty() {
+        return Err(err!("No routes have been provided. Use -h or --help for more information"));
+    }
+
+    let settings = Settings { insecure: matches.is_present("insecure"), ..Settings::default() };
+    let client = HttpsClient::new(&settings)?;
+    let mut report = check(&all_routes, &client);
+    if matches.is_present("probe") {
+        let probes = all_routes.iter().map(|route| probe_route(route, &client));
+        for (problems, probe_problems) in report.routes.iter_mut().zip(join_all(probes).await) {
+            problems.extend(probe_problems);
+        }
+    }
+
+    for (route, problems) in all_routes.iter().zip(&report.routes) {
+        if problems.is_empty() {
+            info!("{}", Green.paint(format!("[ok] {} to {}", route.src, route.dests_to_string())));
+        }
+        for problem in problems {
+            warn!("{}", Red.paint(format!("[failed] {} to {} ({})", route.src, route.dests_to_string(), problem)));
+        }
+    }
+    for problem in &report.general {
+        warn!("{}", Red.paint(format!("[failed] {}", problem)));
+    }
+
+    let failed = report.routes.iter().filter(|problems| !problems.is_empty()).count();
+    info!("Checked {} routes: {} ok, {} failed", all_routes.len(), all_routes.len() - failed, failed);
+    if failed > 0 || !report.general.is_empty() {
+        return Err(err!("{} problems found", report.routes.iter().map(|p| p.len()).sum::<usize>() + report.general.len()));
+    }
+    Ok(())
+}
+
+/// Find the problems we'd have serving some routes, short of listening
+/// for them or sending anything to their destinations:
+fn check(routes: &[Route], client: &HttpsClient) -> Report {
+    let mut report = Report::default();
+    let listen_addrs: Vec<Result<ListenAddr, Error>> = routes.iter().map(Route::listen_addr).collect();
+    let all_addrs: Vec<ListenAddr> = listen_addrs.iter().filter_map(|addr| addr.as_ref().ok()).cloned().collect();
+    let mut by_addr: HashMap<ListenAddr, Vec<Route>> = HashMap::new();
+    for (route, listen_addr) in routes.iter().zip(listen_addrs) {
+        let mut problems = vec![];
+        match listen_addr {
+            Ok(addr) => {
+                let addr = listeners::shared_addr(&addr, &all_addrs).unwrap_or(addr);
+                by_addr.entry(addr).or_default().push(route.clone())
+            },
+            Err(e) => problems.push(e.to_string())
+        }
+        if let Err(e) = route.options.oidc.check() {
+            problems.push(e.to_string());
+        }
+        for dest in &route.dests {
+            if let DestLocation::FilePath(path) = dest {
+                // Paths that use parts of the request path can't be checked:
+                if !path.contains('$') && !path.contains('{') && !Path::new(path).exists() {
+                    problems.push(format!("'{}' does not exist", path));
+                }
+            }
+        }
+        report.routes.push(problems);
+    }
+
+    let mut addrs: Vec<_> = by_addr.into_iter().collect();
+    addrs.sort_by_key(|(addr, _)| addr.to_string());
+    for (addr, routes) in addrs {
+        if let Err(e) = listeners::listener_protocol(&addr, &routes) {
+            report.general.push(e.to_string());
+        }
+        if let Err(e) = acceptor::check(&addr, &routes) {
+            report.general.push(e.to_string());
+        }
+    }
+    if let Err(e) = client.use_tls_options(routes.iter().map(|route| &route.options.tls)) {
+        report.general.push(e.to_string());
+    }
+    report
+}
+
+/// Send a request to each URL destination of a route, reporting those
+/// that don't respond:
+async fn probe_route(route: &Route, client: &HttpsClient) -> Vec<String> {
+    let probes = route.dests.iter().filter_map(|dest| match dest {
+        DestLocation::Url(url) | DestLocation::UnixSocket { url, .. } => Some(probe(url, route, client)),
+        _ => None
+    });
+    join_all(probes).await.into_iter().flatten().collect()
+}
+
+/// Probe the root of a destination, since its path may use parts of the
+/// request path:
+async fn probe(url: &Url, route: &Route, client: &HttpsClient) -> Option<String> {
+    let mut url = url.clone();
+    url.set_path("/");
+    url.set_query(None);
+    let req = match Request::get(url.as_str()).body(Body::empty()) {
+        Ok(req) => req,
+        Err(e) => return Some(format!("can't probe {}: {}", url, e))
+    };
+    let sent = time::timeout(PROBE_TIMEOUT, async {
+        let resp = client.request(req, &route.options.tls).await?;
+        proxy::read_body(resp.into_body()).await?;
+        Ok::<_, Error>(())
+    });
+    match sent.await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{} can't be reached: {}", url, e)),
+        Err(_) => Some(format!("{} didn't respond within {:#?}", url, PROBE_TIMEOUT))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn reports_problems_with_routes() {
+        let routes = routes::from_str("8080 to ./src and 8081 to ./missing and 8082 to ./files/{path..} and 8083 to status://204 with listener-protocol=http2 and 8083/a to 9000 with listener-protocol=http1").unwrap();
+        let client = HttpsClient::new(&Settings::default()).unwrap();
+        let report = check(&routes, &client);
+        assert!(report.routes[0].is_empty());
+        assert_eq!(report.routes[1], vec!["'./missing' does not exist".to_owned()]);
+        assert!(report.routes[2].is_empty());
+        assert_eq!(report.general.len(), 1);
+        assert!(report.general[0].contains("different listener protocols"));
+    }
+}
@@ -0,0 +1,125 @@
+use hyper::{ Request, Response, Uri };
+use hyper::http::uri::Scheme;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use log::{ warn };
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use crate::body::{ Body };
+use crate::connector::{ Resolve, TimeoutConnector, UnixConnector };
+use crate::errors::{ Error };
+use crate::options::{ TlsOptions };
+use crate::settings::{ Settings };
+use crate::tls::{ HttpsConnector, TlsBackend };
+
+pub use hyper_util::client::legacy::Error as ClientError;
+
+type Connector = TimeoutConnector<UnixConnector<HttpsConnector>>;
+
+/// What a request made with `HttpsClient` hands back:
+pub type ResponseFuture = Pin<Box<dyn Future<Output=Result<Response<Body>, ClientError>> + Send>>;
+
+/// The clients we proxy requests through. It's cheap to clone, and clones
+/// share the same connection pools, so keep-alive connections are reused.
+/// Requests to `h2c://` URLs are sent over HTTP/2 without TLS (as gRPC
+/// services expect), and everything else over HTTP/1.1 or HTTPS.
+#[derive(Clone)]
+pub struct HttpsClient {
+    connect_timeout: Option<Duration>,
+    /// Don't verify any destination's certificates (`--insecure`):
+    insecure: bool,
+    tls_backend: TlsBackend,
+    /// Where to connect to for some hosts, in place of where DNS says (`--resolve`):
+    resolve: Vec<Resolve>,
+    default: Clients,
+    /// Clients for routes that configure TLS for themselves, keyed by that
+    /// configuration. These are rebuilt each time routes are loaded:
+    custom: Arc<Mutex<HashMap<TlsOptions, Clients>>>
+}
+
+#[derive(Clone)]
+struct Clients {
+    http1: Client<Connector, Body>,
+    h2c: Client<Connector, Body>
+}
+
+impl HttpsClient {
+    pub fn new(settings: &Settings) -> Result<HttpsClient, Error> {
+        let tls = TlsOptions { insecure: settings.insecure, ..TlsOptions::default() };
+        let https = HttpsConnector::new(settings.tls_backend, &tls, &settings.resolve)?;
+        Ok(HttpsClient {
+            connect_timeout: settings.connect_timeout,
+            insecure: settings.insecure,
+            tls_backend: settings.tls_backend,
+            resolve: settings.resolve.clone(),
+            default: Clients::new(https, settings.connect_timeout),
+            custom: Arc::new(Mutex::new(HashMap::new()))
+        })
+    }
+
+    /// Prepare clients for each of the TLS configurations given, replacing
+    /// those we had before. Certificates and keys are read from disk again,
+    /// so that they can be rotated by reloading the routes.
+    pub fn use_tls_options<'a>(&self, all_tls: impl IntoIterator<Item=&'a TlsOptions>) -> Result<(), Error> {
+        let mut custom = HashMap::new();
+        for tls in all_tls {
+            if *tls == TlsOptions::default() || custom.contains_key(tls) {
+                continue;
+            }
+            let effective = TlsOptions { insecure: tls.insecure || self.insecure, ..tls.clone() };
+            let https = HttpsConnector::new(self.tls_backend, &effective, &self.resolve)?;
+            let clients = Clients::new(https, self.connect_timeout);
+            custom.insert(tls.clone(), clients);
+        }
+        *self.custom.lock().unwrap() = custom;
+        Ok(())
+    }
+
+    pub fn request(&self, mut req: Request<Body>, tls: &TlsOptions) -> ResponseFuture {
+        let clients = self.clients(tls);
+        let sent = if req.uri().scheme_str() == Some("h2c") {
+            *req.uri_mut() = with_http_scheme(req.uri());
+            clients.h2c.request(req)
+        } else {
+            clients.http1.request(req)
+        };
+        Box::pin(async move { Ok(sent.await?.map(Body::from)) })
+    }
+
+    pub fn get(&self, uri: Uri, tls: &TlsOptions) -> ResponseFuture {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        self.request(req, tls)
+    }
+
+    fn clients(&self, tls: &TlsOptions) -> Clients {
+        if *tls == TlsOptions::default() {
+            return self.default.clone();
+        }
+        match self.custom.lock().unwrap().get(tls) {
+            Some(clients) => clients.clone(),
+            None => {
+                warn!("No client prepared for {:?}; using the default one", tls);
+                self.default.clone()
+            }
+        }
+    }
+}
+
+impl Clients {
+    fn new(https: HttpsConnector, connect_timeout: Option<Duration>) -> Clients {
+        let connector = || TimeoutConnector::new(UnixConnector::new(https.clone()), connect_timeout);
+        Clients {
+            http1: Client::builder(TokioExecutor::new()).build(connector()),
+            h2c: Client::builder(TokioExecutor::new()).http2_only(true).build(connector())
+        }
+    }
+}
+
+fn with_http_scheme(uri: &Uri) -> Uri {
+    let mut parts = uri.clone().into_parts();
+    parts.scheme = Some(Scheme::HTTP);
+    Uri::from_parts(parts).expect("only the scheme has changed")
+}
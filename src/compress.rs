@@ -0,0 +1,489 @@
+use hyper::{ HeaderMap, Method, Request, Response, StatusCode };
+use hyper::header::{ HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, ETAG, RANGE };
+use log::{ warn };
+use std::io::{ self, Write };
+use std::sync::{ Arc, Mutex };
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::headers;
+
+/// Responses smaller than this aren't worth compressing, if not provided:
+pub const DEFAULT_COMPRESS_MIN_SIZE: usize = 1024;
+
+/// The content types we compress, if not provided:
+pub const DEFAULT_COMPRESS_TYPES: &[&str] = &[
+    "text/*",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml"
+];
+
+/// How hard brotli tries. It goes up to 11, but anything past the middle
+/// is too slow to do as responses stream by.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compress responses for clients that accept it, as they stream back.
+#[derive(Debug,Clone,PartialEq)]
+pub struct CompressOptions {
+    /// The encodings we use, in order of preference (none if we don't
+    /// compress at all):
+    pub encodings: Vec<Encoding>,
+    /// Responses smaller than this are left alone:
+    pub min_size: usize,
+    /// The content types worth compressing, like `text/*`:
+    pub types: Vec<String>
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip
+}
+
+impl Default for CompressOptions {
+    fn default() -> CompressOptions {
+        CompressOptions {
+            encodings: vec![],
+            min_size: DEFAULT_COMPRESS_MIN_SIZE,
+            types: DEFAULT_COMPRESS_TYPES.iter().map(|t| t.to_string()).collect()
+        }
+    }
+}
+
+impl CompressOptions {
+    /// Parse `true` or `false`, or the encodings to use in order of
+    /// preference, like `br,gzip`:
+    pub fn parse_encodings(input: &str) -> Result<Vec<Encoding>, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "true" | "yes" | "on" => return Ok(vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip]),
+            "false" | "no" | "off" => return Ok(vec![]),
+            _ => {}
+        }
+        input.split(',').map(Encoding::parse).collect()
+    }
+
+    /// Parse a comma separated list of content types, like `text/*,application/json`:
+    pub fn parse_types(input: &str) -> Result<Vec<String>, Error> {
+        input.split(',')
+            .map(|t| {
+                let t = t.trim().to_lowercase();
+                if t.contains('/') { Ok(t) } else { Err(err!("Expecting a content type like text/* but got '{}'", t)) }
+            })
+            .collect()
+    }
+}
+
+impl Encoding {
+    pub fn parse(input: &str) -> Result<Encoding, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "br" => Ok(Encoding::Brotli),
+            "zstd" => Ok(Encoding::Zstd),
+            "gzip" => Ok(Encoding::Gzip),
+            _ => Err(err!("Expecting 'br', 'zstd' or 'gzip' but got '{}'", input.trim()))
+        }
+    }
+
+    /// How the encoding is named in `Accept-Encoding` and `Content-Encoding`:
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip"
+        }
+    }
+}
+
+/// Which of our encodings the client would like best, going by the quality
+/// values in its `Accept-Encoding` header, and then by our preference.
+pub fn negotiate(req: &Request<Body>, options: &CompressOptions) -> Option<Encoding> {
+    if options.encodings.is_empty() || req.method() == Method::HEAD {
+        return None;
+    }
+    accepted(req.headers(), &options.encodings).into_iter().next()
+}
+
+/// The encodings the client accepts out of those given (in order of our
+/// preference), best first.
+pub fn accepted(headers: &HeaderMap, encodings: &[Encoding]) -> Vec<Encoding> {
+    let accepted: Vec<(String, f32)> = headers.get_all(ACCEPT_ENCODING).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next()?.trim().to_lowercase();
+            let q = parts
+                .filter_map(|p| {
+                    let p = p.trim().to_lowercase();
+                    p.strip_prefix("q=").and_then(|q| q.trim().parse().ok())
+                })
+                .next()
+                .unwrap_or(1.0);
+            if name.is_empty() { None } else { Some((name, q)) }
+        })
+        .collect();
+    let quality = |encoding: Encoding| {
+        accepted.iter().find(|(name, _)| name == encoding.as_str())
+            .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+            .map(|&(_, q)| q)
+            .unwrap_or(0.0)
+    };
+
+    let mut by_quality: Vec<(Encoding, f32)> = encodings.iter()
+        .map(|&encoding| (encoding, quality(encoding)))
+        .filter(|&(_, q)| q > 0.0)
+        .collect();
+    // Sorting is stable, so our preference breaks ties:
+    by_quality.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap());
+    by_quality.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+/// Compress a response with the encoding the client asked for, if it's
+/// worth compressing. Responses that could have been compressed say they
+/// vary on `Accept-Encoding` either way, so that caches keep them apart.
+pub fn compress(mut resp: Response<Body>, encoding: Option<Encoding>, options: &CompressOptions) -> Response<Body> {
+    if options.encodings.is_empty() || !is_compressible(resp.status(), resp.headers(), options) {
+        return resp;
+    }
+    headers::add_vary(resp.headers_mut(), "Accept-Encoding");
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return resp
+    };
+    let output = Output::default();
+    let encoder = match Coder::encoder(encoding, output.clone()) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            warn!("Not compressing response: {}", e);
+            return resp;
+        }
+    };
+
+    let headers = resp.headers_mut();
+    remove_length(headers);
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+    resp.map(|body| transcode(body, encoder, output))
+}
+
+/// Ask the destination for a compressed response, which we decompress on
+/// the way back with `decompress`. Requests for part of a response are
+/// left alone, since there's no decompressing part of one.
+pub fn accept_compressed(req: &mut Request<Body>) {
+    if !req.headers().contains_key(RANGE) {
+        req.headers_mut().insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, br"));
+    }
+}
+
+/// Decompress a response from a destination if it's gzip or brotli
+/// encoded, so that the client gets the original.
+pub fn decompress(mut resp: Response<Body>, method: &Method) -> Response<Body> {
+    let encoding = match resp.headers().get(CONTENT_ENCODING).and_then(|e| e.to_str().ok()) {
+        Some(encoding) => encoding.trim().to_lowercase(),
+        None => return resp
+    };
+    if *method == Method::HEAD
+        || resp.status() == StatusCode::NO_CONTENT
+        || resp.status() == StatusCode::PARTIAL_CONTENT
+        || resp.status() == StatusCode::NOT_MODIFIED {
+        return resp;
+    }
+    let output = Output::default();
+    let decoder = match encoding.as_str() {
+        "gzip" | "x-gzip" => Coder::GzipDecoder(flate2::write::GzDecoder::new(output.clone())),
+        "br" => Coder::BrotliDecoder(Box::new(brotli::DecompressorWriter::new(output.clone(), 4096))),
+        _ => return resp
+    };
+
+    let headers = resp.headers_mut();
+    headers.remove(CONTENT_ENCODING);
+    remove_length(headers);
+    resp.map(|body| transcode(body, decoder, output))
+}
+
+/// Once a body is compressed or decompressed, we don't know how long it
+/// is, can't serve ranges of it, and it's only weakly the same as before.
+pub fn remove_length(headers: &mut HeaderMap) {
+    headers.remove(CONTENT_LENGTH);
+    headers.remove(ACCEPT_RANGES);
+    let weak_etag = headers.get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .and_then(|etag| HeaderValue::from_str(&format!("W/{}", etag)).ok());
+    if let Some(etag) = weak_etag {
+        headers.insert(ETAG, etag);
+    }
+}
+
+/// Is this response one we'd compress? It needs to be a type we compress,
+/// not already encoded, and (if we know how big it is) not too small.
+fn is_compressible(status: StatusCode, headers: &HeaderMap, options: &CompressOptions) -> bool {
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || headers.contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+    let no_transform = headers.get_all(CACHE_CONTROL).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|d| d.trim().eq_ignore_ascii_case("no-transform"));
+    if no_transform {
+        return false;
+    }
+    let too_small = headers.get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok())
+        .filter(|&len| len < options.min_size)
+        .is_some();
+    if too_small {
+        return false;
+    }
+
+    headers::content_type_matches(headers, &options.types)
+}
+
+/// Compress or decompress a body as it streams by, sending on whatever
+/// the coder has produced after each chunk.
+fn transcode(mut body: Body, mut coder: Coder, output: Output) -> Body {
+    let (mut sender, new_body) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => {
+                    sender.abort();
+                    return;
+                }
+            };
+            if let Err(e) = coder.write(&chunk) {
+                warn!("Error {} response: {}", coder.doing(), e);
+                sender.abort();
+                return;
+            }
+            let coded = output.take();
+            // The client has gone away, so stop:
+            if !coded.is_empty() && sender.send_data(coded.into()).await.is_err() {
+                return;
+            }
+        }
+        let doing = coder.doing();
+        if let Err(e) = coder.finish() {
+            warn!("Error {} response: {}", doing, e);
+            sender.abort();
+            return;
+        }
+        let _ = sender.send_data(output.take().into()).await;
+    });
+    new_body
+}
+
+/// Compresses or decompresses a stream, writing the result to an `Output`:
+enum Coder {
+    BrotliEncoder(Box<brotli::CompressorWriter<Output>>),
+    ZstdEncoder(zstd::stream::write::Encoder<Output>),
+    GzipEncoder(flate2::write::GzEncoder<Output>),
+    BrotliDecoder(Box<brotli::DecompressorWriter<Output>>),
+    GzipDecoder(flate2::write::GzDecoder<Output>)
+}
+
+impl Coder {
+    fn encoder(encoding: Encoding, output: Output) -> io::Result<Coder> {
+        Ok(match encoding {
+            Encoding::Brotli => Coder::BrotliEncoder(Box::new(brotli::CompressorWriter::new(output, 4096, BROTLI_QUALITY, BROTLI_WINDOW))),
+            Encoding::Zstd => Coder::ZstdEncoder(zstd::stream::write::Encoder::new(output, ZSTD_LEVEL)?),
+            Encoding::Gzip => Coder::GzipEncoder(flate2::write::GzEncoder::new(output, flate2::Compression::default()))
+        })
+    }
+
+    fn doing(&self) -> &'static str {
+        match self {
+            Coder::BrotliDecoder(_) | Coder::GzipDecoder(_) => "decompressing",
+            _ => "compressing"
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Coder::BrotliEncoder(encoder) => encoder.write_all(bytes),
+            Coder::ZstdEncoder(encoder) => encoder.write_all(bytes),
+            Coder::GzipEncoder(encoder) => encoder.write_all(bytes),
+            Coder::BrotliDecoder(decoder) => decoder.write_all(bytes),
+            Coder::GzipDecoder(decoder) => decoder.write_all(bytes)
+        }
+    }
+
+    /// Write out the end of the stream, complaining if what we were
+    /// decompressing was cut short:
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Coder::BrotliEncoder(encoder) => {
+                encoder.into_inner();
+            },
+            Coder::ZstdEncoder(encoder) => {
+                encoder.finish()?;
+            },
+            Coder::GzipEncoder(encoder) => {
+                encoder.finish()?;
+            },
+            Coder::BrotliDecoder(decoder) => {
+                decoder.into_inner()
+                    .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "brotli stream was cut short"))?;
+            },
+            Coder::GzipDecoder(decoder) => {
+                decoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where coders write to, so that we can take what they've produced so far:
+#[derive(Clone,Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::Read;
+    use super::*;
+
+    fn req(accept_encoding: &str) -> Request<Body> {
+        Request::builder().header("accept-encoding", accept_encoding).body(Body::empty()).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        let options = CompressOptions { encodings: CompressOptions::parse_encodings("true").unwrap(), ..CompressOptions::default() };
+        assert_eq!(negotiate(&req("gzip, deflate, br"), &options), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&req("gzip;q=1.0, br;q=0.5"), &options), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&req("*"), &options), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&req("br;q=0, *;q=0.1"), &options), Some(Encoding::Zstd));
+        assert_eq!(negotiate(&req("identity"), &options), None);
+
+        let gzip_only = CompressOptions { encodings: CompressOptions::parse_encodings("gzip").unwrap(), ..CompressOptions::default() };
+        assert_eq!(negotiate(&req("br, gzip"), &gzip_only), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&req("gzip"), &CompressOptions::default()), None);
+        assert!(CompressOptions::parse_encodings("deflate").is_err());
+
+        let all = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+        assert_eq!(accepted(&headers(&[("accept-encoding", "br;q=0.5, gzip")]), &all), vec![Encoding::Gzip, Encoding::Brotli]);
+    }
+
+    #[test]
+    fn only_compresses_some_responses() {
+        let options = CompressOptions { encodings: vec![Encoding::Gzip], ..CompressOptions::default() };
+        let ok = StatusCode::OK;
+        assert!(is_compressible(ok, &headers(&[("content-type", "text/html; charset=utf-8")]), &options));
+        assert!(is_compressible(ok, &headers(&[("content-type", "application/json"), ("content-length", "5000")]), &options));
+        assert!(!is_compressible(ok, &headers(&[("content-type", "application/json"), ("content-length", "50")]), &options));
+        assert!(!is_compressible(ok, &headers(&[("content-type", "image/png")]), &options));
+        assert!(!is_compressible(ok, &headers(&[]), &options));
+        assert!(!is_compressible(ok, &headers(&[("content-type", "text/css"), ("content-encoding", "gzip")]), &options));
+        assert!(!is_compressible(ok, &headers(&[("content-type", "text/css"), ("cache-control", "public, no-transform")]), &options));
+        assert!(!is_compressible(StatusCode::NOT_MODIFIED, &headers(&[("content-type", "text/css")]), &options));
+    }
+
+    #[test]
+    fn round_trips_bodies() {
+        let original = "hello world\n".repeat(1000).into_bytes();
+        let code = |mut coder: Coder, output: Output, input: &[u8]| -> Vec<u8> {
+            for chunk in input.chunks(1000) {
+                coder.write(chunk).unwrap();
+            }
+            coder.finish().unwrap();
+            output.take()
+        };
+
+        let compressed = Output::default();
+        let gzipped = code(Coder::encoder(Encoding::Gzip, compressed.clone()).unwrap(), compressed, &original);
+        let decompressed = Output::default();
+        assert_eq!(code(Coder::GzipDecoder(flate2::write::GzDecoder::new(decompressed.clone())), decompressed, &gzipped), original);
+
+        let compressed = Output::default();
+        let brotlied = code(Coder::encoder(Encoding::Brotli, compressed.clone()).unwrap(), compressed, &original);
+        let decompressed = Output::default();
+        assert_eq!(code(Coder::BrotliDecoder(Box::new(brotli::DecompressorWriter::new(decompressed.clone(), 4096))), decompressed, &brotlied), original);
+    }
+
+    #[test]
+    fn compresses_responses_clients_can_decode() {
+        let original = "hello world\n".repeat(1000);
+        let options = CompressOptions { encodings: CompressOptions::parse_encodings("true").unwrap(), ..CompressOptions::default() };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let compressed = |encoding: Encoding| -> Vec<u8> {
+            runtime.block_on(async {
+                let resp = Response::builder().header("content-type", "text/plain").body(Body::from(original.clone())).unwrap();
+                let resp = compress(resp, Some(encoding), &options);
+                assert_eq!(resp.headers()[CONTENT_ENCODING], encoding.as_str());
+                assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+                crate::proxy::read_body(resp.into_body()).await.unwrap()
+            })
+        };
+
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&compressed(Encoding::Gzip)[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, original);
+
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&compressed(Encoding::Brotli)[..], 4096).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, original);
+
+        assert_eq!(zstd::stream::decode_all(&compressed(Encoding::Zstd)[..]).unwrap(), original.as_bytes());
+    }
+
+    #[test]
+    fn decompresses_destination_responses() {
+        let original = "hello world\n".repeat(1000);
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+        gzip.write_all(original.as_bytes()).unwrap();
+        let gzipped = gzip.finish().unwrap();
+        let mut brotlied = vec![];
+        brotli::CompressorReader::new(original.as_bytes(), 4096, 11, 22).read_to_end(&mut brotlied).unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        for (encoding, body) in [("gzip", gzipped), ("br", brotlied)] {
+            let decompressed = runtime.block_on(async {
+                let resp = Response::builder()
+                    .header("content-encoding", encoding)
+                    .header("content-length", body.len())
+                    .body(Body::from(body))
+                    .unwrap();
+                let resp = decompress(resp, &Method::GET);
+                assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+                assert!(!resp.headers().contains_key(CONTENT_LENGTH));
+                crate::proxy::read_body(resp.into_body()).await.unwrap()
+            });
+            assert_eq!(decompressed, original.as_bytes(), "encoding: {}", encoding);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use tokio::sync::{ Semaphore, SemaphorePermit };
+
+/// How many requests can wait for a free slot, if not provided:
+pub const DEFAULT_MAX_QUEUED: usize = 100;
+
+/// Caps how many requests can be in flight at once. Requests beyond the cap
+/// wait for one to finish, unless `max_queued` are already waiting, in which
+/// case they're turned away.
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    semaphore: Semaphore,
+    max_queued: usize,
+    queued: AtomicUsize
+}
+
+/// Holds a slot until dropped:
+pub struct Admitted<'a> {
+    _permit: SemaphorePermit<'a>
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max_concurrent: usize, max_queued: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            semaphore: Semaphore::new(max_concurrent),
+            max_queued,
+            queued: AtomicUsize::new(0)
+        }
+    }
+
+    /// Wait for a free slot, or hand back None if the queue is full.
+    pub async fn admit(&self) -> Option<Admitted<'_>> {
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return Some(Admitted { _permit: permit });
+        }
+
+        let _queued = Queued::join(&self.queued, self.max_queued)?;
+        // Dropping this future while we wait also takes us out of the queue:
+        let permit = self.semaphore.acquire().await.ok()?;
+        Some(Admitted { _permit: permit })
+    }
+}
+
+/// Wait for room under each limit in turn, handing back None if any of
+/// their queues are full.
+pub async fn admit_all<'a>(limits: &[&'a ConcurrencyLimit]) -> Option<Vec<Admitted<'a>>> {
+    let mut admitted = vec![];
+    for &limit in limits {
+        admitted.push(limit.admit().await?);
+    }
+    Some(admitted)
+}
+
+/// Counts a request as queued until dropped:
+struct Queued<'a>(&'a AtomicUsize);
+
+impl<'a> Queued<'a> {
+    fn join(queued: &'a AtomicUsize, max_queued: usize) -> Option<Queued<'a>> {
+        let prev = queued.fetch_add(1, Ordering::SeqCst);
+        if prev >= max_queued {
+            queued.fetch_sub(1, Ordering::SeqCst);
+            None
+        } else {
+            Some(Queued(queued))
+        }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn turns_requests_away_when_the_queue_is_full() {
+        let limit = ConcurrencyLimit::new(2, 0);
+        let first = block_on(limit.admit());
+        let second = block_on(limit.admit());
+        assert!(first.is_some() && second.is_some());
+        assert!(block_on(limit.admit()).is_none());
+
+        // Slots are given back once requests finish:
+        drop(first);
+        assert!(block_on(limit.admit()).is_some());
+    }
+
+    #[test]
+    fn bounds_the_queue() {
+        let queued = AtomicUsize::new(0);
+        let first = Queued::join(&queued, 2);
+        let second = Queued::join(&queued, 2);
+        assert!(first.is_some() && second.is_some());
+        assert!(Queued::join(&queued, 2).is_none());
+        drop(first);
+        assert!(Queued::join(&queued, 2).is_some());
+        assert_eq!(queued.load(Ordering::SeqCst), 1);
+    }
+}
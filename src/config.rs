@@ -0,0 +1,268 @@
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
+use std::path::Path;
+use crate::errors::{ Error };
+use crate::location::{ SrcLocation, DestLocation };
+use crate::options::{ RouteOptions };
+use crate::routes::{ self, Route };
+
+/// The structure of a config file. This is an alternative to
+/// providing routes on the command line, and looks like:
+///
+/// ```toml
+/// [[routes]]
+/// src = "8080/api"
+/// dest = ["http://localhost:9000", "http://localhost:9001"]
+///
+/// [[routes]]
+/// src = "8080"
+/// dest = "./dist"
+/// cache-control = "max-age=3600"
+/// ```
+///
+/// `dest` can be a list of destinations to balance requests across.
+/// Any keys besides `src` and `dest` are options for that route.
+/// `src` and `dest` can contain `${VAR}` placeholders, which are
+/// replaced with the value of that environment variable.
+///
+/// Options that several routes share can be given a name, as a chain:
+///
+/// ```toml
+/// [chains.internal-api]
+/// auth-file = "./users.htpasswd"
+/// rate-limit = "10/s"
+/// request-header = "X-Internal: yes"
+///
+/// [[routes]]
+/// src = "8080/api"
+/// dest = "9000"
+/// chain = "internal-api"
+/// ```
+///
+/// `chain` can be a list of chains too. Their options are set in the order
+/// they're listed, and then the route's own, which take precedence over
+/// them (or add to them, for options that can be given more than once).
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    chains: BTreeMap<String, BTreeMap<String, toml::Value>>,
+    #[serde(default)]
+    routes: Vec<RouteConfig>
+}
+
+#[derive(Debug,Clone,Deserialize)]
+struct RouteConfig {
+    src: String,
+    dest: Dests,
+    #[serde(default)]
+    chain: Option<toml::Value>,
+    #[serde(flatten)]
+    options: BTreeMap<String, toml::Value>
+}
+
+#[derive(Debug,Clone,Deserialize)]
+#[serde(untagged)]
+enum Dests {
+    One(String),
+    Many(Vec<String>)
+}
+
+/// Load the routes from the config file at the path provided:
+pub fn from_file(path: impl AsRef<Path>) -> Result<Vec<Route>, Error> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        err!("Could not read config file '{}': {}", path.to_string_lossy(), e)
+    })?;
+    from_str(&contents).map_err(|e| {
+        err!("Error in config file '{}': {}", path.to_string_lossy(), e)
+    })
+}
+
+/// Parse the routes out of the contents of a config file:
+pub fn from_str(contents: &str) -> Result<Vec<Route>, Error> {
+    let config: Config = toml::from_str(contents)?;
+
+    let mut routes = vec![];
+    for (idx, route) in config.routes.into_iter().enumerate() {
+        let src = routes::interpolate(&route.src).map_err(|e| {
+            err!("route {}: {}", idx + 1, e)
+        })?;
+        let src = SrcLocation::parse(&src).map_err(|e| {
+            err!("route {}: Error parsing '{}': {}", idx + 1, route.src, e)
+        })?;
+        let dest_strs = match route.dest {
+            Dests::One(dest) => vec![dest],
+            Dests::Many(dests) => dests
+        };
+        if dest_strs.is_empty() {
+            return Err(err!("route {}: Expecting at least one destination", idx + 1));
+        }
+        let mut dests = vec![];
+        for dest in dest_strs {
+            let dest = routes::interpolate(&dest).map_err(|e| {
+                err!("route {}: {}", idx + 1, e)
+            })?;
+            dests.push(DestLocation::parse(&dest).map_err(|e| {
+                err!("route {}: Error parsing '{}': {}", idx + 1, dest, e)
+            })?);
+        }
+        let mut options = RouteOptions::default();
+        for name in route.chain.map(option_values).unwrap_or_default() {
+            let chain = config.chains.get(&name).ok_or_else(|| {
+                err!("route {}: There is no chain called '{}'", idx + 1, name)
+            })?;
+            for (key, value) in chain {
+                for value in option_values(value.clone()) {
+                    options.set(key, &value).map_err(|e| {
+                        err!("route {}: Error parsing option '{}' of chain '{}': {}", idx + 1, key, name, e)
+                    })?;
+                }
+            }
+        }
+        for (key, value) in route.options {
+            for value in option_values(value) {
+                options.set(&key, &value).map_err(|e| {
+                    err!("route {}: Error parsing option '{}': {}", idx + 1, key, e)
+                })?;
+            }
+        }
+        routes.push(Route {
+            src,
+            dests,
+            options
+        });
+    }
+    Ok(routes)
+}
+
+/// Write routes out as the contents of a config file, which `from_str`
+/// parses back into the same routes:
+pub fn to_string(routes: &[Route]) -> Result<String, Error> {
+    #[derive(Serialize)]
+    struct Emitted<'a> {
+        routes: &'a [Route]
+    }
+    Ok(toml::to_string(&Emitted { routes })?)
+}
+
+/// Options are set from strings, as they are on the command line,
+/// so turn TOML values back into those. Arrays set the option once for
+/// each value, as if it had been given several times.
+fn option_values(value: toml::Value) -> Vec<String> {
+    match value {
+        toml::Value::String(s) => vec![s],
+        toml::Value::Array(values) => {
+            values.into_iter()
+                .flat_map(option_values)
+                .collect()
+        },
+        other => vec![other.to_string()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::headers::{ HeaderRule };
+
+    #[test]
+    fn parses_routes_in_order() {
+        let routes = from_str(r#"
+            [[routes]]
+            src = "8080/api"
+            dest = ["http://localhost:9000", "9001"]
+
+            [[routes]]
+            src = "=8080/favicon.ico"
+            dest = "./static/favicon.ico"
+            cache-control = "max-age=60"
+            remove-response-header = ["Server", "X-Powered-By"]
+        "#).unwrap();
+
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].src, SrcLocation::parse("8080/api").unwrap());
+        assert_eq!(routes[0].dests, vec![
+            DestLocation::parse("http://localhost:9000").unwrap(),
+            DestLocation::parse("http://localhost:9001").unwrap()
+        ]);
+        assert!(routes[1].src.exact);
+        assert_ne!(routes[1].src, SrcLocation::parse("8080/favicon.ico").unwrap());
+        assert_ne!(routes[0].src, SrcLocation::parse("+8080/api").unwrap());
+        assert_eq!(routes[1].dests, vec![DestLocation::FilePath("./static/favicon.ico".to_owned())]);
+        assert_eq!(routes[0].options, RouteOptions::default());
+        assert_eq!(routes[1].options.cache_control, Some("max-age=60".to_owned()));
+        assert_eq!(routes[1].options.headers.response, vec![
+            HeaderRule::remove("server").unwrap(),
+            HeaderRule::remove("x-powered-by").unwrap()
+        ]);
+    }
+
+    #[test]
+    fn applies_chains_before_route_options() {
+        let routes = from_str(r#"
+            [chains.internal]
+            cache-control = "no-store"
+            request-header = "X-Internal: yes"
+
+            [chains.traced]
+            request-header = "X-Trace: on"
+
+            [[routes]]
+            src = "8080/api"
+            dest = "9000"
+            chain = ["internal", "traced"]
+            cache-control = "max-age=60"
+            request-header = "X-Route: api"
+
+            [[routes]]
+            src = "8080"
+            dest = "9001"
+            chain = "traced"
+        "#).unwrap();
+
+        assert_eq!(routes[0].options.cache_control, Some("max-age=60".to_owned()));
+        assert_eq!(routes[0].options.headers.request, vec![
+            HeaderRule::set("X-Internal: yes").unwrap(),
+            HeaderRule::set("X-Trace: on").unwrap(),
+            HeaderRule::set("X-Route: api").unwrap()
+        ]);
+        assert_eq!(routes[1].options.headers.request, vec![HeaderRule::set("X-Trace: on").unwrap()]);
+        assert!(from_str("[[routes]]\nsrc = \"8080\"\ndest = \"9000\"\nchain = \"missing\"").is_err());
+        assert!(from_str("[chains.bad]\nwibble = 1\n[[routes]]\nsrc = \"8080\"\ndest = \"9000\"\nchain = \"bad\"").is_err());
+    }
+
+    #[test]
+    fn complains_about_bad_routes() {
+        assert!(from_str("[[routes]]\nsrc = \"8080\"").is_err());
+        assert!(from_str("[[routes]]\nsrc = \"8080\"\ndest = []").is_err());
+        assert!(from_str("[[routes]]\nsrc = \"8080\"\ndest = \"ftp://foo\"").is_err());
+        assert!(from_str("[[routes]]\nsrc = \"8080\"\ndest = \"./foo\"\nwibble = 1").is_err());
+        assert!(from_str("[[routes]]\nsrc = \"8080\"\ndest = \"${WEAVE_TEST_UNSET}\"").is_err());
+    }
+
+    #[test]
+    fn writes_routes_that_parse_back() {
+        let args = r"GET,POST:=api.local:8080/users/{id} to http://localhost:9000/u/$1 with cache-control=no-cache response-header=X-A:1 response-header=X-B:2 spa
+            and +8080/(\d+) to ./files/$1 and-also ./$${WEAVE_TEST_LITERAL}
+            and unix:///tmp/weave.sock/api to unix:///var/run/app.sock/v1
+            and 8080/search?engine=v2 to redirect://https://example.com/new?status=301
+            and 8081/down to status://503?body=Back%20soon&header=Retry-After:3600
+            and 8081/hi to text:text/html://<b>hi</b>\n
+            and 8081/json to text://{}
+            and 8081/exec to exec://./status.sh
+            and 8081/grpc to h2c://localhost:50051
+            and 8082 to upgrade-https:8443";
+        let routes = crate::routes::from_str(args).unwrap();
+        let written = to_string(&routes).unwrap();
+        let parsed = from_str(&written).unwrap();
+        assert_eq!(parsed, routes);
+        for (a, b) in parsed.iter().zip(&routes) {
+            assert_eq!(a.src.to_input(), b.src.to_input());
+        }
+        assert!(written.contains(r#"response-header = ["X-A:1", "X-B:2"]"#));
+        assert!(written.contains("spa = true"));
+        assert_eq!(routes[1].dests[1], DestLocation::parse("./${WEAVE_TEST_LITERAL}").unwrap());
+    }
+}
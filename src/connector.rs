@@ -0,0 +1,280 @@
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{ Connected, Connection };
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::path::{ Path, PathBuf };
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use std::time::Duration;
+use tokio::io::{ AsyncRead, AsyncWrite, ReadBuf };
+use tokio::time;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tower_service::Service;
+use url::Url;
+use crate::errors::{ Error };
+
+/// Wraps a connector, failing any attempt to connect (including the
+/// TLS handshake, for HTTPS) that takes longer than the timeout given.
+/// Connections are pooled and shared across routes, so this applies to
+/// every route rather than being configurable per route.
+#[derive(Debug,Clone)]
+pub struct TimeoutConnector<C> {
+    connector: C,
+    timeout: Option<Duration>
+}
+
+impl <C> TimeoutConnector<C> {
+    pub fn new(connector: C, timeout: Option<Duration>) -> TimeoutConnector<C> {
+        TimeoutConnector { connector, timeout }
+    }
+}
+
+impl <C> Service<Uri> for TimeoutConnector<C>
+where
+    C: Service<Uri, Error = io::Error>,
+    C::Future: Send + 'static
+{
+    type Response = C::Response;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<C::Response, io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.connector.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let connecting = self.connector.call(dst);
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Box::pin(connecting)
+        };
+        Box::pin(async move {
+            time::timeout(timeout, connecting).await.map_err(|_| {
+                let msg = format!("connecting timed out after {:#?}", timeout);
+                io::Error::new(io::ErrorKind::TimedOut, msg)
+            })?
+        })
+    }
+}
+
+/// Wraps a connector, dialing Unix sockets for `unix://` URLs and handing
+/// anything else to the wrapped connector. The socket path is hex encoded
+/// into the host of the URL (see `unix_socket_url`), so that connections
+/// to different sockets are pooled separately.
+#[derive(Debug,Clone)]
+pub struct UnixConnector<C> {
+    connector: C
+}
+
+impl <C> UnixConnector<C> {
+    pub fn new(connector: C) -> UnixConnector<C> {
+        UnixConnector { connector }
+    }
+}
+
+impl <C> Service<Uri> for UnixConnector<C>
+where
+    C: Service<Uri, Error = io::Error>,
+    C::Future: Send + 'static
+{
+    type Response = TokioIo<Stream<C::Response>>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<TokioIo<Stream<C::Response>>, io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.connector.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        if dst.scheme_str() != Some("unix") {
+            let connecting = self.connector.call(dst);
+            return Box::pin(async move {
+                Ok(TokioIo::new(Stream::Other(connecting.await?)))
+            });
+        }
+
+        let path = dst.host().and_then(decode_socket_path);
+        Box::pin(async move {
+            let path = path.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "invalid unix socket URL")
+            })?;
+            Ok(TokioIo::new(connect_unix(&path).await?))
+        })
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix<T>(path: &Path) -> io::Result<Stream<T>> {
+    let stream = UnixStream::connect(path).await.map_err(|e| {
+        io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+    })?;
+    Ok(Stream::Unix(stream))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix<T>(_path: &Path) -> io::Result<Stream<T>> {
+    Err(io::Error::new(io::ErrorKind::Other, "unix sockets are not supported on this platform"))
+}
+
+/// The URL to proxy requests to a Unix socket through, since the socket
+/// path can't be given as a host as-is:
+pub fn unix_socket_url(path: &Path) -> Url {
+    let host: String = path.to_string_lossy().bytes().map(|b| format!("{:02x}", b)).collect();
+    Url::parse(&format!("unix://{}/", host)).expect("hex encoded host is valid")
+}
+
+/// The socket path for a URL built by `unix_socket_url`, if it is one:
+pub fn unix_socket_path(url: &Url) -> Option<PathBuf> {
+    if url.scheme() != "unix" {
+        return None;
+    }
+    decode_socket_path(url.host_str()?)
+}
+
+fn decode_socket_path(host: &str) -> Option<PathBuf> {
+    let bytes = (0..host.len()).step_by(2)
+        .map(|i| u8::from_str_radix(host.get(i..i+2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// Where to connect to for a host and port, in place of where DNS says,
+/// as curl's `--resolve` does (eg `api.example.com:443:10.0.0.5`). The
+/// port can be `*` to mean any. Requests still use the host's name, for
+/// their `Host` header and for TLS.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Resolve {
+    host: String,
+    port: Option<u16>,
+    addr: IpAddr
+}
+
+impl Resolve {
+    pub fn parse(input: &str) -> Result<Resolve, Error> {
+        let mut parts = input.trim().splitn(3, ':');
+        let (host, port, addr) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(host), Some(port), Some(addr)) if !host.is_empty() => (host, port, addr),
+            _ => return Err(err!("Expecting HOST:PORT:ADDRESS, like 'api.example.com:443:10.0.0.5'"))
+        };
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| err!("'{}' is not a valid port", port))?)
+        };
+        // IPv6 addresses can be given with or without brackets:
+        let addr = addr.trim_start_matches('[').trim_end_matches(']').parse()
+            .map_err(|_| err!("'{}' is not an IP address", addr))?;
+        Ok(Resolve { host: host.to_lowercase(), port, addr })
+    }
+}
+
+/// Point a destination at the address `--resolve` gives for it, if any.
+/// Hands back the name of the host it was for:
+pub fn resolve(overrides: &[Resolve], dst: &mut Uri) -> io::Result<String> {
+    let host = dst.host().unwrap_or("").to_owned();
+    let port = dst.port_u16().unwrap_or(if dst.scheme_str() == Some("http") { 80 } else { 443 });
+    let addr = overrides.iter()
+        .find(|o| o.host.eq_ignore_ascii_case(&host) && o.port.map(|p| p == port).unwrap_or(true))
+        .map(|o| o.addr);
+    let addr = match addr {
+        Some(IpAddr::V6(addr)) => format!("[{}]", addr),
+        Some(addr) => addr.to_string(),
+        None => return Ok(host)
+    };
+    let authority = match dst.port_u16() {
+        Some(port) => format!("{}:{}", addr, port),
+        None => addr
+    };
+    let mut parts = dst.clone().into_parts();
+    parts.authority = Some(authority.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?);
+    *dst = Uri::from_parts(parts).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(host)
+}
+
+/// A connection made by `UnixConnector`:
+pub enum Stream<T> {
+    Other(T),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl <T: Connection> Connection for Stream<T> {
+    fn connected(&self) -> Connected {
+        match self {
+            Stream::Other(s) => s.connected(),
+            #[cfg(unix)]
+            Stream::Unix(_) => Connected::new()
+        }
+    }
+}
+
+impl <T: AsyncRead + Unpin> AsyncRead for Stream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Other(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_read(cx, buf)
+        }
+    }
+}
+
+impl <T: AsyncWrite + Unpin> AsyncWrite for Stream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Other(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Other(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Other(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(s) => Pin::new(s).poll_shutdown(cx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn round_trips_unix_socket_paths() {
+        let url = unix_socket_url(Path::new("/var/run/app.sock"));
+        assert_eq!(url.scheme(), "unix");
+        assert_eq!(unix_socket_path(&url), Some(PathBuf::from("/var/run/app.sock")));
+        assert_eq!(unix_socket_path(&Url::parse("http://localhost/").unwrap()), None);
+        assert_eq!(decode_socket_path("2f7"), None);
+    }
+
+    #[test]
+    fn resolves_hosts_to_the_addresses_given() {
+        let overrides = vec![
+            Resolve::parse("api.example.com:443:10.0.0.5").unwrap(),
+            Resolve::parse("API.example.com:*:[fd00::5]").unwrap()
+        ];
+        let resolved = |uri: &str| {
+            let mut dst: Uri = uri.parse().unwrap();
+            let host = resolve(&overrides, &mut dst).unwrap();
+            (host, dst.host().unwrap().to_owned(), dst.port_u16())
+        };
+        assert_eq!(resolved("https://api.example.com/v1"), ("api.example.com".to_owned(), "10.0.0.5".to_owned(), None));
+        assert_eq!(resolved("http://Api.Example.com:8080/"), ("Api.Example.com".to_owned(), "[fd00::5]".to_owned(), Some(8080)));
+        assert_eq!(resolved("http://other.example.com/"), ("other.example.com".to_owned(), "other.example.com".to_owned(), None));
+        assert!(Resolve::parse("api.example.com:443").is_err());
+        assert!(Resolve::parse("api.example.com:443:example.net").is_err());
+    }
+}
@@ -0,0 +1,308 @@
+use ansi_term::Color::{ Green, Red };
+use clap::ArgMatches;
+use hyper::{ Request };
+use lazy_static::lazy_static;
+use log::{ info, warn };
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex, RwLock, Weak };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::time::Duration;
+use tokio::time::{ self, sleep };
+use url::Url;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::location::{ FixedResponse, ResolvedLocation };
+use crate::matcher::{ Matcher };
+use crate::options::{ parse_period, TlsOptions };
+use crate::proxy;
+use crate::HttpsClient;
+
+/// Where the Consul agent is, unless `CONSUL_HTTP_ADDR` or `--consul` say otherwise:
+const DEFAULT_ADDR: &str = "http://127.0.0.1:8500";
+/// How often we ask Consul which instances of a service are healthy,
+/// unless `--consul-interval` says otherwise:
+pub const DEFAULT_CONSUL_INTERVAL: Duration = Duration::from_secs(10);
+/// How long we wait for Consul to answer:
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// The instances of each service that `consul://` destinations are
+    /// for, kept by name so that they carry on across reloads for as long
+    /// as some route still wants them:
+    static ref SERVICES: Mutex<HashMap<String, Weak<Instances>>> = Mutex::new(HashMap::new());
+}
+
+/// Where to find the Consul agent that `consul://` destinations are
+/// looked up with, and how often to look them up again.
+#[derive(Debug,Clone)]
+pub struct ConsulOptions {
+    pub addr: Url,
+    /// Sent as `X-Consul-Token`, if Consul's ACLs need one:
+    pub token: Option<String>,
+    pub interval: Duration
+}
+
+impl Default for ConsulOptions {
+    fn default() -> ConsulOptions {
+        ConsulOptions {
+            addr: Url::parse(DEFAULT_ADDR).unwrap(),
+            token: None,
+            interval: DEFAULT_CONSUL_INTERVAL
+        }
+    }
+}
+
+impl ConsulOptions {
+    /// The agent is found the way Consul's own tools find it, from
+    /// `CONSUL_HTTP_ADDR` and `CONSUL_HTTP_TOKEN`, unless `--consul` says
+    /// where it is:
+    pub fn from_matches(matches: &ArgMatches) -> Result<ConsulOptions, Error> {
+        let mut options = ConsulOptions::default();
+        let addr = matches.value_of("consul").map(|s| s.to_owned()).or_else(|| std::env::var("CONSUL_HTTP_ADDR").ok());
+        if let Some(addr) = addr {
+            options.addr = parse_addr(&addr).map_err(|e| err!("Invalid Consul address '{}': {}", addr, e))?;
+        }
+        options.token = std::env::var("CONSUL_HTTP_TOKEN").ok().filter(|token| !token.is_empty());
+        if let Some(s) = matches.value_of("consul-interval") {
+            options.interval = parse_period(s).map_err(|e| err!("Invalid --consul-interval '{}': {}", s, e))?;
+        }
+        Ok(options)
+    }
+}
+
+/// Consul addresses can leave out the scheme (eg `127.0.0.1:8500`):
+fn parse_addr(input: &str) -> Result<Url, Error> {
+    let url = if input.contains("://") { Url::parse(input)? } else { Url::parse(&format!("http://{}", input))? };
+    if !["http", "https"].contains(&url.scheme()) || url.cannot_be_a_base() {
+        return Err(err!("Expecting an http:// or https:// address"));
+    }
+    Ok(url)
+}
+
+/// The healthy instances of a service, as of the last time we asked
+/// Consul, which requests to it take turns going to.
+#[derive(Debug)]
+pub struct Instances {
+    service: String,
+    addrs: RwLock<Vec<(String, u16)>>,
+    next: AtomicUsize,
+    /// Is something keeping these up to date?
+    refreshing: AtomicBool
+}
+
+impl Instances {
+    /// The instances of a service, shared with any other routes to it:
+    pub fn of(service: &str) -> Arc<Instances> {
+        let mut services = SERVICES.lock().unwrap();
+        if let Some(instances) = services.get(service).and_then(Weak::upgrade) {
+            return instances;
+        }
+        services.retain(|_, instances| instances.strong_count() > 0);
+        let instances = Arc::new(Instances {
+            service: service.to_owned(),
+            addrs: RwLock::new(vec![]),
+            next: AtomicUsize::new(0),
+            refreshing: AtomicBool::new(false)
+        });
+        services.insert(service.to_owned(), Arc::downgrade(&instances));
+        instances
+    }
+
+    /// Where a request for a `consul://` destination would go for each of
+    /// the service's instances:
+    pub fn each(&self, location: ResolvedLocation) -> Vec<ResolvedLocation> {
+        let url = match location {
+            ResolvedLocation::Url(url) => url,
+            location => return vec![location]
+        };
+        self.addrs.read().unwrap().iter()
+            .map(|(host, port)| ResolvedLocation::Url(with_addr(url.clone(), host, *port)))
+            .collect()
+    }
+
+    /// Send a request for a `consul://` destination to the next of the
+    /// service's instances, or answer it with a 503 if none are healthy:
+    pub fn pick(&self, location: ResolvedLocation) -> ResolvedLocation {
+        let url = match location {
+            ResolvedLocation::Url(url) => url,
+            location => return location
+        };
+        let addrs = self.addrs.read().unwrap();
+        if addrs.is_empty() {
+            return ResolvedLocation::Fixed(FixedResponse {
+                status: 503,
+                headers: vec![],
+                body: format!("Weave: No healthy instances of '{}' in Consul", self.service)
+            });
+        }
+        let (host, port) = &addrs[self.next.fetch_add(1, Ordering::Relaxed) % addrs.len()];
+        ResolvedLocation::Url(with_addr(url, host, *port))
+    }
+}
+
+fn with_addr(mut url: Url, host: &str, port: u16) -> Url {
+    let _ = url.set_host(Some(host));
+    let _ = url.set_port(Some(port));
+    url
+}
+
+/// Start looking up the instances of each service that routes have
+/// `consul://` destinations for, unless we already are. Lookups for a
+/// service stop once no routes want it.
+pub fn spawn_lookups(matcher: &Matcher, client: &HttpsClient, options: &ConsulOptions) {
+    for instances in matcher.consul_instances() {
+        if instances.refreshing.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+        tokio::spawn(look_up_periodically(client.clone(), options.clone(), instances.service.clone(), Arc::downgrade(instances)));
+    }
+}
+
+async fn look_up_periodically(client: HttpsClient, options: ConsulOptions, service: String, instances: Weak<Instances>) {
+    let mut failing = None;
+    loop {
+        let res = look_up(&client, &options, &service).await;
+
+        // The routes we were looking up for are gone, so stop:
+        let instances = match instances.upgrade() {
+            Some(instances) => instances,
+            None => return
+        };
+
+        match res {
+            Ok(addrs) => {
+                failing = None;
+                let mut current = instances.addrs.write().unwrap();
+                if *current != addrs {
+                    let listed: Vec<String> = addrs.iter().map(|(host, port)| format!("{}:{}", host, port)).collect();
+                    if addrs.is_empty() {
+                        warn!("{}", Red.paint(format!("[consul] {} has no healthy instances", service)));
+                    } else {
+                        info!("{}", Green.paint(format!("[consul] {} has {} healthy instances ({})", service, addrs.len(), listed.join(", "))));
+                    }
+                    *current = addrs;
+                }
+            },
+            // We keep sending requests to the instances we knew of, and
+            // only say so when the lookups start failing (or fail differently):
+            Err(e) => {
+                let e = e.to_string();
+                if failing.as_ref() != Some(&e) {
+                    warn!("{}", Red.paint(format!("[consul] Can't look up {} ({})", service, e)));
+                    failing = Some(e);
+                }
+            }
+        }
+
+        drop(instances);
+        sleep(options.interval).await;
+    }
+}
+
+/// An instance of a service, as Consul lists them:
+#[derive(Debug,Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Entry {
+    node: Node,
+    service: Service
+}
+
+#[derive(Debug,Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    address: String
+}
+
+#[derive(Debug,Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    #[serde(default)]
+    address: String,
+    port: u16
+}
+
+/// Ask Consul for the instances of a service that are passing their
+/// health checks:
+async fn look_up(client: &HttpsClient, options: &ConsulOptions, service: &str) -> Result<Vec<(String, u16)>, Error> {
+    let mut url = options.addr.clone();
+    url.path_segments_mut()
+        .map_err(|_| err!("Invalid Consul address"))?
+        .pop_if_empty()
+        .extend(&["v1", "health", "service", service]);
+    url.query_pairs_mut().append_pair("passing", "true");
+
+    let mut req = Request::get(url.as_str());
+    if let Some(token) = &options.token {
+        req = req.header("X-Consul-Token", token.as_str());
+    }
+    let req = req.body(Body::empty())?;
+    let looked_up = time::timeout(LOOKUP_TIMEOUT, async {
+        let resp = client.request(req, &TlsOptions::default()).await?;
+        if !resp.status().is_success() {
+            return Err(err!("Consul responded with {}", resp.status()));
+        }
+        proxy::read_body(resp.into_body()).await
+    });
+    let body = looked_up.await.map_err(|_| err!("timed out after {:#?}", LOOKUP_TIMEOUT))??;
+    let entries: Vec<Entry> = serde_json::from_slice(&body)
+        .map_err(|e| err!("Can't understand the instances Consul listed: {}", e))?;
+    Ok(addrs_of(entries))
+}
+
+/// Where to send requests for each instance. Services registered without
+/// an address of their own are on their node's address:
+fn addrs_of(entries: Vec<Entry>) -> Vec<(String, u16)> {
+    let mut addrs: Vec<(String, u16)> = entries.into_iter()
+        .map(|entry| {
+            let addr = if entry.service.address.is_empty() { entry.node.address } else { entry.service.address };
+            // IPv6 addresses need brackets to go in a URL:
+            let addr = if addr.contains(':') { format!("[{}]", addr) } else { addr };
+            (addr, entry.service.port)
+        })
+        .collect();
+    // Consul doesn't promise an order, and we only want to hear about changes:
+    addrs.sort();
+    addrs.dedup();
+    addrs
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn picks_healthy_instances_in_turn() {
+        let entries: Vec<Entry> = serde_json::from_str(r#"[
+            {"Node": {"Address": "10.0.0.2"}, "Service": {"Address": "", "Port": 9001}},
+            {"Node": {"Address": "10.0.0.1"}, "Service": {"Address": "172.17.0.3", "Port": 9000}},
+            {"Node": {"Address": "10.0.0.3"}, "Service": {"Address": "fd00::3", "Port": 9000}}
+        ]"#).unwrap();
+        let instances = Instances::of("consul-test-api");
+        *instances.addrs.write().unwrap() = addrs_of(entries);
+
+        let url = Url::parse("http://consul-test-api/v1/users?page=2").unwrap();
+        let picked: Vec<ResolvedLocation> = (0..4).map(|_| instances.pick(ResolvedLocation::Url(url.clone()))).collect();
+        assert_eq!(picked[0], ResolvedLocation::Url(Url::parse("http://10.0.0.2:9001/v1/users?page=2").unwrap()));
+        assert_eq!(picked[1], ResolvedLocation::Url(Url::parse("http://172.17.0.3:9000/v1/users?page=2").unwrap()));
+        assert_eq!(picked[2], ResolvedLocation::Url(Url::parse("http://[fd00::3]:9000/v1/users?page=2").unwrap()));
+        assert_eq!(picked[3], picked[0]);
+
+        // Routes to the same service share its instances:
+        assert!(Arc::ptr_eq(&instances, &Instances::of("consul-test-api")));
+
+        *instances.addrs.write().unwrap() = vec![];
+        match instances.pick(ResolvedLocation::Url(url)) {
+            ResolvedLocation::Fixed(res) => assert_eq!(res.status, 503),
+            other => panic!("expecting a 503, got {}", other)
+        }
+    }
+
+    #[test]
+    fn parses_agent_addresses() {
+        assert_eq!(parse_addr("127.0.0.1:8500").unwrap().as_str(), "http://127.0.0.1:8500/");
+        assert_eq!(parse_addr("https://consul.internal").unwrap().as_str(), "https://consul.internal/");
+        assert!(parse_addr("unix:///var/run/consul.sock").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use log::{ info };
+use crate::config;
+use crate::errors::{ Error };
+use crate::listeners::{ ListenAddr, Listeners };
+use crate::routes::{ Route };
+use crate::scripts;
+
+/// The routes we're serving, and the listeners serving them. Routes can be
+/// reloaded from the command line and config file (on SIGHUP, say), or
+/// added and removed one at a time (from the admin API). Changes made one
+/// at a time are lost the next time we reload.
+pub struct Control {
+    listeners: Listeners,
+    cli_routes: Vec<Route>,
+    config_path: Option<String>,
+    /// Routes found by watching something (like Docker), which are kept
+    /// when reloading:
+    discovered: Vec<Route>,
+    /// Can we serve no routes at all, since some may be discovered later?
+    discovering: bool,
+    routes: Vec<Route>
+}
+
+impl Control {
+    pub fn new(listeners: Listeners, cli_routes: Vec<Route>, config_path: Option<String>) -> Control {
+        Control {
+            listeners,
+            cli_routes,
+            config_path,
+            discovered: vec![],
+            discovering: false,
+            routes: vec![]
+        }
+    }
+
+    /// The routes we're serving right now, in the order they're matched:
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Serve the routes provided on the command line, along with any from
+    /// the config file, in place of those we're serving now. Their
+    /// scripts are loaded again too:
+    pub fn reload(&mut self) -> Result<(), Error> {
+        let cli_routes = self.cli_routes.iter().map(scripts::reload).collect::<Result<Vec<_>, Error>>()?;
+        let mut routes = with_config_routes(cli_routes, self.config_path.as_deref(), self.discovering)?;
+        routes.extend(self.discovered.iter().cloned());
+        self.set_routes(routes)
+    }
+
+    /// Expect routes to be discovered, so that we can start out serving
+    /// none at all:
+    pub fn discovering(&mut self) {
+        self.discovering = true;
+    }
+
+    /// Serve the routes we've discovered now, in place of those we'd
+    /// discovered before:
+    pub fn set_discovered(&mut self, discovered: Vec<Route>) -> Result<(), Error> {
+        let mut routes: Vec<Route> = self.routes.iter()
+            .filter(|route| !self.discovered.contains(route))
+            .cloned()
+            .collect();
+        routes.extend(discovered.iter().cloned());
+        self.set_routes(routes)?;
+        self.discovered = discovered;
+        Ok(())
+    }
+
+    /// Serve some more routes, after those we're serving now:
+    pub fn add(&mut self, new_routes: Vec<Route>) -> Result<(), Error> {
+        let mut routes = self.routes.clone();
+        routes.extend(new_routes);
+        self.set_routes(routes)
+    }
+
+    /// Stop serving a route, handing it back:
+    pub fn remove(&mut self, idx: usize) -> Result<Route, Error> {
+        if idx >= self.routes.len() {
+            return Err(err!("There is no route {}", idx));
+        }
+        let mut routes = self.routes.clone();
+        let route = routes.remove(idx);
+        self.set_routes(routes)?;
+        Ok(route)
+    }
+
+    /// Where we're listening for the routes we're serving, in the order of
+    /// the routes they're first for:
+    pub fn addrs(&self) -> Vec<ListenAddr> {
+        let mut addrs = vec![];
+        for route in &self.routes {
+            let local_addr = route.listen_addr().ok().and_then(|addr| self.listeners.local_addr(&addr));
+            if let Some(addr) = local_addr {
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+        addrs
+    }
+
+    /// Stop serving any routes, until we're reloaded:
+    pub fn stop(&mut self) {
+        let _ = self.listeners.update(vec![]);
+        self.routes = vec![];
+    }
+
+    /// Swap in a new set of routes. If something is wrong with them, we
+    /// go back to serving the ones we had:
+    fn set_routes(&mut self, routes: Vec<Route>) -> Result<(), Error> {
+        if let Err(e) = self.listeners.update(routes.clone()) {
+            let _ = self.listeners.update(self.routes.clone());
+            return Err(e);
+        }
+        self.routes = routes;
+        Ok(())
+    }
+}
+
+/// Add any routes from the config file to those provided on the command
+/// line, complaining if we end up with no routes at all (unless we're
+/// discovering some).
+fn with_config_routes(mut routes: Vec<Route>, config_path: Option<&str>, discovering: bool) -> Result<Vec<Route>, Error> {
+    if let Some(config_path) = config_path {
+        routes.extend(config::from_file(config_path)?);
+    }
+
+    if routes.is_empty() && !discovering {
+        return Err(err!("No routes have been provided. Use -h or --help for more information"));
+    }
+
+    // Log our routes:
+    for route in &routes {
+        info!("Routing {} to {}", route.src, route.dests_to_string());
+    }
+
+    Ok(routes)
+}
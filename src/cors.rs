@@ -0,0 +1,314 @@
+use hyper::{ HeaderMap, Method, Request, Response, StatusCode };
+use hyper::header::{
+    HeaderValue,
+    ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD,
+    ORIGIN
+};
+use std::time::Duration;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::headers;
+use crate::matcher::{ Matcher };
+use crate::settings::{ Settings };
+
+/// The methods we allow cross-origin requests to use, if not provided:
+pub const DEFAULT_CORS_METHODS: &[Method] = &[
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE
+];
+
+/// Which cross-origin requests browsers can make to a route, and what they
+/// can see of the responses.
+#[derive(Debug,Clone,PartialEq)]
+pub struct CorsOptions {
+    /// The origins allowed to make requests, like `https://example.com`,
+    /// or `*` for any. If empty, we leave CORS to the destination:
+    pub origins: Vec<String>,
+    /// The methods those requests can use:
+    pub methods: Vec<Method>,
+    /// The headers those requests can send. If empty, we allow whichever
+    /// headers a browser asks about:
+    pub headers: Vec<String>,
+    /// The response headers (besides the basic ones) that scripts can see:
+    pub expose: Vec<String>,
+    /// Can requests carry cookies and other credentials?
+    pub credentials: bool,
+    /// How long browsers can remember the answer to a preflight request:
+    pub max_age: Option<Duration>
+}
+
+impl Default for CorsOptions {
+    fn default() -> CorsOptions {
+        CorsOptions {
+            origins: vec![],
+            methods: DEFAULT_CORS_METHODS.to_vec(),
+            headers: vec![],
+            expose: vec![],
+            credentials: false,
+            max_age: None
+        }
+    }
+}
+
+impl CorsOptions {
+    /// Anything goes, with credentials. Handy for development, but not
+    /// something to expose to the world:
+    pub fn permissive() -> CorsOptions {
+        CorsOptions {
+            origins: vec!["*".to_owned()],
+            credentials: true,
+            ..CorsOptions::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.origins.is_empty()
+    }
+
+    /// Parse a comma separated list of origins, `*` for any, or a bool:
+    pub fn parse_origins(input: &str) -> Result<Vec<String>, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "true" | "yes" | "on" | "*" => return Ok(vec!["*".to_owned()]),
+            "false" | "no" | "off" => return Ok(vec![]),
+            _ => {}
+        }
+        input.split(',')
+            .map(|origin| {
+                let origin = origin.trim().trim_end_matches('/');
+                if origin.starts_with("http://") || origin.starts_with("https://") {
+                    Ok(origin.to_lowercase())
+                } else {
+                    Err(err!("Expecting an origin like https://example.com but got '{}'", origin))
+                }
+            })
+            .collect()
+    }
+
+    /// Parse a comma separated list of methods, like `GET,POST`:
+    pub fn parse_methods(input: &str) -> Result<Vec<Method>, Error> {
+        input.split(',')
+            .map(|name| {
+                let name = name.trim().to_uppercase();
+                Method::from_bytes(name.as_bytes()).map_err(|_| err!("Invalid method '{}'", name))
+            })
+            .collect()
+    }
+
+    /// Parse a comma separated list of header names:
+    pub fn parse_headers(input: &str) -> Result<Vec<String>, Error> {
+        input.split(',')
+            .map(|name| headers::parse_name(name).map(|name| name.as_str().to_owned()))
+            .collect()
+    }
+
+    /// What to say the allowed origin is, if this one is allowed. We can
+    /// only say `*` if credentials aren't allowed; otherwise we name it:
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        let any = self.origins.iter().any(|o| o == "*");
+        if any && !self.credentials {
+            Some("*")
+        } else if any || self.origins.iter().any(|o| o.eq_ignore_ascii_case(origin)) {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+}
+
+/// The CORS policy for a cross-origin request, if there is one. That's the
+/// one for the route the request matches, or the permissive one if we were
+/// asked to use that everywhere. Preflight requests match the route that
+/// the request they're asking about would.
+pub fn policy<T>(req: &Request<T>, matcher: &Matcher, settings: &Settings) -> Option<CorsOptions> {
+    if !req.headers().contains_key(ORIGIN) {
+        return None;
+    }
+    let method = requested_method(req).unwrap_or_else(|| req.method().clone());
+    let options = matcher.route_for(req, &method).map(|route| &route.options.cors);
+    match options {
+        Some(options) if options.is_enabled() => Some(options.clone()),
+        _ if settings.cors_permissive => Some(CorsOptions::permissive()),
+        _ => None
+    }
+}
+
+/// Is this a browser asking whether it can make a cross-origin request?
+pub fn is_preflight<T>(req: &Request<T>) -> bool {
+    req.method() == Method::OPTIONS && req.headers().contains_key(ORIGIN) && requested_method(req).is_some()
+}
+
+/// Answer a preflight request. If the origin, method or headers asked
+/// about aren't allowed, we leave out the headers that would allow them.
+pub fn preflight<T>(req: &Request<T>, options: &CorsOptions) -> Response<Body> {
+    let mut resp = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap();
+    let resp_headers = resp.headers_mut();
+    headers::add_vary(resp_headers, "Origin");
+    headers::add_vary(resp_headers, "Access-Control-Request-Method");
+    headers::add_vary(resp_headers, "Access-Control-Request-Headers");
+
+    let allow_origin = match options.allow_origin(origin(req)) {
+        Some(origin) => origin,
+        None => return resp
+    };
+    let method_allowed = requested_method(req)
+        .filter(|method| options.methods.contains(method))
+        .is_some();
+    let requested_headers: Vec<String> = req.headers().get_all(ACCESS_CONTROL_REQUEST_HEADERS).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let headers_allowed = options.headers.is_empty()
+        || requested_headers.iter().all(|name| options.headers.iter().any(|h| h.eq_ignore_ascii_case(name)));
+    if !method_allowed || !headers_allowed {
+        return resp;
+    }
+
+    let methods: Vec<&str> = options.methods.iter().map(|m| m.as_str()).collect();
+    let allow_headers = if options.headers.is_empty() { requested_headers.join(", ") } else { options.headers.join(", ") };
+    insert(resp_headers, ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    insert(resp_headers, ACCESS_CONTROL_ALLOW_METHODS, &methods.join(", "));
+    if !allow_headers.is_empty() {
+        insert(resp_headers, ACCESS_CONTROL_ALLOW_HEADERS, &allow_headers);
+    }
+    if options.credentials {
+        resp_headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+    }
+    if let Some(max_age) = options.max_age {
+        resp_headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+    }
+    resp
+}
+
+/// Tell the browser what it can see of the response to a cross-origin
+/// request. The route's policy replaces whatever the destination said.
+pub fn add_headers(origin: &str, resp_headers: &mut HeaderMap, options: &CorsOptions) {
+    resp_headers.remove(ACCESS_CONTROL_ALLOW_ORIGIN);
+    resp_headers.remove(ACCESS_CONTROL_ALLOW_CREDENTIALS);
+    resp_headers.remove(ACCESS_CONTROL_EXPOSE_HEADERS);
+
+    let allow_origin = options.allow_origin(origin);
+    if allow_origin != Some("*") {
+        headers::add_vary(resp_headers, "Origin");
+    }
+    let allow_origin = match allow_origin {
+        Some(origin) => origin,
+        None => return
+    };
+    insert(resp_headers, ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    if options.credentials {
+        resp_headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+    }
+    if !options.expose.is_empty() {
+        insert(resp_headers, ACCESS_CONTROL_EXPOSE_HEADERS, &options.expose.join(", "));
+    }
+}
+
+/// The origin a cross-origin request came from:
+pub fn origin<T>(req: &Request<T>) -> &str {
+    req.headers().get(ORIGIN).and_then(|o| o.to_str().ok()).unwrap_or("")
+}
+
+fn requested_method<T>(req: &Request<T>) -> Option<Method> {
+    let method = req.headers().get(ACCESS_CONTROL_REQUEST_METHOD)?.to_str().ok()?;
+    Method::from_bytes(method.trim().as_bytes()).ok()
+}
+
+fn insert(headers: &mut HeaderMap, name: hyper::header::HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn preflight_req(origin: &str, method: &str, headers: &str) -> Request<()> {
+        let mut req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method);
+        if !headers.is_empty() {
+            req = req.header(ACCESS_CONTROL_REQUEST_HEADERS, headers);
+        }
+        req.body(()).unwrap()
+    }
+
+    fn options(origins: &str) -> CorsOptions {
+        CorsOptions { origins: CorsOptions::parse_origins(origins).unwrap(), ..CorsOptions::default() }
+    }
+
+    #[test]
+    fn parses_cors_options() {
+        assert_eq!(CorsOptions::parse_origins("true").unwrap(), vec!["*"]);
+        assert_eq!(CorsOptions::parse_origins("https://a.com, http://B.com:8080/").unwrap(), vec!["https://a.com", "http://b.com:8080"]);
+        assert!(CorsOptions::parse_origins("a.com").is_err());
+        assert_eq!(CorsOptions::parse_methods("get, POST").unwrap(), vec![Method::GET, Method::POST]);
+        assert_eq!(CorsOptions::parse_headers("Content-Type, X-Token").unwrap(), vec!["content-type", "x-token"]);
+        assert!(CorsOptions::parse_headers("bad header").is_err());
+    }
+
+    #[test]
+    fn answers_preflight_requests() {
+        let options = CorsOptions { max_age: Some(Duration::from_secs(600)), ..options("https://a.com") };
+        let resp = preflight(&preflight_req("https://a.com", "PUT", "Content-Type, X-Token"), &options);
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let headers = resp.headers();
+        assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://a.com");
+        assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "GET, HEAD, POST, PUT, PATCH, DELETE");
+        assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), "content-type, x-token");
+        assert_eq!(headers.get(ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+        assert!(headers.get(ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+
+        // Other origins, methods and headers get nothing:
+        let denied = |req| preflight(&req, &options).headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none();
+        assert!(denied(preflight_req("https://b.com", "PUT", "")));
+        assert!(denied(preflight_req("https://a.com", "TRACE", "")));
+        let options = CorsOptions { headers: vec!["content-type".to_owned()], ..options.clone() };
+        assert!(preflight(&preflight_req("https://a.com", "PUT", "X-Token"), &options).headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[test]
+    fn adds_headers_to_responses() {
+        let origin = "https://a.com";
+
+        // Any origin, without credentials, is '*':
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("https://upstream.com"));
+        add_headers(origin, &mut headers, &options("*"));
+        assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(headers.get("vary").is_none());
+
+        // With credentials, the origin is named:
+        let mut headers = HeaderMap::new();
+        let options = CorsOptions { expose: vec!["x-total".to_owned()], ..CorsOptions::permissive() };
+        add_headers(origin, &mut headers, &options);
+        assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://a.com");
+        assert_eq!(headers.get(ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+        assert_eq!(headers.get(ACCESS_CONTROL_EXPOSE_HEADERS).unwrap(), "x-total");
+        assert_eq!(headers.get("vary").unwrap(), "Origin");
+
+        // Origins not allowed get nothing:
+        let mut headers = HeaderMap::new();
+        add_headers(origin, &mut headers, &self::options("https://b.com"));
+        assert!(headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+}
@@ -0,0 +1,158 @@
+use hyper::{ HeaderMap, Method, Request };
+use http_body::Body as _;
+use hyper::header::{ CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING };
+use log::{ info, error };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use tokio::fs;
+use url::Url;
+use crate::body::{ Body };
+use crate::connector;
+use crate::options::{ RouteOptions };
+use crate::proxy;
+use crate::settings::{ Settings };
+
+/// The target of the curl commands we log:
+pub const CURL: &str = "weave::curl";
+/// The most of a body we save for a curl command to send. Requests with
+/// more than this are noted as such:
+const MAX_CURL_BODY: usize = 64 * 1024 * 1024;
+
+/// Numbers each request body we save, so that they get files of their own
+/// (along with our process ID, for directories shared between runs):
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Where request bodies are saved, if not provided:
+pub fn default_bodies_dir() -> PathBuf {
+    std::env::temp_dir().join("weave-curl")
+}
+
+/// Log a curl command that sends the same request as we're about to send to
+/// `url`, if we've been asked to, so that failures can be reproduced without
+/// us. Bodies are saved to a file for the command to read, once they've been
+/// passed on, so this hands back a request to send instead.
+pub fn request(req: Request<Body>, url: &Url, route: &RouteOptions, settings: &Settings) -> Request<Body> {
+    let dir = match &settings.curl {
+        Some(dir) => dir.clone(),
+        None => return req
+    };
+    let args = arguments(&req, url, route, settings);
+    if req.body().is_end_stream() {
+        info!(target: CURL, "{}", command(&args, None));
+        return req;
+    }
+
+    let path = dir.join(format!("{}-{}.body", std::process::id(), NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+    req.map(|body| proxy::tee(body, MAX_CURL_BODY, move |teed| {
+        tokio::spawn(async move {
+            if let Err(e) = fs::write(path.clone(), teed.start).await {
+                error!("Failed to save request body to '{}': {}", path.display(), e);
+                return;
+            }
+            let mut line = command(&args, Some(&path));
+            if !teed.complete {
+                line.push_str(&format!("\n(the request body was cut short after {} bytes)", teed.total));
+            } else if teed.total > MAX_CURL_BODY {
+                line.push_str(&format!("\n(only the first {} of {} bytes of the request body were saved)", MAX_CURL_BODY, teed.total));
+            }
+            info!(target: CURL, "{}", line);
+        });
+    }))
+}
+
+/// Everything but the body, quoted for a shell:
+fn arguments(req: &Request<Body>, url: &Url, route: &RouteOptions, settings: &Settings) -> Vec<String> {
+    let mut args = vec!["curl".to_owned()];
+    match req.method() {
+        &Method::GET => {},
+        &Method::HEAD => args.push("--head".to_owned()),
+        method => args.extend(vec!["-X".to_owned(), quote(method.as_str())])
+    }
+
+    let mut url = url.clone();
+    if let Some(socket) = connector::unix_socket_path(&url) {
+        args.extend(vec!["--unix-socket".to_owned(), quote(&socket.to_string_lossy())]);
+        url = Url::parse(&format!("http://localhost{}", &url[url::Position::BeforePath..])).unwrap();
+    } else if url.scheme() == "h2c" {
+        args.push("--http2-prior-knowledge".to_owned());
+        url = Url::parse(&format!("http{}", &url[url::Position::AfterScheme..])).unwrap();
+    }
+
+    let tls = &route.tls;
+    if settings.insecure || tls.insecure {
+        args.push("--insecure".to_owned());
+    }
+    for (flag, path) in &[("--cacert", &tls.ca_file), ("--cert", &tls.client_cert), ("--key", &tls.client_key)] {
+        if let Some(path) = path {
+            args.extend(vec![flag.to_string(), quote(&path.to_string_lossy())]);
+        }
+    }
+
+    for header in headers(req.headers(), route.preserve_host) {
+        args.extend(vec!["-H".to_owned(), quote(&header)]);
+    }
+    args.push(quote(url.as_str()));
+    args
+}
+
+/// The headers curl should send as they are. It works out the host and the
+/// length of the body itself, and how it connects is up to it:
+fn headers(headers: &HeaderMap, preserve_host: bool) -> Vec<String> {
+    headers.iter()
+        .filter(|(name, _)| ![CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING].contains(name))
+        .filter(|(name, _)| preserve_host || name != &HOST)
+        .map(|(name, value)| format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes())))
+        .collect()
+}
+
+fn command(args: &[String], body: Option<&Path>) -> String {
+    let mut command = args.join(" ");
+    if let Some(path) = body {
+        command.push_str(&format!(" --data-binary {}", quote(&format!("@{}", path.display()))));
+    }
+    command
+}
+
+/// Quote an argument for a POSIX shell, if it needs it:
+fn quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@,+%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn quotes_for_the_shell() {
+        assert_eq!(quote("http://a/b?c=1"), "'http://a/b?c=1'");
+        assert_eq!(quote("-X"), "-X");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn builds_commands() {
+        let req = Request::post("/api?q=1")
+            .header("host", "example.com")
+            .header("content-length", "2")
+            .header("x-token", "a b")
+            .body(Body::empty())
+            .unwrap();
+        let url = Url::parse("http://localhost:3000/api?q=1").unwrap();
+        let args = arguments(&req, &url, &RouteOptions::default(), &Settings::default());
+        assert_eq!(command(&args, Some(Path::new("/tmp/1.body"))),
+                   "curl -X POST -H 'x-token: a b' 'http://localhost:3000/api?q=1' --data-binary @/tmp/1.body");
+
+        let unix = connector::unix_socket_url(Path::new("/run/app.sock")).join("/x").unwrap();
+        let req = Request::head("/x").body(Body::empty()).unwrap();
+        let options = RouteOptions { preserve_host: true, ..RouteOptions::default() };
+        assert_eq!(command(&arguments(&req, &unix, &options, &Settings::default()), None),
+                   "curl --head --unix-socket /run/app.sock http://localhost/x");
+    }
+}
@@ -0,0 +1,62 @@
+use ring::rand::{ SecureRandom, SystemRandom };
+use std::time::Duration;
+use crate::errors::{ Error };
+use crate::options::{ parse_duration };
+
+/// An artificial delay before handling each request to a route, so that we
+/// can see how clients cope with slow responses. It's either fixed, or
+/// picked at random from a range each time.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Delay {
+    min: Duration,
+    max: Duration
+}
+
+impl Delay {
+    /// Parse a delay like `500ms`, or a range like `100ms-2s`:
+    pub fn parse(input: &str) -> Result<Delay, Error> {
+        let (min, max) = match input.find('-') {
+            Some(idx) => (parse_duration(&input[..idx])?, parse_duration(&input[idx+1..])?),
+            None => {
+                let delay = parse_duration(input)?;
+                (delay, delay)
+            }
+        };
+        if min > max {
+            return Err(err!("The shortest delay in '{}' is longer than the longest", input));
+        }
+        Ok(Delay { min, max })
+    }
+
+    /// How long to wait for this time around:
+    pub fn pick(&self) -> Duration {
+        let range = (self.max - self.min).as_millis() as u64;
+        if range == 0 {
+            return self.min;
+        }
+        let mut bytes = [0u8; 8];
+        SystemRandom::new().fill(&mut bytes).expect("random numbers are available");
+        self.min + Duration::from_millis(u64::from_le_bytes(bytes) % (range + 1))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn picks_delays_in_range() {
+        let fixed = Delay::parse("500ms").unwrap();
+        assert_eq!(fixed.pick(), Duration::from_millis(500));
+
+        let jitter = Delay::parse("100ms-2s").unwrap();
+        for _ in 0..100 {
+            let delay = jitter.pick();
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_secs(2));
+        }
+
+        assert!(Delay::parse("2s-100ms").is_err());
+        assert!(Delay::parse("soon").is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use ansi_term::Color::{ Red, Yellow };
+use futures::channel::oneshot;
+use hyper::{ HeaderMap, Request, Response };
+use hyper::header::{ HeaderName, CONNECTION, DATE, TRANSFER_ENCODING };
+use log::{ debug, warn };
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::time::Duration;
+use tokio::time;
+use url::Url;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::headers;
+use crate::options::{ RouteOptions, TlsOptions };
+use crate::proxy::{ self, Teed };
+use crate::request_id;
+use crate::HttpsClient;
+
+/// The target of what we log about differences between destinations:
+pub const DIFF: &str = "weave::diff";
+/// How much of each body we compare:
+const MAX_DIFF_BODY: usize = 1024 * 1024;
+/// How long we wait for a shadow to respond before giving up on it:
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Compare the responses of a route's destination with those of a shadow
+/// destination, like a rewrite of the same service, and log any
+/// differences. Clients only ever see the response from the destination.
+#[derive(Debug,Clone,Default)]
+pub struct DiffOptions {
+    /// Where to send copies of requests to. Like a mirror, this is just a
+    /// scheme, host and port:
+    pub shadow: Option<Url>,
+    /// Headers whose values are expected to differ, on top of `Date` and
+    /// those about the connection:
+    pub ignore_headers: Vec<HeaderName>,
+    /// Matches of this in bodies are left out when comparing them, for
+    /// things like timestamps and IDs:
+    pub ignore: Option<Regex>
+}
+
+impl PartialEq for DiffOptions {
+    fn eq(&self, other: &Self) -> bool {
+        self.shadow == other.shadow
+            && self.ignore_headers == other.ignore_headers
+            && self.ignore.as_ref().map(|r| r.as_str()) == other.ignore.as_ref().map(|r| r.as_str())
+    }
+}
+
+impl DiffOptions {
+    /// Parse a comma separated list of header names:
+    pub fn parse_headers(input: &str) -> Result<Vec<HeaderName>, Error> {
+        input.split(',').map(headers::parse_name).collect()
+    }
+
+    pub fn parse_pattern(input: &str) -> Result<Regex, Error> {
+        Regex::new(input).map_err(|e| err!("Invalid regular expression: {}", e))
+    }
+
+    fn ignores_header(&self, name: &HeaderName) -> bool {
+        [CONNECTION, DATE, TRANSFER_ENCODING].contains(name)
+            || name == "keep-alive"
+            || self.ignore_headers.contains(name)
+    }
+}
+
+/// A response from one of the destinations we're comparing.
+#[derive(Debug,Clone,PartialEq)]
+struct Observed {
+    status: u16,
+    headers: HeaderMap,
+    body: Teed
+}
+
+/// A request sent to a shadow, waiting for the destination's response to
+/// compare with.
+pub struct Shadowed {
+    method: String,
+    path: String,
+    /// The request's ID, ready to log after its path:
+    id: String,
+    options: DiffOptions,
+    shadow: oneshot::Receiver<Result<Observed, Error>>
+}
+
+/// Send a copy of a request on its way to `dest` to the route's shadow, if
+/// it has one. The body needs buffering to be sent twice, so this hands
+/// back a request to use instead.
+pub async fn shadow(req: Request<Body>, dest: &Url, options: &RouteOptions, client: &HttpsClient) -> Result<(Request<Body>, Option<Shadowed>), Error> {
+    let shadow = match &options.diff.shadow {
+        Some(shadow) => shadow,
+        None => return Ok((req, None))
+    };
+
+    let id = request_id::suffix(&req);
+    let (parts, body) = req.into_parts();
+    let body = proxy::read_body(body).await?;
+
+    let mut url = shadow.clone();
+    url.set_path(dest.path());
+    url.set_query(dest.query());
+    let mut copy = proxy::clone_request(&parts, &body);
+    *copy.uri_mut() = format!("{}", url).parse().unwrap();
+    if !options.preserve_host {
+        copy.headers_mut().remove("host");
+    }
+    let (sender, receiver) = oneshot::channel();
+    tokio::spawn(send(copy, client.clone(), options.tls.clone(), sender));
+
+    let shadowed = Shadowed {
+        method: parts.method.to_string(),
+        path: parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/").to_owned(),
+        id,
+        options: options.diff.clone(),
+        shadow: receiver
+    };
+    Ok((Request::from_parts(parts, Body::from(body)), Some(shadowed)))
+}
+
+async fn send(req: Request<Body>, client: HttpsClient, tls: TlsOptions, sender: oneshot::Sender<Result<Observed, Error>>) {
+    let sent = time::timeout(SHADOW_TIMEOUT, async {
+        let resp = client.request(req, &tls).await?;
+        let (parts, body) = resp.into_parts();
+        let mut start = proxy::read_body(body).await?;
+        let total = start.len();
+        start.truncate(MAX_DIFF_BODY);
+        Ok::<_, Error>(Observed { status: parts.status.as_u16(), headers: parts.headers, body: Teed { start, total, complete: true } })
+    });
+    let observed = match sent.await {
+        Ok(observed) => observed,
+        Err(_) => Err(err!("timed out after {:#?}", SHADOW_TIMEOUT))
+    };
+    let _ = sender.send(observed);
+}
+
+impl Shadowed {
+    /// Compare the destination's response with the shadow's, once its body
+    /// has been passed on:
+    pub fn compare(self, resp: Response<Body>) -> Response<Body> {
+        let status = resp.status().as_u16();
+        let headers = resp.headers().clone();
+        resp.map(|body| proxy::tee(body, MAX_DIFF_BODY, move |body| {
+            tokio::spawn(self.report(Observed { status, headers, body }));
+        }))
+    }
+
+    async fn report(self, primary: Observed) {
+        let shadow = match self.shadow.await {
+            Ok(Ok(shadow)) => shadow,
+            Ok(Err(e)) => {
+                warn!(target: DIFF, "{}", Red.paint(format!("[diff] {} {}{} (shadow failed: {})", self.method, self.path, self.id, e)));
+                return;
+            },
+            Err(_) => return
+        };
+        if !primary.body.complete {
+            debug!(target: DIFF, "[diff] {} {}{} (response not passed on in full, so not compared)", self.method, self.path, self.id);
+            return;
+        }
+        let differences = differences(&primary, &shadow, &self.options);
+        if differences.is_empty() {
+            debug!(target: DIFF, "[diff] {} {}{} (no differences)", self.method, self.path, self.id);
+        } else {
+            warn!(target: DIFF, "{}", Yellow.paint(format!("[diff] {} {}{} ({})", self.method, self.path, self.id, differences.join("; "))));
+        }
+    }
+}
+
+/// How the shadow's response differs from the destination's:
+fn differences(primary: &Observed, shadow: &Observed, options: &DiffOptions) -> Vec<String> {
+    let mut differences = vec![];
+    if primary.status != shadow.status {
+        differences.push(format!("status {} vs {}", primary.status, shadow.status));
+    }
+
+    let names: BTreeSet<&str> = primary.headers.keys().chain(shadow.headers.keys())
+        .filter(|name| !options.ignores_header(name))
+        .map(|name| name.as_str())
+        .collect();
+    for name in names {
+        let values = |headers: &HeaderMap| -> String {
+            let values: Vec<String> = headers.get_all(name).iter()
+                .map(|v| format!("{:?}", String::from_utf8_lossy(v.as_bytes())))
+                .collect();
+            if values.is_empty() { "none".to_owned() } else { values.join(", ") }
+        };
+        let (ours, theirs) = (values(&primary.headers), values(&shadow.headers));
+        if ours != theirs {
+            differences.push(format!("header {}: {} vs {}", name, ours, theirs));
+        }
+    }
+
+    let (ours, theirs) = (comparable(&primary.body.start, options), comparable(&shadow.body.start, options));
+    if primary.body.total != shadow.body.total && options.ignore.is_none() {
+        differences.push(format!("body {} vs {} bytes", primary.body.total, shadow.body.total));
+    } else if ours != theirs {
+        let at = ours.iter().zip(theirs.iter()).take_while(|(a, b)| a == b).count();
+        differences.push(format!("body differs from byte {}", at));
+    }
+    differences
+}
+
+/// The part of a body to compare, without anything we've been told to
+/// ignore:
+fn comparable(body: &[u8], options: &DiffOptions) -> Vec<u8> {
+    match &options.ignore {
+        Some(ignore) => ignore.replace_all(&String::from_utf8_lossy(body), "").into_owned().into_bytes(),
+        None => body.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn observed(status: u16, headers: &[(&str, &str)], body: &str) -> Observed {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+        }
+        Observed { status, headers: map, body: Teed { start: body.as_bytes().to_vec(), total: body.len(), complete: true } }
+    }
+
+    #[test]
+    fn finds_differences() {
+        let options = DiffOptions::default();
+        let a = observed(200, &[("date", "Mon"), ("x-a", "1"), ("x-b", "2")], "hello world");
+        let b = observed(500, &[("date", "Tue"), ("x-a", "1"), ("x-c", "3")], "hello there");
+        assert_eq!(differences(&a, &b, &options), vec![
+            "status 200 vs 500",
+            "header x-b: \"2\" vs none",
+            "header x-c: none vs \"3\"",
+            "body differs from byte 6"
+        ]);
+        assert!(differences(&a, &a, &options).is_empty());
+    }
+
+    #[test]
+    fn ignores_what_it_is_told_to() {
+        let options = DiffOptions {
+            shadow: None,
+            ignore_headers: DiffOptions::parse_headers("x-request-id").unwrap(),
+            ignore: Some(DiffOptions::parse_pattern(r#""id":\d+"#).unwrap())
+        };
+        let a = observed(200, &[("x-request-id", "1")], r#"{"id":1,"name":"a"}"#);
+        let b = observed(200, &[("x-request-id", "2")], r#"{"id":1234,"name":"a"}"#);
+        assert!(differences(&a, &b, &options).is_empty());
+        let c = observed(200, &[], r#"{"id":5,"name":"b"}"#);
+        // Bytes are counted once what's ignored is left out:
+        assert_eq!(differences(&a, &c, &options), vec!["body differs from byte 10"]);
+    }
+}
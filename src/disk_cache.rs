@@ -0,0 +1,520 @@
+use bytes::Bytes;
+use hyper::{ HeaderMap, StatusCode };
+use hyper::header::{ HeaderName, HeaderValue };
+use log::{ debug, warn };
+use serde::{ Deserialize, Serialize };
+use std::collections::HashMap;
+use std::io::{ Read, Seek, SeekFrom };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::fs::{ self, File };
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use crate::body::{ Body };
+use crate::cache::{ Head };
+use crate::errors::{ Error };
+
+/// How much disk space cached responses can take up, if a cache
+/// directory is provided without a size:
+pub const DEFAULT_CACHE_DISK_SIZE: usize = 1024 * 1024 * 1024;
+
+/// How many bytes we read from disk at a time when streaming cached
+/// responses back:
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Counts up for each response we start writing, so that responses to
+/// the same URL being written at once don't share a temporary file:
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// The on-disk tier of the response cache, so that large responses don't
+/// take up memory and cached responses survive restarts. Each response has
+/// a file of its own, named after a hash of its URL, holding its body and
+/// then a description of the rest of it (so that the body can be written
+/// as it streams by):
+///
+/// ```text
+/// body | head (JSON) | length of the head (8 bytes, little endian)
+/// ```
+///
+/// Files are written under a temporary name and renamed once complete, so
+/// we never see half written ones, and bodies are checked against the SHA-1
+/// they were written with as they're read back.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    index: Mutex<Index>
+}
+
+/// What's in the cache directory, so that lookups don't touch the disk
+/// unless there's something to read:
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Indexed>,
+    /// The total size of the files:
+    size: u64,
+    /// Counts up each time an entry is used, so that we can
+    /// evict the least recently used ones:
+    tick: u64
+}
+
+struct Indexed {
+    head: Head,
+    body_len: u64,
+    body_sha1: String,
+    file_len: u64,
+    last_used: u64
+}
+
+/// How the head of a response is written to disk. Header values aren't
+/// necessarily text, so they're base64 encoded.
+#[derive(Serialize,Deserialize)]
+struct StoredHead {
+    key: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    vary: Vec<(String, Option<String>)>,
+    stored_ms: u64,
+    initial_age_ms: u64,
+    fresh_for_ms: u64,
+    body_len: u64,
+    body_sha1: String
+}
+
+/// A fresh response found on disk.
+pub enum Found {
+    /// Small enough to keep in memory too, so read in full (and checked):
+    Read(Head, Vec<u8>),
+    /// Streamed from disk, and checked once it's all been read:
+    Streamed(Head, Body)
+}
+
+impl DiskCache {
+    /// Use the directory provided for cached responses, creating it if
+    /// needed and picking up any responses already in it. Files we can't
+    /// make sense of, and any left half written, are removed.
+    pub fn open(dir: &Path, max_size: u64) -> Result<DiskCache, Error> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| err!("Can't create cache directory {}: {}", dir.display(), e))?;
+        let mut found = vec![];
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| err!("Can't read cache directory {}: {}", dir.display(), e))?;
+        for dir_entry in entries {
+            let path = dir_entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            match read_head(&path) {
+                Ok((key, indexed, modified)) => found.push((key, indexed, modified)),
+                Err(e) => {
+                    debug!("Removing {} from the cache directory: {}", path.display(), e);
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+
+        // The files written longest ago count as the least recently used:
+        found.sort_by_key(|(_, _, modified)| *modified);
+        let cache = DiskCache { dir: dir.to_owned(), max_size, index: Mutex::new(Index::default()) };
+        let mut evicted = vec![];
+        {
+            let mut index = cache.index.lock().unwrap();
+            for (key, indexed, _) in found {
+                evicted.extend(index.insert(key, indexed, max_size));
+            }
+        }
+        for key in evicted {
+            let _ = std::fs::remove_file(cache.path(&key));
+        }
+        Ok(cache)
+    }
+
+    /// How many responses are cached, and how much space they take up:
+    pub fn usage(&self) -> (usize, u64) {
+        let index = self.index.lock().unwrap();
+        (index.entries.len(), index.size)
+    }
+
+    /// The fresh response to a request for `key`, if we have one. Those
+    /// up to `max_read` bytes long are read into memory.
+    pub async fn get(self: &Arc<Self>, key: &str, req_headers: &HeaderMap, max_read: u64) -> Option<Found> {
+        let (head, body_len, body_sha1, file_len) = {
+            let mut index = self.index.lock().unwrap();
+            index.tick += 1;
+            let tick = index.tick;
+            let indexed = index.entries.get_mut(key)?;
+            if !indexed.head.is_fresh_for(req_headers) {
+                return None;
+            }
+            indexed.last_used = tick;
+            (indexed.head.clone(), indexed.body_len, indexed.body_sha1.clone(), indexed.file_len)
+        };
+        let path = self.path(key);
+
+        if body_len <= max_read {
+            let read = fs::read(&path).await;
+            return match read {
+                Ok(mut bytes) if bytes.len() as u64 == file_len => {
+                    bytes.truncate(body_len as usize);
+                    if sha1::Sha1::from(&bytes).digest().to_string() == body_sha1 {
+                        Some(Found::Read(head, bytes))
+                    } else {
+                        self.drop_corrupt(key, "its body doesn't match its checksum");
+                        None
+                    }
+                },
+                Ok(_) => {
+                    self.drop_corrupt(key, "it's the wrong size");
+                    None
+                },
+                Err(e) => {
+                    self.drop_corrupt(key, &e.to_string());
+                    None
+                }
+            };
+        }
+
+        let file = match File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                self.drop_corrupt(key, &e.to_string());
+                return None;
+            }
+        };
+        match file.metadata().await {
+            Ok(meta) if meta.len() == file_len => {},
+            _ => {
+                self.drop_corrupt(key, "it's the wrong size");
+                return None;
+            }
+        }
+        Some(Found::Streamed(head, self.stream(key.to_owned(), file, body_len, body_sha1)))
+    }
+
+    /// Stream a body back from disk, checking it as it goes. It's too late
+    /// to take back what we've sent by the time we know the body is bad,
+    /// but we can at least fail the response rather than complete it.
+    fn stream(self: &Arc<Self>, key: String, mut file: File, len: u64, expected_sha1: String) -> Body {
+        let (mut sender, body) = Body::channel();
+        let cache = self.clone();
+
+        tokio::spawn(async move {
+            let mut sha1 = sha1::Sha1::new();
+            let mut remaining = len;
+            let mut buf = vec![0; READ_CHUNK_SIZE];
+            while remaining > 0 {
+                let max = std::cmp::min(remaining, READ_CHUNK_SIZE as u64) as usize;
+                let n = match file.read(&mut buf[..max]).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) => {
+                        warn!("Error streaming cached response: {}", e);
+                        sender.abort();
+                        return;
+                    }
+                };
+                remaining -= n as u64;
+                sha1.update(&buf[..n]);
+                let chunk = Bytes::copy_from_slice(&buf[..n]);
+                if remaining == 0 && sha1.digest().to_string() != expected_sha1 {
+                    cache.drop_corrupt(&key, "its body doesn't match its checksum");
+                    sender.abort();
+                    return;
+                }
+                // The client has gone away, so stop reading:
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+            if remaining > 0 {
+                cache.drop_corrupt(&key, "it's shorter than it should be");
+                sender.abort();
+            }
+        });
+
+        body
+    }
+
+    /// Start writing a response to `key` to disk:
+    pub async fn writer(self: &Arc<Self>, key: &str) -> Result<Writer, Error> {
+        let tmp_path = self.dir.join(format!("{}.{}.tmp", hash(key), WRITES.fetch_add(1, Ordering::Relaxed)));
+        let file = File::create(&tmp_path).await?;
+        Ok(Writer {
+            cache: self.clone(),
+            key: key.to_owned(),
+            tmp_path,
+            file,
+            len: 0,
+            sha1: sha1::Sha1::new(),
+            done: false
+        })
+    }
+
+    /// Drop the cached responses to these keys, handing back which we had:
+    pub fn purge(&self, keys: impl Fn(&str) -> bool) -> Vec<String> {
+        let purged: Vec<String> = {
+            let mut index = self.index.lock().unwrap();
+            let purged: Vec<String> = index.entries.keys().filter(|key| keys(key)).cloned().collect();
+            for key in &purged {
+                index.remove(key);
+            }
+            purged
+        };
+        for key in &purged {
+            let _ = std::fs::remove_file(self.path(key));
+        }
+        purged
+    }
+
+    fn drop_corrupt(&self, key: &str, reason: &str) {
+        warn!("Dropping cached response to {}: {}", key, reason);
+        self.index.lock().unwrap().remove(key);
+        let _ = std::fs::remove_file(self.path(key));
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(hash(key))
+    }
+}
+
+/// Writes a response to disk as it streams by. Nothing is cached unless it's
+/// finished, and if it's dropped before then, the partial file is removed.
+pub struct Writer {
+    cache: Arc<DiskCache>,
+    key: String,
+    tmp_path: PathBuf,
+    file: File,
+    len: u64,
+    sha1: sha1::Sha1,
+    done: bool
+}
+
+impl Writer {
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.len += chunk.len() as u64;
+        if self.len > self.cache.max_size {
+            return Err(err!("{} bytes is too big", self.len));
+        }
+        self.sha1.update(chunk);
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+
+    /// Write the rest of the response out, and move it into place:
+    pub async fn finish(mut self, head: &Head) -> Result<(), Error> {
+        let stored = head.to_stored(&self.key, self.len, self.sha1.digest().to_string());
+        let json = serde_json::to_vec(&stored)?;
+        self.file.write_all(&json).await?;
+        self.file.write_all(&(json.len() as u64).to_le_bytes()).await?;
+        self.file.sync_all().await?;
+
+        let file_len = self.len + json.len() as u64 + 8;
+        if file_len > self.cache.max_size {
+            return Err(err!("{} bytes is too big", file_len));
+        }
+        fs::rename(&self.tmp_path, self.cache.path(&self.key)).await?;
+        self.done = true;
+
+        let indexed = Indexed { head: head.clone(), body_len: self.len, body_sha1: stored.body_sha1, file_len, last_used: 0 };
+        let evicted = self.cache.index.lock().unwrap().insert(self.key.clone(), indexed, self.cache.max_size);
+        for key in evicted {
+            let _ = fs::remove_file(self.cache.path(&key)).await;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+impl Index {
+    /// Add an entry, evicting the least recently used ones to make room for
+    /// it, and handing back the keys of those whose files should go.
+    fn insert(&mut self, key: String, mut indexed: Indexed, max_size: u64) -> Vec<String> {
+        self.remove(&key);
+        let mut evicted = vec![];
+        while self.size + indexed.file_len > max_size {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, indexed)| indexed.last_used)
+                .map(|(key, _)| key.clone())
+                .expect("entries take up space");
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+        self.tick += 1;
+        indexed.last_used = self.tick;
+        self.size += indexed.file_len;
+        self.entries.insert(key, indexed);
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.entries.remove(key) {
+            self.size -= old.file_len;
+        }
+    }
+}
+
+impl Head {
+    fn to_stored(&self, key: &str, body_len: u64, body_sha1: String) -> StoredHead {
+        let ms = |d: Duration| d.as_millis() as u64;
+        StoredHead {
+            key: key.to_owned(),
+            status: self.status.as_u16(),
+            headers: self.headers.iter()
+                .map(|(name, value)| (name.as_str().to_owned(), base64::encode(value.as_bytes())))
+                .collect(),
+            vary: self.vary.iter()
+                .map(|(name, value)| (name.as_str().to_owned(), value.as_ref().map(|v| base64::encode(v.as_bytes()))))
+                .collect(),
+            stored_ms: ms(self.stored.duration_since(UNIX_EPOCH).unwrap_or_default()),
+            initial_age_ms: ms(self.initial_age),
+            fresh_for_ms: ms(self.fresh_for),
+            body_len,
+            body_sha1
+        }
+    }
+
+    fn from_stored(stored: &StoredHead) -> Result<Head, Error> {
+        let name = |name: &str| HeaderName::from_bytes(name.as_bytes()).map_err(|_| err!("Invalid header name '{}'", name));
+        let value = |value: &str| -> Result<HeaderValue, Error> {
+            let bytes = base64::decode(value).map_err(|_| err!("Invalid header value '{}'", value))?;
+            HeaderValue::from_bytes(&bytes).map_err(|_| err!("Invalid header value '{}'", value))
+        };
+        let mut headers = HeaderMap::new();
+        for (n, v) in &stored.headers {
+            headers.append(name(n)?, value(v)?);
+        }
+        let mut vary = vec![];
+        for (n, v) in &stored.vary {
+            vary.push((name(n)?, v.as_ref().map(|v| value(v)).transpose()?));
+        }
+        Ok(Head {
+            status: StatusCode::from_u16(stored.status).map_err(|_| err!("Invalid status {}", stored.status))?,
+            headers,
+            vary,
+            stored: UNIX_EPOCH + Duration::from_millis(stored.stored_ms),
+            initial_age: Duration::from_millis(stored.initial_age_ms),
+            fresh_for: Duration::from_millis(stored.fresh_for_ms)
+        })
+    }
+}
+
+/// Read the head of a cached response back from its file, checking that
+/// the file is the one for its key and is the size it should be.
+fn read_head(path: &Path) -> Result<(String, Indexed, SystemTime), Error> {
+    let mut file = std::fs::File::open(path)?;
+    let meta = file.metadata()?;
+    let file_len = meta.len();
+    if file_len < 8 {
+        return Err(err!("Too short"));
+    }
+    let mut len = [0; 8];
+    file.seek(SeekFrom::End(-8))?;
+    file.read_exact(&mut len)?;
+    let head_len = u64::from_le_bytes(len);
+    if head_len > file_len - 8 {
+        return Err(err!("Too short"));
+    }
+    let mut json = vec![0; head_len as usize];
+    file.seek(SeekFrom::Start(file_len - 8 - head_len))?;
+    file.read_exact(&mut json)?;
+
+    let stored: StoredHead = serde_json::from_slice(&json)?;
+    if path.file_name().and_then(|name| name.to_str()) != Some(&hash(&stored.key)) {
+        return Err(err!("Not named after its key"));
+    }
+    if stored.body_len + head_len + 8 != file_len {
+        return Err(err!("Wrong size"));
+    }
+    let indexed = Indexed {
+        head: Head::from_stored(&stored)?,
+        body_len: stored.body_len,
+        body_sha1: stored.body_sha1,
+        file_len,
+        last_used: 0
+    };
+    Ok((stored.key, indexed, meta.modified()?))
+}
+
+fn hash(key: &str) -> String {
+    sha1::Sha1::from(key).digest().to_string()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn head() -> Head {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+        Head {
+            status: StatusCode::OK,
+            headers,
+            vary: vec![(HeaderName::from_static("accept-encoding"), None)],
+            stored: SystemTime::now(),
+            initial_age: Duration::from_secs(5),
+            fresh_for: Duration::from_secs(60)
+        }
+    }
+
+    /// Write a file the way `Writer` does:
+    fn write(dir: &Path, key: &str, body: &[u8]) {
+        let stored = head().to_stored(key, body.len() as u64, sha1::Sha1::from(body).digest().to_string());
+        let json = serde_json::to_vec(&stored).unwrap();
+        let mut bytes = body.to_vec();
+        bytes.extend_from_slice(&json);
+        bytes.extend_from_slice(&(json.len() as u64).to_le_bytes());
+        std::fs::write(dir.join(hash(key)), bytes).unwrap();
+    }
+
+    #[test]
+    fn picks_up_cached_responses_and_removes_junk() {
+        let dir = std::env::temp_dir().join(format!("weave-disk-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write(&dir, "http://a/one", b"one");
+        write(&dir, "http://a/two", b"two");
+        std::fs::write(dir.join(format!("{}.0.tmp", hash("http://a/three"))), b"half writ").unwrap();
+        std::fs::write(dir.join("junk"), b"junk").unwrap();
+        // Truncated, and renamed to something else:
+        let two = std::fs::read(dir.join(hash("http://a/two"))).unwrap();
+        std::fs::write(dir.join(hash("http://a/four")), &two[1..]).unwrap();
+        std::fs::write(dir.join(hash("http://a/five")), &two).unwrap();
+
+        let cache = DiskCache::open(&dir, 1024 * 1024).unwrap();
+        let index = cache.index.lock().unwrap();
+        let mut keys: Vec<&String> = index.entries.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["http://a/one", "http://a/two"]);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        let one = &index.entries["http://a/one"];
+        assert_eq!(one.body_len, 3);
+        assert_eq!(one.head.headers["content-type"], "text/plain");
+        assert_eq!(one.head.vary, head().vary);
+        assert_eq!(one.head.fresh_for, Duration::from_secs(60));
+        assert!(one.head.is_fresh_for(&HeaderMap::new()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used_files() {
+        let indexed = |file_len| Indexed { head: head(), body_len: 0, body_sha1: String::new(), file_len, last_used: 0 };
+        let mut index = Index::default();
+        assert!(index.insert("a".to_owned(), indexed(4), 10).is_empty());
+        assert!(index.insert("b".to_owned(), indexed(4), 10).is_empty());
+        index.entries.get_mut("a").unwrap().last_used = 100;
+
+        assert_eq!(index.insert("c".to_owned(), indexed(4), 10), vec!["b".to_owned()]);
+        assert_eq!(index.size, 8);
+        // Replacing an entry doesn't count it twice:
+        assert!(index.insert("c".to_owned(), indexed(6), 10).is_empty());
+        assert_eq!(index.size, 10);
+    }
+}
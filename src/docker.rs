@@ -0,0 +1,313 @@
+use clap::ArgMatches;
+use hyper::Uri;
+use log::{ info, warn };
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use tokio::time::sleep;
+use url::Url;
+use crate::client::HttpsClient;
+use crate::connector;
+use crate::control::{ Control };
+use crate::errors::{ Error };
+use crate::options::{ TlsOptions };
+use crate::proxy;
+use crate::routes::{ self, Route };
+
+/// Where Docker listens, unless `DOCKER_HOST` or `--docker-socket` say otherwise:
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+/// The port we listen on for containers with a `weave.host` label,
+/// unless `--docker-port` says otherwise:
+const DEFAULT_PORT: u16 = 8080;
+/// How long to wait before trying Docker again when we can't reach it:
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where to find Docker, and the port to listen on for requests to the
+/// containers it's running that have `weave.host` labels.
+#[derive(Debug,Clone,PartialEq)]
+pub struct DockerOptions {
+    pub socket: PathBuf,
+    pub port: u16
+}
+
+impl DockerOptions {
+    /// Containers are only watched for if asked (with `--docker`):
+    pub fn from_matches(matches: &ArgMatches) -> Result<Option<DockerOptions>, Error> {
+        if !matches.is_present("docker") {
+            return Ok(None);
+        }
+        let socket = match (matches.value_of("docker-socket"), std::env::var("DOCKER_HOST")) {
+            (Some(path), _) => PathBuf::from(path),
+            (None, Ok(host)) => match host.strip_prefix("unix://") {
+                Some(path) => PathBuf::from(path),
+                None => return Err(err!("DOCKER_HOST '{}' is not a unix:// socket; use --docker-socket to say where Docker is", host))
+            },
+            (None, Err(_)) => PathBuf::from(DEFAULT_SOCKET)
+        };
+        let port = match matches.value_of("docker-port") {
+            Some(s) => s.parse().map_err(|_| err!("Invalid --docker-port '{}': Not a valid port", s))?,
+            None => DEFAULT_PORT
+        };
+        Ok(Some(DockerOptions { socket, port }))
+    }
+}
+
+/// A running container, as Docker lists them:
+#[derive(Debug,Clone,Default,Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    id: String,
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    ports: Vec<Port>,
+    #[serde(default)]
+    network_settings: NetworkSettings
+}
+
+#[derive(Debug,Clone,Default,Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Port {
+    #[serde(rename = "IP", default)]
+    ip: Option<String>,
+    private_port: u16,
+    #[serde(default)]
+    public_port: Option<u16>,
+    #[serde(rename = "Type")]
+    kind: String
+}
+
+#[derive(Debug,Clone,Default,Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkSettings {
+    #[serde(default)]
+    networks: BTreeMap<String, Network>
+}
+
+#[derive(Debug,Clone,Default,Deserialize)]
+struct Network {
+    #[serde(rename = "IPAddress", default)]
+    ip_address: String
+}
+
+/// Watch Docker for containers starting and stopping, and serve routes to
+/// those with `weave.*` labels alongside our others. If Docker can't be
+/// reached, we keep trying.
+pub fn spawn(options: DockerOptions, control: Arc<Mutex<Control>>, client: HttpsClient) {
+    info!("Watching for containers on {}", options.socket.display());
+    let mut watcher = Watcher { options, control, client, routes: vec![], warnings: vec![] };
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = watcher.watch().await {
+                warn!("Can't watch for containers on {}: {}", watcher.options.socket.display(), e);
+            }
+            sleep(RETRY_INTERVAL).await;
+        }
+    });
+}
+
+struct Watcher {
+    options: DockerOptions,
+    control: Arc<Mutex<Control>>,
+    client: HttpsClient,
+    /// The routes to containers we're serving, and the warnings we had
+    /// working them out, so that we only log changes:
+    routes: Vec<Route>,
+    warnings: Vec<String>
+}
+
+impl Watcher {
+    /// Look at which containers are running each time Docker tells us
+    /// about something happening to one, until it stops telling us:
+    async fn watch(&mut self) -> Result<(), Error> {
+        let filters = r#"{"type":["container"],"event":["start","die","destroy"]}"#;
+        let events = self.client.get(self.uri("/events", &[("filters", filters)])?, &TlsOptions::default()).await?;
+        if !events.status().is_success() {
+            return Err(err!("Docker responded to a request for events with {}", events.status()));
+        }
+        self.refresh().await?;
+        let mut body = events.into_body();
+        while let Some(chunk) = body.next().await {
+            chunk?;
+            self.refresh().await?;
+        }
+        Ok(())
+    }
+
+    /// Serve routes to the containers that are running now:
+    async fn refresh(&mut self) -> Result<(), Error> {
+        let resp = self.client.get(self.uri("/containers/json", &[])?, &TlsOptions::default()).await?;
+        if !resp.status().is_success() {
+            return Err(err!("Docker responded to a request for containers with {}", resp.status()));
+        }
+        let body = proxy::read_body(resp.into_body()).await?;
+        let containers: Vec<Container> = serde_json::from_slice(&body)
+            .map_err(|e| err!("Can't understand the containers Docker listed: {}", e))?;
+
+        let (routes, warnings) = routes_for(&containers, self.options.port);
+        if warnings != self.warnings {
+            for warning in &warnings {
+                warn!("{}", warning);
+            }
+            self.warnings = warnings;
+        }
+        if routes == self.routes {
+            return Ok(());
+        }
+        for route in routes.iter().filter(|route| !self.routes.contains(route)) {
+            info!("Routing {} to {}", route.src, route.dests_to_string());
+        }
+        for route in self.routes.iter().filter(|route| !routes.contains(route)) {
+            info!("No longer routing {} to {}", route.src, route.dests_to_string());
+        }
+        self.control.lock().unwrap().set_discovered(routes.clone())?;
+        self.routes = routes;
+        Ok(())
+    }
+
+    fn uri(&self, path: &str, query: &[(&str, &str)]) -> Result<Uri, Error> {
+        let mut url: Url = connector::unix_socket_url(&self.options.socket);
+        url.set_path(path);
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        Ok(url.as_str().parse()?)
+    }
+}
+
+/// Work out the routes to containers from their labels:
+///
+/// - `weave.host`: the host to route requests for (eg `api.localhost`),
+///   which we listen for on `--docker-port`.
+/// - `weave.src`: the source to route from, in place of `weave.host`
+///   (eg `8081/api`).
+/// - `weave.port`: the port in the container to send requests to, if it
+///   exposes more than one.
+/// - `weave.options`: options for the route, as they're given on the command
+///   line (eg `preserve-host timeout=10s`).
+///
+/// Containers that publish the port are sent requests on the published
+/// port, and others on their IP address. Warnings are handed back for
+/// containers that can't be routed to.
+fn routes_for(containers: &[Container], port: u16) -> (Vec<Route>, Vec<String>) {
+    let mut routes = vec![];
+    let mut warnings = vec![];
+    for container in containers {
+        if !container.labels.keys().any(|key| key.starts_with("weave.")) {
+            continue;
+        }
+        let name = container.names.first()
+            .map(|name| name.trim_start_matches('/').to_owned())
+            .unwrap_or_else(|| container.id.chars().take(12).collect());
+        match route_for(container, port) {
+            Ok(route) => routes.push(route),
+            Err(e) => warnings.push(format!("Can't route to the container '{}': {}", name, e))
+        }
+    }
+    (routes, warnings)
+}
+
+fn route_for(container: &Container, listen_port: u16) -> Result<Route, Error> {
+    let label = |key: &str| container.labels.get(key).map(|value| value.trim()).filter(|value| !value.is_empty());
+
+    let src = match (label("weave.src"), label("weave.host")) {
+        (Some(src), _) => src.to_owned(),
+        (None, Some(host)) => format!("{}:{}", host, listen_port),
+        (None, None) => return Err(err!("expecting a weave.host or weave.src label"))
+    };
+
+    let tcp_ports: Vec<&Port> = container.ports.iter().filter(|p| p.kind == "tcp").collect();
+    let port = match label("weave.port") {
+        Some(port) => port.parse::<u16>().map_err(|_| err!("weave.port '{}' is not a valid port", port))?,
+        None => match tcp_ports.as_slice() {
+            [port] => port.private_port,
+            [first, rest @ ..] if rest.iter().all(|p| p.private_port == first.private_port) => first.private_port,
+            [] => return Err(err!("it doesn't expose any ports; expecting a weave.port label")),
+            _ => return Err(err!("it exposes several ports; expecting a weave.port label to say which"))
+        }
+    };
+
+    let published = tcp_ports.iter().find(|p| p.private_port == port && p.public_port.is_some());
+    let dest = match published {
+        Some(p) => {
+            let host = match p.ip.as_deref() {
+                None | Some("") | Some("0.0.0.0") | Some("::") => "localhost".to_owned(),
+                Some(ip) if ip.contains(':') => format!("[{}]", ip),
+                Some(ip) => ip.to_owned()
+            };
+            format!("http://{}:{}", host, p.public_port.unwrap())
+        },
+        None => {
+            let ip = container.network_settings.networks.values()
+                .map(|network| network.ip_address.as_str())
+                .find(|ip| !ip.is_empty())
+                .ok_or_else(|| err!("it has no IP address, and doesn't publish port {}", port))?;
+            format!("http://{}:{}", ip, port)
+        }
+    };
+
+    let route = match label("weave.options") {
+        Some(options) => format!("{} to {} with {}", src, dest, options),
+        None => format!("{} to {}", src, dest)
+    };
+    let mut routes = routes::from_str(&route)?;
+    match routes.len() {
+        1 => Ok(routes.remove(0)),
+        _ => Err(err!("expecting one route but got '{}'", route))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::location::{ SrcLocation };
+
+    #[test]
+    fn routes_to_labelled_containers() {
+        let containers: Vec<Container> = serde_json::from_str(r#"[
+            {
+                "Id": "a1b2c3d4e5f6a7b8",
+                "Names": ["/api"],
+                "Labels": {"weave.host": "api.localhost", "weave.options": "preserve-host cache-control='no-cache, no-store'"},
+                "Ports": [{"PrivatePort": 3000, "Type": "tcp"}],
+                "NetworkSettings": {"Networks": {"bridge": {"IPAddress": "172.17.0.2"}}}
+            },
+            {
+                "Id": "b1b2c3d4e5f6a7b8",
+                "Names": ["/web"],
+                "Labels": {"weave.src": "8081/app", "weave.port": "80"},
+                "Ports": [
+                    {"IP": "0.0.0.0", "PrivatePort": 80, "PublicPort": 32768, "Type": "tcp"},
+                    {"IP": "::", "PrivatePort": 80, "PublicPort": 32768, "Type": "tcp"},
+                    {"PrivatePort": 443, "Type": "tcp"}
+                ]
+            },
+            {
+                "Id": "c1b2c3d4e5f6a7b8",
+                "Names": ["/db"],
+                "Labels": {"com.example.team": "data"},
+                "Ports": [{"PrivatePort": 5432, "Type": "tcp"}]
+            },
+            {
+                "Id": "d1b2c3d4e5f6a7b8",
+                "Labels": {"weave.host": "multi.localhost"},
+                "Ports": [{"PrivatePort": 80, "Type": "tcp"}, {"PrivatePort": 443, "Type": "tcp"}]
+            }
+        ]"#).unwrap();
+
+        let (routes, warnings) = routes_for(&containers, 8080);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].src, SrcLocation::parse("api.localhost:8080").unwrap());
+        assert_eq!(routes[0].dests_to_string(), "http://172.17.0.2:3000/");
+        assert!(routes[0].options.preserve_host);
+        assert_eq!(routes[0].options.cache_control, Some("no-cache, no-store".to_owned()));
+        assert_eq!(routes[1].src, SrcLocation::parse("8081/app").unwrap());
+        assert_eq!(routes[1].dests_to_string(), "http://localhost:32768/");
+        assert_eq!(warnings, vec!["Can't route to the container 'd1b2c3d4e5f6': it exposes several ports; expecting a weave.port label to say which".to_owned()]);
+    }
+}
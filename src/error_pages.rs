@@ -0,0 +1,114 @@
+use hyper::{ Request, Response, StatusCode };
+use hyper::header::{ HeaderValue, CONTENT_LENGTH, CONTENT_TYPE };
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::files::{ escape_html };
+use crate::matcher::{ Matcher };
+use crate::settings::{ Settings };
+
+/// Added to the extensions of the error responses we make ourselves, so
+/// that we can swap their bodies for an error page. This is the message
+/// they're about.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ErrorMessage(pub String);
+
+/// An HTML template to respond with in place of our own `404` and `5xx`
+/// responses. `{{status}}`, `{{reason}}`, `{{path}}` and `{{message}}`
+/// in it are replaced with the details of the error.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ErrorPage {
+    path: PathBuf,
+    template: String
+}
+
+impl ErrorPage {
+    pub fn from_file(path: &Path) -> Result<Arc<ErrorPage>, Error> {
+        let template = fs::read_to_string(path)
+            .map_err(|e| err!("Cannot read error page '{}': {}", path.display(), e))?;
+        Ok(Arc::new(ErrorPage { path: path.to_owned(), template }))
+    }
+
+    pub fn render(&self, status: StatusCode, path: &str, message: &str) -> String {
+        self.template
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", status.canonical_reason().unwrap_or(""))
+            .replace("{{path}}", &escape_html(path))
+            .replace("{{message}}", &escape_html(message))
+    }
+}
+
+/// A plain text error response that can be swapped for an error page:
+pub fn error(status: u16, message: impl Into<String>) -> Response<Body> {
+    let message = message.into();
+    let mut resp = Response::builder()
+        .status(status)
+        .body(Body::from(format!("Weave: {}", message)))
+        .unwrap();
+    resp.extensions_mut().insert(ErrorMessage(message));
+    resp
+}
+
+/// The error page for the route a request matches, or else the one for
+/// everything, if there is one:
+pub fn for_request<T>(req: &Request<T>, matcher: &Matcher, settings: &Settings) -> Option<Arc<ErrorPage>> {
+    matcher.route_for(req, req.method())
+        .and_then(|route| route.options.error_page.clone())
+        .or_else(|| settings.error_page.clone())
+}
+
+/// Respond with the error page instead, if this is one of our own `404`
+/// or `5xx` responses:
+pub fn apply(resp: Response<Body>, page: &ErrorPage, path: &str) -> Response<Body> {
+    let status = resp.status();
+    if status != StatusCode::NOT_FOUND && !status.is_server_error() {
+        return resp;
+    }
+    let message = match resp.extensions().get::<ErrorMessage>() {
+        Some(ErrorMessage(message)) => message.clone(),
+        None => return resp
+    };
+    let body = page.render(status, path, &message);
+    let (mut parts, _) = resp.into_parts();
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::proxy;
+
+    fn page(template: &str) -> ErrorPage {
+        ErrorPage { path: PathBuf::from("error.html"), template: template.to_owned() }
+    }
+
+    fn body(resp: Response<Body>) -> String {
+        let body = futures::executor::block_on(proxy::read_body(resp.into_body())).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    #[test]
+    fn renders_error_pages() {
+        let page = page("<h1>{{status}} {{reason}}</h1><p>{{path}}: {{message}}</p>");
+        let resp = apply(error(404, "No routes matched"), &page, "/<script>");
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+        assert_eq!(body(resp), "<h1>404 Not Found</h1><p>/&lt;script&gt;: No routes matched</p>");
+        let resp = apply(error(502, "Upstream connection refused"), &page, "/api");
+        assert_eq!(body(resp), "<h1>502 Bad Gateway</h1><p>/api: Upstream connection refused</p>");
+    }
+
+    #[test]
+    fn leaves_other_responses_alone() {
+        let page = page("oops");
+        // Not an error we'd swap:
+        assert_eq!(body(apply(error(429, "Too many requests"), &page, "/")), "Weave: Too many requests");
+        // Not one of ours:
+        let upstream = Response::builder().status(500).body(Body::from("upstream")).unwrap();
+        assert_eq!(body(apply(upstream, &page, "/")), "upstream");
+    }
+}
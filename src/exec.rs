@@ -0,0 +1,85 @@
+use hyper::{ Request, Response };
+use std::process::Stdio;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::time::Duration;
+use tokio::time;
+use tokio::process::Command;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::error_pages;
+use crate::proxy;
+
+/// How long we let a command run for before giving up on it, if the
+/// route doesn't say otherwise:
+pub const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many commands are running right now, across all routes:
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Run a command and respond with whatever it writes to stdout. Details of
+/// the request are handed to the command in environment variables, much
+/// like CGI. The command is killed if it takes longer than the timeout,
+/// and we refuse to run more than `max_running` commands at once.
+pub async fn exec(req: &Request<Body>, command: &str, path_info: &str, timeout: Duration, max_running: usize) -> Result<Response<Body>, Error> {
+    let _running = match Running::start(max_running) {
+        Some(running) => running,
+        None => {
+            return Ok(error_pages::error(503, format!("Too many commands running (the limit is {})", max_running)))
+        }
+    };
+
+    let mut words = command.split_whitespace();
+    let program = words.next().ok_or_else(|| err!("No command to run"))?;
+    let mut cmd = Command::new(program);
+    cmd.args(words)
+        .stdin(Stdio::null())
+        .env("REQUEST_METHOD", req.method().as_str())
+        .env("REQUEST_PATH", req.uri().path())
+        .env("PATH_INFO", path_info)
+        .env("QUERY_STRING", req.uri().query().unwrap_or(""));
+    for (name, value) in req.headers() {
+        if let Ok(value) = value.to_str() {
+            let name = format!("HTTP_{}", name.as_str().to_uppercase().replace('-', "_"));
+            cmd.env(name, value);
+        }
+    }
+
+    // Dropping the output future (eg on timeout) kills the command:
+    let output = match time::timeout(timeout, cmd.output()).await {
+        Ok(output) => output.map_err(|e| err!("Could not run '{}': {}", command, e))?,
+        Err(_) => return Ok(proxy::timed_out(format!("command timed out after {:#?}", timeout)))
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(err!("'{}' failed ({}): {}", command, output.status, stderr.trim()));
+    }
+
+    let response = Response::builder()
+        .status(200)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from(output.stdout))
+        .unwrap();
+    Ok(response)
+}
+
+/// Counts a command as running until dropped:
+struct Running;
+
+impl Running {
+    fn start(max_running: usize) -> Option<Running> {
+        let prev = RUNNING.fetch_add(1, Ordering::SeqCst);
+        if prev >= max_running {
+            RUNNING.fetch_sub(1, Ordering::SeqCst);
+            None
+        } else {
+            Some(Running)
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
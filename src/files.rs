@@ -0,0 +1,515 @@
+use bytes::Bytes;
+use hyper::{ HeaderMap, Request, Response };
+use log::{ warn };
+use serde::Serialize;
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{ Path, PathBuf };
+use std::time::UNIX_EPOCH;
+use tokio::fs::{ self, File };
+use tokio::io::{ AsyncReadExt, AsyncSeekExt };
+use url::percent_encoding::{ utf8_percent_encode, PATH_SEGMENT_ENCODE_SET };
+use crate::body::{ Body };
+use crate::compress::{ self, Encoding };
+use crate::errors::{ Error };
+use crate::error_pages;
+use crate::options::{ RouteOptions };
+use crate::settings::{ Settings };
+
+/// Serve the file at the path provided, or an index file inside it
+/// if the path is a directory. The file is streamed back in chunks,
+/// so that large files are served using a constant amount of memory.
+/// A single byte range can be asked for using the `Range` header, and
+/// if the client already has the current version (going by the ETag)
+/// we hand back a `304 Not Modified` instead.
+pub async fn serve(req: &Request<Body>, path: &Path, settings: &Settings, options: &RouteOptions) -> Response<Body> {
+    let headers = req.headers();
+    let (file, file_path, meta) = match open(path).await {
+        Ok(opened) => opened,
+        Err(e) => {
+            // Directories without an index file can be listed if asked for:
+            let dir_listing = options.dir_listing.unwrap_or(settings.dir_listing);
+            if dir_listing && is_dir(path).await {
+                return list_dir(req, path).await
+            }
+
+            return error_pages::error(404, format!("Could not read file '{}': {}", path.to_string_lossy(), e))
+        }
+    };
+
+    // Serve a precompressed version of the file instead, if there is one:
+    let (mut file, meta, encoding) = if options.precompressed {
+        precompressed(headers, &file_path).await.unwrap_or((file, meta, None))
+    } else {
+        (file, meta, None)
+    };
+
+    let len = meta.len();
+    let etag = etag(&meta);
+
+    let mut builder = Response::builder();
+    builder = builder.header("ETag", etag.as_str());
+    if options.precompressed {
+        builder = builder.header("Vary", "Accept-Encoding");
+    }
+    if let Some(encoding) = encoding {
+        builder = builder.header("Content-Encoding", encoding.as_str());
+    }
+    if let Some(cache_control) = &options.cache_control {
+        builder = builder.header("Cache-Control", cache_control.as_str());
+    }
+
+    let if_none_match = headers.get("if-none-match").and_then(|h| h.to_str().ok());
+    if let Some(if_none_match) = if_none_match {
+        if etag_matches(if_none_match, &etag) {
+            return builder
+                .status(304)
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+
+    let mime = mime_guess::from_path(&file_path).first_or_octet_stream();
+    let range = headers.get("range").and_then(|r| r.to_str().ok());
+
+    match byte_range(range, len) {
+        ByteRange::Full => {
+            builder
+                .status(200)
+                .header("Content-Type", mime.as_ref())
+                .header("Content-Length", len)
+                .header("Accept-Ranges", "bytes")
+                .body(stream(file, len, settings.chunk_size))
+                .unwrap()
+        }
+        ByteRange::Partial(start, end) => {
+            if let Err(e) = file.seek(SeekFrom::Start(start)).await {
+                return error_pages::error(500, format!("Could not read file '{}': {}", file_path.to_string_lossy(), e))
+            }
+            let range_len = end - start + 1;
+            builder
+                .status(206)
+                .header("Content-Type", mime.as_ref())
+                .header("Content-Length", range_len)
+                .header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+                .header("Accept-Ranges", "bytes")
+                .body(stream(file, range_len, settings.chunk_size))
+                .unwrap()
+        }
+        ByteRange::Unsatisfiable => {
+            Response::builder()
+                .status(416)
+                .header("Content-Range", format!("bytes */{}", len))
+                .header("Accept-Ranges", "bytes")
+                .body(Body::from("Weave: Requested range not satisfiable"))
+                .unwrap()
+        }
+    }
+}
+
+/// Is there a file (or index file) to serve at the path given?
+pub async fn exists(path: &Path) -> bool {
+    open(path).await.is_ok()
+}
+
+/// An entry in a directory listing:
+#[derive(Debug,Clone,Serialize)]
+struct DirEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    /// Seconds since the unix epoch:
+    modified: Option<u64>
+}
+
+/// Respond with a listing of the directory at the path given. This is
+/// HTML unless the client would rather have JSON (going by `Accept`).
+async fn list_dir(req: &Request<Body>, path: &Path) -> Response<Body> {
+    let entries = match read_dir_entries(path).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            return error_pages::error(500, format!("Could not list directory '{}': {}", path.to_string_lossy(), e))
+        }
+    };
+
+    let wants_json = req.headers().get("accept")
+        .and_then(|a| a.to_str().ok())
+        .map(|a| a.contains("application/json"))
+        .unwrap_or(false);
+
+    if wants_json {
+        let json = serde_json::to_string(&entries).expect("dir entries serialize to JSON");
+        Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json))
+            .unwrap()
+    } else {
+        Response::builder()
+            .status(200)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(dir_listing_html(req.uri().path(), &entries)))
+            .unwrap()
+    }
+}
+
+/// Read the entries in a directory, putting directories first
+/// and then sorting by name:
+async fn read_dir_entries(path: &Path) -> Result<Vec<DirEntry>, Error> {
+    let mut read_dir = fs::read_dir(path.to_owned()).await?;
+    let mut entries = vec![];
+
+    while let Some(entry) = read_dir.next_entry().await? {
+        let meta = entry.metadata().await?;
+        let modified = meta.modified().ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+        entries.push(DirEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: meta.is_dir(),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+            modified
+        });
+    }
+
+    entries.sort_by(|a, b| {
+        a.is_dir.cmp(&b.is_dir).reverse().then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(entries)
+}
+
+/// Render a directory listing as HTML. Links are relative to the
+/// path that was requested, so that they work wherever the route is mounted.
+fn dir_listing_html(uri_path: &str, entries: &[DirEntry]) -> String {
+    let base = if uri_path.ends_with('/') {
+        uri_path.to_owned()
+    } else {
+        format!("{}/", uri_path)
+    };
+
+    let mut rows = String::new();
+    if base != "/" {
+        rows.push_str(&format!("<tr><td><a href=\"{}..\">../</a></td><td></td><td></td></tr>\n", base));
+    }
+    for entry in entries {
+        let href = format!("{}{}{}",
+                           base,
+                           utf8_percent_encode(&entry.name, PATH_SEGMENT_ENCODE_SET),
+                           if entry.is_dir { "/" } else { "" });
+        let name = format!("{}{}", entry.name, if entry.is_dir { "/" } else { "" });
+        let size = if entry.is_dir { "-".to_owned() } else { entry.size.to_string() };
+        let modified = entry.modified
+            .map(|secs| httpdate::fmt_http_date(UNIX_EPOCH + std::time::Duration::from_secs(secs)))
+            .unwrap_or_default();
+        rows.push_str(&format!("<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                               escape_html(&href),
+                               escape_html(&name),
+                               size,
+                               modified));
+    }
+
+    let title = format!("Index of {}", escape_html(&base));
+    format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n\
+             <table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n{rows}</table>\n</body>\n</html>\n",
+            title = title,
+            rows = rows)
+}
+
+/// Escape text so that it can be placed in HTML:
+pub fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+/// Is the path a directory?
+async fn is_dir(path: &Path) -> bool {
+    fs::metadata(path).await.map(|m| m.is_dir()).unwrap_or(false)
+}
+
+/// Build an ETag for a file based on its size and modification time,
+/// so that we don't have to read it to know whether it has changed:
+fn etag(meta: &Metadata) -> String {
+    let modified = meta.modified().ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| format!("{:x}.{:x}", d.as_secs(), d.subsec_nanos()))
+        .unwrap_or_else(|| "0".to_owned());
+    format!("\"{:x}-{}\"", meta.len(), modified)
+}
+
+/// Does the value of an `If-None-Match` header match the ETag given?
+/// Weak comparison is used, so `W/` prefixes are ignored.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Which part of a file should be served back:
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum ByteRange {
+    /// The whole file:
+    Full,
+    /// Bytes from the start to the end offset (inclusive):
+    Partial(u64, u64),
+    /// A range was asked for that doesn't overlap with the file:
+    Unsatisfiable
+}
+
+/// Work out which bytes to serve given the value of a `Range` header
+/// and the length of the file. We only support a single range; if several
+/// are asked for or the header is malformed we ignore it, as we're allowed to.
+fn byte_range(range: Option<&str>, len: u64) -> ByteRange {
+    let spec = match range.and_then(|r| r.trim().strip_prefix("bytes=")) {
+        Some(spec) => spec,
+        None => return ByteRange::Full
+    };
+    if spec.contains(',') {
+        return ByteRange::Full
+    }
+
+    let dash = match spec.find('-') {
+        Some(idx) => idx,
+        None => return ByteRange::Full
+    };
+    let (start, end) = (spec[..dash].trim(), spec[dash+1..].trim());
+
+    // 'bytes=-N' means the last N bytes:
+    if start.is_empty() {
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial(len.saturating_sub(n), len - 1),
+            Err(_) => ByteRange::Full
+        }
+    }
+
+    let start = match start.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Full
+    };
+    // 'bytes=N-' means everything from N onwards, and the
+    // end is clamped to the length of the file:
+    let end = if end.is_empty() {
+        len.saturating_sub(1)
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => std::cmp::min(end, len.saturating_sub(1)),
+            _ => return ByteRange::Full
+        }
+    };
+
+    if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+/// Open the file at the path provided, falling back to index files
+/// if it's a directory. Hands back the file, the path to it, and its metadata.
+async fn open(path: &Path) -> Result<(File, PathBuf, Metadata), Error> {
+    let mut last_err = err!("File not found");
+
+    for end in &["", "index.htm", "index.html"] {
+        let mut p = path.to_owned();
+        if !end.is_empty() { p.push(end) }
+        match open_file(&p).await {
+            Ok((file, meta)) => return Ok((file, p, meta)),
+            Err(e) => last_err = e
+        }
+    }
+
+    Err(last_err)
+}
+
+/// The best `.br`, `.zst` or `.gz` version of a file that the client
+/// accepts, if there is one:
+async fn precompressed(headers: &HeaderMap, path: &Path) -> Option<(File, Metadata, Option<Encoding>)> {
+    for encoding in compress::accepted(headers, &[Encoding::Brotli, Encoding::Zstd, Encoding::Gzip]) {
+        let extension = match encoding {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz"
+        };
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".");
+        sidecar.push(extension);
+        if let Ok((file, meta)) = open_file(Path::new(&sidecar)).await {
+            return Some((file, meta, Some(encoding)));
+        }
+    }
+    None
+}
+
+/// Open a single file, complaining if the path is not a file:
+async fn open_file(path: &Path) -> Result<(File, Metadata), Error> {
+    let file = File::open(path).await.map_err(|e| err!("{}", e))?;
+    let meta = file.metadata().await.map_err(|e| err!("{}", e))?;
+    if !meta.is_file() {
+        return Err(err!("Not a file"));
+    }
+    Ok((file, meta))
+}
+
+/// Stream up to `len` bytes of a file back as the body of a response,
+/// reading at most `chunk_size` bytes into memory at a time.
+fn stream(mut file: File, len: u64, chunk_size: usize) -> Body {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut remaining = len;
+        let mut buf = vec![0; chunk_size];
+        while remaining > 0 {
+            let max = std::cmp::min(remaining, chunk_size as u64) as usize;
+            let n = match file.read(&mut buf[..max]).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("Error streaming file: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+            remaining -= n as u64;
+            // The client has gone away, so stop reading:
+            if sender.send_data(Bytes::copy_from_slice(&buf[..n])).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    body
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_byte_ranges() {
+        let cases = vec![
+            // No range, or ones we ignore:
+            (None, ByteRange::Full),
+            (Some("items=0-10"), ByteRange::Full),
+            (Some("bytes=0-10,20-30"), ByteRange::Full),
+            (Some("bytes=wibble"), ByteRange::Full),
+            (Some("bytes=10-5"), ByteRange::Full),
+            // Basic ranges:
+            (Some("bytes=0-0"), ByteRange::Partial(0, 0)),
+            (Some("bytes=0-99"), ByteRange::Partial(0, 99)),
+            (Some("bytes=10-19"), ByteRange::Partial(10, 19)),
+            // Open ended and suffix ranges:
+            (Some("bytes=90-"), ByteRange::Partial(90, 99)),
+            (Some("bytes=-10"), ByteRange::Partial(90, 99)),
+            (Some("bytes=-500"), ByteRange::Partial(0, 99)),
+            // Ends past the file are clamped:
+            (Some("bytes=50-500"), ByteRange::Partial(50, 99)),
+            // Starts past the file can't be satisfied:
+            (Some("bytes=100-"), ByteRange::Unsatisfiable),
+            (Some("bytes=100-200"), ByteRange::Unsatisfiable),
+            (Some("bytes=-0"), ByteRange::Unsatisfiable),
+        ];
+
+        for (range, expected) in cases {
+            assert_eq!(byte_range(range, 100), expected, "range: {:?}", range);
+        }
+    }
+
+    #[test]
+    fn matches_etags() {
+        let etag = "\"10-5f.0\"";
+        assert!(etag_matches("\"10-5f.0\"", etag));
+        assert!(etag_matches("W/\"10-5f.0\"", etag));
+        assert!(etag_matches("\"wibble\", \"10-5f.0\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"10-5f.1\"", etag));
+        assert!(!etag_matches("", etag));
+    }
+
+    #[test]
+    fn dir_listings_escape_names() {
+        let entries = vec![
+            DirEntry { name: "sub dir".to_owned(), is_dir: true, size: 0, modified: None },
+            DirEntry { name: "<b>.txt".to_owned(), is_dir: false, size: 12, modified: Some(0) },
+        ];
+        let html = dir_listing_html("/files", &entries);
+        assert!(html.contains("<a href=\"/files/..\">../</a>"));
+        assert!(html.contains("<a href=\"/files/sub%20dir/\">sub dir/</a>"));
+        assert!(html.contains("&lt;b&gt;.txt</a></td><td>12</td>"));
+        assert!(!html.contains("<b>"));
+    }
+
+    #[test]
+    fn nothing_satisfies_empty_files() {
+        assert_eq!(byte_range(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=-1"), 0), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(None, 0), ByteRange::Full);
+    }
+
+    #[test]
+    fn serves_precompressed_files() {
+        use std::io::{ Read, Write };
+
+        let original = "console.log('hello world');\n".repeat(100);
+        let dir = std::env::temp_dir().join(format!("weave-precompressed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.js");
+        std::fs::write(&path, &original).unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+        gzip.write_all(original.as_bytes()).unwrap();
+        std::fs::write(dir.join("app.js.gz"), gzip.finish().unwrap()).unwrap();
+        let mut brotlied = vec![];
+        brotli::CompressorReader::new(original.as_bytes(), 4096, 11, 22).read_to_end(&mut brotlied).unwrap();
+        std::fs::write(dir.join("app.js.br"), brotlied).unwrap();
+        std::fs::write(dir.join("app.js.zst"), zstd::stream::encode_all(original.as_bytes(), 19).unwrap()).unwrap();
+
+        let settings = Settings::default();
+        let options = RouteOptions { precompressed: true, ..RouteOptions::default() };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let get = |accept_encoding: &str| -> (Option<String>, Vec<u8>) {
+            runtime.block_on(async {
+                let req = Request::get("/app.js").header("accept-encoding", accept_encoding).body(Body::empty()).unwrap();
+                let resp = serve(&req, &path, &settings, &options).await;
+                assert_eq!(resp.status(), 200);
+                assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+                assert_eq!(resp.headers()["content-type"], "text/javascript");
+                let encoding = resp.headers().get("content-encoding").map(|e| e.to_str().unwrap().to_owned());
+                (encoding, crate::proxy::read_body(resp.into_body()).await.unwrap())
+            })
+        };
+
+        let (encoding, body) = get("gzip, br");
+        assert_eq!(encoding.as_deref(), Some("br"));
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&body[..], 4096).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, original);
+
+        let (encoding, body) = get("gzip");
+        assert_eq!(encoding.as_deref(), Some("gzip"));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, original);
+
+        let (encoding, body) = get("zstd, gzip;q=0.5");
+        assert_eq!(encoding.as_deref(), Some("zstd"));
+        assert_eq!(zstd::stream::decode_all(&body[..]).unwrap(), original.as_bytes());
+
+        let (encoding, body) = get("identity");
+        assert_eq!(encoding, None);
+        assert_eq!(body, original.as_bytes());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use hyper::{ Request, Response, Uri };
+use hyper::header::{ HeaderName, HeaderValue, CONTENT_LENGTH, HOST, TRANSFER_ENCODING };
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time;
+use crate::body::{ Body };
+use crate::client::{ HttpsClient };
+use crate::errors::{ Error };
+use crate::options::{ TlsOptions };
+use crate::proxy;
+
+/// How long we wait for an auth service to make up its mind:
+const FORWARD_AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ask an external service whether each request to a route is allowed,
+/// much like Traefik's forward auth.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct ForwardAuth {
+    /// The auth service to ask. If not set, requests aren't checked:
+    pub url: Option<Uri>,
+    /// Headers from the auth service's response to pass on to destinations
+    /// (eg the user it authenticated):
+    pub copy_headers: Vec<HeaderName>
+}
+
+impl ForwardAuth {
+    pub fn parse_url(input: &str) -> Result<Uri, Error> {
+        let uri: Uri = input.parse().map_err(|_| err!("'{}' is not a valid URL", input))?;
+        match uri.scheme_str() {
+            Some("http") | Some("https") => Ok(uri),
+            _ => Err(err!("Expecting an http:// or https:// URL but got '{}'", input))
+        }
+    }
+}
+
+/// Send the method, path and headers of a request (but not its body) on to
+/// the auth service, if the route has one. If it responds with a 2xx, we copy
+/// the headers asked for onto the request and let it through. Otherwise, we
+/// hand back its response (which may be a redirect to a login page, say).
+pub async fn authenticate(req: &mut Request<Body>, options: &ForwardAuth, remote_addr: Option<SocketAddr>, client: &HttpsClient, tls: &TlsOptions) -> Result<Option<Response<Body>>, Error> {
+    let url = match &options.url {
+        Some(url) => url,
+        None => return Ok(None)
+    };
+
+    // Only the auth service gets to set these:
+    for name in &options.copy_headers {
+        req.headers_mut().remove(name);
+    }
+
+    let auth_req = auth_request(req, url, remote_addr);
+    let resp = time::timeout(FORWARD_AUTH_TIMEOUT, client.request(auth_req, tls)).await
+        .map_err(|_| err!("Auth service {} timed out after {:#?}", url, FORWARD_AUTH_TIMEOUT))?
+        .map_err(|e| err!("Auth service {} failed: {}", url, e))?;
+    if !resp.status().is_success() {
+        return Ok(Some(resp));
+    }
+
+    for name in &options.copy_headers {
+        for value in resp.headers().get_all(name) {
+            req.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    Ok(None)
+}
+
+/// The request we send to an auth service. It has the method and headers
+/// of the original request, plus the usual forwarded headers and those
+/// Traefik adds describing the original method and URI.
+fn auth_request(req: &Request<Body>, url: &Uri, remote_addr: Option<SocketAddr>) -> Request<Body> {
+    let mut auth_req = Request::builder()
+        .method(req.method().clone())
+        .uri(url.clone())
+        .body(Body::empty())
+        .unwrap();
+
+    let headers = auth_req.headers_mut();
+    for (name, value) in req.headers() {
+        if name != CONTENT_LENGTH && name != TRANSFER_ENCODING {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+    // HTTP/2 requests have their host in the URI:
+    if !headers.contains_key(HOST) {
+        if let Some(host) = req.uri().authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()) {
+            headers.insert(HOST, host);
+        }
+    }
+    let uri = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    if let Ok(uri) = HeaderValue::from_str(uri) {
+        headers.insert("x-forwarded-uri", uri);
+    }
+    headers.insert("x-forwarded-method", HeaderValue::from_str(req.method().as_str()).unwrap());
+
+    proxy::add_forwarded_headers(&mut auth_req, remote_addr);
+    // The host header should be that of the auth service:
+    auth_req.headers_mut().remove(HOST);
+    auth_req
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn describes_the_original_request_to_the_auth_service() {
+        let req = Request::post("/admin/users?page=2")
+            .header("host", "example.com")
+            .header("cookie", "session=abc")
+            .header("content-length", "5")
+            .body(Body::from("hello"))
+            .unwrap();
+        let url: Uri = "http://localhost:4181/auth".parse().unwrap();
+        let auth_req = auth_request(&req, &url, Some("10.0.0.1:1234".parse().unwrap()));
+
+        assert_eq!(auth_req.method(), "POST");
+        assert_eq!(auth_req.uri(), &url);
+        let headers = auth_req.headers();
+        assert_eq!(headers.get("cookie").unwrap(), "session=abc");
+        assert_eq!(headers.get("x-forwarded-method").unwrap(), "POST");
+        assert_eq!(headers.get("x-forwarded-uri").unwrap(), "/admin/users?page=2");
+        assert_eq!(headers.get("x-forwarded-host").unwrap(), "example.com");
+        assert_eq!(headers.get("x-forwarded-for").unwrap(), "10.0.0.1");
+        assert_eq!(headers.get("x-forwarded-proto").unwrap(), "http");
+        assert!(headers.get("host").is_none());
+        assert!(headers.get("content-length").is_none());
+    }
+}
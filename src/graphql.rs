@@ -0,0 +1,288 @@
+use hyper::{ HeaderMap, Method, Request, Uri };
+use hyper::header::{ CONTENT_LENGTH, CONTENT_TYPE };
+use serde::Deserialize;
+use std::iter::Peekable;
+use std::str::Chars;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::proxy;
+
+/// The most of a request body we'll read to find out which GraphQL
+/// operation it is. Bigger requests don't match routes that ask:
+const MAX_GRAPHQL_BODY: usize = 64 * 1024;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum OperationType {
+    Query,
+    Mutation,
+    Subscription
+}
+
+/// The GraphQL operation a request asks for, added to its extensions when
+/// there are routes that match on it.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Operation {
+    pub kind: OperationType,
+    pub name: Option<String>
+}
+
+/// Conditions on the GraphQL operation of a request, for routes that should
+/// only match some, like sending mutations to a primary and queries to
+/// replicas. Requests must be one of the types given (if any) and have
+/// one of the operation names given (if any).
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct GraphqlMatch {
+    pub kinds: Vec<OperationType>,
+    pub names: Vec<String>
+}
+
+/// The body of a GraphQL request over HTTP:
+#[derive(Debug,Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlRequest {
+    query: String,
+    #[serde(default)]
+    operation_name: Option<String>
+}
+
+impl OperationType {
+    pub fn parse(input: &str) -> Result<OperationType, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "query" => Ok(OperationType::Query),
+            "mutation" => Ok(OperationType::Mutation),
+            "subscription" => Ok(OperationType::Subscription),
+            _ => Err(err!("'{}' is not a GraphQL operation type; expecting 'query', 'mutation' or 'subscription'", input))
+        }
+    }
+}
+
+impl GraphqlMatch {
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty() && self.names.is_empty()
+    }
+
+    /// How many conditions there are, so that routes with more can be
+    /// matched first:
+    pub fn conditions(&self) -> usize {
+        (!self.kinds.is_empty()) as usize + (!self.names.is_empty()) as usize
+    }
+
+    pub fn matches<T>(&self, req: &Request<T>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let operation = match req.extensions().get::<Operation>() {
+            Some(operation) => operation,
+            None => return false
+        };
+        (self.kinds.is_empty() || self.kinds.contains(&operation.kind))
+            && (self.names.is_empty() || operation.name.as_ref().is_some_and(|name| self.names.contains(name)))
+    }
+}
+
+/// Work out which GraphQL operation a request is for, adding it to the
+/// extensions of the request. POSTs have their bodies read for this, if
+/// they're small enough, so a request to send on in their place is handed
+/// back:
+pub async fn sniff(mut req: Request<Body>) -> Result<Request<Body>, Error> {
+    if req.method() == Method::GET {
+        if let Some(operation) = from_uri(req.uri()) {
+            req.extensions_mut().insert(operation);
+        }
+        return Ok(req);
+    }
+    let graphql_type = match content_type(req.headers()) {
+        Some(content_type) if content_type == "application/json" => false,
+        Some(content_type) if content_type == "application/graphql" => true,
+        _ => return Ok(req)
+    };
+    let small_enough = req.headers().get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok())
+        .is_some_and(|len| len <= MAX_GRAPHQL_BODY);
+    if req.method() != Method::POST || !small_enough {
+        return Ok(req);
+    }
+
+    let (mut parts, body) = req.into_parts();
+    let body = proxy::read_body(body).await?;
+    let operation = if graphql_type {
+        std::str::from_utf8(&body).ok().and_then(|document| operation(document, None))
+    } else {
+        serde_json::from_slice::<GraphqlRequest>(&body).ok()
+            .and_then(|req| operation(&req.query, req.operation_name.as_deref()))
+    };
+    if let Some(operation) = operation {
+        parts.extensions.insert(operation);
+    }
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+fn content_type(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    Some(content_type.split(';').next().unwrap_or("").trim().to_lowercase())
+}
+
+/// GraphQL queries can be made with GET, with the query in the URL:
+fn from_uri(uri: &Uri) -> Option<Operation> {
+    let mut query = None;
+    let mut name = None;
+    for (key, value) in url::form_urlencoded::parse(uri.query()?.as_bytes()) {
+        match key.as_ref() {
+            "query" => query = Some(value.into_owned()),
+            "operationName" => name = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    operation(&query?, name.as_deref())
+}
+
+/// The operation in a GraphQL document that a request asks for: the one
+/// with the name given, or else the only one there is:
+fn operation(document: &str, name: Option<&str>) -> Option<Operation> {
+    let mut operations = operations(document);
+    match name {
+        Some(name) => operations.into_iter().find(|op| op.name.as_deref() == Some(name)),
+        None if operations.len() == 1 => operations.pop(),
+        None => None
+    }
+}
+
+/// The operations defined in a GraphQL document. This reads just enough
+/// of it to find their types and names, skipping everything in their
+/// selection sets (and fragments):
+fn operations(document: &str) -> Vec<Operation> {
+    let mut operations = vec![];
+    // The names before the `{` of each definition, like `query GetUser`:
+    let mut words: Vec<String> = vec![];
+    let mut depth = 0usize;
+    let mut directive = false;
+    let mut chars = document.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                chars.by_ref().find(|&c| c == '\n' || c == '\r');
+            },
+            '"' => skip_string(&mut chars),
+            '@' => directive = true,
+            '{' | '(' | '[' => {
+                if c == '{' && depth == 0 {
+                    if let Some(operation) = definition(&words) {
+                        operations.push(operation);
+                    }
+                    words.clear();
+                }
+                depth += 1;
+            },
+            '}' | ')' | ']' => depth = depth.saturating_sub(1),
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c != '_' && !c.is_ascii_alphanumeric() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                if depth == 0 && !directive {
+                    words.push(word);
+                }
+                directive = false;
+            },
+            _ => {}
+        }
+    }
+    operations
+}
+
+fn definition(words: &[String]) -> Option<Operation> {
+    let kind = match words.first().map(|w| w.as_str()) {
+        // A lone selection set is a query:
+        None => return Some(Operation { kind: OperationType::Query, name: None }),
+        Some("query") => OperationType::Query,
+        Some("mutation") => OperationType::Mutation,
+        Some("subscription") => OperationType::Subscription,
+        // Fragments, and anything we don't understand:
+        Some(_) => return None
+    };
+    Some(Operation { kind, name: words.get(1).cloned() })
+}
+
+/// Skip past a string, the opening quote of which we've just seen:
+fn skip_string(chars: &mut Peekable<Chars>) {
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        if chars.peek() != Some(&'"') {
+            // It was an empty string:
+            return;
+        }
+        chars.next();
+        // A block string, which ends with three quotes:
+        let mut quotes = 0;
+        for c in chars {
+            quotes = if c == '"' { quotes + 1 } else { 0 };
+            if quotes == 3 {
+                return;
+            }
+        }
+        return;
+    }
+    let mut escaped = false;
+    for c in chars {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return,
+            _ => escaped = false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn op(kind: OperationType, name: Option<&str>) -> Option<Operation> {
+        Some(Operation { kind, name: name.map(|n| n.to_owned()) })
+    }
+
+    #[test]
+    fn finds_operations() {
+        assert_eq!(operation("{ user(id: 1) { name } }", None), op(OperationType::Query, None));
+        assert_eq!(operation("mutation AddUser($name: String!) @audit { addUser(name: $name) { id } }", None),
+                   op(OperationType::Mutation, Some("AddUser")));
+        let document = r#"
+            # The "query" keyword in a comment doesn't count
+            fragment Fields on User { id name }
+            query GetUser { user(note: "mutation { }") { ...Fields } }
+            subscription Watch { changes(note: """a "block" string""") { id } }
+        "#;
+        assert_eq!(operation(document, Some("Watch")), op(OperationType::Subscription, Some("Watch")));
+        assert_eq!(operation(document, Some("GetUser")), op(OperationType::Query, Some("GetUser")));
+        assert_eq!(operation(document, None), None);
+        assert_eq!(operation(document, Some("Missing")), None);
+    }
+
+    #[test]
+    fn sniffs_requests() {
+        let body = r#"{"query": "mutation Save { save }", "operationName": "Save"}"#;
+        let req = Request::post("/graphql")
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap();
+        let req = futures::executor::block_on(sniff(req)).unwrap();
+        assert_eq!(req.extensions().get::<Operation>(), op(OperationType::Mutation, Some("Save")).as_ref());
+        let mutations = GraphqlMatch { kinds: vec![OperationType::Mutation], names: vec![] };
+        assert!(mutations.matches(&req));
+        assert!(!GraphqlMatch { kinds: vec![], names: vec!["Load".to_owned()] }.matches(&req));
+        let read = futures::executor::block_on(proxy::read_body(req.into_body())).unwrap();
+        assert_eq!(read, body.as_bytes());
+
+        let req = Request::get("/graphql?query=%7B%20me%20%7B%20id%20%7D%20%7D").body(Body::empty()).unwrap();
+        let req = futures::executor::block_on(sniff(req)).unwrap();
+        assert!(!mutations.matches(&req));
+        assert!(GraphqlMatch { kinds: vec![OperationType::Query], names: vec![] }.matches(&req));
+        assert!(GraphqlMatch::default().matches(&Request::get("/").body(()).unwrap()));
+    }
+}
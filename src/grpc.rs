@@ -0,0 +1,115 @@
+use hyper::{ Request, Response, StatusCode };
+use hyper::header::{ HeaderValue, CONTENT_LENGTH, CONTENT_TYPE };
+use crate::body::{ Body };
+use crate::error_pages::{ ErrorMessage };
+
+/// Is a request a gRPC call? gRPC-Web is left out, as it's more like plain
+/// HTTP and its clients understand HTTP statuses:
+pub fn is_grpc<T>(req: &Request<T>) -> bool {
+    req.headers().get(CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .map(|t| t.to_lowercase())
+        .is_some_and(|t| t == "application/grpc" || t.starts_with("application/grpc+") || t.starts_with("application/grpc;"))
+}
+
+/// Does the path of a gRPC call, like `/pkg.Service/Method`, start with a
+/// route's path? Only whole names match, so `/pkg.Service` matches the
+/// methods of that service but not those of `/pkg.ServiceV2`, and `/pkg.`
+/// matches every service in the package:
+pub fn matches_path(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/') || prefix.ends_with('.'),
+        None => false
+    }
+}
+
+/// gRPC clients expect a `200` with a `grpc-status` saying how the call
+/// went, so responses without one (like our own errors) are turned into
+/// one, going by how gRPC maps HTTP statuses:
+pub fn response(resp: Response<Body>) -> Response<Body> {
+    if resp.status() == StatusCode::OK || resp.headers().contains_key("grpc-status") {
+        return resp;
+    }
+    let status = resp.status();
+    let message = match resp.extensions().get::<ErrorMessage>() {
+        Some(ErrorMessage(message)) => message.clone(),
+        None => status.canonical_reason().unwrap_or("").to_owned()
+    };
+    let (mut parts, _) = resp.into_parts();
+    parts.status = StatusCode::OK;
+    parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
+    parts.headers.insert("grpc-status", HeaderValue::from(grpc_status(status)));
+    parts.headers.insert("grpc-message", HeaderValue::from_str(&encode_message(&message)).unwrap());
+    Response::from_parts(parts, Body::empty())
+}
+
+/// https://github.com/grpc/grpc/blob/master/doc/http-grpc-status-mapping.md
+fn grpc_status(status: StatusCode) -> u16 {
+    match status.as_u16() {
+        // INTERNAL:
+        400 => 13,
+        // UNAUTHENTICATED:
+        401 => 16,
+        // PERMISSION_DENIED:
+        403 => 7,
+        // UNIMPLEMENTED:
+        404 => 12,
+        // UNAVAILABLE:
+        429 | 502 | 503 | 504 => 14,
+        // UNKNOWN:
+        _ => 2
+    }
+}
+
+/// `grpc-message` is percent encoded, other than printable ASCII:
+fn encode_message(message: &str) -> String {
+    message.bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'%' => (b as char).to_string(),
+            _ => format!("%{:02X}", b)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::error_pages;
+
+    #[test]
+    fn recognises_grpc_calls() {
+        let call = |content_type: &str| Request::post("/pkg.Service/Method").header(CONTENT_TYPE, content_type).body(()).unwrap();
+        assert!(is_grpc(&call("application/grpc")));
+        assert!(is_grpc(&call("application/grpc+proto")));
+        assert!(!is_grpc(&call("application/grpc-web+proto")));
+        assert!(!is_grpc(&call("application/json")));
+    }
+
+    #[test]
+    fn matches_whole_names() {
+        assert!(matches_path("/pkg.Service", "/pkg.Service/Method"));
+        assert!(matches_path("/pkg.Service/Method", "/pkg.Service/Method"));
+        assert!(matches_path("/pkg.", "/pkg.Other/Method"));
+        assert!(matches_path("/", "/pkg.Other/Method"));
+        assert!(!matches_path("/pkg.Service", "/pkg.ServiceV2/Method"));
+        assert!(!matches_path("/pkg.Service/Get", "/pkg.Service/GetAll"));
+    }
+
+    #[test]
+    fn turns_errors_into_grpc_statuses() {
+        let resp = response(error_pages::error(503, "Destination down: 100%"));
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["grpc-status"], "14");
+        assert_eq!(resp.headers()["grpc-message"], "Destination down: 100%25");
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/grpc");
+
+        let resp = response(Response::builder().status(404).body(Body::from("gone")).unwrap());
+        assert_eq!(resp.headers()["grpc-status"], "12");
+        assert_eq!(resp.headers()["grpc-message"], "Not Found");
+
+        let ok = Response::builder().header("grpc-status", "0").body(Body::empty()).unwrap();
+        assert_eq!(response(ok).headers()["grpc-status"], "0");
+    }
+}
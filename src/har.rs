@@ -0,0 +1,284 @@
+use hyper::{ HeaderMap, Request, Response };
+use http_body::Body as _;
+use hyper::header::{ CONTENT_TYPE, HOST, LOCATION };
+use log::{ warn, error };
+use serde_json::{ json, Value };
+use std::fs::File;
+use std::io::{ self, BufWriter, Seek, SeekFrom, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, SyncSender, TrySendError };
+use std::thread;
+use std::time::{ Instant, SystemTime };
+use crate::body::{ Body };
+use crate::capture::{ CaptureOptions };
+use crate::errors::{ Error };
+use crate::logging;
+use crate::proxy::{ self, Teed };
+
+/// How much of each body we record, if not provided:
+pub const DEFAULT_HAR_BODY_LIMIT: usize = 1024 * 1024;
+/// How many entries can wait to be written before we start dropping them,
+/// rather than holding up requests:
+const MAX_QUEUED_ENTRIES: usize = 1000;
+
+/// What the file starts and ends with. Entries go in between, so that the
+/// file is valid whenever it's read:
+const HEADER: &str = concat!(r#"{"log":{"version":"1.2","creator":{"name":"weave","version":""#, env!("CARGO_PKG_VERSION"), r#""},"entries":["#);
+const TRAILER: &str = "\n]}}\n";
+
+/// Entries we've had to drop since we last said so:
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Records requests and the responses we send back into a HAR file, for
+/// browser devtools and bug reports. Entries are written on a thread of
+/// their own, and headers and bodies are redacted as they are when
+/// capturing.
+#[derive(Debug)]
+pub struct Har {
+    sender: Mutex<SyncSender<Value>>,
+    body_limit: usize,
+    redaction: CaptureOptions
+}
+
+impl Har {
+    pub fn create(path: &Path, body_limit: usize, redaction: CaptureOptions) -> Result<Arc<Har>, Error> {
+        let writer = Writer::create(path.to_owned())
+            .map_err(|e| err!("Cannot create HAR file '{}': {}", path.display(), e))?;
+        let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_ENTRIES);
+        thread::Builder::new()
+            .name("har".to_owned())
+            .spawn(move || writer.run(receiver))?;
+        Ok(Arc::new(Har { sender: Mutex::new(sender), body_limit, redaction }))
+    }
+
+    /// Start recording a request as the client made it. Its body is
+    /// recorded as it's passed on:
+    pub fn start(self: &Arc<Self>, req: &mut Request<Body>) -> Recording {
+        let redaction = &self.redaction;
+        let query = req.uri().query().unwrap_or("");
+        let request = json!({
+            "method": req.method().as_str(),
+            "url": redaction.redact(&request_url(req)),
+            "httpVersion": format!("{:?}", req.version()),
+            "cookies": [],
+            "headers": self.headers(req.headers()),
+            "queryString": url::form_urlencoded::parse(query.as_bytes())
+                .map(|(name, value)| json!({ "name": name, "value": redaction.redact(&value) }))
+                .collect::<Vec<Value>>(),
+            "headersSize": -1,
+            "bodySize": -1
+        });
+        let mime_type = content_type(req.headers());
+
+        let body = Arc::new(Mutex::new(None));
+        if !req.body().is_end_stream() {
+            let teed = Arc::clone(&body);
+            let old_body = std::mem::take(req.body_mut());
+            *req.body_mut() = proxy::tee(old_body, self.body_limit, move |t| *teed.lock().unwrap() = Some(t));
+        }
+        Recording {
+            har: Arc::clone(self),
+            started: SystemTime::now(),
+            instant: Instant::now(),
+            request,
+            mime_type,
+            body
+        }
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> Vec<Value> {
+        headers.iter()
+            .map(|(name, value)| json!({ "name": name.as_str(), "value": self.redaction.header_value(name, value) }))
+            .collect()
+    }
+
+    /// The text of a body, base64 encoded if it isn't text, and a note if
+    /// we didn't keep all of it:
+    fn content(&self, teed: &Teed) -> Value {
+        let mut content = json!({ "size": teed.total });
+        match std::str::from_utf8(&teed.start) {
+            Ok(text) => {
+                content["text"] = json!(self.redaction.redact(text));
+            },
+            Err(_) => {
+                content["text"] = json!(base64::encode(&teed.start));
+                content["encoding"] = json!("base64");
+            }
+        }
+        if teed.start.len() < teed.total {
+            content["comment"] = json!(format!("Truncated to {} of {} bytes", teed.start.len(), teed.total));
+        }
+        content
+    }
+
+    fn write(&self, entry: Value) {
+        if let Err(TrySendError::Full(_)) = self.sender.lock().unwrap().try_send(entry) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A request being recorded, waiting for the response we send back.
+pub struct Recording {
+    har: Arc<Har>,
+    started: SystemTime,
+    instant: Instant,
+    request: Value,
+    mime_type: String,
+    /// The start of the request body, once it's been passed on:
+    body: Arc<Mutex<Option<Teed>>>
+}
+
+impl Recording {
+    /// Record the response to the request, once its body has been sent:
+    pub fn finish(self, resp: Response<Body>) -> Response<Body> {
+        let waited = self.instant.elapsed();
+        let response = json!({
+            "status": resp.status().as_u16(),
+            "statusText": resp.status().canonical_reason().unwrap_or(""),
+            "httpVersion": format!("{:?}", resp.version()),
+            "cookies": [],
+            "headers": self.har.headers(resp.headers()),
+            "redirectURL": resp.headers().get(LOCATION).and_then(|v| v.to_str().ok()).unwrap_or(""),
+            "headersSize": -1
+        });
+        let mime_type = content_type(resp.headers());
+
+        resp.map(|body| {
+            if body.is_end_stream() {
+                let empty = Teed { start: vec![], total: 0, complete: true };
+                self.write(response, &mime_type, &empty, waited);
+                return body;
+            }
+            let limit = self.har.body_limit;
+            proxy::tee(body, limit, move |teed| self.write(response, &mime_type, &teed, waited))
+        })
+    }
+
+    fn write(self, mut response: Value, mime_type: &str, teed: &Teed, waited: std::time::Duration) {
+        let har = &self.har;
+        let mut request = self.request;
+        // Request bodies are usually sent before we respond, but any that
+        // weren't are left out:
+        if let Some(body) = &*self.body.lock().unwrap() {
+            let content = har.content(body);
+            request["bodySize"] = json!(body.total);
+            if content.get("encoding").is_none() {
+                request["postData"] = json!({ "mimeType": self.mime_type, "text": content["text"] });
+            }
+        } else {
+            request["bodySize"] = json!(0);
+        }
+
+        let mut content = har.content(teed);
+        content["mimeType"] = json!(mime_type);
+        response["content"] = content;
+        response["bodySize"] = json!(teed.total);
+
+        let wait = waited.as_secs_f64() * 1000.0;
+        let receive = self.instant.elapsed().as_secs_f64() * 1000.0 - wait;
+        har.write(json!({
+            "startedDateTime": logging::rfc3339(self.started),
+            "time": wait + receive,
+            "request": request,
+            "response": response,
+            "cache": {},
+            "timings": { "send": 0, "wait": wait, "receive": receive }
+        }));
+    }
+}
+
+/// The full URL of a request. HTTP/2 requests have one already:
+fn request_url<T>(req: &Request<T>) -> String {
+    if req.uri().scheme().is_some() {
+        return req.uri().to_string();
+    }
+    let host = req.headers().get(HOST).and_then(|v| v.to_str().ok()).unwrap_or("localhost");
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    format!("http://{}{}", host, path)
+}
+
+fn content_type(headers: &HeaderMap) -> String {
+    headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("").to_owned()
+}
+
+struct Writer {
+    path: PathBuf,
+    file: BufWriter<File>,
+    entries: usize
+}
+
+impl Writer {
+    fn create(path: PathBuf) -> io::Result<Writer> {
+        let mut file = BufWriter::new(File::create(&path)?);
+        file.write_all(HEADER.as_bytes())?;
+        file.write_all(TRAILER.as_bytes())?;
+        file.flush()?;
+        Ok(Writer { path, file, entries: 0 })
+    }
+
+    /// Write entries as they arrive, until there are no more senders:
+    fn run(mut self, receiver: Receiver<Value>) {
+        while let Ok(entry) = receiver.recv() {
+            let mut next = Some(entry);
+            // Write whatever else is waiting before flushing:
+            while let Some(entry) = next {
+                if let Err(e) = self.write(&entry) {
+                    error!("Failed to write to HAR file '{}': {}", self.path.display(), e);
+                }
+                next = receiver.try_recv().ok();
+            }
+            if let Err(e) = self.file.flush() {
+                error!("Failed to write to HAR file '{}': {}", self.path.display(), e);
+            }
+            let dropped = DROPPED.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                warn!("Dropped {} entries that couldn't be written to '{}' quickly enough", dropped, self.path.display());
+            }
+        }
+    }
+
+    /// Write an entry over the trailer, and then the trailer again:
+    fn write(&mut self, entry: &Value) -> io::Result<()> {
+        self.file.seek(SeekFrom::End(-(TRAILER.len() as i64)))?;
+        let separator = if self.entries == 0 { "\n" } else { ",\n" };
+        write!(self.file, "{}{}{}", separator, entry, TRAILER)?;
+        self.entries += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn writes_valid_har_files() {
+        let path = std::env::temp_dir().join(format!("weave-har-test-{}.har", std::process::id()));
+        let mut writer = Writer::create(path.clone()).unwrap();
+        let read = || serde_json::from_str::<Value>(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(read()["log"]["entries"], json!([]));
+
+        writer.write(&json!({ "time": 1 })).unwrap();
+        writer.write(&json!({ "time": 2 })).unwrap();
+        writer.file.flush().unwrap();
+        let har = read();
+        assert_eq!(har["log"]["version"], "1.2");
+        assert_eq!(har["log"]["entries"], json!([{ "time": 1 }, { "time": 2 }]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn records_content() {
+        let (sender, _receiver) = mpsc::sync_channel(1);
+        let har = Har { sender: Mutex::new(sender), body_limit: 4, redaction: CaptureOptions::default() };
+        let text = har.content(&Teed { start: b"abcd".to_vec(), total: 10, complete: true });
+        assert_eq!(text, json!({ "size": 10, "text": "abcd", "comment": "Truncated to 4 of 10 bytes" }));
+        let binary = har.content(&Teed { start: vec![0xff, 0x00], total: 2, complete: true });
+        assert_eq!(binary, json!({ "size": 2, "text": "/wA=", "encoding": "base64" }));
+    }
+}
@@ -0,0 +1,235 @@
+use hyper::HeaderMap;
+use hyper::header::{ HeaderName, HeaderValue, CONTENT_TYPE, COOKIE, VARY };
+use crate::errors::{ Error };
+
+/// Changes to make to the headers of requests on their way to a
+/// destination, and to the headers of responses on their way back.
+/// Rules are applied in the order that they were given.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct HeaderRules {
+    pub request: Vec<HeaderRule>,
+    pub response: Vec<HeaderRule>
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum HeaderRule {
+    /// Set a header, replacing any existing values:
+    Set(HeaderName, HeaderValue),
+    /// Add a value to a header, keeping any existing values:
+    Add(HeaderName, HeaderValue),
+    /// Remove all values of a header:
+    Remove(HeaderName)
+}
+
+/// A condition on the headers of a request, for routes that should only
+/// match some requests. Either the header must be present, or one of its
+/// values must equal the value given.
+#[derive(Debug,Clone,PartialEq)]
+pub struct HeaderMatch {
+    pub name: HeaderName,
+    pub value: Option<HeaderValue>
+}
+
+impl HeaderMatch {
+    /// Parse something like `X-Beta: true`, or just `X-Beta`:
+    pub fn parse(input: &str) -> Result<HeaderMatch, Error> {
+        if input.contains(':') {
+            let (name, value) = parse_name_value(input)?;
+            Ok(HeaderMatch { name, value: Some(value) })
+        } else {
+            Ok(HeaderMatch { name: parse_name(input)?, value: None })
+        }
+    }
+
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        let mut values = headers.get_all(&self.name).iter();
+        match &self.value {
+            Some(value) => values.any(|v| v == value),
+            None => values.next().is_some()
+        }
+    }
+}
+
+impl HeaderRules {
+    pub fn apply_to_request(&self, headers: &mut HeaderMap) {
+        apply(&self.request, headers)
+    }
+
+    pub fn apply_to_response(&self, headers: &mut HeaderMap) {
+        apply(&self.response, headers)
+    }
+}
+
+impl HeaderRule {
+    /// Parse something like `X-App-Env: staging` into a rule to set that header:
+    pub fn set(input: &str) -> Result<HeaderRule, Error> {
+        let (name, value) = parse_name_value(input)?;
+        Ok(HeaderRule::Set(name, value))
+    }
+
+    /// Parse something like `X-App-Env: staging` into a rule to add that header:
+    pub fn add(input: &str) -> Result<HeaderRule, Error> {
+        let (name, value) = parse_name_value(input)?;
+        Ok(HeaderRule::Add(name, value))
+    }
+
+    /// Parse a header name into a rule to remove that header:
+    pub fn remove(input: &str) -> Result<HeaderRule, Error> {
+        Ok(HeaderRule::Remove(parse_name(input)?))
+    }
+}
+
+fn apply(rules: &[HeaderRule], headers: &mut HeaderMap) {
+    for rule in rules {
+        match rule {
+            HeaderRule::Set(name, value) => { headers.insert(name.clone(), value.clone()); },
+            HeaderRule::Add(name, value) => { headers.append(name.clone(), value.clone()); },
+            HeaderRule::Remove(name) => { headers.remove(name); }
+        }
+    }
+}
+
+/// Parse something like `X-App-Env: staging` into a header name and value:
+pub fn parse_name_value(input: &str) -> Result<(HeaderName, HeaderValue), Error> {
+    let idx = input.find(':').ok_or_else(|| {
+        err!("Expecting a header like 'Name: value' but got '{}'", input)
+    })?;
+    let name = parse_name(&input[..idx])?;
+    let value = input[idx+1..].trim();
+    let value = HeaderValue::from_str(value).map_err(|_| {
+        err!("'{}' is not a valid header value", value)
+    })?;
+    Ok((name, value))
+}
+
+pub fn parse_name(input: &str) -> Result<HeaderName, Error> {
+    let name = input.trim();
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+        err!("'{}' is not a valid header name", name)
+    })
+}
+
+/// Find the value of a cookie sent with a request:
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|c| {
+            let c = c.trim();
+            let idx = c.find('=')?;
+            if &c[..idx] == name { Some(&c[idx+1..]) } else { None }
+        })
+        .next()
+}
+
+/// Is the `Content-Type` of a message one of these types? Types can be
+/// exact, like `application/json`, or cover a family, like `text/*`.
+pub fn content_type_matches(headers: &HeaderMap, types: &[String]) -> bool {
+    let content_type = match headers.get(CONTENT_TYPE).and_then(|t| t.to_str().ok()) {
+        Some(content_type) => content_type.split(';').next().unwrap_or("").trim().to_lowercase(),
+        None => return false
+    };
+    types.iter().any(|pattern| {
+        match pattern.strip_suffix("/*") {
+            Some(prefix) => content_type.split('/').next() == Some(prefix),
+            None => *pattern == content_type
+        }
+    })
+}
+
+/// Say that a response depends on a header of the request, if we've not
+/// said so already:
+pub fn add_vary(headers: &mut HeaderMap, name: &'static str) {
+    let varies = headers.get_all(VARY).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case(name));
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static(name));
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_header_rules() {
+        assert_eq!(
+            HeaderRule::set("X-App-Env: staging").unwrap(),
+            HeaderRule::Set(HeaderName::from_static("x-app-env"), HeaderValue::from_static("staging"))
+        );
+        assert_eq!(
+            HeaderRule::add("cache-control:no-cache, no-store").unwrap(),
+            HeaderRule::Add(HeaderName::from_static("cache-control"), HeaderValue::from_static("no-cache, no-store"))
+        );
+        assert_eq!(
+            HeaderRule::remove(" Server ").unwrap(),
+            HeaderRule::Remove(HeaderName::from_static("server"))
+        );
+        assert!(HeaderRule::set("X-App-Env").is_err());
+        assert!(HeaderRule::set("X App: foo").is_err());
+        assert!(HeaderRule::remove("").is_err());
+    }
+
+    #[test]
+    fn matches_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-beta", HeaderValue::from_static("true"));
+        headers.append("accept", HeaderValue::from_static("text/html"));
+        headers.append("accept", HeaderValue::from_static("application/json"));
+
+        assert!(HeaderMatch::parse("X-Beta: true").unwrap().matches(&headers));
+        assert!(!HeaderMatch::parse("X-Beta: false").unwrap().matches(&headers));
+        assert!(HeaderMatch::parse("x-beta").unwrap().matches(&headers));
+        assert!(HeaderMatch::parse("Accept: application/json").unwrap().matches(&headers));
+        assert!(!HeaderMatch::parse("X-Other").unwrap().matches(&headers));
+    }
+
+    #[test]
+    fn applies_header_rules_in_order() {
+        let rules = HeaderRules {
+            request: vec![],
+            response: vec![
+                HeaderRule::remove("server").unwrap(),
+                HeaderRule::set("x-app-env: staging").unwrap(),
+                HeaderRule::add("vary: origin").unwrap(),
+                HeaderRule::set("x-frame-options: deny").unwrap(),
+            ]
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("upstream"));
+        headers.insert("vary", HeaderValue::from_static("accept"));
+        headers.insert("x-frame-options", HeaderValue::from_static("sameorigin"));
+        rules.apply_to_response(&mut headers);
+
+        assert!(headers.get("server").is_none());
+        assert_eq!(headers.get("x-app-env").unwrap(), "staging");
+        assert_eq!(headers.get_all("vary").iter().collect::<Vec<_>>(), vec!["accept", "origin"]);
+        assert_eq!(headers.get_all("x-frame-options").iter().collect::<Vec<_>>(), vec!["deny"]);
+    }
+
+    #[test]
+    fn finds_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("a=1; weave_session=abc.def"));
+        headers.append(COOKIE, HeaderValue::from_static("b=2"));
+        assert_eq!(cookie(&headers, "weave_session"), Some("abc.def"));
+        assert_eq!(cookie(&headers, "b"), Some("2"));
+        assert_eq!(cookie(&headers, "weave"), None);
+    }
+
+    #[test]
+    fn adds_to_vary() {
+        let mut vary = HeaderMap::new();
+        vary.insert(VARY, HeaderValue::from_static("Origin"));
+        add_vary(&mut vary, "Accept-Encoding");
+        assert_eq!(vary.get_all(VARY).iter().collect::<Vec<_>>(), vec!["Origin", "Accept-Encoding"]);
+        let mut already = HeaderMap::new();
+        already.insert(VARY, HeaderValue::from_static("accept-encoding"));
+        add_vary(&mut already, "Accept-Encoding");
+        assert_eq!(already.get_all(VARY).iter().count(), 1);
+    }
+}
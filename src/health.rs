@@ -0,0 +1,82 @@
+use ansi_term::Color::{ Green, Red };
+use hyper::Uri;
+use log::{ info, warn };
+use std::sync::Weak;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
+use tokio::time::{ self, sleep };
+use url::Url;
+use crate::errors::{ Error };
+use crate::location::{ DestLocation };
+use crate::matcher::{ Matcher };
+use crate::options::{ TlsOptions };
+use crate::HttpsClient;
+
+/// Start checking the health of the URL destinations of any routes that
+/// ask for it. Each check updates the matcher's view of whether that
+/// destination is healthy, and checks stop when the matcher is dropped.
+pub fn spawn_checks(matcher: &Matcher, client: &HttpsClient) {
+    for (route, dest, healthy) in matcher.health_flags() {
+        let path = match &route.options.health_check {
+            Some(path) => path,
+            None => continue
+        };
+        let mut url = match dest {
+            DestLocation::Url(url) | DestLocation::UnixSocket { url, .. } => url.clone(),
+            _ => continue
+        };
+        url.set_path(path);
+        url.set_query(None);
+
+        tokio::spawn(check_periodically(
+            client.clone(),
+            url,
+            route.options.tls.clone(),
+            route.options.health_interval,
+            route.options.health_timeout,
+            healthy
+        ));
+    }
+}
+
+async fn check_periodically(client: HttpsClient, url: Url, tls: TlsOptions, interval: Duration, timeout: Duration, healthy: Weak<AtomicBool>) {
+    loop {
+        let res = check(&client, &url, &tls, timeout).await;
+
+        // The routes we were checking for are gone, so stop:
+        let healthy = match healthy.upgrade() {
+            Some(healthy) => healthy,
+            None => return
+        };
+
+        let was_healthy = healthy.swap(res.is_ok(), Ordering::Relaxed);
+        match res {
+            Err(e) if was_healthy => {
+                let unhealthy_string = format!("[unhealthy] {} ({})", url, e);
+                warn!("{}", Red.paint(unhealthy_string));
+            },
+            Ok(()) if !was_healthy => {
+                let healthy_string = format!("[healthy] {}", url);
+                info!("{}", Green.paint(healthy_string));
+            },
+            _ => {}
+        }
+
+        drop(healthy);
+        sleep(interval).await;
+    }
+}
+
+/// A destination is healthy if it responds with a 2xx or 3xx in time:
+async fn check(client: &HttpsClient, url: &Url, tls: &TlsOptions, timeout: Duration) -> Result<(), Error> {
+    let uri: Uri = url.as_str().parse()?;
+    let res = time::timeout(timeout, client.get(uri, tls)).await
+        .map_err(|_| err!("timed out after {:#?}", timeout))??;
+
+    let status = res.status();
+    if status.is_success() || status.is_redirection() {
+        Ok(())
+    } else {
+        Err(err!("responded with {}", status))
+    }
+}
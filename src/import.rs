@@ -0,0 +1,992 @@
+use clap::{ App, AppSettings, Arg };
+use log::{ info, warn };
+use std::collections::HashMap;
+use std::path::Path;
+use crate::config;
+use crate::errors::{ Error };
+use crate::location::{ SrcLocation, DestLocation };
+use crate::logging::{ self, LogFormat };
+use crate::options::{ RouteOptions };
+use crate::routes::{ Route };
+use crate::syslog::{ LogTarget };
+
+/// nginx directives that don't change how requests are routed, so we
+/// needn't warn about leaving them out:
+const IGNORED_NGINX: &[&str] = &[
+    "user", "worker_processes", "worker_connections", "events", "pid", "error_log",
+    "access_log", "log_format", "sendfile", "tcp_nopush", "tcp_nodelay", "keepalive_timeout",
+    "types", "default_type", "charset", "server_tokens", "proxy_http_version"
+];
+
+/// Which kind of config file we're importing routes from.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Format {
+    Nginx,
+    Caddy
+}
+
+/// Routes imported from another server's config, along with warnings
+/// about anything in it we couldn't import.
+#[derive(Debug,Default)]
+pub struct Imported {
+    pub routes: Vec<Route>,
+    pub warnings: Vec<String>
+}
+
+/// A directive in an nginx config or Caddyfile, like `proxy_pass http://app;`
+/// or `location /api { ... }`:
+#[derive(Debug,Clone,PartialEq)]
+struct Directive {
+    name: String,
+    args: Vec<String>,
+    block: Option<Vec<Directive>>,
+    line: usize
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Token {
+    Word(String),
+    Open,
+    Close,
+    End
+}
+
+impl Format {
+    pub fn parse(input: &str) -> Result<Format, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "nginx" => Ok(Format::Nginx),
+            "caddy" | "caddyfile" => Ok(Format::Caddy),
+            _ => Err(err!("'{}' is not a format we can import; expecting 'nginx' or 'caddy'", input))
+        }
+    }
+
+    /// Guess the format of a config file from its name, and failing that,
+    /// from whether it has the blocks an nginx config would:
+    fn guess(path: &Path, contents: &str) -> Format {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_lowercase()).unwrap_or_default();
+        if name.contains("caddy") {
+            Format::Caddy
+        } else if name.contains("nginx") || name.ends_with(".conf") || parse(contents, Format::Nginx).is_ok_and(|ds| ds.iter().any(|d| d.name == "http" || d.name == "server")) {
+            Format::Nginx
+        } else {
+            Format::Caddy
+        }
+    }
+}
+
+impl Imported {
+    fn warn(&mut self, line: usize, message: impl AsRef<str>) {
+        self.warnings.push(format!("line {}: {}", line, message.as_ref()));
+    }
+
+    /// Add a route, or a warning if it doesn't parse:
+    fn add(&mut self, line: usize, src: &str, dests: &[String], options: &[(String, String)]) {
+        match route(src, dests, options) {
+            Ok(route) => self.routes.push(route),
+            Err(e) => self.warn(line, format!("can't import the route '{} to {}': {}", src, dests.join(" and-also "), e))
+        }
+    }
+}
+
+/// `weave import FILE`: turn the `proxy_pass`/`root` style parts of an nginx
+/// config or Caddyfile into a weave config, written to stdout, warning
+/// about the parts that can't be.
+pub fn run(args: impl Iterator<Item=String>) -> Result<(), Error> {
+    let matches = App::new("weave import")
+        .about("Convert the routes in a simple nginx config or Caddyfile into a weave config file.")
+        .usage("weave import FILE [--format nginx|caddy] [--output FILE]")
+        .setting(AppSettings::NoBinaryName)
+        .arg(Arg::with_name("file")
+            .value_name("FILE")
+            .required(true)
+            .help("The nginx config or Caddyfile to import routes from"))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .help("'nginx' or 'caddy'. Guessed from the file name and contents if not given"))
+        .arg(Arg::with_name("output")
+            .long("output")
+            .value_name("FILE")
+            .help("Write the weave config to this file rather than to stdout"))
+        .get_matches_from(args);
+    logging::init(LogFormat::Text, &LogTarget::Stderr, None)?;
+
+    let path = Path::new(matches.value_of("file").unwrap());
+    let contents = std::fs::read_to_string(path).map_err(|e| err!("Cannot read '{}': {}", path.display(), e))?;
+    let format = match matches.value_of("format") {
+        Some(s) => Format::parse(s).map_err(|e| err!("Invalid --format '{}': {}", s, e))?,
+        None => Format::guess(path, &contents)
+    };
+    let imported = match format {
+        Format::Nginx => from_nginx(&contents),
+        Format::Caddy => from_caddy(&contents)
+    }.map_err(|e| err!("Cannot import '{}': {}", path.display(), e))?;
+
+    for warning in &imported.warnings {
+        warn!("{}", warning);
+    }
+    if imported.routes.is_empty() {
+        return Err(err!("No routes could be imported from '{}'", path.display()));
+    }
+    let contents = config::to_string(&imported.routes)?;
+    match matches.value_of("output") {
+        Some(output) => {
+            std::fs::write(output, contents).map_err(|e| err!("Could not write config file '{}': {}", output, e))?;
+            info!("Wrote {} routes to {}", imported.routes.len(), output);
+        },
+        None => print!("{}", contents)
+    }
+    Ok(())
+}
+
+/// Import the routes from the `server` blocks of an nginx config.
+pub fn from_nginx(contents: &str) -> Result<Imported, Error> {
+    let directives = parse(contents, Format::Nginx)?;
+    let mut upstreams = HashMap::new();
+    collect_upstreams(&directives, &mut upstreams);
+    let mut imported = Imported::default();
+    nginx_blocks(&directives, &upstreams, &mut imported);
+    Ok(imported)
+}
+
+/// `upstream` blocks can be defined anywhere in the `http` block, and
+/// `proxy_pass` to them balances requests across their servers:
+fn collect_upstreams(directives: &[Directive], upstreams: &mut HashMap<String, Vec<Directive>>) {
+    for d in directives {
+        match (d.name.as_str(), &d.block) {
+            ("upstream", Some(block)) if d.args.len() == 1 => { upstreams.insert(d.args[0].clone(), block.clone()); },
+            (_, Some(block)) => collect_upstreams(block, upstreams),
+            _ => {}
+        }
+    }
+}
+
+fn nginx_blocks(directives: &[Directive], upstreams: &HashMap<String, Vec<Directive>>, imported: &mut Imported) {
+    for d in directives {
+        match (d.name.as_str(), &d.block) {
+            ("http", Some(block)) => nginx_blocks(block, upstreams, imported),
+            ("server", Some(block)) => nginx_server(block, d.line, upstreams, imported),
+            ("upstream", _) => {},
+            (name, _) if IGNORED_NGINX.contains(&name) => {},
+            (name, _) => imported.warn(d.line, format!("'{}' isn't supported", name))
+        }
+    }
+}
+
+fn nginx_server(block: &[Directive], line: usize, upstreams: &HashMap<String, Vec<Directive>>, imported: &mut Imported) {
+    let mut listens = vec![];
+    let mut names = vec![];
+    let mut root = None;
+    let mut server_return = None;
+    let mut locations = vec![];
+    let mut options = vec![];
+    for d in block {
+        match d.name.as_str() {
+            "listen" => {
+                if let Some(listen) = nginx_listen(d, imported) {
+                    if !listens.contains(&listen) {
+                        listens.push(listen);
+                    }
+                }
+            },
+            "server_name" => {
+                for name in &d.args {
+                    if name == "_" || name.is_empty() || name == "localhost" {
+                        continue;
+                    }
+                    if name.starts_with('~') || name.contains('*') {
+                        imported.warn(d.line, format!("the server name '{}' isn't supported, as it's a pattern", name));
+                        continue;
+                    }
+                    names.push(name.to_lowercase());
+                }
+            },
+            "root" if d.args.len() == 1 => root = Some(d.args[0].clone()),
+            "return" => server_return = Some(d),
+            "location" => locations.push(d),
+            _ => nginx_option(d, &mut options, imported)
+        }
+    }
+
+    if listens.is_empty() {
+        listens.push("80".to_owned());
+    }
+    let mut bases = vec![];
+    for listen in &listens {
+        if names.is_empty() || listen.starts_with("unix://") {
+            bases.push(listen.clone());
+        } else if listen.parse::<u16>().is_ok() {
+            bases.extend(names.iter().map(|name| format!("{}:{}", name, listen)));
+        } else {
+            // Virtual hosts listened for on a particular address:
+            bases.extend(names.iter().map(|name| format!("{}@{}", name, listen)));
+        }
+    }
+
+    // A `return` in the server block happens before any locations are looked at:
+    if let Some(ret) = server_return {
+        if let Some(dest) = nginx_return(ret, imported) {
+            for base in &bases {
+                imported.add(ret.line, &src(base, "/", false, false), std::slice::from_ref(&dest), &options);
+            }
+        }
+        return;
+    }
+
+    let mut has_root_location = false;
+    for location in locations {
+        if let Some((path, exact, dests, preserve, location_options)) = nginx_location(location, root.as_deref(), upstreams, imported) {
+            has_root_location |= path == "/";
+            let mut all_options = options.clone();
+            all_options.extend(location_options);
+            for base in &bases {
+                imported.add(location.line, &src(base, &path, exact, preserve), &dests, &all_options);
+            }
+        }
+    }
+    // Without a `location /`, nginx serves files from the root:
+    if let (false, Some(root)) = (has_root_location, root) {
+        for base in &bases {
+            imported.add(line, &src(base, "/", false, true), std::slice::from_ref(&root), &options);
+        }
+    }
+}
+
+/// Turn `listen 8080`, `listen 127.0.0.1:8080` and the like into the start
+/// of a weave source. Listening on every interface is imported as
+/// listening on localhost, since that's what's wanted to try things out:
+fn nginx_listen(d: &Directive, imported: &mut Imported) -> Option<String> {
+    let addr = d.args.first()?;
+    for arg in &d.args[1..] {
+        match arg.as_str() {
+            "default_server" | "default" | "http2" | "reuseport" => {},
+            "ssl" => imported.warn(d.line, format!("listening with TLS isn't supported; listening on {} without it", addr)),
+            other => imported.warn(d.line, format!("the listen parameter '{}' isn't supported", other))
+        }
+    }
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return Some(format!("unix://{}", path));
+    }
+    let (host, port) = match addr.rfind(':') {
+        Some(idx) if !addr.ends_with(']') => (&addr[..idx], &addr[idx+1..]),
+        _ if addr.parse::<u16>().is_ok() => ("", addr.as_str()),
+        _ => (addr.as_str(), "80")
+    };
+    match host {
+        "" | "*" | "[::]" | "0.0.0.0" | "localhost" => Some(port.to_owned()),
+        host => Some(format!("{}:{}", host, port))
+    }
+}
+
+/// The path a location matches, whether exactly, where to send requests
+/// to it, whether to keep the path it matched when doing so, and options:
+type Location = (String, bool, Vec<String>, bool, Vec<(String, String)>);
+
+fn nginx_location(d: &Directive, server_root: Option<&str>, upstreams: &HashMap<String, Vec<Directive>>, imported: &mut Imported) -> Option<Location> {
+    let (path, exact) = match d.args.iter().map(|a| a.as_str()).collect::<Vec<_>>().as_slice() {
+        ["=", path] => (path.to_string(), true),
+        ["^~", path] => (path.to_string(), false),
+        ["~", _] | ["~*", _] => {
+            imported.warn(d.line, "locations with regular expressions aren't supported");
+            return None;
+        },
+        [path] if path.starts_with('@') => {
+            imported.warn(d.line, "named locations aren't supported");
+            return None;
+        },
+        [path] => (path.to_string(), false),
+        _ => {
+            imported.warn(d.line, "expecting a location like 'location /path'");
+            return None;
+        }
+    };
+
+    let mut proxy_pass = None;
+    let mut root = None;
+    let mut alias = None;
+    let mut ret = None;
+    let mut options = vec![];
+    for inner in d.block.as_deref().unwrap_or(&[]) {
+        match inner.name.as_str() {
+            "proxy_pass" if inner.args.len() == 1 => proxy_pass = Some(inner),
+            "root" if inner.args.len() == 1 => root = Some(inner.args[0].clone()),
+            "alias" if inner.args.len() == 1 => alias = Some(inner.args[0].clone()),
+            "return" => ret = Some(inner),
+            "location" => imported.warn(inner.line, "nested locations aren't supported"),
+            _ => nginx_option(inner, &mut options, imported)
+        }
+    }
+
+    let (dests, preserve) = if let Some(ret) = ret {
+        (vec![nginx_return(ret, imported)?], false)
+    } else if let Some(proxy_pass) = proxy_pass {
+        nginx_proxy_pass(proxy_pass, upstreams, imported)?
+    } else if let Some(alias) = alias {
+        (vec![alias], false)
+    } else if let Some(root) = root.as_deref().or(server_root) {
+        (vec![root.to_owned()], true)
+    } else {
+        imported.warn(d.line, format!("the location '{}' has no proxy_pass, root, alias or return to import", path));
+        return None;
+    };
+    Some((path, exact, dests, preserve, options))
+}
+
+/// Where `proxy_pass` sends requests to. nginx replaces the path that the
+/// location matched with the path of the URL, if it has one, and otherwise
+/// sends the whole path on:
+fn nginx_proxy_pass(d: &Directive, upstreams: &HashMap<String, Vec<Directive>>, imported: &mut Imported) -> Option<(Vec<String>, bool)> {
+    let url = &d.args[0];
+    if url.contains('$') {
+        imported.warn(d.line, "proxy_pass with variables isn't supported");
+        return None;
+    }
+    let (scheme, rest) = match url.find("://") {
+        Some(idx) => (&url[..idx], &url[idx+3..]),
+        None => {
+            imported.warn(d.line, format!("expecting an http:// or https:// URL after proxy_pass but got '{}'", url));
+            return None;
+        }
+    };
+    let (host, path) = match rest.find('/') {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, "")
+    };
+
+    let servers = match upstreams.get(host) {
+        Some(block) => {
+            let mut servers = vec![];
+            for server in block {
+                match (server.name.as_str(), server.args.first()) {
+                    ("server", Some(addr)) => {
+                        if server.args.iter().any(|a| a == "down" || a == "backup") {
+                            continue;
+                        }
+                        if server.args.len() > 1 {
+                            imported.warn(server.line, "upstream server parameters aren't supported");
+                        }
+                        servers.push(addr.clone());
+                    },
+                    (name, _) => imported.warn(server.line, format!("'{}' isn't supported in upstream blocks", name))
+                }
+            }
+            servers
+        },
+        None => vec![host.to_owned()]
+    };
+    if servers.is_empty() {
+        imported.warn(d.line, format!("the upstream '{}' has no servers to import", host));
+        return None;
+    }
+
+    let dests = servers.iter()
+        .map(|server| match server.strip_prefix("unix:") {
+            Some(socket) => format!("unix://{}:{}", socket, if path.is_empty() { "/" } else { path }),
+            None => format!("{}://{}{}", scheme, server, path)
+        })
+        .collect();
+    Some((dests, path.is_empty()))
+}
+
+/// `return CODE [TEXT or URL]`, or `return URL`:
+fn nginx_return(d: &Directive, imported: &mut Imported) -> Option<String> {
+    let (code, arg) = match d.args.as_slice() {
+        [url] if url.parse::<u16>().is_err() => (302, Some(url)),
+        [code] => (code.parse().ok()?, None),
+        [code, arg] => match code.parse() {
+            Ok(code) => (code, Some(arg)),
+            Err(_) => {
+                imported.warn(d.line, format!("'{}' is not a status code", code));
+                return None;
+            }
+        },
+        _ => {
+            imported.warn(d.line, "expecting 'return CODE [TEXT or URL]'");
+            return None;
+        }
+    };
+    match (code, arg) {
+        (301 | 302 | 303 | 307 | 308, Some(url)) => redirect(d.line, url, code, &["https://$host$request_uri", "https://$server_name$request_uri"], '$', imported),
+        (code, arg) => Some(respond(code, arg.map(|a| a.as_str()).unwrap_or("")))
+    }
+}
+
+/// Directives that set route options, in nginx configs:
+fn nginx_option(d: &Directive, options: &mut Vec<(String, String)>, imported: &mut Imported) {
+    let args: Vec<&str> = d.args.iter().map(|a| a.as_str()).collect();
+    let option = match (d.name.as_str(), args.as_slice()) {
+        ("proxy_set_header", [name, value]) if name.eq_ignore_ascii_case("host") && (*value == "$host" || *value == "$http_host") => {
+            Some(("preserve-host", "true".to_owned()))
+        },
+        // We add these ourselves:
+        ("proxy_set_header", [name, _]) if ["x-forwarded-for", "x-forwarded-proto", "x-forwarded-host"].contains(&name.to_lowercase().as_str()) => None,
+        ("proxy_set_header", [name, value]) if !value.contains('$') => Some(("request-header", format!("{}: {}", name, value))),
+        ("add_header", [name, value]) | ("add_header", [name, value, "always"]) if !value.contains('$') => {
+            Some(("response-header", format!("{}: {}", name, value)))
+        },
+        ("gzip", ["on"]) => Some(("compress", "gzip".to_owned())),
+        ("gzip", ["off"]) => None,
+        ("autoindex", ["on"]) => Some(("dir-listing", "true".to_owned())),
+        ("autoindex", ["off"]) => None,
+        ("index", files) if files.contains(&"index.html") => None,
+        ("try_files", [.., last]) if last.ends_with("/index.html") => Some(("spa", "true".to_owned())),
+        (name, _) if IGNORED_NGINX.contains(&name) => None,
+        (name, _) => {
+            imported.warn(d.line, format!("'{}' isn't supported", std::iter::once(name).chain(args.iter().copied()).collect::<Vec<_>>().join(" ")));
+            None
+        }
+    };
+    if let Some((key, value)) = option {
+        options.push((key.to_owned(), value));
+    }
+}
+
+/// Import the routes from the site blocks of a Caddyfile.
+pub fn from_caddy(contents: &str) -> Result<Imported, Error> {
+    let directives = parse(contents, Format::Caddy)?;
+    let mut imported = Imported::default();
+
+    // A Caddyfile with one site can leave out the braces around it:
+    if let Some(first) = directives.first().filter(|d| d.block.is_none() && !d.name.is_empty()) {
+        let addresses = std::iter::once(&first.name).chain(&first.args).cloned().collect::<Vec<_>>();
+        caddy_site(&addresses, &directives[1..], first.line, &mut imported);
+        return Ok(imported);
+    }
+
+    for d in &directives {
+        match &d.block {
+            // Global options, which don't affect routing:
+            Some(_) if d.name.is_empty() => {},
+            Some(_) if d.name.starts_with('(') => imported.warn(d.line, "snippets aren't supported"),
+            Some(block) => {
+                let addresses = std::iter::once(&d.name).chain(&d.args).cloned().collect::<Vec<_>>();
+                caddy_site(&addresses, block, d.line, &mut imported);
+            },
+            None => imported.warn(d.line, format!("'{}' isn't supported outside of a site block", d.name))
+        }
+    }
+    Ok(imported)
+}
+
+/// What to do with requests to a path in a Caddyfile site:
+enum Handler {
+    Proxy(Vec<String>),
+    Files { browse: bool },
+    Fixed(String)
+}
+
+/// A handler for the requests to a path, from the directive on `line`:
+struct Handled {
+    line: usize,
+    path: String,
+    exact: bool,
+    preserve: bool,
+    handler: Handler
+}
+
+fn caddy_site(addresses: &[String], block: &[Directive], line: usize, imported: &mut Imported) {
+    let mut bases = vec![];
+    for address in addresses.iter().flat_map(|a| a.split(',')).map(|a| a.trim()).filter(|a| !a.is_empty()) {
+        if let Some(base) = caddy_address(address, line, imported) {
+            if !bases.contains(&base) {
+                bases.push(base);
+            }
+        }
+    }
+
+    let mut roots = vec![];
+    let mut handlers = vec![];
+    let mut options = vec![];
+    caddy_directives(block, "", false, &mut roots, &mut handlers, &mut options, imported);
+
+    for handled in handlers {
+        let mut route_options = options.clone();
+        let dests = match handled.handler {
+            Handler::Proxy(dests) => dests,
+            Handler::Fixed(dest) => vec![dest],
+            Handler::Files { browse } => {
+                // The root for the most specific path that this is under:
+                let root = roots.iter()
+                    .filter(|(root_path, _)| handled.path.starts_with(root_path.as_str()))
+                    .max_by_key(|(root_path, _)| root_path.len())
+                    .map(|(_, root)| root.clone())
+                    .unwrap_or_else(|| ".".to_owned());
+                if browse {
+                    route_options.push(("dir-listing".to_owned(), "true".to_owned()));
+                }
+                vec![root]
+            }
+        };
+        for base in &bases {
+            imported.add(handled.line, &src(base, &handled.path, handled.exact, handled.preserve), &dests, &route_options);
+        }
+    }
+}
+
+/// Work out what to do with requests in a site (or a `handle` block in one),
+/// where `prefix` is the path that the block is for, and `strip` is whether
+/// it's taken off of requests first (as `handle_path` does):
+fn caddy_directives(
+    block: &[Directive],
+    prefix: &str,
+    strip: bool,
+    roots: &mut Vec<(String, String)>,
+    handlers: &mut Vec<Handled>,
+    options: &mut Vec<(String, String)>,
+    imported: &mut Imported
+) {
+    for d in block {
+        // The first arg can be a path to match on:
+        let (matcher, args) = match d.args.split_first() {
+            Some((first, _)) if first.starts_with('@') => {
+                imported.warn(d.line, "named matchers aren't supported");
+                continue;
+            },
+            Some((first, rest)) if first == "*" || (first.starts_with('/') && (!rest.is_empty() || d.block.is_some() || d.name == "reverse_proxy" || d.name == "file_server")) => {
+                (Some(first.as_str()), rest)
+            },
+            _ => (None, d.args.as_slice())
+        };
+        let (path, exact) = match matcher {
+            Some(matcher) => caddy_path(prefix, matcher),
+            None => (if prefix.is_empty() { "/".to_owned() } else { format!("{}/", prefix) }, false)
+        };
+
+        match d.name.as_str() {
+            "reverse_proxy" => {
+                if d.block.is_some() {
+                    imported.warn(d.line, "reverse_proxy options aren't supported");
+                }
+                if args.is_empty() || args.iter().any(|a| a.contains('{')) {
+                    imported.warn(d.line, "expecting the addresses to proxy to after reverse_proxy");
+                    continue;
+                }
+                let dests = args.iter().map(|a| caddy_upstream(a)).collect();
+                handlers.push(Handled { line: d.line, path, exact, preserve: !strip, handler: Handler::Proxy(dests) });
+            },
+            "root" if args.len() == 1 => roots.push((path, args[0].clone())),
+            "file_server" => {
+                let browse = args.iter().any(|a| a == "browse");
+                handlers.push(Handled { line: d.line, path, exact, preserve: !strip, handler: Handler::Files { browse } });
+            },
+            "redir" if !args.is_empty() && args.len() <= 2 => {
+                let code = match args.get(1).map(|a| a.as_str()) {
+                    None | Some("temporary") => 302,
+                    Some("permanent") => 301,
+                    Some(code) => match code.parse() {
+                        Ok(code) => code,
+                        Err(_) => {
+                            imported.warn(d.line, format!("the redirect status '{}' isn't supported", code));
+                            continue;
+                        }
+                    }
+                };
+                if let Some(dest) = redirect(d.line, &args[0], code, &["https://{host}{uri}"], '{', imported) {
+                    handlers.push(Handled { line: d.line, path, exact, preserve: false, handler: Handler::Fixed(dest) });
+                }
+            },
+            "respond" if args.len() <= 2 => {
+                let (body, code) = match args {
+                    [] => ("", 200),
+                    [arg] => match arg.parse() {
+                        Ok(code) if arg.len() == 3 => ("", code),
+                        _ => (arg.as_str(), 200)
+                    },
+                    [body, code] => match code.parse() {
+                        Ok(code) => (body.as_str(), code),
+                        Err(_) => {
+                            imported.warn(d.line, format!("'{}' is not a status code", code));
+                            continue;
+                        }
+                    },
+                    _ => unreachable!()
+                };
+                handlers.push(Handled { line: d.line, path, exact, preserve: false, handler: Handler::Fixed(respond(code, body)) });
+            },
+            "handle" | "handle_path" => match (&d.block, matcher) {
+                (Some(inner), Some(_)) => {
+                    let inner_prefix = path.trim_end_matches('/').to_owned();
+                    let inner_strip = strip || d.name == "handle_path";
+                    caddy_directives(inner, &inner_prefix, inner_strip, roots, handlers, options, imported);
+                },
+                (Some(inner), None) => caddy_directives(inner, prefix, strip, roots, handlers, options, imported),
+                (None, _) => imported.warn(d.line, format!("expecting a block after {}", d.name))
+            },
+            "header" if matcher.is_none() => {
+                let option = match args {
+                    [name] if name.starts_with('-') => Some(("remove-response-header", name[1..].to_owned())),
+                    [name, value] if name.starts_with('+') => Some(("add-response-header", format!("{}: {}", &name[1..], value))),
+                    [name, value] if !value.contains('{') => Some(("response-header", format!("{}: {}", name, value))),
+                    _ => None
+                };
+                match option {
+                    Some((key, value)) => options.push((key.to_owned(), value)),
+                    None => imported.warn(d.line, "only 'header Name value' and 'header -Name' are supported")
+                }
+            },
+            "encode" if matcher.is_none() => {
+                let encodings: Vec<&str> = args.iter().map(|a| a.as_str()).filter(|a| ["gzip", "zstd"].contains(a)).collect();
+                if encodings.len() < args.len() {
+                    imported.warn(d.line, "only gzip and zstd encodings are supported");
+                }
+                if !encodings.is_empty() {
+                    options.push(("compress".to_owned(), encodings.join(",")));
+                }
+            },
+            "try_files" if args.last().is_some_and(|a| a.ends_with("/index.html")) => {
+                options.push(("spa".to_owned(), "true".to_owned()));
+            },
+            // Logging doesn't affect routing:
+            "log" => {},
+            name => imported.warn(d.line, format!("'{}' isn't supported", name))
+        }
+    }
+}
+
+/// Turn a Caddy site address (like `:8080`, `localhost:8080` or
+/// `http://api.local`) into the start of a weave source:
+fn caddy_address(address: &str, line: usize, imported: &mut Imported) -> Option<String> {
+    let (rest, default_port) = if let Some(rest) = address.strip_prefix("http://") {
+        (rest, Some("80"))
+    } else if let Some(rest) = address.strip_prefix("https://") {
+        imported.warn(line, format!("HTTPS isn't supported; listening for '{}' without TLS", address));
+        (rest, Some("443"))
+    } else {
+        (address, None)
+    };
+    let rest = rest.split('/').next().unwrap_or("");
+    let (host, port) = match rest.rfind(':') {
+        Some(idx) if !rest.ends_with(']') => (&rest[..idx], &rest[idx+1..]),
+        _ => (rest, default_port.unwrap_or_else(|| {
+            imported.warn(line, format!("automatic HTTPS isn't supported; listening for '{}' on port 80", address));
+            "80"
+        }))
+    };
+    if host.contains('*') || host.contains('{') {
+        imported.warn(line, format!("the site address '{}' isn't supported, as it's a pattern", address));
+        return None;
+    }
+    match host {
+        "" | "localhost" => Some(port.to_owned()),
+        host => Some(format!("{}:{}", host, port))
+    }
+}
+
+/// Turn a Caddy path matcher into the path to match and whether to match
+/// it exactly. `/api/*` and `/api*` match anything starting with them,
+/// and other paths need to match exactly:
+fn caddy_path(prefix: &str, matcher: &str) -> (String, bool) {
+    if matcher == "*" {
+        return (format!("{}/", prefix), false);
+    }
+    match matcher.strip_suffix('*') {
+        Some(path) => (format!("{}{}", prefix, path), false),
+        None => (format!("{}{}", prefix, matcher), true)
+    }
+}
+
+/// Caddy upstreams can leave out the host (`:9000`) or be sockets (`unix//path`):
+fn caddy_upstream(upstream: &str) -> String {
+    if let Some(socket) = upstream.strip_prefix("unix/") {
+        format!("unix://{}", socket)
+    } else if upstream.starts_with(':') {
+        format!("localhost{}", upstream)
+    } else {
+        upstream.to_owned()
+    }
+}
+
+/// A redirect to a URL. Redirects to HTTPS on the same host and path become
+/// `upgrade-https`, and other URLs with variables in can't be imported:
+fn redirect(line: usize, url: &str, code: u16, to_https: &[&str], variable: char, imported: &mut Imported) -> Option<String> {
+    if to_https.contains(&url) {
+        return Some("upgrade-https".to_owned());
+    }
+    if url.contains(variable) {
+        imported.warn(line, format!("redirects to URLs with variables (like '{}') aren't supported", url));
+        return None;
+    }
+    if url.starts_with('/') {
+        imported.warn(line, format!("redirects to paths (like '{}') aren't supported; expecting a URL", url));
+        return None;
+    }
+    let joiner = if url.contains('?') { '&' } else { '?' };
+    Some(format!("redirect://{}{}status={}", url, joiner, code))
+}
+
+/// A fixed response with a status and maybe a body:
+fn respond(code: u16, body: &str) -> String {
+    if body.is_empty() {
+        format!("status://{}", code)
+    } else if code == 200 {
+        format!("text://{}", body)
+    } else {
+        let query = url::form_urlencoded::Serializer::new(String::new()).append_pair("body", body).finish();
+        format!("status://{}?{}", code, query)
+    }
+}
+
+/// A weave source made from where to listen and the path to match on:
+fn src(base: &str, path: &str, exact: bool, preserve: bool) -> String {
+    let flags = match (exact, preserve) {
+        (true, true) => "=+",
+        (true, false) => "=",
+        (false, true) => "+",
+        (false, false) => ""
+    };
+    // Sources on sockets separate the socket path from the path with a ':':
+    let joiner = if base.starts_with("unix://") { ":" } else { "" };
+    format!("{}{}{}{}", flags, base, joiner, path)
+}
+
+fn route(src: &str, dests: &[String], options: &[(String, String)]) -> Result<Route, Error> {
+    let src = SrcLocation::parse(src)?;
+    let dests = dests.iter().map(DestLocation::parse).collect::<Result<Vec<_>, Error>>()?;
+    let mut route_options = RouteOptions::default();
+    for (key, value) in options {
+        route_options.set(key, value)?;
+    }
+    Ok(Route { src, dests, options: route_options })
+}
+
+/// Parse an nginx config or Caddyfile into its directives. nginx directives
+/// end with a `;`, and Caddy ones at the end of the line:
+fn parse(input: &str, format: Format) -> Result<Vec<Directive>, Error> {
+    let tokens = tokenize(input, format)?;
+    let mut pos = 0;
+    let directives = parse_block(&tokens, &mut pos, false)?;
+    Ok(directives)
+}
+
+fn parse_block(tokens: &[(Token, usize)], pos: &mut usize, nested: bool) -> Result<Vec<Directive>, Error> {
+    let mut directives = vec![];
+    let mut words: Vec<String> = vec![];
+    let mut first_line = 0;
+    let finish = |words: &mut Vec<String>, line: usize, block: Option<Vec<Directive>>| {
+        let mut args = std::mem::take(words);
+        let name = if args.is_empty() { String::new() } else { args.remove(0) };
+        Directive { name, args, block, line }
+    };
+    while let Some((token, line)) = tokens.get(*pos) {
+        *pos += 1;
+        match token {
+            Token::Word(word) => {
+                if words.is_empty() {
+                    first_line = *line;
+                }
+                words.push(word.clone());
+            },
+            Token::End => {
+                if !words.is_empty() {
+                    directives.push(finish(&mut words, first_line, None));
+                }
+            },
+            Token::Open => {
+                if words.is_empty() {
+                    first_line = *line;
+                }
+                let block = parse_block(tokens, pos, true)?;
+                directives.push(finish(&mut words, first_line, Some(block)));
+            },
+            Token::Close if nested => {
+                if !words.is_empty() {
+                    directives.push(finish(&mut words, first_line, None));
+                }
+                return Ok(directives);
+            },
+            Token::Close => return Err(err!("line {}: Unexpected '}}'", line))
+        }
+    }
+    if nested {
+        return Err(err!("Expecting a '}}' before the end of the file"));
+    }
+    if !words.is_empty() {
+        directives.push(finish(&mut words, first_line, None));
+    }
+    Ok(directives)
+}
+
+/// Split a config into words, braces and the ends of directives. In
+/// Caddyfiles, braces only open and close blocks on their own, since
+/// placeholders like `{host}` use them too:
+fn tokenize(input: &str, format: Format) -> Result<Vec<(Token, usize)>, Error> {
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let standalone = |chars: &std::iter::Peekable<std::str::Chars>| {
+            let mut rest = chars.clone();
+            rest.next();
+            rest.peek().is_none_or(|c| c.is_whitespace() || *c == ';')
+        };
+        match c {
+            '\n' => {
+                chars.next();
+                if format == Format::Caddy {
+                    tokens.push((Token::End, line));
+                }
+                line += 1;
+            },
+            c if c.is_whitespace() => { chars.next(); },
+            '#' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            },
+            ';' if format == Format::Nginx => {
+                chars.next();
+                tokens.push((Token::End, line));
+            },
+            '{' if format == Format::Nginx || standalone(&chars) => {
+                chars.next();
+                tokens.push((Token::Open, line));
+            },
+            '}' if format == Format::Nginx || standalone(&chars) => {
+                chars.next();
+                tokens.push((Token::Close, line));
+            },
+            '"' | '\'' | '`' => {
+                chars.next();
+                let start_line = line;
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c != '`' => {
+                            if let Some(escaped) = chars.next() {
+                                word.push(escaped);
+                            }
+                        },
+                        Some(other) => {
+                            if other == '\n' {
+                                line += 1;
+                            }
+                            word.push(other);
+                        },
+                        None => return Err(err!("line {}: Expecting a closing {}", start_line, c))
+                    }
+                }
+                tokens.push((Token::Word(word), start_line));
+            },
+            _ => {
+                let mut word = String::new();
+                let mut depth = 0;
+                while let Some(&c) = chars.peek() {
+                    // Braces in words are placeholders, like `${var}` or `{host}`:
+                    let in_word = match c {
+                        '{' if format == Format::Caddy || word.ends_with('$') => { depth += 1; true },
+                        '}' if depth > 0 => { depth -= 1; true },
+                        ';' if format == Format::Nginx => false,
+                        '{' | '}' => false,
+                        c => !c.is_whitespace()
+                    };
+                    if !in_word {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push((Token::Word(word), line));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn routes(imported: &Imported) -> Vec<String> {
+        imported.routes.iter()
+            .map(|r| format!("{} to {}", r.src.to_input(), r.dests.iter().map(|d| d.to_input()).collect::<Vec<_>>().join(" and-also ")))
+            .collect()
+    }
+
+    #[test]
+    fn imports_nginx_configs() {
+        let imported = from_nginx(r#"
+            worker_processes 1;
+            http {
+                upstream app {
+                    server 127.0.0.1:9000;
+                    server 127.0.0.1:9001 weight=2;
+                }
+                server {
+                    listen 80;
+                    server_name example.local;
+                    return 301 https://$host$request_uri;
+                }
+                server {
+                    listen 127.0.0.1:8081;
+                    server_name admin.local;
+                    location / { proxy_pass http://127.0.0.1:9003; }
+                }
+                server {
+                    listen 8080;
+                    root /var/www;
+                    add_header X-Frame-Options "DENY";
+                    location /api/ {
+                        proxy_pass http://app/;
+                        proxy_set_header Host $host;
+                    }
+                    location = /health { return 200 "ok"; }
+                    location /static/ { alias /srv/static/; }
+                    location ~ \.php$ { fastcgi_pass unix:/run/php.sock; }
+                    location /old { proxy_pass http://localhost:9002; proxy_buffering off; }
+                }
+            }
+        "#).unwrap();
+        assert_eq!(routes(&imported), vec![
+            "http://example.local/ to upgrade-https",
+            "+http://admin.local@127.0.0.1:8081/ to http://127.0.0.1:9003/",
+            "http://localhost:8080/api/ to http://127.0.0.1:9000/ and-also http://127.0.0.1:9001/",
+            "=http://localhost:8080/health to text:text/plain; charset=utf-8://ok",
+            "http://localhost:8080/static/ to /srv/static/",
+            "+http://localhost:8080/old to http://localhost:9002/",
+            "+http://localhost:8080/ to /var/www"
+        ]);
+        assert!(imported.routes[2].options.preserve_host);
+        assert_eq!(imported.routes[6].options.given, vec![("response-header".to_owned(), "X-Frame-Options: DENY".to_owned())]);
+        assert_eq!(imported.warnings.len(), 3);
+        assert!(imported.warnings[0].contains("upstream server parameters"));
+        assert!(imported.warnings[1].contains("regular expressions"));
+        assert!(imported.warnings[2].contains("'proxy_buffering off' isn't supported"));
+    }
+
+    #[test]
+    fn imports_caddyfiles() {
+        let imported = from_caddy(r#"
+            {
+                email admin@example.com
+            }
+            :8080, api.local:8080 {
+                encode gzip
+                reverse_proxy /api/* localhost:9000 :9001
+                handle_path /static/* {
+                    root * ./public
+                    file_server browse
+                }
+                respond /health "ok" 200
+                redir /old https://example.com/new permanent
+                rewrite * /index.html
+            }
+        "#).unwrap();
+        assert_eq!(routes(&imported), vec![
+            "+http://localhost:8080/api/ to http://localhost:9000/ and-also http://localhost:9001/",
+            "+http://api.local:8080/api/ to http://localhost:9000/ and-also http://localhost:9001/",
+            "http://localhost:8080/static/ to ./public",
+            "http://api.local:8080/static/ to ./public",
+            "=http://localhost:8080/health to text:text/plain; charset=utf-8://ok",
+            "=http://api.local:8080/health to text:text/plain; charset=utf-8://ok",
+            "=http://localhost:8080/old to redirect://https://example.com/new?status=301",
+            "=http://api.local:8080/old to redirect://https://example.com/new?status=301"
+        ]);
+        assert_eq!(imported.warnings, vec!["line 14: 'rewrite' isn't supported".to_owned()]);
+
+        let imported = from_caddy("localhost:3000\nreverse_proxy {$BACKEND}\nreverse_proxy /ws localhost:4000\n").unwrap();
+        assert_eq!(routes(&imported), vec!["=+http://localhost:3000/ws to http://localhost:4000/"]);
+        assert_eq!(imported.warnings.len(), 1);
+    }
+}
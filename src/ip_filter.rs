@@ -0,0 +1,143 @@
+use std::net::{ IpAddr, Ipv4Addr };
+use crate::errors::{ Error };
+
+/// Which client networks may use a route. Denied networks take priority,
+/// and if any networks are allowed, clients must be in one of them.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>
+}
+
+impl IpFilter {
+    /// Is a client with this address allowed? Clients connected over Unix
+    /// sockets have no address, so are only allowed if there's no allow list.
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        let ip = match ip {
+            Some(ip) => normalize(ip),
+            None => return self.allow.is_empty()
+        };
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// A network like `10.0.0.0/8` or `fd00::/8`, or a single address:
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+    pub fn parse(input: &str) -> Result<Cidr, Error> {
+        let input = input.trim();
+        let invalid = || err!("Expecting an IP address or network like '10.0.0.0/8' but got '{}'", input);
+        let (addr, prefix) = match input.find('/') {
+            Some(idx) => (&input[..idx], Some(&input[idx+1..])),
+            None => (input, None)
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max_prefix
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    /// Parse a comma separated list of networks:
+    pub fn parse_list(input: &str) -> Result<Vec<Cidr>, Error> {
+        input.split(',').map(Cidr::parse).collect()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            },
+            _ => false
+        }
+    }
+}
+
+/// Do the first `prefix` bits of two addresses match?
+fn prefix_matches(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = ((prefix / 8) as usize, prefix % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    if bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - bits);
+    a[bytes] & mask == b[bytes] & mask
+}
+
+/// IPv4 clients of a listener on an IPv6 address show up as IPv4-mapped
+/// IPv6 addresses (`::ffff:10.0.0.1`), so treat those as the IPv4 ones:
+fn normalize(ip: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = ip {
+        let s = v6.segments();
+        if s[..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+            return IpAddr::V4(Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8));
+        }
+    }
+    ip
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_networks() {
+        let net = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        let net = Cidr::parse("192.168.1.128/25").unwrap();
+        assert!(net.contains("192.168.1.200".parse().unwrap()));
+        assert!(!net.contains("192.168.1.100".parse().unwrap()));
+        let net = Cidr::parse("fd00::/8").unwrap();
+        assert!(net.contains("fd12::1".parse().unwrap()));
+        assert!(!net.contains("fe80::1".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains("1.2.3.4".parse().unwrap()));
+        assert!(Cidr::parse("127.0.0.1").unwrap().contains("127.0.0.1".parse().unwrap()));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("wibble/8").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn filters_clients() {
+        let filter = IpFilter {
+            allow: Cidr::parse_list("10.0.0.0/8, 127.0.0.1/32").unwrap(),
+            deny: Cidr::parse_list("10.0.0.66").unwrap()
+        };
+        assert!(filter.allows(ip("10.1.2.3")));
+        assert!(filter.allows(ip("::ffff:10.1.2.3")));
+        assert!(filter.allows(ip("127.0.0.1")));
+        assert!(!filter.allows(ip("10.0.0.66")));
+        assert!(!filter.allows(ip("192.168.0.1")));
+        assert!(!filter.allows(None));
+
+        let deny_only = IpFilter { deny: Cidr::parse_list("192.168.0.0/16").unwrap(), ..IpFilter::default() };
+        assert!(deny_only.allows(ip("10.1.2.3")));
+        assert!(!deny_only.allows(ip("192.168.0.1")));
+        assert!(deny_only.allows(None));
+    }
+}
@@ -0,0 +1,405 @@
+use hyper::{ HeaderMap, Request, Response, Uri };
+use hyper::header::{ AUTHORIZATION, HeaderName, HeaderValue, WWW_AUTHENTICATE };
+use lazy_static::lazy_static;
+use ring::{ hmac, signature };
+use serde::Deserialize;
+use serde_json::{ Map, Value };
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use tokio::time;
+use crate::body::{ Body };
+use crate::client::{ HttpsClient };
+use crate::errors::{ Error };
+use crate::options::{ TlsOptions };
+use crate::proxy;
+
+/// How long we use the keys fetched from a JWKS URL before fetching them again:
+const JWKS_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+/// Tokens signed by a key we haven't seen make us fetch keys again (in case
+/// they've been rotated), but no more often than this:
+const JWKS_MIN_AGE: Duration = Duration::from_secs(30);
+/// How long we wait for a JWKS URL to respond:
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// Keys fetched from each JWKS URL, shared by all routes that use it:
+    static ref JWKS: Mutex<HashMap<String, (Instant, Vec<Jwk>)>> = Mutex::new(HashMap::new());
+}
+
+/// Require requests to a route to carry a valid JWT as a bearer token.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct JwtOptions {
+    /// How token signatures are checked. If not set, tokens aren't needed:
+    pub key: Option<JwtKey>,
+    /// Claims that tokens must have (with one of these values if the claim
+    /// is a list), or we respond with a 403:
+    pub required_claims: Vec<(String, String)>,
+    /// Claims to hand to destinations in these headers:
+    pub claim_headers: Vec<(String, HeaderName)>
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum JwtKey {
+    /// Tokens are signed with HS256 using this secret:
+    Secret(String),
+    /// Tokens are signed with RS256 using one of the keys at this URL:
+    Jwks(Uri)
+}
+
+impl JwtKey {
+    pub fn jwks(input: &str) -> Result<JwtKey, Error> {
+        let uri: Uri = input.parse().map_err(|_| err!("'{}' is not a valid URL", input))?;
+        match uri.scheme_str() {
+            Some("http") | Some("https") => Ok(JwtKey::Jwks(uri)),
+            _ => Err(err!("Expecting an http:// or https:// URL but got '{}'", input))
+        }
+    }
+}
+
+/// Parse something like `sub:X-User`, for passing claims on in headers:
+pub fn parse_claim_header(input: &str) -> Result<(String, HeaderName), Error> {
+    let (claim, header) = split_claim(input)?;
+    let header = HeaderName::from_bytes(header.as_bytes())
+        .map_err(|_| err!("'{}' is not a valid header name", header))?;
+    Ok((claim, header))
+}
+
+/// Parse something like `role:admin`, for claims that tokens must have:
+pub fn parse_required_claim(input: &str) -> Result<(String, String), Error> {
+    split_claim(input)
+}
+
+fn split_claim(input: &str) -> Result<(String, String), Error> {
+    let idx = input.find(':').ok_or_else(|| err!("Expecting something like 'claim:value' but got '{}'", input))?;
+    let (claim, value) = (input[..idx].trim(), input[idx+1..].trim());
+    if claim.is_empty() || value.is_empty() {
+        return Err(err!("Expecting something like 'claim:value' but got '{}'", input));
+    }
+    Ok((claim.to_owned(), value.to_owned()))
+}
+
+/// Check the bearer token on a request, if the route asks for one, and add
+/// headers for any claims that should be passed on. If the token isn't good
+/// enough, this hands back a response to send instead.
+pub async fn authenticate(req: &mut Request<Body>, options: &JwtOptions, client: &HttpsClient, tls: &TlsOptions) -> Result<Option<Response<Body>>, Error> {
+    let key = match &options.key {
+        Some(key) => key,
+        None => return Ok(None)
+    };
+
+    // Clients mustn't be able to pretend that they have claims:
+    for (_, header) in &options.claim_headers {
+        req.headers_mut().remove(header);
+    }
+
+    let token = match Token::from_headers(req.headers()) {
+        Ok(token) => token,
+        Err(rejection) => return Ok(Some(rejection.into_response()))
+    };
+    let verified = match key {
+        JwtKey::Secret(secret) => token.verify_secret(secret.as_bytes()),
+        JwtKey::Jwks(uri) => {
+            let keys = jwks_keys(uri, token.kid.as_deref(), client, tls).await?;
+            token.verify_jwks(&keys)
+        }
+    };
+    if let Err(rejection) = verified.and_then(|()| options.check_claims(&token.claims, unix_now())) {
+        return Ok(Some(rejection.into_response()));
+    }
+
+    for (name, value) in options.claim_headers(&token.claims) {
+        req.headers_mut().insert(name, value);
+    }
+    Ok(None)
+}
+
+/// Verify a token signed by one of the keys at a JWKS URL (like an OpenID
+/// Connect ID token), handing back its claims if it's valid and current:
+pub async fn verify_jwks_token(token: &str, jwks: &Uri, client: &HttpsClient, tls: &TlsOptions) -> Result<Map<String, Value>, Error> {
+    let token = Token::parse(token).map_err(|r| err!("{}", r))?;
+    let keys = jwks_keys(jwks, token.kid.as_deref(), client, tls).await?;
+    token.verify_jwks(&keys)
+        .and_then(|()| JwtOptions::default().check_claims(&token.claims, unix_now()))
+        .map_err(|r| err!("{}", r))?;
+    Ok(token.claims)
+}
+
+impl JwtOptions {
+    /// Is the token current, and does it have the claims we require?
+    fn check_claims(&self, claims: &Map<String, Value>, now: u64) -> Result<(), Rejection> {
+        match claims.get("exp").map(|exp| exp.as_u64()) {
+            Some(Some(exp)) if exp > now => {},
+            None => {},
+            _ => return Err(Rejection::InvalidToken("the token has expired"))
+        }
+        match claims.get("nbf").map(|nbf| nbf.as_u64()) {
+            Some(Some(nbf)) if nbf <= now => {},
+            None => {},
+            _ => return Err(Rejection::InvalidToken("the token is not valid yet"))
+        }
+        for (claim, expected) in &self.required_claims {
+            let found = match claims.get(claim) {
+                Some(Value::Array(values)) => values.iter().any(|v| claim_to_string(v) == *expected),
+                Some(value) => claim_to_string(value) == *expected,
+                None => false
+            };
+            if !found {
+                return Err(Rejection::Forbidden(format!("the token needs the claim {}:{}", claim, expected)));
+            }
+        }
+        Ok(())
+    }
+
+    fn claim_headers(&self, claims: &Map<String, Value>) -> Vec<(HeaderName, HeaderValue)> {
+        self.claim_headers.iter()
+            .filter_map(|(claim, header)| {
+                let value = HeaderValue::from_str(&claim_to_string(claims.get(claim)?)).ok()?;
+                Some((header.clone(), value))
+            })
+            .collect()
+    }
+}
+
+/// Strings are used as they are, and anything else as JSON:
+fn claim_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string()
+    }
+}
+
+/// A JWT which has been decoded, but not yet verified:
+struct Token {
+    alg: String,
+    kid: Option<String>,
+    claims: Map<String, Value>,
+    signing_input: String,
+    signature: Vec<u8>
+}
+
+#[derive(Deserialize)]
+struct TokenHeader {
+    alg: String,
+    kid: Option<String>
+}
+
+impl Token {
+    fn from_headers(headers: &HeaderMap) -> Result<Token, Rejection> {
+        let value = headers.get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(Rejection::MissingToken)?;
+        let mut parts = value.trim().splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Token::parse(token.trim()),
+            _ => Err(Rejection::MissingToken)
+        }
+    }
+
+    fn parse(token: &str) -> Result<Token, Rejection> {
+        let malformed = || Rejection::InvalidToken("the token is malformed");
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err(malformed());
+        }
+        let header: TokenHeader = decode_json(parts[0]).ok_or_else(malformed)?;
+        let claims: Map<String, Value> = decode_json(parts[1]).ok_or_else(malformed)?;
+        let signature = decode_base64(parts[2]).ok_or_else(malformed)?;
+        Ok(Token {
+            alg: header.alg,
+            kid: header.kid,
+            claims,
+            signing_input: format!("{}.{}", parts[0], parts[1]),
+            signature
+        })
+    }
+
+    fn verify_secret(&self, secret: &[u8]) -> Result<(), Rejection> {
+        if self.alg != "HS256" {
+            return Err(Rejection::InvalidToken("expecting a token signed with HS256"));
+        }
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        hmac::verify(&key, self.signing_input.as_bytes(), &self.signature)
+            .map_err(|_| Rejection::InvalidToken("the token signature is invalid"))
+    }
+
+    fn verify_jwks(&self, keys: &[Jwk]) -> Result<(), Rejection> {
+        if self.alg != "RS256" {
+            return Err(Rejection::InvalidToken("expecting a token signed with RS256"));
+        }
+        let verified = keys.iter()
+            .filter(|key| key.kty == "RSA")
+            .filter(|key| self.kid.is_none() || key.kid == self.kid)
+            .filter_map(|key| Some((decode_base64(key.n.as_ref()?)?, decode_base64(key.e.as_ref()?)?)))
+            .any(|(n, e)| {
+                let key = signature::RsaPublicKeyComponents { n, e };
+                key.verify(&signature::RSA_PKCS1_2048_8192_SHA256, self.signing_input.as_bytes(), &self.signature).is_ok()
+            });
+        if verified { Ok(()) } else { Err(Rejection::InvalidToken("the token signature is invalid")) }
+    }
+}
+
+/// Why we won't let a request through:
+#[derive(Debug,PartialEq)]
+enum Rejection {
+    MissingToken,
+    InvalidToken(&'static str),
+    Forbidden(String)
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Rejection::MissingToken => write!(f, "a bearer token is required"),
+            Rejection::InvalidToken(reason) => write!(f, "{}", reason),
+            Rejection::Forbidden(reason) => write!(f, "{}", reason)
+        }
+    }
+}
+
+impl Rejection {
+    fn into_response(self) -> Response<Body> {
+        let (status, challenge) = match &self {
+            Rejection::MissingToken =>
+                (401, "Bearer realm=\"weave\"".to_owned()),
+            Rejection::InvalidToken(reason) =>
+                (401, format!("Bearer realm=\"weave\", error=\"invalid_token\", error_description=\"{}\"", reason)),
+            Rejection::Forbidden(_) =>
+                (403, "Bearer realm=\"weave\", error=\"insufficient_scope\"".to_owned())
+        };
+        Response::builder()
+            .status(status)
+            .header(WWW_AUTHENTICATE, challenge)
+            .body(Body::from(format!("Weave: Not authorized ({})", self)))
+            .unwrap()
+    }
+}
+
+/// A key from a JWKS URL. We only use RSA ones:
+#[derive(Debug,Clone,Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>
+}
+
+/// The keys at a JWKS URL, fetching them if we haven't recently, or if
+/// we're asked for a key that we don't have:
+async fn jwks_keys(uri: &Uri, kid: Option<&str>, client: &HttpsClient, tls: &TlsOptions) -> Result<Vec<Jwk>, Error> {
+    let cached = JWKS.lock().unwrap().get(&uri.to_string()).cloned();
+    if let Some((fetched, keys)) = cached {
+        let age = fetched.elapsed();
+        let known = match kid {
+            Some(kid) => keys.iter().any(|k| k.kid.as_deref() == Some(kid)),
+            None => true
+        };
+        if age < JWKS_MIN_AGE || (known && age < JWKS_MAX_AGE) {
+            return Ok(keys);
+        }
+    }
+
+    let keys = fetch_jwks(uri, client, tls).await
+        .map_err(|e| err!("Cannot fetch JWKS keys from {}: {}", uri, e))?;
+    JWKS.lock().unwrap().insert(uri.to_string(), (Instant::now(), keys.clone()));
+    Ok(keys)
+}
+
+async fn fetch_jwks(uri: &Uri, client: &HttpsClient, tls: &TlsOptions) -> Result<Vec<Jwk>, Error> {
+    let fetch = async {
+        let res = client.get(uri.clone(), tls).await?;
+        if !res.status().is_success() {
+            return Err(err!("responded with {}", res.status()));
+        }
+        let bytes = proxy::read_body(res.into_body()).await?;
+        let set: JwkSet = serde_json::from_slice(&bytes)?;
+        Ok(set.keys)
+    };
+    time::timeout(JWKS_TIMEOUT, fetch).await
+        .map_err(|_| err!("timed out after {:#?}", JWKS_TIMEOUT))?
+}
+
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    base64::decode_config(input, base64::URL_SAFE_NO_PAD).ok()
+}
+
+fn decode_json<T: serde::de::DeserializeOwned>(input: &str) -> Option<T> {
+    serde_json::from_slice(&decode_base64(input)?).ok()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use serde_json::json;
+
+    fn sign(header: Value, claims: Value, secret: &str) -> String {
+        let encode = |v: &Value| base64::encode_config(&v.to_string(), base64::URL_SAFE_NO_PAD);
+        let input = format!("{}.{}", encode(&header), encode(&claims));
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let signature = hmac::sign(&key, input.as_bytes());
+        format!("{}.{}", input, base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD))
+    }
+
+    #[test]
+    fn verifies_hs256_tokens() {
+        let token = sign(json!({ "alg": "HS256" }), json!({ "sub": "alice" }), "s3cret");
+        let token = Token::parse(&token).unwrap();
+        assert_eq!(token.verify_secret(b"s3cret"), Ok(()));
+        assert!(token.verify_secret(b"wibble").is_err());
+
+        let none = sign(json!({ "alg": "none" }), json!({ "sub": "alice" }), "s3cret");
+        assert!(Token::parse(&none).unwrap().verify_secret(b"s3cret").is_err());
+
+        assert!(Token::parse("not.a.token").is_err());
+        assert!(Token::parse("wibble").is_err());
+    }
+
+    #[test]
+    fn checks_token_claims() {
+        let options = JwtOptions {
+            key: Some(JwtKey::Secret("s3cret".to_owned())),
+            required_claims: vec![("groups".to_owned(), "admin".to_owned())],
+            claim_headers: vec![]
+        };
+        let claims = |v: Value| v.as_object().unwrap().clone();
+
+        assert_eq!(options.check_claims(&claims(json!({ "groups": ["dev", "admin"], "exp": 200, "nbf": 100 })), 150), Ok(()));
+        assert_eq!(options.check_claims(&claims(json!({ "groups": "admin" })), 150), Ok(()));
+        assert!(options.check_claims(&claims(json!({ "groups": ["admin"], "exp": 100 })), 150).is_err());
+        assert!(options.check_claims(&claims(json!({ "groups": ["admin"], "nbf": 200 })), 150).is_err());
+        assert_eq!(
+            options.check_claims(&claims(json!({ "groups": ["dev"] })), 150),
+            Err(Rejection::Forbidden("the token needs the claim groups:admin".to_owned()))
+        );
+    }
+
+    #[test]
+    fn passes_claims_on_as_headers() {
+        let options = JwtOptions {
+            claim_headers: vec![
+                parse_claim_header("sub:X-User").unwrap(),
+                parse_claim_header("admin:X-Admin").unwrap(),
+                parse_claim_header("missing:X-Missing").unwrap()
+            ],
+            ..JwtOptions::default()
+        };
+        let claims = json!({ "sub": "alice", "admin": true });
+        let headers = options.claim_headers(claims.as_object().unwrap());
+        assert_eq!(headers, vec![
+            (HeaderName::from_static("x-user"), HeaderValue::from_static("alice")),
+            (HeaderName::from_static("x-admin"), HeaderValue::from_static("true"))
+        ]);
+        assert!(parse_claim_header("sub").is_err());
+        assert!(parse_claim_header("sub:Bad Header").is_err());
+    }
+}
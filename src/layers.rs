@@ -0,0 +1,156 @@
+use hyper::{ Request, Response };
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ Context, Poll };
+use tower_service::Service;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+
+/// What a `RouteService` hands back. It can borrow from the route for as
+/// long as the request is being handled:
+pub type RouteFuture<'a> = Pin<Box<dyn Future<Output=Result<Response<Body>, Error>> + Send + 'a>>;
+
+/// The handling of a request once it has matched a route, as a
+/// `tower_service::Service`. A stack of these is built for each request;
+/// weave authenticates the request, then passes it through any layers
+/// that have been added, and then on to the route's destination.
+pub type RouteService<'a> = Box<dyn Service<Request<Body>, Response=Response<Body>, Error=Error, Future=RouteFuture<'a>> + Send + 'a>;
+
+/// Something to put between matching a request to a route and sending it
+/// on to the route's destination, like authentication, logging or
+/// transformation. Layers see every route's requests; the `Matched`
+/// extension says which route each one is for. Add them with
+/// `WeaveServerBuilder::layer`.
+pub trait Layer: Send + Sync {
+    /// Wrap the rest of the handling of a request:
+    fn layer<'a>(&self, inner: RouteService<'a>) -> RouteService<'a>;
+}
+
+/// Added to the extensions of requests before they reach any layers.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Matched {
+    /// The route the request matched, like
+    /// `http://localhost:8080/api to http://localhost:9000/`:
+    pub route: String,
+    /// Which of the route's destinations it's going to:
+    pub dest: String
+}
+
+/// The layers added to a server, the first added being outermost.
+#[derive(Clone,Default)]
+pub struct Layers(Vec<Arc<dyn Layer>>);
+
+impl fmt::Debug for Layers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Layers({})", self.0.len())
+    }
+}
+
+impl Layers {
+    pub fn push(&mut self, layer: Arc<dyn Layer>) {
+        self.0.push(layer);
+    }
+
+    /// Wrap a service in each layer, so that the first added sees requests
+    /// first (and responses last):
+    pub fn wrap<'a>(&self, service: RouteService<'a>) -> RouteService<'a> {
+        self.0.iter().rev().fold(service, |service, layer| layer.layer(service))
+    }
+}
+
+/// A service that calls a function with each request, and is always ready
+/// for another. Layers that wrap a service should wait for it to be ready
+/// too, so this is best kept for services that don't:
+pub fn service_fn<'a, F>(f: F) -> RouteService<'a>
+where F: FnMut(Request<Body>) -> RouteFuture<'a> + Send + 'a {
+    Box::new(ServiceFn(f))
+}
+
+struct ServiceFn<F>(F);
+
+impl<'a, F> Service<Request<Body>> for ServiceFn<F>
+where F: FnMut(Request<Body>) -> RouteFuture<'a> {
+    type Response = Response<Body>;
+    type Error = Error;
+    type Future = RouteFuture<'a>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> RouteFuture<'a> {
+        (self.0)(req)
+    }
+}
+
+/// Send a request through a service, once it's ready for it:
+pub async fn call(mut service: RouteService<'_>, req: Request<Body>) -> Result<Response<Body>, Error> {
+    futures::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+    service.call(req).await
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use futures::executor::block_on;
+
+    /// Adds a header to requests and responses, as a user of the library
+    /// might:
+    struct Tag(&'static str);
+
+    struct Tagged<'a> {
+        tag: &'static str,
+        inner: RouteService<'a>
+    }
+
+    impl Layer for Tag {
+        fn layer<'a>(&self, inner: RouteService<'a>) -> RouteService<'a> {
+            Box::new(Tagged { tag: self.0, inner })
+        }
+    }
+
+    impl<'a> Service<Request<Body>> for Tagged<'a> {
+        type Response = Response<Body>;
+        type Error = Error;
+        type Future = RouteFuture<'a>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, mut req: Request<Body>) -> RouteFuture<'a> {
+            req.headers_mut().append("x-seen", self.tag.parse().unwrap());
+            let resp = self.inner.call(req);
+            let tag = self.tag;
+            Box::pin(async move {
+                let mut resp = resp.await?;
+                resp.headers_mut().append("x-seen", tag.parse().unwrap());
+                Ok(resp)
+            })
+        }
+    }
+
+    #[test]
+    fn wraps_services_in_order() {
+        let mut layers = Layers::default();
+        layers.push(Arc::new(Tag("outer")));
+        layers.push(Arc::new(Tag("inner")));
+        let dest = String::from("dest");
+        let service = layers.wrap(service_fn(|req: Request<Body>| {
+            let seen: Vec<String> = req.headers().get_all("x-seen").iter().map(|v| v.to_str().unwrap().to_owned()).collect();
+            let dest = &dest;
+            Box::pin(async move {
+                Ok(Response::new(Body::from(format!("{} saw {}", dest, seen.join(",")))))
+            })
+        }));
+
+        let resp = block_on(call(service, Request::new(Body::empty()))).unwrap();
+        let seen: Vec<&str> = resp.headers().get_all("x-seen").iter().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(seen, vec!["inner", "outer"]);
+        let body = block_on(crate::proxy::read_body(resp.into_body())).unwrap();
+        assert_eq!(body, b"dest saw outer,inner");
+    }
+}
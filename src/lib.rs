@@ -0,0 +1,722 @@
+//! Weave routes requests to files, other servers and commands, given routes
+//! like `8080/api to 9000/v1`. Most use it from the command line, but the
+//! same router and proxy can be embedded in other programs with
+//! `WeaveServer`:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), stargate::Error> {
+//! let server = stargate::WeaveServer::builder()
+//!     .parse_routes("8080/api to 9000 and 8080 to ./public")?
+//!     .start()?;
+//! println!("Listening on {:?}", server.addrs());
+//! server.shutdown();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Tests can use `WeaveServer::spawn` instead, to serve routes on free
+//! ports and find out which.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use hyper::{Request, Response};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, REFERER, SET_COOKIE, USER_AGENT};
+use log::{info, warn};
+use tokio::time::sleep;
+use std::result::Result::{Ok, Err};
+use location::ResolvedLocation;
+use url::Url;
+use ansi_term::Color::{Green, Purple, Red, Yellow};
+
+#[macro_use]
+pub mod errors;
+mod body;
+mod location;
+pub mod routes;
+pub mod logging;
+pub mod log_file;
+pub mod syslog;
+mod matcher;
+mod graphql;
+mod grpc;
+pub mod settings;
+mod files;
+pub mod config;
+mod options;
+mod health;
+mod consul;
+mod srv;
+mod proxy;
+mod breaker;
+mod connector;
+mod client;
+mod tls;
+mod acceptor;
+mod acme;
+mod headers;
+mod exec;
+mod listeners;
+mod auth;
+mod jwt;
+mod forward_auth;
+mod oidc;
+mod api_keys;
+mod ip_filter;
+mod rate_limit;
+mod concurrency;
+mod delay;
+mod chaos;
+mod mirror;
+mod diff;
+mod sticky;
+mod cache;
+mod compress;
+mod cors;
+mod error_pages;
+pub mod maintenance;
+mod disk_cache;
+mod replace;
+mod transform;
+mod plugins;
+mod scripts;
+mod rewrite;
+mod security;
+mod stats;
+mod control;
+pub mod admin;
+pub mod docker;
+pub mod metrics;
+mod statsd;
+mod trace;
+mod capture;
+mod har;
+mod cassette;
+mod curl;
+pub mod replay;
+pub mod check;
+pub mod import;
+mod request_id;
+mod server;
+mod layers;
+
+pub use body::Body;
+pub use errors::Error;
+pub use layers::{Layer, Matched, RouteFuture, RouteService, service_fn};
+pub use listeners::ListenAddr;
+pub use location::{SrcLocation, DestLocation};
+pub use matcher::Matcher;
+pub use options::RouteOptions;
+pub use routes::Route;
+pub use server::{WeaveServer, WeaveServerBuilder};
+pub use settings::Settings;
+
+use matcher::Resolved;
+use concurrency::ConcurrencyLimit;
+use ip_filter::IpFilter;
+use client::HttpsClient;
+use request_id::RequestId;
+
+/// Handle a single request, given a matcher that defines how to map from input to output:
+pub(crate) async fn handle_request<'a>(mut req: Request<Body>, socket_addr: Arc<ListenAddr>, remote_addr: Option<SocketAddr>, matcher: Arc<Matcher>, client: HttpsClient, settings: Arc<Settings>, concurrency: Option<Arc<ConcurrencyLimit>>) -> Response<Body> {
+    let before_time = std::time::Instant::now();
+    // Give the request an ID, or use the client's, to tie our logs to the
+    // destination's. It's sent on, and back to the client:
+    let request_id = if settings.request_ids { Some(RequestId::for_request(req.headers())) } else { None };
+    if let Some(id) = &request_id {
+        id.insert(req.headers_mut());
+        req.extensions_mut().insert(id.clone());
+    }
+    // Routes can match on the GraphQL operation a request is for, which
+    // may mean reading its body before anything else:
+    if matcher.sniffs_graphql() {
+        req = match graphql::sniff(req).await {
+            Ok(req) => req,
+            Err(e) => return error_pages::error(400, format!("Failed to read the request body: {}", e))
+        };
+    }
+    // Cross-origin requests get CORS headers on whatever we respond with.
+    // We answer preflight requests ourselves, before any authentication,
+    // since browsers don't send credentials with them:
+    let cors = cors::policy(&req, &matcher, &settings);
+    if let Some(cors) = &cors {
+        if cors::is_preflight(&req) {
+            let mut resp = cors::preflight(&req, cors);
+            if let Some(id) = &request_id {
+                id.insert(resp.headers_mut());
+            }
+            let preflight_string = format!("[{}] {} (CORS preflight) in {:#?}", resp.status().as_str(), src_path(&socket_addr, &req), before_time.elapsed());
+            info!(target: logging::REQUESTS, "{}", Green.paint(preflight_string));
+            logging::access(&logging::AccessEntry {
+                time: std::time::SystemTime::now(),
+                method: req.method().as_str(),
+                path: req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/"),
+                version: &format!("{:?}", req.version()),
+                user: None,
+                referer: req.headers().get(REFERER).and_then(|v| v.to_str().ok()),
+                user_agent: req.headers().get(USER_AGENT).and_then(|v| v.to_str().ok()),
+                route: None,
+                dest: None,
+                status: resp.status().as_u16(),
+                bytes: Some(0),
+                duration: before_time.elapsed(),
+                client: remote_addr.map(|addr| addr.ip()),
+                request_id: request_id.as_ref().map(|id| id.as_str())
+            });
+            return resp;
+        }
+    }
+    let origin = cors::origin(&req).to_owned();
+    // Our own 404 and 5xx responses can be swapped for an error page:
+    let error_page = error_pages::for_request(&req, &matcher, &settings);
+    let path = req.uri().path().to_owned();
+    let path_and_query = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/").to_owned();
+    let version = format!("{:?}", req.version());
+    let user = auth::username(req.headers());
+    let referer = req.headers().get(REFERER).and_then(|v| v.to_str().ok()).map(|v| v.to_owned());
+    let user_agent = req.headers().get(USER_AGENT).and_then(|v| v.to_str().ok()).map(|v| v.to_owned());
+    let is_grpc = grpc::is_grpc(&req);
+    // Count the request against the route it's for, if any:
+    let route = matcher.route_for(&req, req.method());
+    let route_name = route.map(stats::route_name);
+    let in_flight = route.map(|route| stats::for_route(route).start());
+    let timer = metrics::start(route_name.as_deref());
+    let method = req.method().to_string();
+    let statsd = settings.statsd.clone();
+    // Trace the request, and tell destinations which trace it's part of:
+    let tracer = settings.tracer.clone();
+    let start_time = std::time::SystemTime::now();
+    // Record the request as the client made it, if we're making a HAR file:
+    let recording = settings.har.as_ref().map(|har| har.start(&mut req));
+    let trace_context = tracer.as_ref().map(|_| trace::TraceContext::for_request(req.headers()));
+    if let Some(context) = &trace_context {
+        req.headers_mut().insert(trace::TRACEPARENT, context.traceparent());
+    }
+
+    let mut resp = route_request(req, socket_addr, remote_addr, matcher, client, settings, concurrency).await;
+    if resp.status().is_client_error() || resp.status().is_server_error() {
+        let message = resp.extensions().get::<error_pages::ErrorMessage>().map(|m| m.0.clone());
+        stats::record_error(route_name.clone(), method.clone(), path.clone(), resp.status().as_u16(), message);
+    }
+    // gRPC clients want to hear about errors in gRPC's own terms:
+    if is_grpc {
+        resp = grpc::response(resp);
+    } else if let Some(error_page) = &error_page {
+        resp = error_pages::apply(resp, error_page, &path);
+    }
+    if let Some(cors) = &cors {
+        cors::add_headers(&origin, resp.headers_mut(), cors);
+    }
+    if let Some(id) = &request_id {
+        id.insert(resp.headers_mut());
+    }
+    if let Some(recording) = recording {
+        resp = recording.finish(resp);
+    }
+    if let Some(in_flight) = in_flight {
+        in_flight.finish(resp.status().as_u16());
+    }
+    let dest = resp.extensions().get::<metrics::Destination>().map(|d| d.0.as_str());
+    if let Some(timer) = timer {
+        timer.finish(dest, resp.status().as_u16());
+    }
+    if let Some(statsd) = &statsd {
+        statsd.record(route_name.as_deref(), dest, resp.status().as_u16(), before_time.elapsed());
+    }
+    if let (Some(tracer), Some(context)) = (&tracer, &trace_context) {
+        tracer.record(context, &trace::SpanInfo {
+            method: &method,
+            path: &path,
+            route: route_name.as_deref(),
+            dest,
+            status: resp.status().as_u16(),
+            start: start_time,
+            end: std::time::SystemTime::now()
+        });
+    }
+    logging::access(&logging::AccessEntry {
+        time: std::time::SystemTime::now(),
+        method: &method,
+        path: &path_and_query,
+        version: &version,
+        user: user.as_deref(),
+        referer: referer.as_deref(),
+        user_agent: user_agent.as_deref(),
+        route: route_name.as_deref(),
+        dest,
+        status: resp.status().as_u16(),
+        bytes: resp.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()),
+        duration: before_time.elapsed(),
+        client: remote_addr.map(|addr| addr.ip()),
+        request_id: request_id.as_ref().map(|id| id.as_str())
+    });
+    resp
+}
+
+/// Where a request was made to, and its ID if it has one, for logging:
+fn src_path<T>(socket_addr: &ListenAddr, req: &Request<T>) -> String {
+    // HTTP/2 requests have absolute URIs, so just take the path from them:
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let id = request_id::suffix(req);
+    match socket_addr {
+        ListenAddr::Unix(_) => format!("{}:{}{}", socket_addr, path, id),
+        ListenAddr::Tcp(_) => format!("{}{}{}", socket_addr, path, id)
+    }
+}
+
+async fn route_request(req: Request<Body>, socket_addr: Arc<ListenAddr>, remote_addr: Option<SocketAddr>, matcher: Arc<Matcher>, client: HttpsClient, settings: Arc<Settings>, concurrency: Option<Arc<ConcurrencyLimit>>) -> Response<Body> {
+    let before_time = std::time::Instant::now();
+    let src_path = src_path(&socket_addr, &req);
+    if let Some(resp) = acme::http_challenge(&req) {
+        info!(target: logging::REQUESTS, "{}", Green.paint(format!("[200] {} (ACME challenge) in {:#?}", src_path, before_time.elapsed())));
+        return resp;
+    }
+    if acceptor::is_misdirected(&req) {
+        let misdirected_string = format!("[421] {} (not the host the connection was made for) in {:#?}", src_path, before_time.elapsed());
+        warn!(target: logging::REQUESTS, "{}", Red.paint(misdirected_string));
+        return Response::builder()
+            .status(421)
+            .body(Body::from("Weave: Misdirected request"))
+            .unwrap()
+    }
+    if req.method() == "PURGE" {
+        return purge(&req, &src_path, remote_addr, &matcher, &settings, before_time);
+    }
+    let resolved = matcher.resolve_with_route(&req);
+
+    match resolved {
+        None => {
+            let duration = before_time.elapsed();
+
+            // Routes matched the path but not the method:
+            let allowed = matcher.allowed_methods(&req);
+            if !allowed.is_empty() {
+                let allowed: Vec<&str> = allowed.iter().map(|m| m.as_str()).collect();
+                let allowed = allowed.join(", ");
+                let not_allowed_string = format!("[405] {} {} (allowed: {}) in {:#?}", req.method(), src_path, allowed, duration);
+                warn!(target: logging::REQUESTS, "{}", Red.paint(not_allowed_string));
+                return Response::builder()
+                    .status(405)
+                    .header("allow", allowed)
+                    .body(Body::from("Weave: Method not allowed"))
+                    .unwrap()
+            }
+
+            let not_found_string = format!("[no matching routes] {} in {:#?}", src_path, duration);
+            warn!(target: logging::REQUESTS, "{}", Red.paint(not_found_string));
+            error_pages::error(404, "No routes matched")
+        }
+        Some(ref resolved) if !resolved.route.options.ip_filter.allows(remote_addr.map(|addr| addr.ip())) => {
+            let duration = before_time.elapsed();
+            let client = match remote_addr {
+                Some(addr) => addr.ip().to_string(),
+                None => "unix socket".to_owned()
+            };
+            let forbidden_string = format!("[403] {} to {} (client {} not allowed) in {:#?}",
+                                           src_path,
+                                           resolved.location,
+                                           client,
+                                           duration);
+            warn!(target: logging::REQUESTS, "{}", Purple.paint(forbidden_string));
+            Response::builder()
+                .status(403)
+                .body(Body::from("Weave: Forbidden"))
+                .unwrap()
+        }
+        Some(ref resolved) if resolved.route.options.maintenance.is_down() => {
+            let duration = before_time.elapsed();
+            let maintenance_string = format!("[503] {} to {} (down for maintenance) in {:#?}",
+                                             src_path,
+                                             resolved.location,
+                                             duration);
+            warn!(target: logging::REQUESTS, "{}", Yellow.paint(maintenance_string));
+            resolved.route.options.maintenance.response(req.uri().path())
+        }
+        Some(ref resolved) if !resolved.route.options.auth.allows(req.headers()) => {
+            let duration = before_time.elapsed();
+            let unauthorized_string = format!("[401] {} to {} (not authenticated) in {:#?}",
+                                              src_path,
+                                              resolved.location,
+                                              duration);
+            warn!(target: logging::REQUESTS, "{}", Red.paint(unauthorized_string));
+            auth::unauthorized()
+        }
+        Some(ref resolved) if !resolved.healthy => {
+            let duration = before_time.elapsed();
+            let unhealthy_string = format!("[503] {} to {} (no healthy destinations) in {:#?}",
+                                           src_path,
+                                           resolved.location,
+                                           duration);
+            warn!(target: logging::REQUESTS, "{}", Red.paint(unhealthy_string));
+            error_pages::error(503, "No healthy destinations")
+        }
+        Some(resolved) => {
+            if let Err(wait) = resolved.limiter.check(&resolved.route.options.rate_limit, req.headers(), remote_addr) {
+                let duration = before_time.elapsed();
+                let limited_string = format!("[429] {} to {} (rate limited) in {:#?}",
+                                             src_path,
+                                             resolved.location,
+                                             duration);
+                warn!(target: logging::REQUESTS, "{}", Yellow.paint(limited_string));
+                // Round up, so clients don't come back too early:
+                let retry_after = wait.as_secs_f64().ceil() as u64;
+                return Response::builder()
+                    .status(429)
+                    .header("retry-after", retry_after.to_string())
+                    .body(Body::from("Weave: Too many requests"))
+                    .unwrap()
+            }
+
+            // Wait for the route, and then weave as a whole, to have room for
+            // another request:
+            let limits: Vec<&ConcurrencyLimit> = resolved.concurrency.into_iter().chain(concurrency.as_deref()).collect();
+            let admitted = concurrency::admit_all(&limits).await;
+            if admitted.is_none() {
+                let duration = before_time.elapsed();
+                let busy_string = format!("[503] {} to {} (too many requests queued) in {:#?}",
+                                          src_path,
+                                          resolved.location,
+                                          duration);
+                warn!(target: logging::REQUESTS, "{}", Red.paint(busy_string));
+                return error_pages::error(503, "Too many requests queued")
+            }
+
+            // Pretend to be slow, if asked to:
+            let delayed = match resolved.route.options.delay {
+                Some(delay) => {
+                    let delay = delay.pick();
+                    sleep(delay).await;
+                    format!(" (delayed {:#?})", delay)
+                },
+                None => String::new()
+            };
+
+            // Fail on purpose, if asked to:
+            if let Some(fault) = resolved.faults.inject(&resolved.route.options.chaos) {
+                let duration = before_time.elapsed();
+                let chaos_string = format!("[{}] {} to {}{} (chaos) in {:#?}",
+                                           fault,
+                                           src_path,
+                                           resolved.location,
+                                           delayed,
+                                           duration);
+                warn!(target: logging::REQUESTS, "{}", Red.paint(chaos_string));
+                return fault.into_response()
+            }
+
+            let dest_path = &resolved.location;
+            match do_handle_request(req, &resolved, remote_addr, &client, &settings).await {
+                Ok(mut resp) => {
+                    resp.extensions_mut().insert(metrics::Destination(resolved.dest.to_string()));
+                    let duration = before_time.elapsed();
+                    let status_code = resp.status().as_u16();
+                    let status_col =
+                        if status_code >= 200 && status_code < 300 { Green } else if status_code >= 300 && status_code < 400 { Yellow } else { Red };
+
+                    let retries = match resp.extensions().get::<proxy::Retries>() {
+                        Some(proxy::Retries(n)) => format!(" ({} retries)", n),
+                        None => String::new()
+                    };
+                    let timed_out = match resp.extensions().get::<proxy::TimedOut>() {
+                        Some(proxy::TimedOut(reason)) => format!(" ({})", reason),
+                        None => String::new()
+                    };
+                    let key_label = match resp.extensions().get::<api_keys::ApiKeyLabel>() {
+                        Some(api_keys::ApiKeyLabel(label)) => format!(" (key {})", label),
+                        None => String::new()
+                    };
+                    let cached = match resp.extensions().get::<cache::CacheStatus>() {
+                        Some(cache::CacheStatus::Hit) => " (cache hit)",
+                        Some(cache::CacheStatus::Miss) => " (cache miss)",
+                        None if resp.extensions().get::<cassette::Replayed>().is_some() => " (replayed)",
+                        None => ""
+                    };
+                    let info_string = format!("[{}] {} to {}{}{}{}{}{} in {:#?}",
+                                              resp.status().as_str(),
+                                              src_path,
+                                              dest_path.to_string(),
+                                              delayed,
+                                              cached,
+                                              retries,
+                                              timed_out,
+                                              key_label,
+                                              duration);
+                    info!(target: logging::REQUESTS, "{}", status_col.paint(info_string));
+                    resp
+                }
+                Err(err) => {
+                    let duration = before_time.elapsed();
+                    let error_string = format!("[500] {} to {}{} ({}) in {:#?}",
+                                               src_path,
+                                               dest_path.to_string(),
+                                               delayed,
+                                               err,
+                                               duration);
+                    warn!(target: logging::REQUESTS, "{}", Red.paint(error_string));
+                    error_pages::error(500, err.to_string())
+                }
+            }
+        }
+    }
+}
+
+/// Drop cached responses, given `PURGE /path`, or `PURGE /path/*` for
+/// everything under a path. Only clients in `--purge-from` can do this.
+fn purge(req: &Request<Body>, src_path: &str, remote_addr: Option<SocketAddr>, matcher: &Matcher, settings: &Settings, before_time: std::time::Instant) -> Response<Body> {
+    let filter = IpFilter { allow: settings.purge_from.clone(), deny: vec![] };
+    // Clients connected over a Unix socket are local:
+    let allowed = match remote_addr {
+        Some(addr) => filter.allows(Some(addr.ip())),
+        None => true
+    };
+    if !allowed {
+        let forbidden_string = format!("[403] PURGE {} (client not allowed) in {:#?}", src_path, before_time.elapsed());
+        warn!(target: logging::REQUESTS, "{}", Purple.paint(forbidden_string));
+        return Response::builder()
+            .status(403)
+            .body(Body::from("Weave: Forbidden"))
+            .unwrap()
+    }
+
+    // Work out where the path (or prefix) would be proxied to:
+    let path = req.uri().path();
+    let prefix = path.ends_with('*');
+    let uri = if prefix { path.trim_end_matches('*').to_owned() } else { req.uri().to_string() };
+    let mut lookup = Request::get(uri.as_str()).body(()).unwrap();
+    *lookup.headers_mut() = req.headers().clone();
+    let urls: Vec<Url> = match matcher.resolve_each_dest(&lookup) {
+        Some((route, locations)) if route.options.cache.enabled => {
+            locations.into_iter().filter_map(|location| match location {
+                ResolvedLocation::Url(url) => Some(url),
+                _ => None
+            }).collect()
+        },
+        _ => {
+            let not_found_string = format!("[no cached routes] PURGE {} in {:#?}", src_path, before_time.elapsed());
+            warn!(target: logging::REQUESTS, "{}", Red.paint(not_found_string));
+            return Response::builder()
+                .status(404)
+                .body(Body::from("Weave: No cached routes matched"))
+                .unwrap()
+        }
+    };
+
+    let purged = cache::purge(&urls, prefix);
+    let purged_string = format!("[200] PURGE {} ({} cached responses purged) in {:#?}", src_path, purged, before_time.elapsed());
+    info!(target: logging::REQUESTS, "{}", Green.paint(purged_string));
+    Response::builder()
+        .status(200)
+        .body(Body::from(format!("Weave: Purged {} cached responses", purged)))
+        .unwrap()
+}
+
+/// Authenticate a request to a route, and then pass it through any layers
+/// we've been given on its way to the route's destination:
+async fn do_handle_request(mut req: Request<Body>, resolved: &Resolved<'_>, remote_addr: Option<SocketAddr>, client: &HttpsClient, settings: &Settings) -> Result<Response<Body>, Error> {
+    let route = resolved.route;
+    let key_label = match api_keys::authenticate(req.headers(), &route.options.api_keys)? {
+        Ok(label) => label,
+        Err(resp) => return Ok(resp)
+    };
+    if let Some(resp) = jwt::authenticate(&mut req, &route.options.jwt, client, &route.options.tls).await? {
+        return Ok(resp);
+    }
+    if let Some(resp) = oidc::authenticate(&mut req, &route.options.oidc, client, &route.options.tls).await? {
+        return Ok(resp);
+    }
+    if let Some(resp) = forward_auth::authenticate(&mut req, &route.options.forward_auth, remote_addr, client, &route.options.tls).await? {
+        return Ok(resp);
+    }
+
+    req.extensions_mut().insert(layers::Matched { route: stats::route_name(route), dest: resolved.dest.to_string() });
+    let service = layers::service_fn(move |req| Box::pin(dispatch(req, resolved, remote_addr, client, settings)));
+    let mut resp = layers::call(settings.layers.wrap(service), req).await?;
+    if let Some(label) = key_label {
+        resp.extensions_mut().insert(label);
+    }
+    Ok(resp)
+}
+
+/// Send a request on to the destination picked for it:
+async fn dispatch(req: Request<Body>, resolved: &Resolved<'_>, remote_addr: Option<SocketAddr>, client: &HttpsClient, settings: &Settings) -> Result<Response<Body>, Error> {
+    let route = resolved.route;
+    let (mut req, redirected) = match scripts::on_request(req, &resolved.location, &route.options.script)? {
+        scripts::Outcome::Send(req, url) => (req, url.map(ResolvedLocation::Url)),
+        scripts::Outcome::Reject(resp) => return Ok(resp)
+    };
+    let location = redirected.as_ref().unwrap_or(&resolved.location);
+    if let ResolvedLocation::Url(_) = location {
+        proxy::use_http1(&mut req);
+        if settings.forwarded_headers {
+            proxy::add_forwarded_headers(&mut req, remote_addr);
+        }
+    }
+    route.options.headers.apply_to_request(req.headers_mut());
+    let req = transform::request(req, &route.options.transform).await?;
+    let mut req = match plugins::filter_request(req, &route.options.plugins).await? {
+        Ok(req) => req,
+        Err(resp) => return Ok(resp)
+    };
+    let encoding = compress::negotiate(&req, &route.options.compress);
+    let method = req.method().clone();
+
+    let mut resp = match location {
+        // Proxy to the URI our request matched against:
+        ResolvedLocation::Url(url) => {
+            let rewrites = route.options.rewrite_location || route.options.cookies.is_enabled();
+            let url_mapping = if rewrites { Some(rewrite::UrlMapping::new(&req, url)) } else { None };
+            let mut cache_req = cache::CacheRequest::new(&req, url, &route.options.cache);
+            let cached = match &mut cache_req {
+                Some(cache_req) => cache_req.cached(settings.cache_size).await,
+                None => None
+            };
+            let mut resp = match cached {
+                Some(resp) => resp,
+                None => {
+                    if route.options.decompress {
+                        compress::accept_compressed(&mut req);
+                    }
+                    let mut resp = proxy_or_replay(req, resolved, url, client, settings).await?;
+                    if route.options.decompress {
+                        resp = compress::decompress(resp, &method);
+                    }
+                    match cache_req {
+                        Some(cache_req) => cache_req.store(resp, settings.cache_size),
+                        None => resp
+                    }
+                }
+            };
+            if let Some(url_mapping) = &url_mapping {
+                if route.options.rewrite_location {
+                    rewrite::rewrite_location(&mut resp, url, url_mapping);
+                }
+                if route.options.cookies.is_enabled() {
+                    rewrite::rewrite_cookies(&mut resp, url_mapping, &route.options.cookies);
+                }
+            }
+            resp
+        }
+        // Proxy to the filesystem:
+        ResolvedLocation::FilePath { path, root } => {
+            // Single page apps want the root index file for any path that doesn't exist:
+            let mut path = path.as_path();
+            if route.options.spa && !files::exists(path).await {
+                path = root.as_path();
+            }
+            files::serve(&req, path, settings, &route.options).await
+        }
+        // Redirect to the URL our request matched against:
+        ResolvedLocation::Redirect { url, status } => {
+            Response::builder()
+                .status(*status)
+                .header("location", url.as_str())
+                .body(Body::empty())
+                .unwrap()
+        }
+        // Send the client to the same place over HTTPS:
+        ResolvedLocation::UpgradeHttps { port } => {
+            upgrade_https(&req, *port)
+        }
+        // Run a command and hand back its output:
+        ResolvedLocation::Exec { command, path_info } => {
+            let timeout = route.options.timeout.unwrap_or(exec::DEFAULT_EXEC_TIMEOUT);
+            exec::exec(&req, command, path_info, timeout, settings.max_exec).await?
+        }
+        // Hand back a fixed response:
+        ResolvedLocation::Fixed(fixed) => {
+            let mut resp = Response::builder()
+                .status(fixed.status)
+                .body(Body::from(fixed.body.clone()))
+                .unwrap();
+            for (name, value) in &fixed.headers {
+                resp.headers_mut().append(name, value.clone());
+            }
+            resp
+        }
+    };
+
+    route.options.security.apply(resp.headers_mut(), settings.security_headers);
+    route.options.headers.apply_to_response(resp.headers_mut());
+    let resp = replace::replace(resp, &method, &route.options.replace).await?;
+    let resp = transform::response(resp, &method, &route.options.transform).await?;
+    let resp = plugins::filter_response(resp, &method, &route.options.plugins).await?;
+    let resp = scripts::on_response(resp, &route.options.script)?;
+    let mut resp = compress::compress(resp, encoding, &route.options.compress);
+    if let Some(cookie) = &resolved.sticky_cookie {
+        resp.headers_mut().append(SET_COOKIE, HeaderValue::from_str(cookie).unwrap());
+    }
+    Ok(resp)
+}
+
+/// Redirect to the HTTPS version of the URL requested. We use a 308 so
+/// that clients make the same request again, rather than turning a POST
+/// into a GET (as they may for a 301).
+fn upgrade_https(req: &Request<Body>, port: Option<u16>) -> Response<Body> {
+    let host = match matcher::request_host(req) {
+        Some(host) => host,
+        None => {
+            return Response::builder()
+                .status(400)
+                .body(Body::from("Weave: Can't redirect to HTTPS without a Host header"))
+                .unwrap()
+        }
+    };
+    let port = match port {
+        Some(port) if port != 443 => format!(":{}", port),
+        _ => String::new()
+    };
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    Response::builder()
+        .status(308)
+        .header("location", format!("https://{}{}{}", host, port, path))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Send a request on to a URL destination, unless we can answer it from
+/// the cassette:
+async fn proxy_or_replay(req: Request<Body>, resolved: &Resolved<'_>, url: &Url, client: &HttpsClient, settings: &Settings) -> Result<Response<Body>, Error> {
+    let (req, tape) = match &settings.cassette {
+        Some(cassette) => match cassette.lookup(req, url).await? {
+            cassette::Lookup::Answered(resp) => return Ok(resp),
+            cassette::Lookup::Send(req, tape) => (req, Some(tape))
+        },
+        None => (req, None)
+    };
+    let options = &resolved.route.options;
+    let req = mirror::mirror(req, url, options, client).await?;
+    let (req, shadowed) = diff::shadow(req, url, options, client).await?;
+    let (req, capture) = capture::request(req, url, options, settings);
+    let req = curl::request(req, url, options, settings);
+    let mut resp = proxy_with_breaker(req, resolved, url, client).await?;
+    if let Some(capture) = capture {
+        resp = capture.response(resp);
+    }
+    if let Some(shadowed) = shadowed {
+        resp = shadowed.compare(resp);
+    }
+    match tape {
+        Some(tape) => tape.record(resp).await,
+        None => Ok(resp)
+    }
+}
+
+async fn proxy_with_breaker(req: Request<Body>, resolved: &Resolved<'_>, url: &Url, client: &HttpsClient) -> Result<Response<Body>, Error> {
+    let options = &resolved.route.options;
+    if options.breaker_failures == 0 {
+        return proxy::proxy(req, url, options, client).await
+    }
+
+    // Fail fast if this destination has been failing:
+    let breaker = resolved.breaker;
+    let name = resolved.dest.to_string();
+    if !breaker.allow() {
+        return Ok(error_pages::error(503, format!("Circuit breaker open for {}", name)))
+    }
+
+    let res = proxy::proxy(req, url, options, client).await;
+    match &res {
+        Ok(resp) if !resp.status().is_server_error() => breaker.record_success(&name),
+        _ => breaker.record_failure(&name, options.breaker_failures, options.breaker_cooldown)
+    }
+    res
+}
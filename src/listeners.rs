@@ -0,0 +1,529 @@
+use arc_swap::{ ArcSwap, ArcSwapOption };
+use futures::channel::oneshot;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::Duration;
+use hyper::{ Request, Response };
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{ TokioExecutor, TokioIo };
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use log::{ debug, info, warn, error };
+use tokio::io::{ AsyncRead, AsyncWrite };
+use tokio::net::{ TcpListener, TcpStream };
+#[cfg(unix)]
+use tokio::net::{ UnixListener, UnixStream };
+use tokio::time::{ self, sleep };
+use tokio_rustls::TlsAcceptor;
+use crate::acceptor::{ self, TlsConfig, TlsConnection, HANDSHAKE_TIMEOUT };
+use crate::acme;
+use crate::body::{ Body };
+use crate::chaos;
+use crate::concurrency::{ ConcurrencyLimit };
+use crate::consul;
+use crate::errors::{ Error };
+use crate::health;
+use crate::matcher::{ LocalAddr, Matcher };
+use crate::options::{ ListenerProtocol };
+use crate::routes::{ Route };
+use crate::srv;
+use crate::settings::{ Settings };
+use crate::HttpsClient;
+
+/// The listeners we're currently serving requests on, keyed by the
+/// address they're bound to. Updating the routes swaps a new
+/// `Matcher` into each existing listener, starts listeners for new
+/// socket addresses and shuts down those that no longer have routes.
+pub struct Listeners {
+    client: HttpsClient,
+    settings: Arc<Settings>,
+    /// How many requests can be handled at once, across all listeners:
+    concurrency: Option<Arc<ConcurrencyLimit>>,
+    /// Listen on a free port in place of each one asked for, so that
+    /// servers for tests can run at once:
+    free_ports: bool,
+    running: HashMap<ListenAddr, Listener>
+}
+
+/// Where a listener accepts connections; a TCP socket address, or the
+/// path to a Unix socket:
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf)
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => addr.fmt(f),
+            ListenAddr::Unix(path) => write!(f, "unix://{}", path.display())
+        }
+    }
+}
+
+struct Listener {
+    /// Where we're really listening, which differs from the address asked
+    /// for when that has port 0, or we're using free ports:
+    local_addr: ListenAddr,
+    matcher: Arc<ArcSwap<Matcher>>,
+    protocol: ListenerProtocol,
+    /// How to accept TLS connections, if the routes are for `https://` sources:
+    tls: Arc<ArcSwapOption<TlsConfig>>,
+    shutdown: oneshot::Sender<()>
+}
+
+impl Listeners {
+    pub fn new(client: HttpsClient, settings: Arc<Settings>) -> Listeners {
+        let concurrency = settings.max_concurrent
+            .map(|max| Arc::new(ConcurrencyLimit::new(max, settings.max_queued)));
+        Listeners {
+            client,
+            settings,
+            concurrency,
+            free_ports: false,
+            running: HashMap::new()
+        }
+    }
+
+    /// Listen on free ports rather than those asked for, from now on:
+    pub fn use_free_ports(&mut self) {
+        self.free_ports = true;
+    }
+
+    /// Serve the routes provided, replacing any we were serving before.
+    /// Errors if we can't work out where to serve the routes, in which
+    /// case nothing is changed, or if any new listener fails to start.
+    pub fn update(&mut self, routes: Vec<Route>) -> Result<(), Error> {
+        // Partition provided routes based on the address we'll serve them on:
+        let mut by_addr = vec![];
+        for route in routes {
+            route.options.oidc.check().map_err(|e| err!("Invalid options for {}: {}", route.src, e))?;
+            by_addr.push((route.listen_addr()?, route));
+        }
+        let addrs: Vec<ListenAddr> = by_addr.iter().map(|(addr, _)| addr.clone()).collect();
+        let mut map = HashMap::new();
+        for (listen_addr, route) in by_addr {
+            let listen_addr = shared_addr(&listen_addr, &addrs).unwrap_or(listen_addr);
+            let rs: &mut Vec<Route> = map.entry(listen_addr).or_default();
+            rs.push(route);
+        }
+        let mut protocols = HashMap::new();
+        let mut tls_configs = HashMap::new();
+        for (listen_addr, routes) in &map {
+            let protocol = listener_protocol(listen_addr, routes)?;
+            tls_configs.insert(listen_addr.clone(), acceptor::server_config(listen_addr, routes, protocol, &self.settings)?);
+            protocols.insert(listen_addr.clone(), protocol);
+        }
+
+        // Prepare clients for routes that make their own TLS connections:
+        self.client.use_tls_options(map.values().flatten().map(|route| &route.options.tls))?;
+
+        // Shut down listeners that no longer have any routes:
+        let stale: Vec<ListenAddr> = self.running.keys()
+            .filter(|addr| !map.contains_key(addr))
+            .cloned()
+            .collect();
+        for listen_addr in stale {
+            if let Some(listener) = self.running.remove(&listen_addr) {
+                info!("Stopping listener on {}", listen_addr);
+                let _ = listener.shutdown.send(());
+            }
+        }
+
+        // Swap the new routes into existing listeners, or start new ones:
+        let mut errors = vec![];
+        for (listen_addr, routes) in map {
+            let protocol = protocols[&listen_addr];
+            let tls = tls_configs.remove(&listen_addr).unwrap_or_default();
+            let matcher = Matcher::new(routes);
+            health::spawn_checks(&matcher, &self.client);
+            consul::spawn_lookups(&matcher, &self.client, &self.settings.consul);
+            srv::spawn_lookups(&matcher);
+            if let (Some(tls), Some(options)) = (&tls, &self.settings.acme) {
+                acme::spawn_orders(tls.acme_hosts(), &self.client, options);
+            }
+            if let Some(listener) = self.running.get(&listen_addr) {
+                if listener.protocol != protocol {
+                    warn!("The listener protocol for {} can't be changed without restarting", listen_addr);
+                }
+                listener.matcher.store(Arc::new(matcher));
+                listener.tls.store(tls);
+                continue;
+            }
+            match self.start(&listen_addr, matcher, protocol, tls) {
+                Ok(listener) => { self.running.insert(listen_addr, listener); },
+                Err(e) => errors.push(format!("{}: {}", listen_addr, e))
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(err!("Failed to listen on {}", errors.join(", ")))
+        }
+    }
+
+    /// Where we're really listening for requests to an address:
+    pub fn local_addr(&self, listen_addr: &ListenAddr) -> Option<ListenAddr> {
+        let addrs: Vec<ListenAddr> = self.running.keys().cloned().collect();
+        let listen_addr = shared_addr(listen_addr, &addrs).unwrap_or_else(|| listen_addr.clone());
+        self.running.get(&listen_addr).map(|listener| listener.local_addr.clone())
+    }
+
+    fn start(&self, listen_addr: &ListenAddr, matcher: Matcher, protocol: ListenerProtocol, tls: Option<Arc<TlsConfig>>) -> Result<Listener, Error> {
+        let matcher = Arc::new(ArcSwap::from_pointee(matcher));
+        let tls = Arc::new(ArcSwapOption::new(tls));
+        let (shutdown, shutdown_rx) = oneshot::channel();
+
+        let local_addr = match listen_addr {
+            ListenAddr::Tcp(socket_addr) => {
+                let mut socket_addr = *socket_addr;
+                if self.free_ports {
+                    socket_addr.set_port(0);
+                }
+                let listener = bind_tcp(&socket_addr)?;
+                let local_addr = ListenAddr::Tcp(listener.local_addr()?);
+                tokio::spawn(handle_requests(
+                    listener,
+                    protocol,
+                    local_addr.clone(),
+                    Arc::clone(&matcher),
+                    self.client.clone(),
+                    Arc::clone(&self.settings),
+                    self.concurrency.clone(),
+                    Arc::clone(&tls),
+                    shutdown_rx
+                ));
+                local_addr
+            },
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let server = handle_requests(
+                    bind_unix(path)?,
+                    protocol,
+                    listen_addr.clone(),
+                    Arc::clone(&matcher),
+                    self.client.clone(),
+                    Arc::clone(&self.settings),
+                    self.concurrency.clone(),
+                    Arc::clone(&tls),
+                    shutdown_rx
+                );
+                // Tidy up the socket once we stop listening on it:
+                let path = path.clone();
+                tokio::spawn(async move {
+                    server.await;
+                    let _ = std::fs::remove_file(path);
+                });
+                listen_addr.clone()
+            },
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => {
+                return Err(err!("Unix sockets are not supported on this platform"));
+            }
+        };
+
+        Ok(Listener {
+            local_addr,
+            matcher,
+            protocol,
+            tls,
+            shutdown
+        })
+    }
+}
+
+/// Routes for an address on a port that's also listened to on every
+/// interface (like `8080` alongside `api.local:8080`) can't have a listener
+/// of their own, so they share that one. Hands back the address of the
+/// listener to share, if there is one:
+pub(crate) fn shared_addr(listen_addr: &ListenAddr, addrs: &[ListenAddr]) -> Option<ListenAddr> {
+    let addr = match listen_addr {
+        ListenAddr::Tcp(addr) if addr.port() != 0 && !addr.ip().is_unspecified() => addr,
+        _ => return None
+    };
+    addrs.iter().find(|other| match other {
+        ListenAddr::Tcp(other) => {
+            other.port() == addr.port() && other.ip().is_unspecified() && (other.is_ipv6() || addr.is_ipv4())
+        },
+        _ => false
+    }).cloned()
+}
+
+/// Work out which protocol a listener should speak from the routes served
+/// on it, complaining if they disagree:
+pub(crate) fn listener_protocol(listen_addr: &ListenAddr, routes: &[Route]) -> Result<ListenerProtocol, Error> {
+    let mut protocol = None;
+    for route in routes {
+        match (protocol, route.options.listener_protocol) {
+            (Some(a), Some(b)) if a != b => {
+                return Err(err!("Routes on {} ask for different listener protocols ({:?} and {:?})", listen_addr, a, b));
+            },
+            (None, Some(b)) => protocol = Some(b),
+            _ => {}
+        }
+    }
+    Ok(protocol.unwrap_or_default())
+}
+
+/// Only speak the versions of HTTP asked for. By default, hyper speaks
+/// HTTP/1.1 and switches to HTTP/2 if a connection starts with it:
+fn builder(protocol: ListenerProtocol) -> auto::Builder<TokioExecutor> {
+    let builder = auto::Builder::new(TokioExecutor::new());
+    match protocol {
+        ListenerProtocol::Auto => builder,
+        ListenerProtocol::Http1 => builder.http1_only(),
+        ListenerProtocol::Http2 => builder.http2_only()
+    }
+}
+
+/// Start listening on a TCP address. This needs doing from a tokio runtime:
+fn bind_tcp(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Start listening on a Unix socket, replacing any socket left behind by
+/// a previous run (we only remove sockets, not other files):
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<UnixListener, Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+/// Something we accept connections from:
+trait Listen {
+    type Conn: ConnAddrs + AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    async fn accept(&self) -> io::Result<Self::Conn>;
+}
+
+impl Listen for TcpListener {
+    type Conn = TcpStream;
+
+    async fn accept(&self) -> io::Result<TcpStream> {
+        Ok(TcpListener::accept(self).await?.0)
+    }
+}
+
+#[cfg(unix)]
+impl Listen for UnixListener {
+    type Conn = UnixStream;
+
+    async fn accept(&self) -> io::Result<UnixStream> {
+        Ok(UnixListener::accept(self).await?.0)
+    }
+}
+
+/// Wait for the next connection. Errors with a connection that went away
+/// before we got to it are skipped, and others (like running out of file
+/// descriptors) are waited out, as hyper's own listeners do:
+async fn accept<L: Listen>(listener: &L) -> L::Conn {
+    loop {
+        match listener.accept().await {
+            Ok(conn) => return conn,
+            Err(e) if is_connection_error(&e) => debug!("Accepting a connection failed: {}", e),
+            Err(e) => {
+                error!("Accepting a connection failed: {}", e);
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset)
+}
+
+/// Where a connection is from, to tell destinations about, and which of
+/// our addresses it reached, to match routes on. Connections over Unix
+/// sockets don't have addresses like these:
+trait ConnAddrs {
+    fn remote_socket_addr(&self) -> Option<SocketAddr>;
+    fn local_socket_addr(&self) -> Option<SocketAddr>;
+}
+
+impl ConnAddrs for TcpStream {
+    fn remote_socket_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        self.local_addr().ok()
+    }
+}
+
+#[cfg(unix)]
+impl ConnAddrs for UnixStream {
+    fn remote_socket_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+    fn local_socket_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Handle incoming requests by matching on routes and dispatching as necessary,
+/// until told to shut down. Requests being handled then are finished first.
+#[allow(clippy::too_many_arguments)]
+async fn handle_requests<L: Listen>(
+    listener: L,
+    protocol: ListenerProtocol,
+    listen_addr: ListenAddr,
+    matcher: Arc<ArcSwap<Matcher>>,
+    client: HttpsClient,
+    settings: Arc<Settings>,
+    concurrency: Option<Arc<ConcurrencyLimit>>,
+    tls: Arc<ArcSwapOption<TlsConfig>>,
+    mut shutdown: oneshot::Receiver<()>
+) {
+    let socket_addr = Arc::new(listen_addr);
+    let builder = builder(protocol);
+    let graceful = GracefulShutdown::new();
+
+    loop {
+        let conn = tokio::select! {
+            conn = accept(&listener) => conn,
+            _ = &mut shutdown => break
+        };
+        // Where the connection is from, to tell destinations about:
+        let remote_addr = conn.remote_socket_addr();
+        let local_addr = conn.local_socket_addr();
+        let socket_addr = Arc::clone(&socket_addr);
+        let matcher = Arc::clone(&matcher);
+        let client = client.clone();
+        let settings = Arc::clone(&settings);
+        let concurrency = concurrency.clone();
+        let service = move |tls: Option<TlsConnection>| service_fn(move |req: Request<Incoming>| {
+            let mut req = req.map(Body::from);
+            // Routes for one of our addresses only match requests that reached it:
+            if let Some(local_addr) = local_addr {
+                req.extensions_mut().insert(LocalAddr(local_addr));
+            }
+            if let Some(tls) = &tls {
+                req.extensions_mut().insert(tls.clone());
+            }
+            acceptor::set_client_cert_headers(&mut req);
+            let socket_addr = Arc::clone(&socket_addr);
+            // Use whichever routes are current when the request arrives:
+            let matcher = matcher.load_full();
+            let client = client.clone();
+            let settings = Arc::clone(&settings);
+            let concurrency = concurrency.clone();
+            async move {
+                let resp = crate::handle_request(req, socket_addr, remote_addr, matcher, client, settings, concurrency).await;
+                // Erroring closes the connection without a response:
+                if resp.extensions().get::<chaos::Abort>().is_some() {
+                    return Err(io::Error::other("Aborted on purpose"));
+                }
+                Ok(resp)
+            }
+        });
+        // Handshakes happen away from here, so that slow clients don't
+        // hold up others connecting. Use whichever certificates are current:
+        let tls = tls.load_full();
+        let builder = builder.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let served = match tls {
+                None => watcher.watch(builder.serve_connection(TokioIo::new(conn), service(None)).into_owned()).await,
+                Some(config) => {
+                    let stream = match time::timeout(HANDSHAKE_TIMEOUT, TlsAcceptor::from(Arc::clone(&config.server)).accept(conn)).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => return debug!("TLS handshake failed: {}", e),
+                        Err(_) => return debug!("TLS handshake timed out")
+                    };
+                    // The CA only wanted to see the certificate for its challenge:
+                    if stream.get_ref().1.alpn_protocol() == Some(acme::ACME_TLS_ALPN) {
+                        return;
+                    }
+                    let service = service(Some(TlsConnection::of(&stream, &config)));
+                    watcher.watch(builder.serve_connection(TokioIo::new(stream), service).into_owned()).await
+                }
+            };
+            if let Err(e) = served {
+                debug!("Error serving connection: {}", e);
+            }
+        });
+    }
+
+    // Stop listening, and wait for the requests we're handling:
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+/// Serve requests on a TCP address with the function given, as the admin
+/// API and metrics are, until we exit:
+pub(crate) fn serve<F, R>(addr: &SocketAddr, handle: F) -> io::Result<()>
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output=Response<Body>> + Send + 'static
+{
+    let listener = bind_tcp(addr)?;
+    let builder = builder(ListenerProtocol::Auto);
+    tokio::spawn(async move {
+        loop {
+            let conn = accept(&listener).await;
+            let handle = handle.clone();
+            let service = service_fn(move |req: Request<Incoming>| {
+                let resp = handle(req.map(Body::from));
+                async move { Ok::<_, Infallible>(resp.await) }
+            });
+            let conn = builder.serve_connection(TokioIo::new(conn), service).into_owned();
+            tokio::spawn(async move {
+                if let Err(e) = conn.await {
+                    debug!("Error serving connection: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::routes;
+
+    fn routes(s: &str) -> Vec<Route> {
+        routes::from_args(s.split_whitespace().map(|s| s.to_owned())).unwrap().0
+    }
+
+    #[test]
+    fn works_out_listener_protocols() {
+        let addr = ListenAddr::Unix("/tmp/weave.sock".into());
+        assert_eq!(listener_protocol(&addr, &routes("8080 to 9000")).unwrap(), ListenerProtocol::Auto);
+        assert_eq!(
+            listener_protocol(&addr, &routes("8080/a to 9000 and 8080/b to 9001 with listener-protocol=http2")).unwrap(),
+            ListenerProtocol::Http2
+        );
+        assert!(listener_protocol(&addr, &routes("8080/a to 9000 with listener-protocol=http1 and 8080/b to 9001 with listener-protocol=http2")).is_err());
+    }
+
+    #[test]
+    fn shares_listeners_on_every_interface() {
+        let tcp = |addr: &str| ListenAddr::Tcp(addr.parse().unwrap());
+        let addrs = vec![tcp("0.0.0.0:8080"), tcp("127.0.0.1:8080"), tcp("127.0.0.1:9090"), tcp("[::1]:8080")];
+        assert_eq!(shared_addr(&addrs[1], &addrs), Some(tcp("0.0.0.0:8080")));
+        assert_eq!(shared_addr(&addrs[0], &addrs), None);
+        assert_eq!(shared_addr(&addrs[2], &addrs), None);
+        // An IPv4 wildcard doesn't cover IPv6 addresses, but an IPv6 one does:
+        assert_eq!(shared_addr(&addrs[3], &addrs), None);
+        assert_eq!(shared_addr(&addrs[3], &[tcp("[::]:8080")]), Some(tcp("[::]:8080")));
+    }
+}
@@ -1,11 +1,16 @@
-use url::Url;
+use hyper::Method;
+use hyper::header::{ HeaderName, HeaderValue };
+use url::{ Host, Url };
 use regex::Regex;
 use lazy_static::lazy_static;
+use serde::{ Serialize, Serializer };
 use std::str::FromStr;
 use std::path::{ self, PathBuf };
 use std::fmt;
 use std::borrow::Cow;
 use crate::errors::{ Error };
+use crate::headers;
+use crate::connector;
 
 /// A source location. It should be something that looks a little
 /// like a URL, so that we know what interface and port to listen on, and
@@ -15,21 +20,121 @@ pub struct SrcLocation {
     pub url: Url,
     pub path_regex: Option<Regex>,
     /// Do we want this to be for exact matches only?
-    pub exact: bool
+    pub exact: bool,
+    /// Keep the part of the path that matched when building the destination
+    /// path, rather than stripping it (eg `+8080/api to 9000` routes `/api/foo`
+    /// to `localhost:9000/api/foo` rather than `localhost:9000/foo`):
+    pub preserve_prefix: bool,
+    /// Only match requests for this host (from the `Host` header), if set.
+    /// Domain names other than `localhost` are taken to be virtual hosts
+    /// like this, and we listen on every interface for them, unless an
+    /// address to listen on follows an '@' (eg `api.local@127.0.0.1:8080`),
+    /// in which case that's what the `url` is for:
+    pub host: Option<String>,
+    /// Only match requests with one of these methods (eg `POST:8080/api`).
+    /// If empty, requests with any method match:
+    pub methods: Vec<Method>,
+    /// Only match requests with these query parameters (eg `8080/search?engine=v2`).
+    /// A parameter without a value (eg `?debug`) need only be present:
+    pub query: Vec<(String, Option<String>)>,
+    /// If the path was given as a regular expression (eg `/articles/(\d+)`),
+    /// this is that expression as written. Destinations can then refer to
+    /// its capture groups with `$1`, `$name` and so on.
+    pub pattern: Option<String>,
+    /// Listen on this Unix socket rather than a TCP port, if set
+    /// (eg `unix:///tmp/weave.sock/api`):
+    pub socket: Option<PathBuf>
 }
 
 impl SrcLocation {
     pub fn parse(input: impl AsRef<str>) -> Result<SrcLocation, Error> {
-        // Starts with '=' means exact match. chop off if found.
+        lazy_static!{
+            // Starts with something like 'GET:' or 'PUT,POST:'?
+            static ref METHODS_RE: Regex = Regex::new(r"^([A-Z]+(?:,[A-Z]+)*):([^/]|$)").expect("methods_re");
+        }
         let mut input: &str = input.as_ref();
+
+        // Starts with some methods means only match those. chop off if found.
+        let mut methods = vec![];
+        if let Some(cap) = METHODS_RE.captures(input) {
+            let names = cap.get(1).unwrap();
+            for name in names.as_str().split(',') {
+                methods.push(Method::from_bytes(name.as_bytes()).map_err(|_| {
+                    err!("Invalid method '{}'", name)
+                })?);
+            }
+            input = &input[names.end() + 1..];
+        }
+
+        // Starts with '=' means exact match, and '+' means preserve the
+        // matched prefix. Either order is fine. chop off if found.
         let mut exact = false;
-        if input.starts_with("=") {
+        let mut preserve_prefix = false;
+        loop {
+            if input.starts_with('=') && !exact {
+                exact = true;
+            } else if input.starts_with('+') && !preserve_prefix {
+                preserve_prefix = true;
+            } else {
+                break
+            }
             input = &input[1..];
-            exact = true;
+        }
+
+        // Starts with 'unix://' means listen on a Unix socket. The route's
+        // path follows the socket path, and is parsed as usual from here:
+        let mut socket = None;
+        let unix_input;
+        if let Some(rest) = input.strip_prefix("unix://") {
+            let (socket_path, path) = split_socket_path(rest)?;
+            socket = Some(socket_path);
+            unix_input = format!("localhost{}", path);
+            input = &unix_input;
+        }
+
+        // Starts with a virtual host and an '@' (eg `api.local@127.0.0.1:8080`)
+        // means match that host, but listen on the address that follows:
+        let mut vhost = None;
+        let vhost_input;
+        let (addr, _) = split_path(input);
+        let after_scheme = addr.find("://").map(|idx| idx + 3).unwrap_or(0);
+        if let Some(idx) = addr[after_scheme..].find('@') {
+            let name = &addr[after_scheme..after_scheme + idx];
+            match Host::parse(name) {
+                Ok(Host::Domain(_)) => vhost = Some(name.to_lowercase()),
+                _ => return Err(err!("Expecting a host name before the '@' but got '{}'", name))
+            }
+            vhost_input = format!("{}{}", &input[..after_scheme], &input[after_scheme + idx + 1..]);
+            input = &vhost_input;
+        }
+
+        // Is the path a regular expression? If so, compile it as written,
+        // since parsing it as part of a URL would mangle it:
+        let (addr, path) = split_path(input);
+        if is_regex_path(path) {
+            let url = parse_url(addr)?;
+            let host = vhost.or_else(|| virtual_host(&url));
+            let regex_string = if exact { format!("^{}$", path) } else { format!("^{}", path) };
+            let re = Regex::new(&regex_string).map_err(|e| {
+                err!("Invalid regular expression '{}': {}", path, e)
+            })?;
+            return Ok(SrcLocation {
+                url,
+                path_regex: Some(re),
+                exact,
+                preserve_prefix,
+                host,
+                methods,
+                query: vec![],
+                pattern: Some(path.to_owned()),
+                socket
+            });
         }
 
         // Assume something like a URL has been provided:
         let url = parse_url(input)?;
+        let host = vhost.or_else(|| virtual_host(&url));
+        let query = url.query().map(parse_query_conditions).unwrap_or_default();
 
         // Does the path contain match points (eg {foo}, {bar..}, {lark:.*})?
         // If so, form a regex based on those. If not, build simple regex to
@@ -39,14 +144,101 @@ impl SrcLocation {
         Ok(SrcLocation {
             url,
             path_regex,
-            exact
+            exact,
+            preserve_prefix,
+            host,
+            methods,
+            query,
+            pattern: None,
+            socket
         })
     }
 }
 
+/// Regexes can't be compared, but `path_regex` is built from the `url`
+/// and `pattern` anyway, so everything else is:
 impl PartialEq for SrcLocation {
     fn eq(&self, other: &Self) -> bool {
         self.url == other.url
+            && self.pattern == other.pattern
+            && self.exact == other.exact
+            && self.preserve_prefix == other.preserve_prefix
+            && self.host == other.host
+            && self.methods == other.methods
+            && self.query == other.query
+            && self.socket == other.socket
+    }
+}
+
+/// Parse the query string of a source location into the parameters
+/// that requests must have to match it:
+fn parse_query_conditions(query: &str) -> Vec<(String, Option<String>)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = url::form_urlencoded::parse(pair.as_bytes());
+            let (key, value) = parts.next().unwrap_or_default();
+            let value = if pair.contains('=') { Some(value.into_owned()) } else { None };
+            (key.into_owned(), value)
+        })
+        .collect()
+}
+
+impl SrcLocation {
+    /// Does a request with the given method match this location? Allowing
+    /// GET means allowing HEAD too, since they are answered the same way.
+    pub fn allows_method(&self, method: &Method) -> bool {
+        self.methods.is_empty()
+            || self.methods.contains(method)
+            || (method == Method::HEAD && self.methods.contains(&Method::GET))
+    }
+
+    /// Does a request with the given query string have the query
+    /// parameters that this location asks for?
+    pub fn allows_query(&self, query: Option<&str>) -> bool {
+        if self.query.is_empty() {
+            return true
+        }
+        let pairs: Vec<(Cow<str>, Cow<str>)> = url::form_urlencoded::parse(query.unwrap_or("").as_bytes()).collect();
+        self.query.iter().all(|(key, value)| {
+            pairs.iter().any(|(k, v)| k == key && value.as_ref().map(|value| v == value).unwrap_or(true))
+        })
+    }
+}
+
+impl SrcLocation {
+    /// This location written out so that it parses back into the same
+    /// thing, for config files. `Display` leaves out things like whether
+    /// it's an exact match, so it's only for logging:
+    pub fn to_input(&self) -> String {
+        let mut out = String::new();
+        if !self.methods.is_empty() {
+            let methods: Vec<&str> = self.methods.iter().map(|m| m.as_str()).collect();
+            out.push_str(&methods.join(","));
+            out.push(':');
+        }
+        if self.exact {
+            out.push('=');
+        }
+        if self.preserve_prefix {
+            out.push('+');
+        }
+        let path = match &self.pattern {
+            Some(pattern) => pattern.clone(),
+            // Match points like `{foo}` are percent encoded in URLs:
+            None => self.url[url::Position::BeforePath..].replace("%7B", "{").replace("%7D", "}")
+        };
+        match &self.socket {
+            Some(socket) => out.push_str(&format!("unix://{}:{}", socket.display(), path)),
+            None => out.push_str(&format!("{}{}", self.with_vhost(&self.url[..url::Position::BeforePath]), path))
+        }
+        out
+    }
+}
+
+impl Serialize for SrcLocation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_input())
     }
 }
 
@@ -59,8 +251,98 @@ impl FromStr for SrcLocation {
 
 impl fmt::Display for SrcLocation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.url.fmt(f)
+        if !self.methods.is_empty() {
+            let methods: Vec<&str> = self.methods.iter().map(|m| m.as_str()).collect();
+            write!(f, "{}:", methods.join(","))?;
+        }
+        if let Some(socket) = &self.socket {
+            write!(f, "unix://{}:", socket.display())?;
+            return match &self.pattern {
+                Some(pattern) => f.write_str(pattern),
+                None => f.write_str(&self.url[url::Position::BeforePath..])
+            };
+        }
+        let url = self.with_vhost(self.url.as_str());
+        match &self.pattern {
+            Some(pattern) => write!(f, "{}{}", url.trim_end_matches('/'), pattern),
+            None => f.write_str(&url)
+        }
+    }
+}
+
+impl SrcLocation {
+    /// Is the host in the `url` only a virtual host to match, rather than
+    /// an address to listen on?
+    pub fn listens_anywhere(&self) -> bool {
+        match (&self.host, self.url.host()) {
+            (Some(vhost), Some(Host::Domain(domain))) => domain.eq_ignore_ascii_case(vhost),
+            _ => false
+        }
+    }
+
+    /// Some or all of the `url`, with the virtual host put back in front of
+    /// the address to listen on if they were given separately:
+    fn with_vhost<'a>(&self, url: &'a str) -> Cow<'a, str> {
+        match &self.host {
+            Some(vhost) if !self.listens_anywhere() => {
+                let after_scheme = url.find("://").map(|idx| idx + 3).unwrap_or(0);
+                Cow::Owned(format!("{}{}@{}", &url[..after_scheme], vhost, &url[after_scheme..]))
+            },
+            _ => Cow::Borrowed(url)
+        }
+    }
+}
+
+/// Domain names other than `localhost` are virtual hosts to match on:
+fn virtual_host(url: &Url) -> Option<String> {
+    match url.host() {
+        Some(Host::Domain(domain)) if !domain.eq_ignore_ascii_case("localhost") => {
+            Some(domain.to_lowercase())
+        },
+        _ => None
+    }
+}
+
+/// Split something like `localhost:8080/foo` into the part before the
+/// path (`localhost:8080`) and the path (`/foo`):
+fn split_path(input: &str) -> (&str, &str) {
+    let after_scheme = input.find("://").map(|idx| idx + 3).unwrap_or(0);
+    match input[after_scheme..].find('/') {
+        Some(idx) => input.split_at(after_scheme + idx),
+        None => (input, "")
+    }
+}
+
+/// Split something like `/tmp/weave.sock/api` into the path of a Unix socket
+/// and the path to match requests on. The socket path ends at a ':' if there
+/// is one, or else after the first part ending in `.sock`:
+fn split_socket_path(input: &str) -> Result<(PathBuf, &str), Error> {
+    let sock_end = input.match_indices(".sock")
+        .map(|(idx, _)| idx + ".sock".len())
+        .find(|&end| end == input.len() || input[end..].starts_with('/'));
+    let (socket, path) = if let Some(idx) = input.find(':') {
+        (&input[..idx], &input[idx+1..])
+    } else if let Some(end) = sock_end {
+        input.split_at(end)
+    } else {
+        (input, "")
+    };
+    if !socket.starts_with('/') && !socket.starts_with('.') {
+        return Err(err!("Expecting a path to a unix socket after 'unix://', like 'unix:///tmp/weave.sock'"));
+    }
+    if !path.is_empty() && !path.starts_with('/') {
+        return Err(err!("Expecting the path to match on to start with a '/', but got '{}'", path));
+    }
+    Ok((socket.into(), path))
+}
+
+/// Is a path a regular expression? We say it is if it contains any groups
+/// besides match points, since match points look like groups too.
+fn is_regex_path(path: &str) -> bool {
+    lazy_static!{
+        static ref MATCH_POINT_RE: Regex = Regex::new(r"\([a-zA-Z][a-zA-Z0-9_-]*(\.\.)?\)").expect("match_point_re");
     }
+    MATCH_POINT_RE.replace_all(path, "").contains('(')
 }
 
 /// If a path contains match points (eg {foo}, {bar..}, {lark:.*}),
@@ -126,23 +408,163 @@ fn convert_path_to_regex(path: &str, exact: bool) -> Option<Regex> {
 pub enum DestLocation {
     Url(Url),
     FilePath(String),
+    /// Redirect to a URL rather than proxying to it (eg
+    /// `redirect://https://example.com/new?status=301`):
+    Redirect { url: Url, status: u16 },
+    /// Respond with a fixed status, and optionally a body and headers
+    /// (eg `status://503?body=Back%20soon&header=Retry-After:3600`):
+    Status(FixedResponse),
+    /// Respond with the text provided (eg `text://{"ok":true}`), and a
+    /// content type if one is given (eg `text:text/html://<b>hi</b>`):
+    Text(FixedResponse),
+    /// Run a command and respond with its output (eg `exec://./status.sh`):
+    Exec(String),
+    /// Proxy to a server listening on a Unix socket (eg `unix:///var/run/app.sock/api`).
+    /// The URL is what we send requests to (see `connector::unix_socket_url`):
+    UnixSocket { socket: PathBuf, url: Url },
+    /// Redirect to the same host, path and query over HTTPS (eg `upgrade-https`,
+    /// or `upgrade-https:8443` if HTTPS isn't on the usual port):
+    UpgradeHttps { port: Option<u16> },
+    /// Proxy to one of the healthy instances of a service registered in
+    /// Consul (eg `consul://api/v1`). The URL is what we send requests to,
+    /// once an instance's address and port are swapped in (see `consul::Instances`):
+    Consul { service: String, url: Url },
+    /// Proxy to the targets of a DNS SRV record (eg `srv://_http._tcp.api.example.com`).
+    /// The URL is what we send requests to, once a target's host and port
+    /// are swapped in (see `srv::Targets`):
+    Srv { name: String, url: Url },
+}
+
+/// A response that we hand back as-is, without going anywhere for it:
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct FixedResponse {
+    pub status: u16,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub body: String
 }
 
 impl DestLocation {
     pub fn parse(input: impl AsRef<str>) -> Result<DestLocation, Error> {
         let s = input.as_ref().trim().to_owned();
 
+        // Starts with 'redirect://', so redirect to the URL that follows:
+        if let Some(rest) = s.strip_prefix("redirect://") {
+            let (url, status) = parse_redirect(rest)?;
+            return Ok(DestLocation::Redirect { url, status });
+        }
+
+        // Is 'upgrade-https', so redirect to HTTPS:
+        if let Some(port) = parse_upgrade_https(&s)? {
+            return Ok(DestLocation::UpgradeHttps { port });
+        }
+
+        // Starts with 'status://', so respond with that status:
+        if let Some(rest) = s.strip_prefix("status://") {
+            return Ok(DestLocation::Status(parse_status(rest)?));
+        }
+
+        // Starts with 'exec://', so run the command that follows:
+        if let Some(command) = s.strip_prefix("exec://") {
+            if command.trim().is_empty() {
+                return Err(err!("Expecting a command to run after 'exec://'"));
+            }
+            return Ok(DestLocation::Exec(command.trim().to_owned()));
+        }
+
+        // Starts with 'consul://', so proxy to an instance of the service named:
+        if let Some(rest) = s.strip_prefix("consul://") {
+            let (service, url) = parse_consul(rest)?;
+            return Ok(DestLocation::Consul { service, url });
+        }
+
+        // Starts with 'srv://', so proxy to the targets of the SRV record named:
+        if let Some(rest) = s.strip_prefix("srv://") {
+            let (name, url) = parse_srv(rest)?;
+            return Ok(DestLocation::Srv { name, url });
+        }
+
+        // Starts with 'unix://', so proxy to the socket at the path that follows:
+        if let Some(rest) = s.strip_prefix("unix://") {
+            let (socket, path) = split_socket_path(rest)?;
+            let mut url = connector::unix_socket_url(&socket);
+            url.set_path(path);
+            return Ok(DestLocation::UnixSocket { socket, url });
+        }
+
+        // Starts with 'text://' or 'text:TYPE://', so respond with that text.
+        // We don't trim it, since whitespace may be intended:
+        if let Some(res) = parse_text(input.as_ref())? {
+            return Ok(DestLocation::Text(res));
+        }
+
         // Starts with a '.' or '/', so will assume it's a filepath:
         if [Some('.'), Some(path::MAIN_SEPARATOR)].contains(&s.chars().next()) {
             return Ok(DestLocation::FilePath(s.into()));
         }
 
+        // Starts with 'h2c://', so proxy over HTTP/2 without TLS (eg for
+        // gRPC). The scheme tells the client to do so:
+        if let Some(rest) = s.strip_prefix("h2c://") {
+            let url = parse_url(rest)?;
+            let url = Url::parse(&format!("h2c{}", &url[url::Position::AfterScheme..])).map_err(|e| {
+                err!("Invalid h2c URL: {}", e)
+            })?;
+            return Ok(DestLocation::Url(url));
+        }
+
         // Else, assume something like a URL has been provided:
         let url = parse_url(s)?;
         Ok(DestLocation::Url(url))
     }
 }
 
+impl DestLocation {
+    /// This location written out so that it parses back into the same
+    /// thing, for config files. `Display` leaves out things like the
+    /// headers of fixed responses, so it's only for logging:
+    pub fn to_input(&self) -> String {
+        match self {
+            DestLocation::Redirect { url, status } => {
+                let mut url = url.clone();
+                if *status != 302 {
+                    url.query_pairs_mut().append_pair("status", &status.to_string());
+                }
+                format!("redirect://{}", url)
+            },
+            DestLocation::Status(res) => {
+                let mut query = url::form_urlencoded::Serializer::new(String::new());
+                if !res.body.is_empty() {
+                    query.append_pair("body", &res.body);
+                }
+                for (name, value) in &res.headers {
+                    query.append_pair("header", &format!("{}:{}", name, String::from_utf8_lossy(value.as_bytes())));
+                }
+                let query = query.finish();
+                if query.is_empty() {
+                    format!("status://{}", res.status)
+                } else {
+                    format!("status://{}?{}", res.status, query)
+                }
+            },
+            DestLocation::Text(res) => {
+                let content_type = res.headers.iter()
+                    .find(|(name, _)| name == hyper::header::CONTENT_TYPE)
+                    .map(|(_, value)| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                    .unwrap_or_default();
+                format!("text:{}://{}", content_type, escape_text(&res.body))
+            },
+            DestLocation::UnixSocket { socket, url } => format!("unix://{}:{}", socket.display(), url.path()),
+            _ => self.to_string()
+        }
+    }
+}
+
+impl Serialize for DestLocation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_input())
+    }
+}
+
 impl FromStr for DestLocation {
     type Err = Error;
     fn from_str(input: &str) -> Result<Self, Self::Err> {
@@ -154,7 +576,17 @@ impl fmt::Display for DestLocation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             DestLocation::Url(url) => url.fmt(f),
-            DestLocation::FilePath(path) => path.fmt(f)
+            DestLocation::FilePath(path) => path.fmt(f),
+            DestLocation::Redirect { url, status } => write!(f, "redirect://{} ({})", url, status),
+            DestLocation::Status(res) => write!(f, "status://{}", res.status),
+            DestLocation::Text(res) => write!(f, "text://{}", res.body),
+            DestLocation::Exec(command) => write!(f, "exec://{}", command),
+            DestLocation::UnixSocket { socket, url } if url.path() == "/" => write!(f, "unix://{}", socket.display()),
+            DestLocation::UnixSocket { socket, url } => write!(f, "unix://{}:{}", socket.display(), url.path()),
+            DestLocation::UpgradeHttps { port: None } => write!(f, "upgrade-https"),
+            DestLocation::UpgradeHttps { port: Some(port) } => write!(f, "upgrade-https:{}", port),
+            DestLocation::Consul { service, url } => write!(f, "consul://{}{}", service, &url[url::Position::BeforePath..]),
+            DestLocation::Srv { name, url } => write!(f, "srv://{}{}", name, &url[url::Position::BeforePath..])
         }
     }
 }
@@ -164,18 +596,199 @@ impl fmt::Display for DestLocation {
 #[derive(Debug,Clone,PartialEq,Eq)]
 pub enum ResolvedLocation {
     Url(Url),
-    FilePath(PathBuf)
+    /// A file to serve, and the directory (or file) the route points at,
+    /// with any captures filled in:
+    FilePath { path: PathBuf, root: PathBuf },
+    Redirect { url: Url, status: u16 },
+    Fixed(FixedResponse),
+    /// A command to run, and the rest of the path after what the route matched:
+    Exec { command: String, path_info: String },
+    /// Redirect to HTTPS, on this port if not the usual one:
+    UpgradeHttps { port: Option<u16> }
 }
 
 impl fmt::Display for ResolvedLocation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ResolvedLocation::Url(url) => url.fmt(f),
-            ResolvedLocation::FilePath(path) => path.to_string_lossy().fmt(f)
+            ResolvedLocation::Url(url) => match connector::unix_socket_path(url) {
+                Some(path) => write!(f, "unix://{}:{}", path.display(), url.path()),
+                None => url.fmt(f)
+            },
+            ResolvedLocation::FilePath { path, .. } => path.to_string_lossy().fmt(f),
+            ResolvedLocation::Redirect { url, status } => write!(f, "redirect://{} ({})", url, status),
+            ResolvedLocation::Fixed(_) => write!(f, "inline response"),
+            ResolvedLocation::Exec { command, .. } => write!(f, "exec://{}", command),
+            ResolvedLocation::UpgradeHttps { .. } => write!(f, "upgrade-https")
         }
     }
 }
 
+/// Parse the URL to redirect to, taking the status code to redirect
+/// with from its `status` query parameter (defaulting to 302):
+fn parse_redirect(input: &str) -> Result<(Url, u16), Error> {
+    let mut url = parse_url(input)?;
+
+    let mut status = 302;
+    let mut query = vec![];
+    for (key, value) in url.query_pairs() {
+        if key == "status" {
+            status = match value.parse() {
+                Ok(s @ 301..=303) | Ok(s @ 307..=308) => s,
+                _ => return Err(err!("Expecting a redirect status of 301, 302, 303, 307 or 308 but got '{}'", value))
+            };
+        } else {
+            query.push((key.into_owned(), value.into_owned()));
+        }
+    }
+
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+    Ok((url, status))
+}
+
+/// Parse `upgrade-https` or `upgrade-https:PORT`, handing back None if it's
+/// something else:
+fn parse_upgrade_https(input: &str) -> Result<Option<Option<u16>>, Error> {
+    let rest = match input.strip_prefix("upgrade-https") {
+        Some(rest) => rest,
+        None => return Ok(None)
+    };
+    if rest.is_empty() {
+        return Ok(Some(None));
+    }
+    match rest.strip_prefix(':').map(|port| port.parse::<u16>()) {
+        Some(Ok(port)) => Ok(Some(Some(port))),
+        _ => Err(err!("Expecting 'upgrade-https' or 'upgrade-https:PORT' but got '{}'", input))
+    }
+}
+
+/// Parse something like `api/v1`, the name of a service in Consul and an
+/// optional path. Consul knows which ports instances are on, so there's
+/// no port:
+fn parse_consul(input: &str) -> Result<(String, Url), Error> {
+    let idx = input.find(['/', '?']).unwrap_or(input.len());
+    let (service, rest) = input.split_at(idx);
+    if service.is_empty() || !service.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        return Err(err!("Expecting the name of a service after 'consul://', like 'consul://api', but got '{}'", service));
+    }
+    // The host is swapped for an instance's address, so any will do:
+    let url = Url::parse(&format!("http://consul.invalid{}", rest))
+        .map_err(|e| err!("Invalid consul:// destination: {}", e))?;
+    Ok((service.to_owned(), url))
+}
+
+/// Parse something like `_http._tcp.api.example.com/v1`, the name of an
+/// SRV record and an optional path. Records for `_https` services are
+/// sent requests over HTTPS:
+fn parse_srv(input: &str) -> Result<(String, Url), Error> {
+    let idx = input.find(['/', '?']).unwrap_or(input.len());
+    let (name, rest) = input.split_at(idx);
+    let valid = !name.is_empty() && name.trim_end_matches('.').split('.')
+        .all(|label| !label.is_empty() && label.len() <= 63 && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    if !valid {
+        return Err(err!("Expecting the name of an SRV record after 'srv://', like 'srv://_http._tcp.api.example.com', but got '{}'", name));
+    }
+    let scheme = if name.starts_with("_https.") { "https" } else { "http" };
+    // The host is swapped for a target's, so any will do:
+    let url = Url::parse(&format!("{}://srv.invalid{}", scheme, rest))
+        .map_err(|e| err!("Invalid srv:// destination: {}", e))?;
+    Ok((name.to_owned(), url))
+}
+
+/// Parse something like `503?body=Back%20soon&header=Retry-After:3600`:
+fn parse_status(input: &str) -> Result<FixedResponse, Error> {
+    let (status, query) = match input.find('?') {
+        Some(idx) => (&input[..idx], &input[idx+1..]),
+        None => (input, "")
+    };
+    let status = match status.trim_end_matches('/').parse() {
+        Ok(status @ 100..=599) => status,
+        _ => return Err(err!("Expecting a status code between 100 and 599 but got '{}'", status))
+    };
+
+    let mut res = FixedResponse { status, headers: vec![], body: String::new() };
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match &*key {
+            "body" => {
+                res.body = value.into_owned();
+            },
+            "header" => {
+                res.headers.push(headers::parse_name_value(&value)?);
+            },
+            _ => {
+                return Err(err!("Unknown parameter '{}'; expecting 'body' or 'header'", key));
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Parse something like `text://{"ok":true}` or `text:text/html://<b>hi</b>`,
+/// handing back None if it's not a text destination. In the text, `\n`, `\t`
+/// and `\r` are replaced with the characters they stand for, and `\\` with `\`.
+/// If no content type is given, we serve JSON as such and all else as plain text.
+fn parse_text(input: &str) -> Result<Option<FixedResponse>, Error> {
+    let rest = match input.trim_start().strip_prefix("text") {
+        Some(rest) => rest,
+        None => return Ok(None)
+    };
+    let (content_type, text) = if let Some(text) = rest.strip_prefix("://") {
+        (None, text)
+    } else if let (Some(rest), Some(idx)) = (rest.strip_prefix(':'), rest.find("://")) {
+        (Some(&rest[..idx-1]), &rest[idx+2..])
+    } else {
+        return Ok(None)
+    };
+
+    let body = unescape_text(text);
+    let content_type = match content_type {
+        Some(content_type) => content_type,
+        None if serde_json::from_str::<serde_json::Value>(&body).is_ok() => "application/json",
+        None => "text/plain; charset=utf-8"
+    };
+    let content_type = HeaderValue::from_str(content_type).map_err(|_| {
+        err!("'{}' is not a valid content type", content_type)
+    })?;
+
+    Ok(Some(FixedResponse {
+        status: 200,
+        headers: vec![(hyper::header::CONTENT_TYPE, content_type)],
+        body
+    }))
+}
+
+fn unescape_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('\\') => out.push('\\'),
+            // Leave anything else as it was:
+            Some(other) => { out.push('\\'); out.push(other); },
+            None => out.push('\\')
+        }
+    }
+    out
+}
+
+/// The opposite of `unescape_text`:
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+        .replace('\r', "\\r")
+}
+
 /// Parse something that looks like a URL into one:
 fn parse_url(input: impl AsRef<str>) -> Result<Url, Error> {
     let mut s = Cow::Borrowed(input.as_ref());
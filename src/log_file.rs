@@ -0,0 +1,205 @@
+use log::{ warn, error };
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ self, BufWriter, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, SyncSender, TrySendError };
+use std::thread;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use crate::errors::{ Error };
+use crate::settings::{ parse_size };
+
+/// How many rotated log files we keep, if not provided:
+pub const DEFAULT_LOG_KEEP: usize = 7;
+/// How many lines can wait to be written before we start dropping them,
+/// rather than holding up requests:
+const MAX_QUEUED_LINES: usize = 10_000;
+
+/// Lines we've had to drop since we last said so:
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// When to move the log file aside and start a new one.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Rotation {
+    Never,
+    /// Once the file is this many bytes:
+    Size(u64),
+    /// At the start of each UTC day:
+    Daily,
+    /// At the start of each hour:
+    Hourly
+}
+
+impl Rotation {
+    /// Parse `daily`, `hourly` or a size like `100m`:
+    pub fn parse(input: &str) -> Result<Rotation, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "daily" => Ok(Rotation::Daily),
+            "hourly" => Ok(Rotation::Hourly),
+            s => Ok(Rotation::Size(parse_size(s).map_err(|_| err!("Expecting 'daily', 'hourly' or a size like 100m"))? as u64))
+        }
+    }
+
+    /// Which day or hour a time falls in, for time based rotation:
+    fn period(self, time: SystemTime) -> u64 {
+        let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        match self {
+            Rotation::Daily => secs / 86400,
+            Rotation::Hourly => secs / 3600,
+            Rotation::Never | Rotation::Size(_) => 0
+        }
+    }
+}
+
+/// A file that lines are written to on a thread of its own, so that a slow
+/// disk can't hold up requests. When rotated, `access.log` becomes
+/// `access.log.1`, which becomes `access.log.2` and so on, up to the number
+/// of files we keep.
+pub struct LogFile {
+    sender: Mutex<SyncSender<String>>
+}
+
+impl LogFile {
+    pub fn open(path: &Path, rotation: Rotation, keep: usize) -> Result<LogFile, Error> {
+        let writer = Writer::open(path.to_owned(), rotation, keep)
+            .map_err(|e| err!("Cannot open log file '{}': {}", path.display(), e))?;
+        let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_LINES);
+        thread::Builder::new()
+            .name("log-file".to_owned())
+            .spawn(move || writer.run(receiver))?;
+        Ok(LogFile { sender: Mutex::new(sender) })
+    }
+
+    /// Write a line, or drop it if too many are waiting to be written:
+    pub fn write(&self, line: String) {
+        if let Err(TrySendError::Full(_)) = self.sender.lock().unwrap().try_send(line) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    rotation: Rotation,
+    keep: usize,
+    file: BufWriter<File>,
+    /// How big the file is, for size based rotation:
+    size: u64,
+    /// Which day or hour the file is for, for time based rotation:
+    period: u64
+}
+
+impl Writer {
+    fn open(path: PathBuf, rotation: Rotation, keep: usize) -> io::Result<Writer> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let meta = file.metadata()?;
+        // A file left from an earlier day is rotated as soon as we write:
+        let period = rotation.period(meta.modified().unwrap_or_else(|_| SystemTime::now()));
+        Ok(Writer {
+            path,
+            rotation,
+            keep,
+            file: BufWriter::new(file),
+            size: meta.len(),
+            period
+        })
+    }
+
+    /// Write lines as they arrive, until there are no more senders:
+    fn run(mut self, receiver: Receiver<String>) {
+        while let Ok(line) = receiver.recv() {
+            let mut next = Some(line);
+            // Write whatever else is waiting before flushing:
+            while let Some(line) = next {
+                if let Err(e) = self.write(&line) {
+                    error!("Failed to write to log file '{}': {}", self.path.display(), e);
+                }
+                next = receiver.try_recv().ok();
+            }
+            if let Err(e) = self.file.flush() {
+                error!("Failed to write to log file '{}': {}", self.path.display(), e);
+            }
+            let dropped = DROPPED.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                warn!("Dropped {} lines that couldn't be written to '{}' quickly enough", dropped, self.path.display());
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.should_rotate(line.len() as u64 + 1) {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn should_rotate(&self, len: u64) -> bool {
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max) => self.size > 0 && self.size + len > max,
+            Rotation::Daily | Rotation::Hourly => self.rotation.period(SystemTime::now()) != self.period
+        }
+    }
+
+    /// Shuffle the old files along, dropping the oldest, and start afresh:
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let from = rotated(n);
+                if from.exists() {
+                    fs::rename(from, rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        self.period = self.rotation.period(SystemTime::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_rotation() {
+        assert_eq!(Rotation::parse("daily").unwrap(), Rotation::Daily);
+        assert_eq!(Rotation::parse("Hourly").unwrap(), Rotation::Hourly);
+        assert_eq!(Rotation::parse("1k").unwrap(), Rotation::Size(1024));
+        assert!(Rotation::parse("weekly").is_err());
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("weave-log-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let mut writer = Writer::open(path.clone(), Rotation::Size(10), 2).unwrap();
+        for line in &["one", "two", "three", "four", "five", "six"] {
+            writer.write(line).unwrap();
+        }
+        writer.file.flush().unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        // "one" and "two" were rotated out of the files we keep:
+        assert_eq!(read("access.log"), "six\n");
+        assert_eq!(read("access.log.1"), "four\nfive\n");
+        assert_eq!(read("access.log.2"), "three\n");
+        assert!(!dir.join("access.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,14 +1,372 @@
-
 use env_logger::{ Env, Builder };
+use env_logger::filter;
+use lazy_static::lazy_static;
+use log::{ info, LevelFilter };
+use regex::Regex;
+use serde_json::json;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use crate::errors::{ Error };
+use crate::log_file::{ LogFile };
+use crate::syslog::{ LogTarget, SystemLogger };
 
 const LOG: &str = "WEAVE_LOG";
 const LOG_STYLE: &str = "WEAVE_LOG_STYLE";
 
-pub fn init() {
+/// The target of the colored line we log about each request, for people
+/// to read:
+pub const REQUESTS: &str = "weave::requests";
+/// The target of the line we log about each request in a structured
+/// `--log-format`, for log pipelines to read:
+pub const ACCESS: &str = "weave::access";
+
+lazy_static! {
+    static ref FORMAT: RwLock<LogFormat> = RwLock::new(LogFormat::Text);
+    /// Where requests are logged to instead, if not alongside everything else:
+    static ref FILE: RwLock<Option<LogFile>> = RwLock::new(None);
+}
+
+/// How we log requests.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum LogFormat {
+    /// A colored line describing each request:
+    Text,
+    /// A JSON object for each request, and for everything else we log:
+    Json,
+    /// The NCSA Common Log Format, for log analyzers:
+    Common,
+    /// The Combined Log Format; the common one plus referer and user agent:
+    Combined
+}
+
+impl LogFormat {
+    pub fn parse(input: &str) -> Result<LogFormat, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            _ => Err(err!("Expecting 'text', 'json', 'common' or 'combined'"))
+        }
+    }
+}
+
+/// What we log about each request in a structured format.
+#[derive(Debug,Clone,PartialEq)]
+pub struct AccessEntry<'a> {
+    pub time: SystemTime,
+    pub method: &'a str,
+    /// The path and query the request was made to:
+    pub path: &'a str,
+    /// The HTTP version of the request, like `HTTP/1.1`:
+    pub version: &'a str,
+    /// The user the request says it's from, if it uses Basic authentication:
+    pub user: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    /// The route the request matched, if any:
+    pub route: Option<&'a str>,
+    /// Which of the route's destinations the request was sent to, if any:
+    pub dest: Option<&'a str>,
+    pub status: u16,
+    /// The size of the response body, if we know it up front:
+    pub bytes: Option<u64>,
+    pub duration: Duration,
+    /// Requests over Unix sockets don't come from an IP address:
+    pub client: Option<IpAddr>,
+    /// The request's ID, unless `--no-request-ids` was given:
+    pub request_id: Option<&'a str>
+}
+
+/// Start logging to the target given. Requests are logged in the format
+/// given, to the file given if there is one:
+pub fn init(format: LogFormat, target: &LogTarget, file: Option<LogFile>) -> Result<(), Error> {
+    if *target != LogTarget::Stderr {
+        init_system(format, target)?;
+        *FORMAT.write().unwrap() = format;
+        *FILE.write().unwrap() = file;
+        return Ok(());
+    }
+
     let env = Env::new()
         .filter_or(LOG, "info")
         .write_style(LOG_STYLE);
 
-    Builder::from_env(env)
-        .init();
-}
\ No newline at end of file
+    let mut builder = Builder::from_env(env);
+    match format {
+        LogFormat::Text => {},
+        LogFormat::Json => {
+            // Log pipelines get one JSON object per request, rather than the
+            // lines for people, and everything else in JSON too:
+            builder
+                .filter_module(REQUESTS, LevelFilter::Off)
+                .format(|buf, record| {
+                    if record.target() == ACCESS {
+                        return writeln!(buf, "{}", record.args());
+                    }
+                    let line = json!({
+                        "timestamp": rfc3339(SystemTime::now()),
+                        "level": record.level().to_string(),
+                        "target": record.module_path().unwrap_or_else(|| record.target()),
+                        "message": record.args().to_string()
+                    });
+                    writeln!(buf, "{}", line)
+                });
+        },
+        LogFormat::Common | LogFormat::Combined => {
+            // Log analyzers get the lines they expect for requests, and
+            // other messages look as they usually do:
+            builder
+                .filter_module(REQUESTS, LevelFilter::Off)
+                .format(|buf, record| {
+                    if record.target() == ACCESS {
+                        return writeln!(buf, "{}", record.args());
+                    }
+                    writeln!(buf, "[{} {:<5} {}] {}",
+                             buf.timestamp(),
+                             buf.default_styled_level(record.level()),
+                             record.module_path().unwrap_or_else(|| record.target()),
+                             record.args())
+                });
+        }
+    }
+    builder.init();
+    *FORMAT.write().unwrap() = format;
+    *FILE.write().unwrap() = file;
+    Ok(())
+}
+
+/// Log to syslog or journald, which keep their own timestamps and levels,
+/// so messages are sent as they are:
+fn init_system(format: LogFormat, target: &LogTarget) -> Result<(), Error> {
+    let mut builder = filter::Builder::new();
+    builder.parse(&std::env::var(LOG).unwrap_or_else(|_| "info".to_owned()));
+    if format != LogFormat::Text {
+        builder.filter_module(REQUESTS, LevelFilter::Off);
+    }
+    let filter = builder.build();
+    let max_level = filter.filter();
+    log::set_boxed_logger(Box::new(SystemLogger::new(target, filter)?))
+        .map_err(|e| err!("Cannot start logging: {}", e))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Log a request, if we're logging requests in a structured format:
+pub fn access(entry: &AccessEntry) {
+    let line = match *FORMAT.read().unwrap() {
+        LogFormat::Text => return,
+        LogFormat::Json => access_json(entry),
+        LogFormat::Common => access_common(entry),
+        LogFormat::Combined => format!("{} \"{}\" \"{}\"",
+                                       access_common(entry),
+                                       quoted(entry.referer.unwrap_or("-")),
+                                       quoted(entry.user_agent.unwrap_or("-")))
+    };
+    match &*FILE.read().unwrap() {
+        Some(file) => file.write(line),
+        None => info!(target: ACCESS, "{}", line)
+    }
+}
+
+/// `host ident user [time] "request line" status bytes`:
+fn access_common(entry: &AccessEntry) -> String {
+    format!("{} - {} [{}] \"{} {} {}\" {} {}",
+            entry.client.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_owned()),
+            entry.user.map(quoted).unwrap_or_else(|| "-".to_owned()),
+            clf_time(entry.time),
+            entry.method,
+            quoted(entry.path),
+            entry.version,
+            entry.status,
+            entry.bytes.map(|b| b.to_string()).unwrap_or_else(|| "-".to_owned()))
+}
+
+/// Escape quotes, backslashes and anything unprintable, as Apache does,
+/// so that each field stays where log analyzers expect it:
+fn quoted(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out
+}
+
+/// A UTC date and time like `10/Oct/2000:13:55:36 +0000`:
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (year, month, day, hours, minutes, seconds) = civil(since_epoch.as_secs());
+    format!("{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, hours, minutes, seconds)
+}
+
+fn access_json(entry: &AccessEntry) -> String {
+    json!({
+        "timestamp": rfc3339(entry.time),
+        "method": entry.method,
+        "path": entry.path,
+        "route": entry.route,
+        "destination": entry.dest,
+        "status": entry.status,
+        "bytes": entry.bytes,
+        "duration_ms": entry.duration.as_secs_f64() * 1000.0,
+        "client_ip": entry.client.map(|ip| ip.to_string()),
+        "request_id": entry.request_id
+    }).to_string()
+}
+
+/// A UTC date and time like `2019-10-12T07:20:50.520Z`:
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (year, month, day, hours, minutes, seconds) = civil(since_epoch.as_secs());
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, hours, minutes, seconds, since_epoch.subsec_millis())
+}
+
+/// Parse a date and time like `2019-10-12T07:20:50.520Z` or
+/// `2019-10-12T09:20:50+02:00`:
+pub fn parse_rfc3339(input: &str) -> Option<SystemTime> {
+    lazy_static! {
+        static ref RFC3339: Regex = Regex::new(r"^(\d{4})-(\d{2})-(\d{2})[Tt ](\d{2}):(\d{2}):(\d{2})(?:\.(\d+))?(?:[Zz]|([+-])(\d{2}):(\d{2}))$").unwrap();
+    }
+    let caps = RFC3339.captures(input.trim())?;
+    let num = |i: usize| caps.get(i).map(|m| m.as_str().parse::<i64>().unwrap()).unwrap_or(0);
+    // Only milliseconds are kept:
+    let millis = caps.get(7).map(|m| format!("{:0<3}", &m.as_str()[..m.as_str().len().min(3)]).parse().unwrap()).unwrap_or(0);
+    let offset = match caps.get(8).map(|m| m.as_str()) {
+        Some("-") => -(num(9) * 3600 + num(10) * 60),
+        _ => num(9) * 3600 + num(10) * 60
+    };
+    from_civil(num(1), num(2), num(3), num(4) * 3600 + num(5) * 60 + num(6) - offset, millis)
+}
+
+/// Parse a date and time like `10/Oct/2000:13:55:36 +0000`:
+pub fn parse_clf_time(input: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    lazy_static! {
+        static ref CLF_TIME: Regex = Regex::new(r"^(\d{2})/(\w{3})/(\d{4}):(\d{2}):(\d{2}):(\d{2}) ([+-])(\d{2})(\d{2})$").unwrap();
+    }
+    let caps = CLF_TIME.captures(input.trim())?;
+    let num = |i: usize| caps[i].parse::<i64>().unwrap();
+    let month = MONTHS.iter().position(|m| *m == &caps[2])? as i64 + 1;
+    let offset = if &caps[7] == "-" { -(num(8) * 3600 + num(9) * 60) } else { num(8) * 3600 + num(9) * 60 };
+    from_civil(num(3), month, num(1), num(4) * 3600 + num(5) * 60 + num(6) - offset, 0)
+}
+
+/// The time at a UTC date, plus some seconds and milliseconds (the
+/// inverse of `civil`):
+fn from_civil(year: i64, month: i64, day: i64, secs: i64, millis: u64) -> Option<SystemTime> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let secs = days * 86400 + secs;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64) + Duration::from_millis(millis))
+}
+
+/// The UTC year, month, day, hours, minutes and seconds, given seconds
+/// since the Unix epoch (see http://howardhinnant.github.io/date_algorithms.html):
+fn civil(secs: u64) -> (i64, u32, u32, u64, u64, u64) {
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let secs_of_day = secs % 86400;
+    (year, month, day, secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_millis(951_827_696_789)), "2000-02-29T12:34:56.789Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(1_704_067_199)), "2023-12-31T23:59:59.000Z");
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_millis(951_827_696_789);
+        assert_eq!(parse_rfc3339("2000-02-29T12:34:56.789Z"), Some(time));
+        assert_eq!(parse_rfc3339("2000-02-29T14:34:56.789123+02:00"), Some(time));
+        assert_eq!(parse_rfc3339(&rfc3339(UNIX_EPOCH)), Some(UNIX_EPOCH));
+        assert_eq!(parse_rfc3339("yesterday"), None);
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(parse_clf_time("10/Oct/2000:13:55:36 +0000"), Some(time));
+        assert_eq!(parse_clf_time("10/Oct/2000:06:55:36 -0700"), Some(time));
+        assert_eq!(parse_clf_time(&clf_time(time)), Some(time));
+    }
+
+    #[test]
+    fn writes_json_access_entries() {
+        let entry = AccessEntry {
+            time: UNIX_EPOCH,
+            method: "GET",
+            path: "/api?x=1",
+            version: "HTTP/1.1",
+            user: None,
+            referer: None,
+            user_agent: None,
+            route: Some("8080/api to 9000"),
+            dest: None,
+            status: 200,
+            bytes: Some(12),
+            duration: Duration::from_millis(5),
+            client: Some("127.0.0.1".parse().unwrap()),
+            request_id: Some("abc123")
+        };
+        let value: serde_json::Value = serde_json::from_str(&access_json(&entry)).unwrap();
+        assert_eq!(value["timestamp"], "1970-01-01T00:00:00.000Z");
+        assert_eq!(value["path"], "/api?x=1");
+        assert_eq!(value["route"], "8080/api to 9000");
+        assert!(value["destination"].is_null());
+        assert_eq!(value["bytes"], 12);
+        assert_eq!(value["duration_ms"], 5.0);
+        assert_eq!(value["client_ip"], "127.0.0.1");
+        assert_eq!(value["request_id"], "abc123");
+    }
+
+    #[test]
+    fn writes_common_access_entries() {
+        let entry = AccessEntry {
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            method: "GET",
+            path: "/a\"b",
+            version: "HTTP/1.0",
+            user: Some("frank"),
+            referer: None,
+            user_agent: Some("curl/7.0"),
+            route: None,
+            dest: None,
+            status: 404,
+            bytes: None,
+            duration: Duration::from_millis(5),
+            client: None,
+            request_id: None
+        };
+        assert_eq!(access_common(&entry), r#"- - frank [10/Oct/2000:13:55:36 +0000] "GET /a\"b HTTP/1.0" 404 -"#);
+        assert_eq!(quoted("a\\b\n"), r#"a\\b\x0a"#);
+    }
+}
@@ -1,205 +1,382 @@
-use futures::TryFutureExt;
+use futures::{Stream, StreamExt};
 use std::env;
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use hyper::{Client, Server, Body, Request, Response};
-use hyper::service::{make_service_fn, service_fn};
-use hyper_tls::HttpsConnector;
-use log::{debug, info, warn, error};
+use std::path::Path;
+use log::{debug, info, error};
 use std::result::Result::{Ok, Err};
-use location::ResolvedLocation;
-use clap::{App, AppSettings};
-use futures_util::future::join_all;
-use tokio::fs;
-use ansi_term::Color::{Green, Red, Yellow};
+use clap::{App, AppSettings, Arg};
+use stargate::{check, config, err, import, logging, log_file, maintenance, metrics, replay, routes, syslog};
+use stargate::{Error, Settings, WeaveServer};
+use stargate::admin::AdminOptions;
+use stargate::docker::DockerOptions;
 
 static EXAMPLES: &str = "EXAMPLES:";
 
-#[macro_use]
-pub mod errors;
-mod location;
-mod routes;
-mod logging;
-mod matcher;
-
-use matcher::Matcher;
-use errors::Error;
-use routes::Route;
-
 #[tokio::main]
 async fn main() -> Result<(), Error>  {
-    logging::init();
-    debug!("Starting");
     run().await?;
     Ok(())
 }
 
 async fn run() -> Result<(), Error> {
-    let (routes, other_args) = routes::from_args(env::args().skip(1)).map_err(|e| {
+    if env::args().nth(1).as_deref() == Some("replay") {
+        return replay::run(env::args().skip(2)).await;
+    }
+    if env::args().nth(1).as_deref() == Some("check") {
+        return check::run(env::args().skip(2)).await;
+    }
+    if env::args().nth(1).as_deref() == Some("import") {
+        return import::run(env::args().skip(2));
+    }
+    let (mut cli_routes, other_args) = routes::from_args(env::args().skip(1)).map_err(|e| {
         err!("failed to parse routes: {}", e)
     })?;
-    let _ = App::new("weave")
+    cli_routes.extend(routes::from_env()?);
+    let matches = App::new("weave")
         .author("James Wilson <james@jsdw.me>")
         .about("A lightweight HTTP router and file server.")
         .version("0.2")
         .after_help(EXAMPLES)
-        .usage("weave SOURCE to DEST [and SOURCE to DEST ...]")
+        .usage("weave [SOURCE to DEST [and SOURCE to DEST ...]] [--config FILE] [--stdin]\n    weave check [SOURCE to DEST ...] [--config FILE] [--probe]\n    weave import FILE [--format nginx|caddy] [--output FILE]\n    weave replay FILE --target URL [--speed FACTOR]")
         .setting(AppSettings::NoBinaryName)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .help("Load routes from a TOML config file, in addition to any provided as arguments or in the WEAVE_ROUTES environment variable. Send SIGHUP to reload it"))
+        .arg(Arg::with_name("stdin")
+            .long("stdin")
+            .help("Read routes from standard input as well, one per line (eg '8080 to ./dist'). Blank lines and lines starting with '#' are ignored"))
+        .arg(Arg::with_name("emit-config")
+            .long("emit-config")
+            .value_name("FILE")
+            .help("Write the routes provided as arguments (or in WEAVE_ROUTES or on stdin) to this TOML config file, for use with --config, rather than serving them. Use '-' to write them to stdout"))
+        .arg(Arg::with_name("chunk-size")
+            .long("chunk-size")
+            .value_name("BYTES")
+            .help("How much of a file to read into memory at a time when serving it (eg 8k, 1m). Defaults to 64k"))
+        .arg(Arg::with_name("dir-listing")
+            .long("dir-listing")
+            .help("List the contents of directories that have no index file, rather than responding with a 404"))
+        .arg(Arg::with_name("connect-timeout")
+            .long("connect-timeout")
+            .value_name("DURATION")
+            .help("How long to wait when connecting to a destination before responding with a 504 (eg 500ms, 2s)"))
+        .arg(Arg::with_name("no-forwarded-headers")
+            .long("no-forwarded-headers")
+            .help("Don't add X-Forwarded-For, X-Forwarded-Proto, X-Forwarded-Host and Forwarded headers to proxied requests"))
+        .arg(Arg::with_name("no-request-ids")
+            .long("no-request-ids")
+            .help("Don't give each request an ID (or use the one in its X-Request-Id header) to log, send on to destinations and send back in an X-Request-Id header"))
+        .arg(Arg::with_name("max-exec")
+            .long("max-exec")
+            .value_name("COUNT")
+            .help("How many exec:// commands can run at once. Requests beyond this get a 503. Defaults to 8"))
+        .arg(Arg::with_name("max-concurrent")
+            .long("max-concurrent")
+            .value_name("COUNT")
+            .help("How many requests can be handled at once, across all routes. Requests beyond this wait for one to finish. Routes can also limit this with the 'max-concurrent' option"))
+        .arg(Arg::with_name("max-queued")
+            .long("max-queued")
+            .value_name("COUNT")
+            .help("How many requests can wait when --max-concurrent are already being handled. Requests beyond this get a 503. Defaults to 100"))
+        .arg(Arg::with_name("cache-size")
+            .long("cache-size")
+            .value_name("BYTES")
+            .help("How much memory responses cached for routes with the 'cache' option can take up (eg 512k, 256m). Defaults to 64m"))
+        .arg(Arg::with_name("cache-dir")
+            .long("cache-dir")
+            .value_name("DIR")
+            .help("Cache responses on disk in this directory as well as in memory, so that they survive restarts. Large responses are only kept on disk"))
+        .arg(Arg::with_name("cache-disk-size")
+            .long("cache-disk-size")
+            .value_name("BYTES")
+            .help("How much disk space responses cached in --cache-dir can take up (eg 512m, 10g). Defaults to 1g"))
+        .arg(Arg::with_name("purge-from")
+            .long("purge-from")
+            .value_name("NETWORKS")
+            .help("Which client networks can drop cached responses with 'PURGE /path' (or 'PURGE /path/*' for everything under a path), as a comma separated list (eg 10.0.0.0/8,::1). Defaults to 127.0.0.1,::1"))
+        .arg(Arg::with_name("cors-permissive")
+            .long("cors-permissive")
+            .help("Allow cross-origin requests from any origin, with credentials, to routes without their own 'cors' option. Handy for development, but not in production"))
+        .arg(Arg::with_name("security-headers")
+            .long("security-headers")
+            .help("Add Strict-Transport-Security, X-Content-Type-Options, X-Frame-Options and Referrer-Policy headers to responses that don't have them. Routes can turn this off with 'security-headers=false', or change each with the 'hsts', 'content-type-options', 'frame-options', 'referrer-policy' and 'csp' options"))
+        .arg(Arg::with_name("error-page")
+            .long("error-page")
+            .value_name("FILE")
+            .help("Respond with this HTML file instead of plain text when no route matches, a file is missing or a destination fails. {{status}}, {{reason}}, {{path}} and {{message}} in it are filled in. Routes can have their own with the 'error-page' option"))
+        .arg(Arg::with_name("maintenance")
+            .long("maintenance")
+            .help("Start with routes that have the 'maintenance' option down for maintenance, responding with a 503. Send SIGUSR1 to bring them back up (or take them down again)"))
+        .arg(Arg::with_name("admin")
+            .long("admin")
+            .value_name("ADDRESS")
+            .help("Serve an API for listing, adding and removing routes, seeing their stats, reloading routes and going down for maintenance on this address (eg 127.0.0.1:9900). Needs --admin-token"))
+        .arg(Arg::with_name("admin-token")
+            .long("admin-token")
+            .value_name("TOKEN")
+            .help("The token that requests to the admin API must provide, as 'Authorization: Bearer TOKEN'"))
+        .arg(Arg::with_name("admin-dashboard")
+            .long("admin-dashboard")
+            .help("Serve a dashboard showing routes, request rates, responses by status and recent errors at the root of the --admin address. It asks for the --admin-token"))
+        .arg(Arg::with_name("docker")
+            .long("docker")
+            .help("Route to running Docker containers from their labels, as they start and stop: 'weave.host' for the host to route requests for (on --docker-port), or 'weave.src' for a source to route from, 'weave.port' for the port in the container to send them to if it exposes several, and 'weave.options' for route options"))
+        .arg(Arg::with_name("docker-socket")
+            .long("docker-socket")
+            .value_name("PATH")
+            .help("Where to talk to Docker for --docker. Defaults to the unix:// socket in DOCKER_HOST, or /var/run/docker.sock"))
+        .arg(Arg::with_name("docker-port")
+            .long("docker-port")
+            .value_name("PORT")
+            .help("The port to listen on for requests to containers with a 'weave.host' label. Defaults to 8080"))
+        .arg(Arg::with_name("consul")
+            .long("consul")
+            .value_name("ADDRESS")
+            .help("Where the Consul agent that consul://SERVICE destinations are looked up with is (eg http://127.0.0.1:8500). Defaults to CONSUL_HTTP_ADDR, or 127.0.0.1:8500. Requests go to the service's instances in turn, skipping those failing their health checks"))
+        .arg(Arg::with_name("consul-interval")
+            .long("consul-interval")
+            .value_name("DURATION")
+            .help("How often to ask Consul which instances of each service are healthy (eg 30s). Defaults to 10s"))
+        .arg(Arg::with_name("metrics")
+            .long("metrics")
+            .value_name("ADDRESS")
+            .help("Serve Prometheus metrics at /metrics on this address (eg 127.0.0.1:9901): requests by route, destination and status, requests in flight and how long responses took"))
+        .arg(Arg::with_name("statsd")
+            .long("statsd")
+            .value_name("ADDRESS")
+            .help("Send a counter and a timing for each request to this StatsD server over UDP (eg 127.0.0.1:8125), tagged DogStatsD style with the route, destination and status"))
+        .arg(Arg::with_name("statsd-prefix")
+            .long("statsd-prefix")
+            .value_name("PREFIX")
+            .help("What the names of metrics sent to --statsd start with. Defaults to 'weave.'"))
+        .arg(Arg::with_name("statsd-tags")
+            .long("statsd-tags")
+            .value_name("TAGS")
+            .help("Tags to add to every metric sent to --statsd, as a comma separated list (eg env:dev,team:web)"))
+        .arg(Arg::with_name("otlp-endpoint")
+            .long("otlp-endpoint")
+            .value_name("URL")
+            .help("Send a span for each request to this OpenTelemetry collector over OTLP/HTTP (eg http://localhost:4318). Requests carry on with traces from their traceparent header, and pass it on to destinations"))
+        .arg(Arg::with_name("otlp-service-name")
+            .long("otlp-service-name")
+            .value_name("NAME")
+            .help("The service name that spans sent to --otlp-endpoint are from. Defaults to 'weave'"))
+        .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .value_name("FORMAT")
+            .help("How to log requests: 'text' (the default) for a colored line each, or 'json' for a JSON object each with the timestamp, method, path, route, destination, status, bytes, duration and client IP (other messages are logged as JSON too), or 'common' or 'combined' for the Apache style log formats that log analyzers understand"))
+        .arg(Arg::with_name("log-file")
+            .long("log-file")
+            .value_name("FILE")
+            .help("Log requests to this file rather than alongside other messages, in the --log-format given, or else 'combined'"))
+        .arg(Arg::with_name("log-rotate")
+            .long("log-rotate")
+            .value_name("WHEN")
+            .help("Start a new --log-file once it reaches a size (eg 100m), or 'daily' or 'hourly'. The old one is renamed with a .1 suffix, and older ones move along to .2 and so on"))
+        .arg(Arg::with_name("log-keep")
+            .long("log-keep")
+            .value_name("COUNT")
+            .help("How many rotated log files to keep before deleting the oldest. Defaults to 7"))
+        .arg(Arg::with_name("log-target")
+            .long("log-target")
+            .value_name("TARGET")
+            .help("Where to log to: 'stderr' (the default), 'syslog' for the local syslog daemon, 'syslog://host:port' for a syslog server over UDP, or 'journald'. Messages are sent with priorities to match their levels"))
+        .arg(Arg::with_name("capture")
+            .long("capture")
+            .help("Log the headers and the start of the bodies of the requests we proxy, and the responses we get back. Authorization and cookie headers are redacted. Routes can turn this on or off with the 'capture' option"))
+        .arg(Arg::with_name("capture-body-limit")
+            .long("capture-body-limit")
+            .value_name("SIZE")
+            .help("How much of each body to log when capturing (eg 16k). Defaults to 4k"))
+        .arg(Arg::with_name("capture-redact-headers")
+            .long("capture-redact-headers")
+            .value_name("NAMES")
+            .help("Comma separated headers to redact when capturing, on top of Authorization, Proxy-Authorization, Cookie and Set-Cookie"))
+        .arg(Arg::with_name("capture-redact")
+            .long("capture-redact")
+            .value_name("REGEX")
+            .help("Redact matches of this regular expression in URLs, headers and bodies when capturing (eg '\"password\":\"[^\"]*\"')"))
+        .arg(Arg::with_name("har")
+            .long("har")
+            .value_name("FILE")
+            .help("Record requests and the responses we send back into this HAR file, to inspect in browser devtools or share in bug reports. Headers and bodies are redacted as they are for --capture"))
+        .arg(Arg::with_name("har-body-limit")
+            .long("har-body-limit")
+            .value_name("SIZE")
+            .help("How much of each body to record in the --har file. Defaults to 1m"))
+        .arg(Arg::with_name("cassette")
+            .long("cassette")
+            .value_name("DIR")
+            .help("Save the responses of URL destinations to this directory, and answer requests from them, to work offline or make tests repeatable. Responses are matched on the method, URL and body of the request"))
+        .arg(Arg::with_name("cassette-mode")
+            .long("cassette-mode")
+            .value_name("MODE")
+            .help("'record' to send every request on and save the responses, 'replay' to only answer from saved responses, or 'auto' (the default) to answer from saved responses where there are any and record the rest"))
+        .arg(Arg::with_name("curl")
+            .long("curl")
+            .help("Log a curl command for each request we proxy, to reproduce it without weave. Nothing is redacted, so keep these logs to yourself"))
+        .arg(Arg::with_name("curl-bodies")
+            .long("curl-bodies")
+            .value_name("DIR")
+            .help("Where to save request bodies for --curl commands to send. Defaults to a weave-curl directory in the system's temporary directory"))
+        .arg(Arg::with_name("insecure")
+            .long("insecure")
+            .help("Don't verify the certificates of HTTPS destinations. Routes can also do this with the 'insecure' option"))
+        .arg(Arg::with_name("tls-backend")
+            .long("tls-backend")
+            .value_name("NAME")
+            .help("Which TLS library to make HTTPS connections with: 'native' or 'rustls'. Which are available depends on how weave was built"))
+        .arg(Arg::with_name("self-signed")
+            .long("self-signed")
+            .help("Serve https:// sources without their own 'cert' and 'key' options with a self-signed certificate for their hosts. It's made the first time it's needed and kept in ~/.cache/weave/self-signed, so that it can be trusted"))
+        .arg(Arg::with_name("acme")
+            .long("acme")
+            .help("Get certificates from Let's Encrypt for the hosts of https:// sources (eg https://example.com) without their own 'cert' and 'key' options, and renew them before they expire. They're kept in ~/.cache/weave/acme. The hosts must reach us on port 443 (or on port 80, with '--acme-challenge http-01')"))
+        .arg(Arg::with_name("acme-email")
+            .long("acme-email")
+            .value_name("EMAIL")
+            .requires("acme")
+            .help("Who the CA can tell about problems with --acme certificates"))
+        .arg(Arg::with_name("acme-directory")
+            .long("acme-directory")
+            .value_name("URL")
+            .requires("acme")
+            .help("The ACME directory of the CA to get --acme certificates from. Defaults to Let's Encrypt's (https://acme-v02.api.letsencrypt.org/directory); use https://acme-staging-v02.api.letsencrypt.org/directory to try things out"))
+        .arg(Arg::with_name("acme-challenge")
+            .long("acme-challenge")
+            .value_name("TYPE")
+            .requires("acme")
+            .help("How to prove to the CA that we serve a host: 'tls-alpn-01' (the default) on https:// sources on port 443, or 'http-01' on routes for http:// sources on port 80 (eg 'http://example.com to https://example.com with upgrade-https')"))
+        .arg(Arg::with_name("resolve")
+            .long("resolve")
+            .value_name("HOST:PORT:ADDRESS")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Connect to this address for requests to a destination host and port (eg api.example.com:443:10.0.0.5), as curl does, keeping the host's name for the Host header and TLS. The port can be * for any. Can be given more than once"))
         .get_matches_from(other_args);
-
-    if routes.is_empty() {
-        return Err(err!("No routes have been provided. Use -h or --help for more information"));
+    if matches.is_present("stdin") {
+        cli_routes.extend(routes::from_stdin()?);
     }
-
-    // Log our routes:
-    for route in &routes {
-        info!("Routing {} to {}", route.src, route.dest);
+    if let Some(path) = matches.value_of("emit-config") {
+        if cli_routes.is_empty() {
+            return Err(err!("No routes have been provided to write to --emit-config"));
+        }
+        let contents = config::to_string(&cli_routes)?;
+        if path == "-" {
+            print!("{}", contents);
+        } else {
+            std::fs::write(path, contents).map_err(|e| err!("Could not write config file '{}': {}", path, e))?;
+        }
+        return Ok(());
     }
-
-    // Partition provided routes based on the SocketAddr we'll serve them on:
-    let mut map = HashMap::new();
-    for route in routes {
-        let socket_addr = route.src_socket_addr()?;
-        let rs: &mut Vec<Route> = map.entry(socket_addr).or_default();
-        rs.push(route);
+    let log_format = match matches.value_of("log-format") {
+        Some(s) => Some(logging::LogFormat::parse(s).map_err(|e| err!("Invalid --log-format '{}': {}", s, e))?),
+        None => None
+    };
+    let (log_format, log_file) = match matches.value_of("log-file") {
+        Some(path) => {
+            // Colored lines are no use in a file:
+            let log_format = match log_format {
+                Some(logging::LogFormat::Text) => return Err(err!("--log-format text can't be used with --log-file")),
+                Some(log_format) => log_format,
+                None => logging::LogFormat::Combined
+            };
+            let rotation = match matches.value_of("log-rotate") {
+                Some(s) => log_file::Rotation::parse(s).map_err(|e| err!("Invalid --log-rotate '{}': {}", s, e))?,
+                None => log_file::Rotation::Never
+            };
+            let keep = match matches.value_of("log-keep") {
+                Some(s) => s.parse().map_err(|_| err!("Invalid --log-keep '{}': Not a valid number", s))?,
+                None => log_file::DEFAULT_LOG_KEEP
+            };
+            (log_format, Some(log_file::LogFile::open(Path::new(path), rotation, keep)?))
+        },
+        None => (log_format.unwrap_or(logging::LogFormat::Text), None)
+    };
+    let log_target = match matches.value_of("log-target") {
+        Some(s) => syslog::LogTarget::parse(s).map_err(|e| err!("Invalid --log-target '{}': {}", s, e))?,
+        None => syslog::LogTarget::Stderr
+    };
+    logging::init(log_format, &log_target, log_file)?;
+    debug!("Starting");
+    let settings = Settings::from_matches(&matches)?;
+    let mut builder = WeaveServer::builder()
+        .settings(settings);
+    for route in cli_routes {
+        builder = builder.route(route);
     }
-
-    let mut vec = Vec::new();
-    for (socket_addr, routes) in map {
-        let handler = handle_requests(socket_addr, routes);
-        vec.push(handler);
+    if let Some(config_path) = matches.value_of("config") {
+        builder = builder.config(config_path);
     }
-    join_all(vec).await;
-    Result::<_, Error>::Ok(())
-}
-
-/// Handle incoming requests by matching on routes and dispatching as necessary
-async fn handle_requests(socket_addr: SocketAddr, routes: Vec<Route>) {
-    let socket_addr_outer = socket_addr.clone();
+    if let Some(admin) = AdminOptions::from_matches(&matches)? {
+        builder = builder.admin(admin);
+    }
+    if let Some(docker) = DockerOptions::from_matches(&matches)? {
+        builder = builder.docker(docker);
+    }
+    let server = builder.start()?;
 
-    let matcher = Arc::new(Matcher::new(routes));
-    let socket_addr = Arc::new(socket_addr);
+    if let Some(addr) = matches.value_of("metrics") {
+        let addr = addr.parse().map_err(|_| err!("Invalid --metrics '{}': Not a valid socket address", addr))?;
+        metrics::spawn(addr)?;
+    }
 
-    let make_svc = make_service_fn(move |_| {
-        let socket_addr = Arc::clone(&socket_addr);
-        let matcher = Arc::clone(&matcher);
-        async {
-            Ok::<_, Error>(service_fn(move |_req| {
-                let socket_addr = Arc::clone(&socket_addr);
-                let matcher = Arc::clone(&matcher);
-                async {
-                    Ok::<_, Error>(handle_request(_req, socket_addr, matcher).await)
-                }
-            }))
+    // Routes with the 'maintenance' option go down for maintenance (or
+    // come back up) each time we're asked to:
+    if matches.is_present("maintenance") {
+        maintenance::set_down(true);
+        info!("Down for maintenance");
+    }
+    let mut toggles = maintenance_signals()?;
+    tokio::spawn(async move {
+        while let Some(()) = toggles.next().await {
+            if maintenance::toggle() {
+                info!("Down for maintenance");
+            } else {
+                info!("Back up after maintenance");
+            }
         }
     });
 
-    let server = Server::bind(&socket_addr_outer)
-        .serve(make_svc);
-
-    if let Err(e) = server.await {
-        error!("{}", e);
+    // Each time we're asked to, reload the config file and swap the new
+    // routes in. If something is wrong, we keep serving the old routes:
+    let mut reloads = reload_signals()?;
+    while let Some(()) = reloads.next().await {
+        info!("Reloading routes");
+        if let Err(e) = server.reload() {
+            error!("Failed to reload routes: {}", e);
+        }
     }
+    Ok(())
 }
 
-/// Handle a single request, given a matcher that defines how to map from input to output:
-async fn handle_request<'a>(req: Request<Body>, socket_addr: Arc<SocketAddr>, matcher: Arc<Matcher>) -> Response<Body> {
-    let before_time = std::time::Instant::now();
-    let src_path = format!("{}{}", socket_addr, req.uri());
-    let dest_path = matcher.resolve(req.uri());
-
-    match dest_path {
-        None => {
-            let duration = before_time.elapsed();
-            let not_found_string = format!("[no matching routes] {} in {:#?}", src_path, duration);
-            warn!("{}", Red.paint(not_found_string));
-            Response::builder()
-                .status(404)
-                .body(Body::from("Weave: No routes matched"))
-                .unwrap()
-        }
-        Some(dest_path) => {
-            match do_handle_request(req, &dest_path).await {
-                Ok(resp) => {
-                    let duration = before_time.elapsed();
-                    let status_code = resp.status().as_u16();
-                    let status_col =
-                        if status_code >= 200 && status_code < 300 { Green } else if status_code >= 300 && status_code < 400 { Yellow } else { Red };
-
-                    let info_string = format!("[{}] {} to {} in {:#?}",
-                                              resp.status().as_str(),
-                                              src_path,
-                                              dest_path.to_string(),
-                                              duration);
-                    info!("{}", status_col.paint(info_string));
-                    resp
-                }
-                Err(err) => {
-                    let duration = before_time.elapsed();
-                    let error_string = format!("[500] {} to {} ({}) in {:#?}",
-                                               src_path,
-                                               dest_path.to_string(),
-                                               err,
-                                               duration);
-                    warn!("{}", Red.paint(error_string));
-                    Response::builder()
-                        .status(500)
-                        .body(Body::from(format!("Weave: {}", err)))
-                        .unwrap()
-                }
-            }
-        }
-    }
+/// A stream which fires each time we should reload our routes (on SIGHUP):
+#[cfg(unix)]
+fn reload_signals() -> Result<impl Stream<Item=()> + Unpin, Error> {
+    use tokio::signal::unix::{ signal, SignalKind };
+    let signals = signal(SignalKind::hangup())?;
+    Ok(Box::pin(futures::stream::unfold(signals, |mut signals| async {
+        signals.recv().await.map(|()| ((), signals))
+    })))
 }
 
-async fn do_handle_request(mut req: Request<Body>, dest_path: &ResolvedLocation) -> Result<Response<Body>, Error> {
-    match dest_path {
-        // Proxy to the URI our request matched against:
-        ResolvedLocation::Url(url) => {
-            // Set the request URI to our new destination:
-            *req.uri_mut() = format!("{}", url).parse().unwrap();
-            // Remove the host header (it's set according to URI if not present):
-            req.headers_mut().remove("host");
-            // Supoprt HTTPS (8 DNS worker threads):
-            let https = HttpsConnector::new()?;
-            // Proxy the request through and pass back the response:
-            let response = Client::builder()
-                .build(https)
-                .request(req)
-                .await?;
-            Ok(response)
-        }
-        // Proxy to the filesystem:
-        ResolvedLocation::FilePath(path) => {
-            let mut file = Err(err!("File not found"));
-            let mut mime = None;
+#[cfg(not(unix))]
+fn reload_signals() -> Result<impl Stream<Item=()> + Unpin, Error> {
+    Ok(futures::stream::pending())
+}
 
-            for end in &["", "index.htm", "index.html"] {
-                let mut p = path.clone();
-                if !end.is_empty() { p.push(end) }
-                mime = Some(mime_guess::from_path(&p).first_or_octet_stream());
-                file = fs::read(p).map_err(|e| err!("{}", e)).await;
-                if file.is_ok() { break; }
-            }
+/// A stream which fires each time we should go down for maintenance or
+/// come back up (on SIGUSR1):
+#[cfg(unix)]
+fn maintenance_signals() -> Result<impl Stream<Item=()> + Unpin, Error> {
+    use tokio::signal::unix::{ signal, SignalKind };
+    let signals = signal(SignalKind::user_defined1())?;
+    Ok(Box::pin(futures::stream::unfold(signals, |mut signals| async {
+        signals.recv().await.map(|()| ((), signals))
+    })))
+}
 
-            let response = match file {
-                Ok(file) => {
-                    Response::builder()
-                        .status(200)
-                        .header("Content-Type", mime.unwrap().as_ref())
-                        .body(Body::from(file))
-                        .unwrap()
-                }
-                Err(e) => {
-                    let msg = format!("Weave: Could not read file '{}': {}", path.to_string_lossy(), e);
-                    Response::builder()
-                        .status(404)
-                        .body(Body::from(msg))
-                        .unwrap()
-                }
-            };
-            Ok(response)
-        }
-    }
-}
\ No newline at end of file
+#[cfg(not(unix))]
+fn maintenance_signals() -> Result<impl Stream<Item=()> + Unpin, Error> {
+    Ok(futures::stream::pending())
+}
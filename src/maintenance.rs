@@ -0,0 +1,97 @@
+use hyper::{ Response, StatusCode };
+use hyper::header::{ HeaderValue, CONTENT_TYPE, RETRY_AFTER };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Duration;
+use crate::body::{ Body };
+use crate::error_pages::{ self, ErrorPage };
+
+/// How long we tell clients to wait before trying again, if not provided:
+pub const DEFAULT_MAINTENANCE_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Are we down for maintenance? This is switched at runtime, and only
+/// affects routes with the `maintenance` option.
+static DOWN: AtomicBool = AtomicBool::new(false);
+
+/// How a route behaves while we're down for maintenance.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Maintenance {
+    /// Does this route go down with us? If not, it's served as usual:
+    pub enabled: bool,
+    /// An HTML page to respond with (see `ErrorPage` for what's filled in):
+    pub page: Option<Arc<ErrorPage>>,
+    /// How long to tell clients to wait before trying again:
+    pub retry_after: Duration
+}
+
+impl Default for Maintenance {
+    fn default() -> Maintenance {
+        Maintenance {
+            enabled: false,
+            page: None,
+            retry_after: DEFAULT_MAINTENANCE_RETRY_AFTER
+        }
+    }
+}
+
+impl Maintenance {
+    /// Is this route down for maintenance right now?
+    pub fn is_down(&self) -> bool {
+        self.enabled && is_down()
+    }
+
+    /// The 503 we respond with while down for maintenance. Without a page
+    /// of its own, this can be swapped for an error page like other 503s.
+    pub fn response(&self, path: &str) -> Response<Body> {
+        let message = "Down for maintenance";
+        let mut resp = match &self.page {
+            Some(page) => {
+                let mut resp = Response::new(Body::from(page.render(StatusCode::SERVICE_UNAVAILABLE, path, message)));
+                *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+                resp
+            },
+            None => error_pages::error(503, message)
+        };
+        resp.headers_mut().insert(RETRY_AFTER, HeaderValue::from(self.retry_after.as_secs()));
+        resp
+    }
+}
+
+pub fn is_down() -> bool {
+    DOWN.load(Ordering::Relaxed)
+}
+
+/// Go down for maintenance, or come back up:
+pub fn set_down(down: bool) {
+    DOWN.store(down, Ordering::Relaxed);
+}
+
+/// Go down for maintenance if we're up, or back up if we're down, handing
+/// back whether we're now down:
+pub fn toggle() -> bool {
+    !DOWN.fetch_xor(true, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn only_affects_routes_that_ask() {
+        let route = Maintenance { enabled: true, retry_after: Duration::from_secs(60), ..Maintenance::default() };
+        let other = Maintenance::default();
+        assert!(!route.is_down());
+
+        assert!(toggle());
+        assert!(route.is_down());
+        assert!(!other.is_down());
+        let resp = route.response("/");
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "60");
+
+        assert!(!toggle());
+        assert!(!route.is_down());
+    }
+}
@@ -1,29 +1,105 @@
-use hyper::Uri;
+use hyper::{ Method, Request, Uri };
 use url::Url;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::cmp::{ Ordering };
+use std::net::{ IpAddr, SocketAddr };
 use std::path::PathBuf;
 use std::borrow::{ Borrow, Cow };
+use std::sync::{ Arc, Weak };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering as AtomicOrdering };
+use crate::acceptor::{ TlsConnection };
+use crate::breaker::{ Breaker };
+use crate::chaos::{ FaultInjector };
+use crate::concurrency::{ ConcurrencyLimit };
+use crate::consul::{ Instances };
+use crate::srv::{ Targets };
+use crate::grpc;
+use crate::listeners::{ ListenAddr };
+use crate::rate_limit::{ RateLimiter };
 use crate::routes::{ Route };
+use crate::sticky::{ self, Sticky };
 use crate::location::{ DestLocation, ResolvedLocation };
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Matcher {
-    routes: Vec<Route>
+    routes: Vec<Route>,
+    /// For each route, the address it's listened for on, unless that's
+    /// every interface. Routes for an address can share a listener on
+    /// every interface, so only requests that reached it match them:
+    addrs: Vec<Option<IpAddr>>,
+    /// For each route, a counter used to rotate between its destinations:
+    next_dest: Vec<AtomicUsize>,
+    /// For each route, whether each of its destinations is healthy.
+    /// Health checks update these; destinations start out healthy:
+    healthy: Vec<Vec<Arc<AtomicBool>>>,
+    /// For each route, a circuit breaker for each of its destinations:
+    breakers: Vec<Vec<Breaker>>,
+    /// For each route, where each of its destinations that are looked up
+    /// (like `consul://` and `srv://`) could go (and `None` for the others):
+    looked_up: Vec<Vec<Option<LookedUp>>>,
+    /// For each route, the rate limits of each of its clients:
+    limiters: Vec<RateLimiter>,
+    /// For each route, how many requests it can handle at once:
+    concurrency: Vec<Option<ConcurrencyLimit>>,
+    /// For each route, what decides which of its requests fail on purpose:
+    faults: Vec<FaultInjector>,
+    /// Do any routes match on the GraphQL operation of requests?
+    sniffs_graphql: bool
+}
+
+/// Which of our addresses the connection a request came in on reached,
+/// kept in the request's extensions:
+#[derive(Debug,Clone,Copy)]
+pub struct LocalAddr(pub SocketAddr);
+
+/// Where a destination that's looked up, rather than given, could go:
+#[derive(Debug)]
+enum LookedUp {
+    Consul(Arc<Instances>),
+    Srv(Arc<Targets>)
+}
+
+/// The outcome of successfully matching a request against our routes:
+#[derive(Debug, Clone)]
+pub struct Resolved<'a> {
+    /// The route that matched:
+    pub route: &'a Route,
+    /// Which of the route's destinations was picked:
+    pub dest: &'a DestLocation,
+    /// Where the request will actually be routed to:
+    pub location: ResolvedLocation,
+    /// Is the destination healthy? If none of the route's destinations
+    /// are healthy, one is picked anyway and this is false:
+    pub healthy: bool,
+    /// The circuit breaker for the destination that was picked:
+    pub breaker: &'a Breaker,
+    /// The rate limiter for the route:
+    pub limiter: &'a RateLimiter,
+    /// The concurrency limit for the route, if it has one:
+    pub concurrency: Option<&'a ConcurrencyLimit>,
+    /// Decides which requests to the route fail on purpose:
+    pub faults: &'a FaultInjector,
+    /// A cookie to hand back to the client, so that it sticks to the
+    /// destination that was picked:
+    pub sticky_cookie: Option<String>
 }
 
 impl Matcher {
     /// Build a new matcher given some routes we'd like to match on:
     pub fn new(mut routes: Vec<Route>) -> Matcher {
-        // Ordering:
+        // Ordering (routes for a specific host come before the rest, and
+        // routes with conditions on methods, query parameters, headers,
+        // GraphQL operations or gRPC before otherwise equal ones):
         // 1. basic exact match (longest first)
         // 2. regex exact match (in order declared)
         // 3. basic prefix (longest first)
         // 4. regex prefix (in order declared)
         routes.sort_by(|a, b| {
-            // Put all exact matching routes first:
-            a.src.exact.cmp(&b.src.exact).reverse().then_with(|| {
+            // Put routes for specific hosts first:
+            let by_host = a.src.host.is_some().cmp(&b.src.host.is_some()).reverse();
+            // Then all exact matching routes:
+            by_host.then_with(|| a.src.exact.cmp(&b.src.exact).reverse()).then_with(|| {
                 match (a.src.path_regex.is_some(), b.src.path_regex.is_some()) {
                     // If regex, put that last, but maintain
                     // ordering within regex'd paths:
@@ -38,36 +114,305 @@ impl Matcher {
                             .reverse()
                     }
                 }
+            }).then_with(|| {
+                // Else, routes with more conditions (on methods, query
+                // parameters, headers, GraphQL operations or gRPC) come first:
+                conditions(b).cmp(&conditions(a))
             })
         });
-        Matcher { routes }
+        let addrs = routes.iter()
+            .map(|route| match route.listen_addr() {
+                Ok(ListenAddr::Tcp(addr)) if !addr.ip().is_unspecified() => Some(addr.ip()),
+                _ => None
+            })
+            .collect();
+        let next_dest = routes.iter().map(|_| AtomicUsize::new(0)).collect();
+        let healthy = routes.iter()
+            .map(|route| route.dests.iter().map(|_| Arc::new(AtomicBool::new(true))).collect())
+            .collect();
+        let breakers = routes.iter()
+            .map(|route| route.dests.iter().map(|_| Breaker::new()).collect())
+            .collect();
+        let looked_up = routes.iter()
+            .map(|route| route.dests.iter().map(|dest| match dest {
+                DestLocation::Consul { service, .. } => Some(LookedUp::Consul(Instances::of(service))),
+                DestLocation::Srv { name, .. } => Some(LookedUp::Srv(Targets::of(name))),
+                _ => None
+            }).collect())
+            .collect();
+        let limiters = routes.iter().map(|_| RateLimiter::new()).collect();
+        let concurrency = routes.iter()
+            .map(|route| route.options.max_concurrent.map(|max| ConcurrencyLimit::new(max, route.options.max_queued)))
+            .collect();
+        let faults = routes.iter().map(|route| FaultInjector::new(route.options.chaos.seed)).collect();
+        let sniffs_graphql = routes.iter().any(|route| !route.options.when_graphql.is_empty());
+        Matcher { routes, addrs, next_dest, healthy, breakers, looked_up, limiters, concurrency, faults, sniffs_graphql }
+    }
+
+    /// Do requests need `graphql::sniff`ing before they're matched?
+    pub fn sniffs_graphql(&self) -> bool {
+        self.sniffs_graphql
+    }
+
+    /// Hand back each destination of each route, along with a handle
+    /// that health checks can use to mark that destination as healthy or not.
+    pub fn health_flags(&self) -> impl Iterator<Item=(&Route, &DestLocation, Weak<AtomicBool>)> {
+        self.routes.iter().zip(&self.healthy).flat_map(|(route, healthy)| {
+            route.dests.iter().zip(healthy).map(move |(dest, healthy)| {
+                (route, dest, Arc::downgrade(healthy))
+            })
+        })
+    }
+
+    /// The instances of each service that our `consul://` destinations
+    /// are for, for `consul::spawn_lookups` to keep up to date:
+    pub fn consul_instances(&self) -> impl Iterator<Item=&Arc<Instances>> {
+        self.looked_up.iter().flatten().flatten().filter_map(|looked_up| match looked_up {
+            LookedUp::Consul(instances) => Some(instances),
+            _ => None
+        })
+    }
+
+    /// The targets of each SRV record that our `srv://` destinations are
+    /// for, for `srv::spawn_lookups` to keep up to date:
+    pub fn srv_targets(&self) -> impl Iterator<Item=&Arc<Targets>> {
+        self.looked_up.iter().flatten().flatten().filter_map(|looked_up| match looked_up {
+            LookedUp::Srv(targets) => Some(targets),
+            _ => None
+        })
     }
 
     /// Match a Uri against the routes provided. This returns
     /// the Location to serve up.
+    #[cfg(test)]
     pub fn resolve(&self, uri: &Uri) -> Option<ResolvedLocation> {
+        let req = Request::builder().uri(uri.clone()).body(()).unwrap();
+        self.resolve_with_route(&req).map(|resolved| resolved.location)
+    }
+
+    /// Match a request against the routes provided. This returns the
+    /// route that matched and the destination picked from it, as
+    /// well as the Location to serve up.
+    pub fn resolve_with_route<T>(&self, req: &Request<T>) -> Option<Resolved<'_>> {
+        let uri = req.uri();
+        let host = request_host(req);
+
         // Find a matching route. We assume routes are ordered and
         // the first match wins.
-        self.routes.iter().find_map(|route| resolve_route(uri, route))
+        (0..self.routes.len()).find_map(|route_idx| {
+            let route = &self.routes[route_idx];
+            if !self.matches_request(route_idx, req, &host) || !route.src.allows_method(req.method()) {
+                return None
+            }
+            let healthy = &self.healthy[route_idx];
+            let breakers = &self.breakers[route_idx];
+            let limiter = &self.limiters[route_idx];
+            let concurrency = self.concurrency[route_idx].as_ref();
+            let faults = &self.faults[route_idx];
+            let is_healthy = |idx: usize| healthy[idx].load(AtomicOrdering::Relaxed);
+
+            let first_dest = &route.dests[0];
+            let location = self.resolve_dest(uri, route_idx, 0)?;
+
+            // Only one destination; nothing to balance:
+            if route.dests.len() == 1 {
+                return Some(Resolved { route, dest: first_dest, location, healthy: is_healthy(0), breaker: &breakers[0], limiter, concurrency, faults, sticky_cookie: None })
+            }
+
+            // Else, stick with the client's destination if the route wants
+            // us to and we know which one that is:
+            let sticky = route.options.sticky.as_ref();
+            if let Some(idx) = sticky.and_then(|sticky| sticky.pick(route, req.headers(), is_healthy)) {
+                let dest = &route.dests[idx];
+                let location = self.resolve_dest(uri, route_idx, idx)?;
+                return Some(Resolved { route, dest, location, healthy: true, breaker: &breakers[idx], limiter, concurrency, faults, sticky_cookie: None })
+            }
+
+            // Else, rotate through the destinations, skipping unhealthy ones:
+            let len = route.dests.len();
+            let start = self.next_dest[route_idx].fetch_add(1, AtomicOrdering::Relaxed) % len;
+            let idx = (0..len)
+                .map(|n| (start + n) % len)
+                .find(|&idx| is_healthy(idx))
+                .unwrap_or(start);
+            let dest = &route.dests[idx];
+            let location = self.resolve_dest(uri, route_idx, idx)?;
+            // Hand the client a cookie to stick to this destination from now on:
+            let sticky_cookie = match sticky {
+                Some(Sticky::Cookie) => Some(sticky::set_cookie(route, idx)),
+                _ => None
+            };
+            Some(Resolved { route, dest, location, healthy: is_healthy(idx), breaker: &breakers[idx], limiter, concurrency, faults, sticky_cookie })
+        })
     }
 }
 
-fn resolve_route(uri: &Uri, route: &Route) -> Option<ResolvedLocation> {
+impl Matcher {
+    /// Where a request would go for one of a route's destinations,
+    /// picking where to send it for those that are looked up:
+    fn resolve_dest(&self, uri: &Uri, route_idx: usize, idx: usize) -> Option<ResolvedLocation> {
+        let route = &self.routes[route_idx];
+        let location = resolve_route(uri, route, &route.dests[idx])?;
+        Some(match &self.looked_up[route_idx][idx] {
+            Some(LookedUp::Consul(instances)) => instances.pick(location),
+            Some(LookedUp::Srv(targets)) => targets.pick(location),
+            None => location
+        })
+    }
+
+    /// The route a request would match if it had the method given,
+    /// without picking a destination for it:
+    pub fn route_for<T>(&self, req: &Request<T>, method: &Method) -> Option<&Route> {
+        let host = request_host(req);
+        self.routes.iter().enumerate().find(|(route_idx, route)| {
+            self.matches_request(*route_idx, req, &host)
+                && route.src.allows_method(method)
+                && resolve_route(req.uri(), route, &route.dests[0]).is_some()
+        }).map(|(_, route)| route)
+    }
+
+    /// Where a request would go for each of the destinations of the first
+    /// route it matches, whatever its method. Cached responses are kept by
+    /// destination, so this is what purging them needs.
+    pub fn resolve_each_dest<T>(&self, req: &Request<T>) -> Option<(&Route, Vec<ResolvedLocation>)> {
+        let host = request_host(req);
+        self.routes.iter().enumerate().find_map(|(route_idx, route)| {
+            if !self.matches_request(route_idx, req, &host) {
+                return None
+            }
+            let mut locations = vec![];
+            for (idx, dest) in route.dests.iter().enumerate() {
+                let location = resolve_route(req.uri(), route, dest)?;
+                // Responses are cached for each place a looked up
+                // destination could go:
+                match &self.looked_up[route_idx][idx] {
+                    Some(LookedUp::Consul(instances)) => locations.extend(instances.each(location)),
+                    Some(LookedUp::Srv(targets)) => locations.extend(targets.each(location)),
+                    None => locations.push(location)
+                }
+            }
+            Some((route, locations))
+        })
+    }
+
+    /// If a request didn't match any routes, it may be that routes matched
+    /// its path but not its method. This hands back the methods those
+    /// routes allow, so that we can tell the client about them.
+    pub fn allowed_methods<T>(&self, req: &Request<T>) -> Vec<Method> {
+        let host = request_host(req);
+        let mut methods: Vec<Method> = vec![];
+        for (route_idx, route) in self.routes.iter().enumerate() {
+            if !self.matches_request(route_idx, req, &host) {
+                continue
+            }
+            if resolve_route(req.uri(), route, &route.dests[0]).is_none() {
+                continue
+            }
+            for method in &route.src.methods {
+                if !methods.contains(method) {
+                    methods.push(method.clone());
+                }
+            }
+        }
+        methods
+    }
+}
+
+impl Matcher {
+    /// Does a request satisfy the conditions a route puts on it (other than
+    /// the path and method, which are checked separately)?
+    fn matches_request<T>(&self, route_idx: usize, req: &Request<T>, host: &Option<String>) -> bool {
+        let route = &self.routes[route_idx];
+        if route.src.host.is_some() && &route.src.host != host {
+            return false
+        }
+        if let (Some(ip), Some(LocalAddr(local_addr))) = (self.addrs[route_idx], req.extensions().get::<LocalAddr>()) {
+            if ip.to_canonical() != local_addr.ip().to_canonical() {
+                return false
+            }
+        }
+        route.src.allows_query(req.uri().query())
+            && route.options.when_headers.iter().all(|m| m.matches(req.headers()))
+            && route.options.when_graphql.matches(req)
+            && (!route.options.grpc || grpc::is_grpc(req))
+    }
+}
+
+/// Does a path start with a route's? gRPC routes only match whole service
+/// and method names:
+fn starts_with_src(route: &Route, path: &str) -> bool {
+    if route.options.grpc {
+        grpc::matches_path(route.src.url.path(), path)
+    } else {
+        path.starts_with(route.src.url.path())
+    }
+}
+
+/// How many conditions besides the path does a route have?
+fn conditions(route: &Route) -> usize {
+    let methods = if route.src.methods.is_empty() { 0 } else { 1 };
+    methods + route.src.query.len() + route.options.when_headers.len() + route.options.when_graphql.conditions() + route.options.grpc as usize
+}
+
+/// The host that a request is for, without any port, lowercased. Requests
+/// over TLS that don't say are for the host the client asked for with SNI:
+pub fn request_host<T>(req: &Request<T>) -> Option<String> {
+    let host = req.headers().get("host")
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host())
+        .or_else(|| req.extensions().get::<TlsConnection>()?.server_name.as_deref())?;
+    // Strip the port (taking care not to chop up IPv6 addresses):
+    let host = match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host
+    };
+    Some(host.to_lowercase())
+}
+
+fn resolve_route(uri: &Uri, route: &Route, dest: &DestLocation) -> Option<ResolvedLocation> {
     let path = uri.path();
 
+    // Don't pass on the query parameters we matched on if asked not to:
+    let stripped_uri;
+    let uri = if route.options.strip_matched_query && !route.src.query.is_empty() {
+        stripped_uri = strip_query_params(uri, &route.src.query);
+        &stripped_uri
+    } else {
+        uri
+    };
+
     // Attempt to match on provided regex:
     if let Some(re) = &route.src.path_regex {
         let re_captures = re.captures(path);
         if let Some(captures) = re_captures {
-            let rest_of_path = &path[ captures.get(0).unwrap().end().. ];
-            Some(match route.dest.clone() {
+            let rest_of_path = if route.src.preserve_prefix || route.options.grpc { path } else { &path[ captures.get(0).unwrap().end().. ] };
+            let is_pattern = route.src.pattern.is_some();
+            Some(match dest.clone() {
                 DestLocation::Url(url) => {
-                    let expanded_url = expand_url_with_captures(&captures, url);
+                    let expanded_url = expand_url_with_captures(&captures, url, is_pattern);
                     ResolvedLocation::Url(merge_tail_and_uri_with_url(rest_of_path, uri, expanded_url))
                 },
                 DestLocation::FilePath(path) => {
-                    let expanded_path = expand_path_with_captures(&captures, path);
-                    ResolvedLocation::FilePath(merge_tail_with_path(rest_of_path, expanded_path))
+                    let expanded_path = expand_path_with_captures(&captures, path, is_pattern);
+                    let path = merge_tail_with_path(rest_of_path, expanded_path.clone());
+                    ResolvedLocation::FilePath { path, root: expanded_path }
+                },
+                DestLocation::Redirect { url, status } => {
+                    let expanded_url = expand_url_with_captures(&captures, url, is_pattern);
+                    let url = merge_tail_and_uri_with_url(rest_of_path, uri, expanded_url);
+                    ResolvedLocation::Redirect { url, status }
+                },
+                DestLocation::Status(res) | DestLocation::Text(res) => {
+                    ResolvedLocation::Fixed(res)
+                },
+                DestLocation::Exec(command) => {
+                    ResolvedLocation::Exec { command, path_info: rest_of_path.to_owned() }
+                },
+                DestLocation::UnixSocket { url, .. } | DestLocation::Consul { url, .. } | DestLocation::Srv { url, .. } => {
+                    ResolvedLocation::Url(merge_tail_and_uri_with_url(rest_of_path, uri, url))
+                },
+                DestLocation::UpgradeHttps { port } => {
+                    ResolvedLocation::UpgradeHttps { port }
                 }
             })
         } else {
@@ -76,14 +421,31 @@ fn resolve_route(uri: &Uri, route: &Route) -> Option<ResolvedLocation> {
     }
     // No regex, so see whether incoming path starts with route src:
     else if (route.src.exact && path == route.src.url.path())
-            || (!route.src.exact && path.starts_with(route.src.url.path())) {
-        let rest_of_path = &path[ route.src.url.path().len().. ];
-        Some(match route.dest.clone() {
+            || (!route.src.exact && starts_with_src(route, path)) {
+        let rest_of_path = if route.src.preserve_prefix || route.options.grpc { path } else { &path[ route.src.url.path().len().. ] };
+        Some(match dest.clone() {
             DestLocation::Url(url) => {
                 ResolvedLocation::Url(merge_tail_and_uri_with_url(rest_of_path, uri, url))
             },
             DestLocation::FilePath(filepath) => {
-                ResolvedLocation::FilePath(merge_tail_with_path(rest_of_path, filepath.into()))
+                let root = PathBuf::from(filepath);
+                ResolvedLocation::FilePath { path: merge_tail_with_path(rest_of_path, root.clone()), root }
+            },
+            DestLocation::Redirect { url, status } => {
+                let url = merge_tail_and_uri_with_url(rest_of_path, uri, url);
+                ResolvedLocation::Redirect { url, status }
+            },
+            DestLocation::Status(res) | DestLocation::Text(res) => {
+                ResolvedLocation::Fixed(res)
+            },
+            DestLocation::Exec(command) => {
+                ResolvedLocation::Exec { command, path_info: rest_of_path.to_owned() }
+            },
+            DestLocation::UnixSocket { url, .. } | DestLocation::Consul { url, .. } | DestLocation::Srv { url, .. } => {
+                ResolvedLocation::Url(merge_tail_and_uri_with_url(rest_of_path, uri, url))
+            },
+            DestLocation::UpgradeHttps { port } => {
+                ResolvedLocation::UpgradeHttps { port }
             }
         })
     }
@@ -93,18 +455,51 @@ fn resolve_route(uri: &Uri, route: &Route) -> Option<ResolvedLocation> {
     }
 }
 
-fn expand_url_with_captures(captures: &regex::Captures, mut url: Url) -> Url {
-    let new_path = expand_str_with_captures(captures, url.path()).into_owned();
+fn strip_query_params(uri: &Uri, params: &[(String, Option<String>)]) -> Uri {
+    let query = uri.query().unwrap_or("");
+    let kept: Vec<&str> = query.split('&')
+        .filter(|pair| {
+            match url::form_urlencoded::parse(pair.as_bytes()).next() {
+                Some((key, _)) => !params.iter().any(|(k, _)| *k == key),
+                None => false
+            }
+        })
+        .collect();
+    let path_and_query = if kept.is_empty() {
+        uri.path().to_owned()
+    } else {
+        format!("{}?{}", uri.path(), kept.join("&"))
+    };
+    path_and_query.parse().unwrap_or_else(|_| uri.clone())
+}
+
+fn expand_url_with_captures(captures: &regex::Captures, mut url: Url, is_pattern: bool) -> Url {
+    let new_path = if is_pattern {
+        expand_str_with_groups(captures, url.path())
+    } else {
+        expand_str_with_captures(captures, url.path()).into_owned()
+    };
     url.set_path(&new_path);
     url
 }
 
-fn expand_path_with_captures(captures: &regex::Captures, path: String) -> PathBuf {
+fn expand_path_with_captures(captures: &regex::Captures, path: String, is_pattern: bool) -> PathBuf {
+    if is_pattern {
+        return expand_str_with_groups(captures, &path).into();
+    }
     let new_path = expand_str_with_captures(captures, &path);
     let s: &str = new_path.borrow();
     s.into()
 }
 
+/// Sources given as regular expressions refer to their capture groups
+/// with `$1`, `$name` and so on, rather than with match points:
+fn expand_str_with_groups(captures: &regex::Captures, s: &str) -> String {
+    let mut expanded = String::new();
+    captures.expand(s, &mut expanded);
+    expanded
+}
+
 fn expand_str_with_captures<'a>(captures: &regex::Captures, s: &'a str) -> Cow<'a, str> {
     lazy_static!{
         // Are we matching on parts of the path?
@@ -184,6 +579,7 @@ mod test {
     use url::Url;
     use std::path::PathBuf;
     use crate::location::{ SrcLocation, DestLocation };
+    use crate::options::{ RouteOptions };
 
     use super::*;
 
@@ -191,6 +587,12 @@ mod test {
     fn url (u: &str) -> Url { Url::from_str(u).unwrap() }
     fn resolved_url (u: &str) -> ResolvedLocation { ResolvedLocation::Url(url(u)) }
     fn path (s: &str) -> PathBuf { s.into() }
+    fn resolved_file (p: &str, root: &str) -> ResolvedLocation { ResolvedLocation::FilePath { path: p.into(), root: root.into() } }
+    fn request (s: &str, host: Option<&str>) -> Request<()> {
+        let mut req = Request::builder().uri(s);
+        if let Some(host) = host { req = req.header("host", host); }
+        req.body(()).unwrap()
+    }
 
     #[test]
     fn basic_merging_with_urls() {
@@ -241,14 +643,16 @@ mod test {
         let routes = vec![
             Route {
                 src: SrcLocation::parse("=8080/foo/bar").unwrap(),
-                dest: DestLocation::parse("9090/1").unwrap()
+                dests: vec![DestLocation::parse("9090/1").unwrap()],
+                options: RouteOptions::default()
             },
             // This path is longer, and so can accidentally be sorted
             // before the above if path length is taken into account
             // when it shouldn't be:
             Route {
                 src: SrcLocation::parse("=8080/favicon.ico").unwrap(),
-                dest: DestLocation::parse("9090/2").unwrap()
+                dests: vec![DestLocation::parse("9090/2").unwrap()],
+                options: RouteOptions::default()
             }
         ];
 
@@ -269,24 +673,279 @@ mod test {
         }
     }
 
+    #[test]
+    fn preserve_prefix_keeps_matched_path() {
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse("+8080/api").unwrap(),
+                dests: vec![DestLocation::parse("9090/v1").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse("+=8080/health").unwrap(),
+                dests: vec![DestLocation::parse("9091").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse("+8080/(user)/avatar").unwrap(),
+                dests: vec![DestLocation::parse("./avatars").unwrap()],
+                options: RouteOptions::default()
+            }
+        ];
+
+        let matcher = Matcher::new(routes);
+        let cases = vec![
+            (uri("/api/users?a=1"), Some(resolved_url("http://localhost:9090/v1/api/users?a=1"))),
+            (uri("/health"), Some(resolved_url("http://localhost:9091/health"))),
+            (uri("/health/wibble"), None),
+            (uri("/bob/avatar"), Some(resolved_file("./avatars/bob/avatar", "./avatars"))),
+        ];
+
+        for (uri, expected) in cases {
+            let res = matcher.resolve(&uri);
+            assert_eq!(res, expected, "original URI: {}", uri);
+        }
+    }
+
+    #[test]
+    fn routes_grpc_calls_by_service() {
+        let grpc = RouteOptions { grpc: true, ..RouteOptions::default() };
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse("50051/pkg.Users").unwrap(),
+                dests: vec![DestLocation::parse("h2c://localhost:9001").unwrap()],
+                options: grpc.clone()
+            },
+            Route {
+                src: SrcLocation::parse("50051/pkg.").unwrap(),
+                dests: vec![DestLocation::parse("h2c://localhost:9002").unwrap()],
+                options: grpc
+            },
+            Route {
+                src: SrcLocation::parse("50051").unwrap(),
+                dests: vec![DestLocation::parse("9003").unwrap()],
+                options: RouteOptions::default()
+            }
+        ];
+
+        let matcher = Matcher::new(routes);
+        let call = |path: &str, content_type: &str| {
+            let req = Request::builder().uri(path).header("content-type", content_type).body(()).unwrap();
+            matcher.resolve_with_route(&req).map(|resolved| resolved.location)
+        };
+        assert_eq!(call("/pkg.Users/Get", "application/grpc"), Some(resolved_url("h2c://localhost:9001/pkg.Users/Get")));
+        assert_eq!(call("/pkg.UsersV2/Get", "application/grpc+proto"), Some(resolved_url("h2c://localhost:9002/pkg.UsersV2/Get")));
+        assert_eq!(call("/other.Users/Get", "application/grpc"), Some(resolved_url("http://localhost:9003/other.Users/Get")));
+        assert_eq!(call("/pkg.Users/Get", "application/json"), Some(resolved_url("http://localhost:9003/pkg.Users/Get")));
+    }
+
+    #[test]
+    fn match_on_virtual_hosts() {
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse("8080").unwrap(),
+                dests: vec![DestLocation::parse("./site").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse("api.local:8080").unwrap(),
+                dests: vec![DestLocation::parse("9000").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse("=web.local:8080/health").unwrap(),
+                dests: vec![DestLocation::parse("9001").unwrap()],
+                options: RouteOptions::default()
+            }
+        ];
+
+        let matcher = Matcher::new(routes);
+        let cases = vec![
+            (request("/foo", Some("api.local:8080")), resolved_url("http://localhost:9000/foo")),
+            (request("/foo", Some("API.LOCAL")), resolved_url("http://localhost:9000/foo")),
+            (request("/health", Some("web.local:8080")), resolved_url("http://localhost:9001/")),
+            (request("/foo", Some("web.local:8080")), resolved_file("./site/foo", "./site")),
+            (request("/foo", Some("localhost:8080")), resolved_file("./site/foo", "./site")),
+            (request("/foo", None), resolved_file("./site/foo", "./site")),
+        ];
+
+        for (req, expected) in cases {
+            let res = matcher.resolve_with_route(&req).map(|r| r.location);
+            assert_eq!(res, Some(expected), "original request: {:?}", req);
+        }
+
+        // Virtual hosts are listened for on every interface, but the other
+        // route only on localhost, so only requests that reached it match it:
+        let arriving_at = |addr: SocketAddr, host: &str| {
+            let mut req = request("/foo", Some(host));
+            req.extensions_mut().insert(LocalAddr(addr));
+            matcher.resolve_with_route(&req).map(|r| r.location)
+        };
+        let localhost = matcher.routes.iter().find(|route| route.src.host.is_none()).unwrap().src_socket_addr().unwrap();
+        let elsewhere = "192.0.2.10:8080".parse().unwrap();
+        assert_eq!(arriving_at(elsewhere, "api.local"), Some(resolved_url("http://localhost:9000/foo")));
+        assert_eq!(arriving_at(elsewhere, "localhost"), None);
+        assert_eq!(arriving_at(localhost, "localhost"), Some(resolved_file("./site/foo", "./site")));
+    }
+
+    #[test]
+    fn match_on_methods() {
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse("8080/api").unwrap(),
+                dests: vec![DestLocation::parse("9000").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse("POST:8080/api").unwrap(),
+                dests: vec![DestLocation::parse("9001").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse("GET,PUT:=8080/files").unwrap(),
+                dests: vec![DestLocation::parse("./files").unwrap()],
+                options: RouteOptions::default()
+            }
+        ];
+
+        let matcher = Matcher::new(routes);
+        let req = |method: Method, s: &str| {
+            let mut req = request(s, None);
+            *req.method_mut() = method;
+            req
+        };
+        let cases = vec![
+            (req(Method::POST, "/api/foo"), Some(resolved_url("http://localhost:9001/foo"))),
+            (req(Method::GET, "/api/foo"), Some(resolved_url("http://localhost:9000/foo"))),
+            (req(Method::PUT, "/files"), Some(resolved_file("./files", "./files"))),
+            (req(Method::HEAD, "/files"), Some(resolved_file("./files", "./files"))),
+            (req(Method::DELETE, "/files"), None),
+        ];
+
+        for (req, expected) in cases {
+            let res = matcher.resolve_with_route(&req).map(|r| r.location);
+            assert_eq!(res, expected, "original request: {:?}", req);
+        }
+
+        assert_eq!(matcher.allowed_methods(&req(Method::DELETE, "/files")), vec![Method::GET, Method::PUT]);
+        assert!(matcher.allowed_methods(&req(Method::DELETE, "/nope")).is_empty());
+    }
+
+    #[test]
+    fn match_on_headers() {
+        let mut beta_options = RouteOptions::default();
+        beta_options.set("when-header", "X-Beta: true").unwrap();
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse("8080").unwrap(),
+                dests: vec![DestLocation::parse("9000").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse("8080").unwrap(),
+                dests: vec![DestLocation::parse("9001").unwrap()],
+                options: beta_options
+            }
+        ];
+
+        let matcher = Matcher::new(routes);
+        let mut req = request("/foo", None);
+        assert_eq!(matcher.resolve_with_route(&req).map(|r| r.location), Some(resolved_url("http://localhost:9000/foo")));
+        req.headers_mut().insert("x-beta", "true".parse().unwrap());
+        assert_eq!(matcher.resolve_with_route(&req).map(|r| r.location), Some(resolved_url("http://localhost:9001/foo")));
+        req.headers_mut().insert("x-beta", "false".parse().unwrap());
+        assert_eq!(matcher.resolve_with_route(&req).map(|r| r.location), Some(resolved_url("http://localhost:9000/foo")));
+    }
+
+    #[test]
+    fn match_on_query_params() {
+        let mut strip_options = RouteOptions::default();
+        strip_options.set("strip-matched-query", "true").unwrap();
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse("8080/search").unwrap(),
+                dests: vec![DestLocation::parse("9000").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse("8080/search?engine=v2").unwrap(),
+                dests: vec![DestLocation::parse("9002").unwrap()],
+                options: strip_options
+            },
+            Route {
+                src: SrcLocation::parse("8080/search?debug").unwrap(),
+                dests: vec![DestLocation::parse("9003").unwrap()],
+                options: RouteOptions::default()
+            }
+        ];
+
+        let matcher = Matcher::new(routes);
+        let cases = vec![
+            (uri("/search?q=foo"), resolved_url("http://localhost:9000/?q=foo")),
+            (uri("/search?q=foo&engine=v1"), resolved_url("http://localhost:9000/?q=foo&engine=v1")),
+            (uri("/search?q=foo&engine=v2"), resolved_url("http://localhost:9002/?q=foo")),
+            (uri("/search?engine=v2"), resolved_url("http://localhost:9002/")),
+            (uri("/search?debug&q=foo"), resolved_url("http://localhost:9003/?debug&q=foo")),
+            (uri("/search?debug=1"), resolved_url("http://localhost:9003/?debug=1")),
+        ];
+
+        for (uri, expected) in cases {
+            let res = matcher.resolve(&uri);
+            assert_eq!(res, Some(expected), "original URI: {}", uri);
+        }
+    }
+
+    #[test]
+    fn redirect_with_rest_of_path() {
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse("8080/old").unwrap(),
+                dests: vec![DestLocation::parse("redirect://https://example.com/new?status=301&a=1").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse("8080").unwrap(),
+                dests: vec![DestLocation::parse("redirect://https://example.com").unwrap()],
+                options: RouteOptions::default()
+            }
+        ];
+
+        let matcher = Matcher::new(routes);
+        let cases = vec![
+            (uri("/old/foo?b=2"), ResolvedLocation::Redirect { url: url("https://example.com/new/foo?a=1&b=2"), status: 301 }),
+            (uri("/foo"), ResolvedLocation::Redirect { url: url("https://example.com/foo"), status: 302 }),
+        ];
+
+        for (uri, expected) in cases {
+            let res = matcher.resolve(&uri);
+            assert_eq!(res, Some(expected), "original URI: {}", uri);
+        }
+
+        assert!(DestLocation::parse("redirect://https://example.com?status=200").is_err());
+    }
+
     #[test]
     fn dont_add_trailing_slash_to_exact_match() {
         let routes = vec![
             Route {
                 src: SrcLocation::parse("8080/hello/bar").unwrap(),
-                dest: DestLocation::parse("9090/wibble/bar").unwrap()
+                dests: vec![DestLocation::parse("9090/wibble/bar").unwrap()],
+                options: RouteOptions::default()
             },
             Route {
                 src: SrcLocation::parse("8080/hello/bar.json").unwrap(),
-                dest: DestLocation::parse("9090/wibble/bar.json").unwrap()
+                dests: vec![DestLocation::parse("9090/wibble/bar.json").unwrap()],
+                options: RouteOptions::default()
             },
             Route {
                 src: SrcLocation::parse("=8080/hello/wibble").unwrap(),
-                dest: DestLocation::parse("9090/hi/wibble").unwrap()
+                dests: vec![DestLocation::parse("9090/hi/wibble").unwrap()],
+                options: RouteOptions::default()
             },
             Route {
                 src: SrcLocation::parse("=8080/hello/wibble.json").unwrap(),
-                dest: DestLocation::parse("9090/hi/wibble.json").unwrap()
+                dests: vec![DestLocation::parse("9090/hi/wibble.json").unwrap()],
+                options: RouteOptions::default()
             },
         ];
 
@@ -304,19 +963,51 @@ mod test {
         }
     }
 
+    #[test]
+    fn expand_regex_capture_groups() {
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse(r"8080/articles/(\d+)").unwrap(),
+                dests: vec![DestLocation::parse("http://backend/posts/$1").unwrap()],
+                options: RouteOptions::default()
+            },
+            Route {
+                src: SrcLocation::parse(r"=8080/(?P<user>[a-z]+)/avatar\.(png|jpg)").unwrap(),
+                dests: vec![DestLocation::parse("./avatars/$user.$2").unwrap()],
+                options: RouteOptions::default()
+            }
+        ];
+
+        let matcher = Matcher::new(routes);
+        let cases = vec![
+            (uri("/articles/42"), Some(resolved_url("http://backend/posts/42"))),
+            (uri("/articles/42/comments?page=2"), Some(resolved_url("http://backend/posts/42/comments?page=2"))),
+            (uri("/articles/latest"), None),
+            (uri("/bob/avatar.png"), Some(resolved_file("./avatars/bob.png", "./avatars/bob.png"))),
+            (uri("/bob/avatar.png/wibble"), None),
+        ];
+
+        for (uri, expected) in cases {
+            let res = matcher.resolve(&uri);
+            assert_eq!(res, expected, "original URI: {}", uri);
+        }
+    }
+
     #[test]
     fn match_first_available_regex_pattern() {
         let routes = vec![
             Route {
                 src: SrcLocation::parse("8080/(foo)/bar").unwrap(),
-                dest: DestLocation::parse("9090/bar/(foo)/1").unwrap()
+                dests: vec![DestLocation::parse("9090/bar/(foo)/1").unwrap()],
+                options: RouteOptions::default()
             },
             // This path is longer, and so can accidentally be sorted
             // before the above if path length is taken into account
             // when it shouldn't be:
             Route {
                 src: SrcLocation::parse("8080/(foo)/(bar)").unwrap(),
-                dest: DestLocation::parse("9090/(bar)/(foo)/2").unwrap()
+                dests: vec![DestLocation::parse("9090/(bar)/(foo)/2").unwrap()],
+                options: RouteOptions::default()
             }
         ];
 
@@ -332,13 +1023,15 @@ mod test {
             // This basic prefix route should not be picked:
             Route {
                 src: SrcLocation::parse("8080/hello/bar/").unwrap(),
-                dest: DestLocation::parse("9090/wibble/0/").unwrap()
+                dests: vec![DestLocation::parse("9090/wibble/0/").unwrap()],
+                options: RouteOptions::default()
             },
             // This regex path should be picked, because exact regex routes
             // should always match over prefix routes:
             Route {
                 src: SrcLocation::parse("=8080/(hello)/(bar)/wibble").unwrap(),
-                dest: DestLocation::parse("9090/wibble/1/").unwrap()
+                dests: vec![DestLocation::parse("9090/wibble/1/").unwrap()],
+                options: RouteOptions::default()
             },
         ];
 
@@ -354,12 +1047,14 @@ mod test {
             // This basic prefix route should not be picked:
             Route {
                 src: SrcLocation::parse("8080/foo").unwrap(),
-                dest: DestLocation::parse("9090/1").unwrap()
+                dests: vec![DestLocation::parse("9090/1").unwrap()],
+                options: RouteOptions::default()
             },
             // This shorter but exact route should be picked:
             Route {
                 src: SrcLocation::parse("=8080/foo").unwrap(),
-                dest: DestLocation::parse("9090/2").unwrap()
+                dests: vec![DestLocation::parse("9090/2").unwrap()],
+                options: RouteOptions::default()
             },
         ];
 
@@ -369,6 +1064,74 @@ mod test {
         assert_eq!(res, Some(expected));
     }
 
+    #[test]
+    fn rotate_between_destinations() {
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse("8080/foo").unwrap(),
+                dests: vec![
+                    DestLocation::parse("9090/a").unwrap(),
+                    DestLocation::parse("9091/b").unwrap(),
+                ],
+                options: RouteOptions::default()
+            },
+            // Not matching this route shouldn't affect the rotation:
+            Route {
+                src: SrcLocation::parse("=8080/bar").unwrap(),
+                dests: vec![
+                    DestLocation::parse("9090/bar").unwrap(),
+                    DestLocation::parse("9091/bar").unwrap(),
+                ],
+                options: RouteOptions::default()
+            },
+        ];
+
+        let matcher = Matcher::new(routes);
+        let cases = vec![
+            (uri("/foo/1"), resolved_url("http://localhost:9090/a/1")),
+            (uri("/foo/2"), resolved_url("http://localhost:9091/b/2")),
+            (uri("/foo/3"), resolved_url("http://localhost:9090/a/3")),
+            (uri("/bar"), resolved_url("http://localhost:9090/bar")),
+            (uri("/foo/4"), resolved_url("http://localhost:9091/b/4")),
+        ];
+
+        for (uri, expected) in cases {
+            let res = matcher.resolve(&uri);
+            assert_eq!(res, Some(expected), "original URI: {}", uri);
+        }
+    }
+
+    #[test]
+    fn skip_unhealthy_destinations() {
+        let routes = vec![
+            Route {
+                src: SrcLocation::parse("8080/foo").unwrap(),
+                dests: vec![
+                    DestLocation::parse("9090/a").unwrap(),
+                    DestLocation::parse("9091/b").unwrap(),
+                    DestLocation::parse("9092/c").unwrap(),
+                ],
+                options: RouteOptions::default()
+            },
+        ];
+
+        let matcher = Matcher::new(routes);
+        let flags: Vec<_> = matcher.health_flags().map(|(_, _, flag)| flag.upgrade().unwrap()).collect();
+        flags[1].store(false, AtomicOrdering::Relaxed);
+
+        let res: Vec<_> = (0..4).map(|_| matcher.resolve_with_route(&request("/foo", None)).unwrap()).collect();
+        assert_eq!(res[0].location, resolved_url("http://localhost:9090/a"));
+        assert_eq!(res[1].location, resolved_url("http://localhost:9092/c"));
+        assert_eq!(res[2].location, resolved_url("http://localhost:9092/c"));
+        assert_eq!(res[3].location, resolved_url("http://localhost:9090/a"));
+        assert!(res.iter().all(|r| r.healthy));
+
+        // If nothing is healthy we still pick something, but say so:
+        flags.iter().for_each(|flag| flag.store(false, AtomicOrdering::Relaxed));
+        let res = matcher.resolve_with_route(&request("/foo", None)).unwrap();
+        assert!(!res.healthy);
+    }
+
     #[test]
     fn regex_with_urls() {
         let routes = vec![
@@ -376,38 +1139,45 @@ mod test {
             // in favour of exact regex ones where applicable:
             Route {
                 src: SrcLocation::parse("8080/hello/bar/").unwrap(),
-                dest: DestLocation::parse("9090/wibble/0/").unwrap()
+                dests: vec![DestLocation::parse("9090/wibble/0/").unwrap()],
+                options: RouteOptions::default()
             },
             // Regex based but *not* exact (no trailing '='), so should
             // be less specific than all of the below:
             Route {
                 src: SrcLocation::parse("8080/(foo)/bar").unwrap(),
-                dest: DestLocation::parse("9090/bar/(foo)/nonexact").unwrap()
+                dests: vec![DestLocation::parse("9090/bar/(foo)/nonexact").unwrap()],
+                options: RouteOptions::default()
             },
             Route {
                 src: SrcLocation::parse("=8080/(foo)/bar").unwrap(),
-                dest: DestLocation::parse("9090/bar/(foo)/1").unwrap()
+                dests: vec![DestLocation::parse("9090/bar/(foo)/1").unwrap()],
+                options: RouteOptions::default()
             },
             // Multiple captures helps test that we have built up the
             // right regex to match on in the first place, since greediness
             // can lead to only the last capture being spotted:
             Route {
                 src: SrcLocation::parse("=8080/(foo)/(bar)").unwrap(),
-                dest: DestLocation::parse("9090/(bar)/(foo)/2").unwrap()
+                dests: vec![DestLocation::parse("9090/(bar)/(foo)/2").unwrap()],
+                options: RouteOptions::default()
             },
             Route {
                 src: SrcLocation::parse("=8080/(foo)/(bar)/wibble").unwrap(),
-                dest: DestLocation::parse("9090/wibble/(bar)/(foo).json3").unwrap()
+                dests: vec![DestLocation::parse("9090/wibble/(bar)/(foo).json3").unwrap()],
+                options: RouteOptions::default()
             },
             // This should capture anything with at least one '/' in the middle:
             Route {
                 src: SrcLocation::parse("=8080/(foo..)/(bar)/boom").unwrap(),
-                dest: DestLocation::parse("9090/boom/(bar)/(foo)/4").unwrap()
+                dests: vec![DestLocation::parse("9090/boom/(bar)/(foo)/4").unwrap()],
+                options: RouteOptions::default()
             },
             // This should capture anything with 'BOOM' in the middle
             Route {
                 src: SrcLocation::parse("=8080/(foo..)/BOOM/(bar..)").unwrap(),
-                dest: DestLocation::parse("9090/(foo)/exploding/(bar)").unwrap()
+                dests: vec![DestLocation::parse("9090/(foo)/exploding/(bar)").unwrap()],
+                options: RouteOptions::default()
             },
         ];
 
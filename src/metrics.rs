@@ -0,0 +1,194 @@
+use hyper::{ Method, Request, Response, StatusCode };
+use hyper::header::{ CONTENT_TYPE };
+use lazy_static::lazy_static;
+use log::{ info };
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::Instant;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::listeners;
+
+/// The upper bounds of the buckets that response times are counted in, in
+/// seconds:
+const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// We only keep track of anything once we've been asked to serve metrics:
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+/// Added to the extensions of responses from a destination, naming which
+/// of the route's destinations it was, to label metrics with.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Destination(pub String);
+
+/// Everything we've counted so far, labelled by route (and destination,
+/// once we know it). Requests that don't match a route have an empty route
+/// label, and those that aren't sent to a destination an empty
+/// destination label.
+#[derive(Debug,Default)]
+struct Registry {
+    in_flight: BTreeMap<String, i64>,
+    /// By route, destination and class of status (eg `2xx`):
+    requests: BTreeMap<(String, String, String), u64>,
+    /// By route and destination:
+    durations: BTreeMap<(String, String), Histogram>
+}
+
+#[derive(Debug,Default)]
+struct Histogram {
+    /// How many responses took at most as long as each bucket allows:
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64
+}
+
+/// A request being handled, which counts as in flight until finished with.
+pub struct Timer {
+    route: String,
+    started: Instant
+}
+
+/// Start counting a request to a route (or to no route, if it didn't match
+/// one). Hands back nothing if we're not serving metrics:
+pub fn start(route: Option<&str>) -> Option<Timer> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let route = route.unwrap_or("").to_owned();
+    *REGISTRY.lock().unwrap().in_flight.entry(route.clone()).or_default() += 1;
+    Some(Timer { route, started: Instant::now() })
+}
+
+impl Timer {
+    /// Count the response to the request:
+    pub fn finish(self, dest: Option<&str>, status: u16) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let dest = dest.unwrap_or("").to_owned();
+        let class = format!("{}xx", (status / 100).clamp(1, 5));
+        let mut registry = REGISTRY.lock().unwrap();
+        *registry.requests.entry((self.route.clone(), dest.clone(), class)).or_default() += 1;
+        let histogram = registry.durations.entry((self.route.clone(), dest)).or_default();
+        for (count, bound) in histogram.buckets.iter_mut().zip(BUCKETS) {
+            if elapsed <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.sum += elapsed;
+        histogram.count += 1;
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(in_flight) = REGISTRY.lock().unwrap().in_flight.get_mut(&self.route) {
+            *in_flight -= 1;
+        }
+    }
+}
+
+/// Serve metrics at `/metrics` on the address provided, until we exit:
+pub fn spawn(addr: SocketAddr) -> Result<(), Error> {
+    listeners::serve(&addr, |req| async move { handle(&req) })
+        .map_err(|e| err!("Failed to listen on {} for metrics: {}", addr, e))?;
+    ENABLED.store(true, Ordering::Relaxed);
+    info!("Serving metrics on {}/metrics", addr);
+    Ok(())
+}
+
+fn handle<T>(req: &Request<T>) -> Response<Body> {
+    if req.uri().path() != "/metrics" {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Weave: Metrics are served at /metrics"))
+            .unwrap();
+    }
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header("allow", "GET, HEAD")
+            .body(Body::from("Weave: Method not allowed"))
+            .unwrap();
+    }
+    Response::builder()
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(REGISTRY.lock().unwrap().render()))
+        .unwrap()
+}
+
+impl Registry {
+    /// Write everything out in the Prometheus text format:
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP weave_requests_in_flight Requests being handled right now.\n");
+        out.push_str("# TYPE weave_requests_in_flight gauge\n");
+        for (route, count) in &self.in_flight {
+            let _ = writeln!(out, "weave_requests_in_flight{{route=\"{}\"}} {}", escape(route), count);
+        }
+
+        out.push_str("# HELP weave_requests_total Requests handled, by class of response status.\n");
+        out.push_str("# TYPE weave_requests_total counter\n");
+        for ((route, dest, class), count) in &self.requests {
+            let _ = writeln!(out, "weave_requests_total{{{},status=\"{}\"}} {}", labels(route, dest), class, count);
+        }
+
+        out.push_str("# HELP weave_request_duration_seconds How long responses took to be ready.\n");
+        out.push_str("# TYPE weave_request_duration_seconds histogram\n");
+        for ((route, dest), histogram) in &self.durations {
+            let labels = labels(route, dest);
+            for (count, bound) in histogram.buckets.iter().zip(BUCKETS) {
+                let _ = writeln!(out, "weave_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+            }
+            let _ = writeln!(out, "weave_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count);
+            let _ = writeln!(out, "weave_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+            let _ = writeln!(out, "weave_request_duration_seconds_count{{{}}} {}", labels, histogram.count);
+        }
+
+        out
+    }
+}
+
+fn labels(route: &str, dest: &str) -> String {
+    format!("route=\"{}\",destination=\"{}\"", escape(route), escape(dest))
+}
+
+/// Escape a label value, as the text format asks:
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn renders_metrics() {
+        let mut registry = Registry::default();
+        registry.in_flight.insert("8080/a".to_owned(), 1);
+        registry.requests.insert(("8080/a".to_owned(), "9000".to_owned(), "2xx".to_owned()), 3);
+        registry.durations.insert(("8080/a".to_owned(), "9000".to_owned()), Histogram {
+            buckets: [0, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2],
+            sum: 0.5,
+            count: 3
+        });
+        let out = registry.render();
+        assert!(out.contains("weave_requests_in_flight{route=\"8080/a\"} 1\n"));
+        assert!(out.contains("weave_requests_total{route=\"8080/a\",destination=\"9000\",status=\"2xx\"} 3\n"));
+        assert!(out.contains("weave_request_duration_seconds_bucket{route=\"8080/a\",destination=\"9000\",le=\"0.01\"} 1\n"));
+        assert!(out.contains("weave_request_duration_seconds_bucket{route=\"8080/a\",destination=\"9000\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("weave_request_duration_seconds_count{route=\"8080/a\",destination=\"9000\"} 3\n"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
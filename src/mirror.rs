@@ -0,0 +1,89 @@
+use ansi_term::Color::{ Red };
+use hyper::{ Request };
+use log::{ debug, warn };
+use std::time::Duration;
+use tokio::time;
+use url::Url;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::options::{ RouteOptions, TlsOptions };
+use crate::proxy;
+use crate::HttpsClient;
+
+/// How long we wait for a mirror to respond before giving up on it:
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parse the URL of a mirror. It's just a scheme, host and port, since
+/// mirrored requests have the same path and query as the originals.
+pub fn parse_url(input: &str) -> Result<Url, Error> {
+    let url = Url::parse(input).map_err(|e| err!("'{}' is not a valid URL: {}", input, e))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(err!("Expecting an http:// or https:// URL but got '{}'", input));
+    }
+    if url.path() != "/" || url.query().is_some() {
+        return Err(err!("Expecting a URL without a path or query but got '{}'", input));
+    }
+    Ok(url)
+}
+
+/// Send a copy of a request on its way to `dest` to the route's mirror as
+/// well, if it has one. We don't wait for (or care about) the mirror's
+/// response, beyond logging any failures. The body needs buffering to be
+/// sent twice, so this hands back a request to use instead.
+pub async fn mirror(req: Request<Body>, dest: &Url, options: &RouteOptions, client: &HttpsClient) -> Result<Request<Body>, Error> {
+    let mirror = match &options.mirror {
+        Some(mirror) => mirror,
+        None => return Ok(req)
+    };
+
+    let (parts, body) = req.into_parts();
+    let body = proxy::read_body(body).await?;
+
+    let mut url = mirror.clone();
+    url.set_path(dest.path());
+    url.set_query(dest.query());
+    let mut copy = proxy::clone_request(&parts, &body);
+    *copy.uri_mut() = format!("{}", url).parse().unwrap();
+    if !options.preserve_host {
+        copy.headers_mut().remove("host");
+    }
+    tokio::spawn(send(copy, url, client.clone(), options.tls.clone()));
+
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+async fn send(req: Request<Body>, url: Url, client: HttpsClient, tls: TlsOptions) {
+    // Read the whole response, so that the connection can be reused:
+    let sent = time::timeout(MIRROR_TIMEOUT, async {
+        let resp = client.request(req, &tls).await?;
+        let status = resp.status();
+        proxy::read_body(resp.into_body()).await?;
+        Ok::<_, Error>(status)
+    });
+
+    match sent.await {
+        Ok(Ok(status)) => {
+            debug!("[mirror] {} responded with {}", url, status);
+        },
+        Ok(Err(e)) => {
+            warn!("{}", Red.paint(format!("[mirror] {} failed: {}", url, e)));
+        },
+        Err(_) => {
+            warn!("{}", Red.paint(format!("[mirror] {} timed out after {:#?}", url, MIRROR_TIMEOUT)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_mirror_urls() {
+        assert_eq!(parse_url("http://localhost:8081").unwrap().as_str(), "http://localhost:8081/");
+        assert!(parse_url("https://shadow.example.com/").is_ok());
+        assert!(parse_url("http://localhost:8081/v2").is_err());
+        assert!(parse_url("ftp://localhost").is_err());
+    }
+}
@@ -0,0 +1,460 @@
+use hyper::{ Method, Request, Response, Uri };
+use hyper::header::{ ACCEPT, CONTENT_TYPE, LOCATION, SET_COOKIE };
+use lazy_static::lazy_static;
+use ring::{ digest, hmac };
+use ring::rand::{ SecureRandom, SystemRandom };
+use serde::{ Deserialize, Serialize };
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::time;
+use url::{ Url, form_urlencoded };
+use crate::body::{ Body };
+use crate::client::{ HttpsClient, ResponseFuture };
+use crate::errors::{ Error };
+use crate::headers::{ cookie };
+use crate::jwt;
+use crate::options::{ TlsOptions };
+use crate::proxy;
+
+/// Holds the signed session of a user that has logged in:
+const SESSION_COOKIE: &str = "weave_session";
+/// Holds the state of a login that's in progress. Both cookies are named
+/// after the route's issuer and client ID too:
+const STATE_COOKIE: &str = "weave_oidc_state";
+/// How long users stay logged in for:
+const SESSION_DURATION: Duration = Duration::from_secs(12 * 60 * 60);
+/// How long users have to log in with the provider:
+const STATE_DURATION: Duration = Duration::from_secs(10 * 60);
+/// How long we wait for the provider to respond:
+const OIDC_TIMEOUT: Duration = Duration::from_secs(10);
+/// Headers we hand to destinations describing who is logged in:
+const USER_HEADER: &str = "x-forwarded-user";
+const EMAIL_HEADER: &str = "x-forwarded-email";
+
+lazy_static! {
+    /// The endpoints of each provider, found using OpenID Connect discovery:
+    static ref PROVIDERS: Mutex<HashMap<String, Provider>> = Mutex::new(HashMap::new());
+    /// Signs session cookies for routes without an `oidc-cookie-secret`.
+    /// Sessions signed with this don't survive a restart:
+    static ref RANDOM_SECRET: Vec<u8> = {
+        let mut secret = vec![0; 32];
+        SystemRandom::new().fill(&mut secret).expect("random numbers are available");
+        secret
+    };
+}
+
+/// Make users log in with an OpenID Connect provider before they can use
+/// a route, handling the provider's callback ourselves and keeping track
+/// of who is logged in with a signed session cookie.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct OidcOptions {
+    /// The provider's issuer URL. If not set, users needn't log in:
+    pub issuer: Option<Url>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    /// Where the provider sends users back to after logging in. Its path
+    /// must be matched by the route:
+    pub redirect_url: Option<Url>,
+    /// The secret that session cookies are signed with:
+    pub cookie_secret: Option<String>
+}
+
+impl OidcOptions {
+    /// Complain if some of the options we need are missing:
+    pub fn check(&self) -> Result<(), Error> {
+        if self.issuer.is_none() {
+            if *self != OidcOptions::default() {
+                return Err(err!("The oidc options need an 'oidc-issuer'"));
+            }
+            return Ok(());
+        }
+        if self.client_id.is_none() {
+            return Err(err!("'oidc-issuer' needs an 'oidc-client-id'"));
+        }
+        if self.redirect_url.is_none() {
+            return Err(err!("'oidc-issuer' needs an 'oidc-redirect-url'"));
+        }
+        Ok(())
+    }
+
+    /// Cookies are signed with a key of their own for each issuer and
+    /// client ID, so that one route can't be logged in to with another's:
+    fn cookie_key(&self) -> hmac::Key {
+        let secret = match &self.cookie_secret {
+            Some(secret) => secret.as_bytes(),
+            None => &RANDOM_SECRET
+        };
+        let key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret), self.client().as_bytes());
+        hmac::Key::new(hmac::HMAC_SHA256, key.as_ref())
+    }
+
+    /// Name one of our cookies after the issuer and client ID, so that
+    /// routes don't overwrite each other's:
+    fn cookie_name(&self, name: &str) -> String {
+        let hash = digest::digest(&digest::SHA256, self.client().as_bytes());
+        let suffix: String = hash.as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}_{}", name, suffix)
+    }
+
+    fn client(&self) -> String {
+        let issuer = self.issuer.as_ref().map(|i| i.as_str()).unwrap_or_default();
+        format!("{} {}", issuer, self.client_id.as_deref().unwrap_or_default())
+    }
+}
+
+/// Parse an `http://` or `https://` URL for one of the oidc options:
+pub fn parse_url(input: &str) -> Result<Url, Error> {
+    let url = Url::parse(input).map_err(|e| err!("'{}' is not a valid URL: {}", input, e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        _ => Err(err!("Expecting an http:// or https:// URL but got '{}'", input))
+    }
+}
+
+/// The signed contents of the session cookie:
+#[derive(Debug,PartialEq,Serialize,Deserialize)]
+struct Session {
+    sub: String,
+    email: Option<String>,
+    /// The issuer and client ID of the route that the user logged in to:
+    iss: String,
+    aud: String,
+    exp: u64
+}
+
+/// The signed contents of the state cookie, set while logging in:
+#[derive(Debug,PartialEq,Serialize,Deserialize)]
+struct State {
+    state: String,
+    url: String,
+    exp: u64
+}
+
+#[derive(Debug,Clone,Deserialize)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String
+}
+
+/// Let the request through if the user has logged in (telling the
+/// destination who they are), handle the provider's callback, or send
+/// browsers off to log in. Other clients just get a 401.
+pub async fn authenticate(req: &mut Request<Body>, options: &OidcOptions, client: &HttpsClient, tls: &TlsOptions) -> Result<Option<Response<Body>>, Error> {
+    let (issuer, client_id, redirect_url) = match (&options.issuer, &options.client_id, &options.redirect_url) {
+        (Some(issuer), Some(client_id), Some(redirect_url)) => (issuer, client_id, redirect_url),
+        _ => return Ok(None)
+    };
+    let key = options.cookie_key();
+    let session_cookie = options.cookie_name(SESSION_COOKIE);
+
+    // Only we get to say who the user is:
+    req.headers_mut().remove(USER_HEADER);
+    req.headers_mut().remove(EMAIL_HEADER);
+
+    if req.uri().path() == redirect_url.path() {
+        return callback(req, options, client, tls).await.map(Some);
+    }
+
+    let session: Option<Session> = cookie(req.headers(), &session_cookie).and_then(|c| unsign(&key, c));
+    let session = session.filter(|s| s.exp > unix_now() && s.iss == issuer.as_str() && s.aud == *client_id);
+    if let Some(session) = session {
+        if let Ok(user) = session.sub.parse() {
+            req.headers_mut().insert(USER_HEADER, user);
+        }
+        if let Some(Ok(email)) = session.email.map(|e| e.parse()) {
+            req.headers_mut().insert(EMAIL_HEADER, email);
+        }
+        return Ok(None);
+    }
+
+    let wants_html = match req.headers().get(ACCEPT).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept.contains("text/html"),
+        None => false
+    };
+    if !wants_html {
+        let response = Response::builder()
+            .status(401)
+            .body(Body::from("Weave: Login required"))
+            .unwrap();
+        return Ok(Some(response));
+    }
+
+    let provider = provider(issuer, client, tls).await?;
+    let state = State {
+        state: random_string(),
+        url: local_url(req.uri()).to_owned(),
+        exp: unix_now() + STATE_DURATION.as_secs()
+    };
+    let mut login_url = Url::parse(&provider.authorization_endpoint)?;
+    login_url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", client_id)
+        .append_pair("redirect_uri", redirect_url.as_str())
+        .append_pair("scope", "openid email profile")
+        .append_pair("state", &state.state);
+
+    let response = Response::builder()
+        .status(302)
+        .header(LOCATION, login_url.as_str())
+        .header(SET_COOKIE, set_cookie(&options.cookie_name(STATE_COOKIE), &sign(&key, &state), STATE_DURATION, redirect_url))
+        .body(Body::empty())
+        .unwrap();
+    Ok(Some(response))
+}
+
+/// The provider sends users back here with a code, which we exchange for
+/// an ID token to find out who they are:
+async fn callback(req: &Request<Body>, options: &OidcOptions, client: &HttpsClient, tls: &TlsOptions) -> Result<Response<Body>, Error> {
+    let (issuer, client_id, redirect_url) = match (&options.issuer, &options.client_id, &options.redirect_url) {
+        (Some(issuer), Some(client_id), Some(redirect_url)) => (issuer, client_id, redirect_url),
+        _ => return Err(err!("OpenID Connect is not configured"))
+    };
+    let key = options.cookie_key();
+    let query: HashMap<String, String> = form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+        .into_owned()
+        .collect();
+
+    if let Some(error) = query.get("error") {
+        return Ok(bad_login(401, &format!("the provider said '{}'", error)));
+    }
+    let state: Option<State> = cookie(req.headers(), &options.cookie_name(STATE_COOKIE)).and_then(|c| unsign(&key, c));
+    let state = match state {
+        Some(state) if state.exp > unix_now() && query.get("state") == Some(&state.state) => state,
+        _ => return Ok(bad_login(400, "the login has expired, or was started elsewhere"))
+    };
+    let code = match query.get("code") {
+        Some(code) => code,
+        None => return Ok(bad_login(400, "no code was given"))
+    };
+
+    let provider = provider(issuer, client, tls).await?;
+    let form = {
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", redirect_url.as_str())
+            .append_pair("client_id", client_id);
+        if let Some(secret) = &options.client_secret {
+            form.append_pair("client_secret", secret);
+        }
+        form.finish()
+    };
+    let token_req = Request::builder()
+        .method(Method::POST)
+        .uri(provider.token_endpoint.parse::<Uri>()?)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(ACCEPT, "application/json")
+        .body(Body::from(form))
+        .unwrap();
+    let token: TokenResponse = fetch_json(client.request(token_req, tls), "token endpoint").await?;
+
+    let claims = jwt::verify_jwks_token(&token.id_token, &provider.jwks_uri.parse()?, client, tls).await
+        .map_err(|e| err!("Invalid ID token: {}", e))?;
+    if claims.get("iss").and_then(|v| v.as_str()) != Some(provider.issuer.as_str()) {
+        return Err(err!("Invalid ID token: it was issued by someone else"));
+    }
+    let audience_ok = match claims.get("aud") {
+        Some(Value::String(aud)) => aud == client_id,
+        Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(client_id.as_str())),
+        _ => false
+    };
+    if !audience_ok {
+        return Err(err!("Invalid ID token: it's for another client"));
+    }
+
+    let session = Session {
+        sub: claims.get("sub").and_then(|v| v.as_str()).unwrap_or_default().to_owned(),
+        email: claims.get("email").and_then(|v| v.as_str()).map(|e| e.to_owned()),
+        iss: issuer.as_str().to_owned(),
+        aud: client_id.to_owned(),
+        exp: unix_now() + SESSION_DURATION.as_secs()
+    };
+    let response = Response::builder()
+        .status(302)
+        .header(LOCATION, state.url.as_str())
+        .header(SET_COOKIE, set_cookie(&options.cookie_name(SESSION_COOKIE), &sign(&key, &session), SESSION_DURATION, redirect_url))
+        .header(SET_COOKIE, set_cookie(&options.cookie_name(STATE_COOKIE), "", Duration::from_secs(0), redirect_url))
+        .body(Body::empty())
+        .unwrap();
+    Ok(response)
+}
+
+/// Where to send users back to after logging in. Only paths on this site
+/// are allowed, so `//elsewhere.com/` can't be used as an open redirect:
+fn local_url(uri: &Uri) -> &str {
+    match uri.path_and_query().map(|p| p.as_str()) {
+        Some(url) if uri.authority().is_none() && url.starts_with('/') && !url.starts_with("//") && !url.starts_with("/\\") => url,
+        _ => "/"
+    }
+}
+
+fn bad_login(status: u16, reason: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(format!("Weave: Login failed ({})", reason)))
+        .unwrap()
+}
+
+/// Find the provider's endpoints, remembering them for next time:
+async fn provider(issuer: &Url, client: &HttpsClient, tls: &TlsOptions) -> Result<Provider, Error> {
+    if let Some(provider) = PROVIDERS.lock().unwrap().get(issuer.as_str()) {
+        return Ok(provider.clone());
+    }
+    let discovery = format!("{}/.well-known/openid-configuration", issuer.as_str().trim_end_matches('/'));
+    let provider: Provider = fetch_json(client.get(discovery.parse()?, tls), "discovery endpoint").await?;
+    PROVIDERS.lock().unwrap().insert(issuer.as_str().to_owned(), provider.clone());
+    Ok(provider)
+}
+
+async fn fetch_json<T: DeserializeOwned>(res: ResponseFuture, what: &str) -> Result<T, Error> {
+    let res = time::timeout(OIDC_TIMEOUT, res).await
+        .map_err(|_| err!("The provider's {} timed out after {:#?}", what, OIDC_TIMEOUT))??;
+    let status = res.status();
+    let body = proxy::read_body(res.into_body()).await?;
+    if !status.is_success() {
+        return Err(err!("The provider's {} responded with {}: {}", what, status, String::from_utf8_lossy(&body)));
+    }
+    serde_json::from_slice(&body).map_err(|e| err!("The provider's {} responded with unexpected JSON: {}", what, e))
+}
+
+fn set_cookie(name: &str, value: &str, max_age: Duration, redirect_url: &Url) -> String {
+    let secure = if redirect_url.scheme() == "https" { "; Secure" } else { "" };
+    format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}", name, value, max_age.as_secs(), secure)
+}
+
+/// Serialize something, and sign it so that it can't be tampered with:
+fn sign<T: Serialize>(key: &hmac::Key, value: &T) -> String {
+    let payload = base64::encode_config(&serde_json::to_vec(value).expect("cookies serialize to JSON"), base64::URL_SAFE_NO_PAD);
+    let tag = hmac::sign(key, payload.as_bytes());
+    format!("{}.{}", payload, base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD))
+}
+
+fn unsign<T: DeserializeOwned>(key: &hmac::Key, signed: &str) -> Option<T> {
+    let idx = signed.rfind('.')?;
+    let (payload, tag) = (&signed[..idx], &signed[idx+1..]);
+    let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD).ok()?;
+    hmac::verify(key, payload.as_bytes(), &tag).ok()?;
+    serde_json::from_slice(&base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?).ok()
+}
+
+fn random_string() -> String {
+    let mut bytes = [0; 16];
+    SystemRandom::new().fill(&mut bytes).expect("random numbers are available");
+    base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn signs_cookies() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"s3cret");
+        let session = Session { sub: "alice".to_owned(), email: None, iss: "https://accounts.example.com/".to_owned(), aud: "weave".to_owned(), exp: 100 };
+        let signed = sign(&key, &session);
+        assert_eq!(unsign::<Session>(&key, &signed).as_ref(), Some(&session));
+
+        let other_key = hmac::Key::new(hmac::HMAC_SHA256, b"wibble");
+        assert_eq!(unsign::<Session>(&other_key, &signed), None);
+        let tampered = sign(&key, &Session { sub: "mallory".to_owned(), ..session });
+        let tampered = format!("{}.{}", tampered.split('.').next().unwrap(), signed.split('.').nth(1).unwrap());
+        assert_eq!(unsign::<Session>(&key, &tampered), None);
+    }
+
+    #[test]
+    fn complains_about_missing_options() {
+        assert!(OidcOptions::default().check().is_ok());
+        let mut options = OidcOptions { issuer: Some(parse_url("https://accounts.example.com").unwrap()), ..OidcOptions::default() };
+        assert!(options.check().is_err());
+        options.client_id = Some("weave".to_owned());
+        assert!(options.check().is_err());
+        options.redirect_url = Some(parse_url("https://dash.example.com/oauth2/callback").unwrap());
+        assert!(options.check().is_ok());
+        assert!(OidcOptions { client_id: Some("weave".to_owned()), ..OidcOptions::default() }.check().is_err());
+    }
+
+    fn options(issuer: &str, client_id: &str) -> OidcOptions {
+        OidcOptions {
+            issuer: Some(parse_url(issuer).unwrap()),
+            client_id: Some(client_id.to_owned()),
+            redirect_url: Some(parse_url("https://dash.example.com/oauth2/callback").unwrap()),
+            ..OidcOptions::default()
+        }
+    }
+
+    fn user(options: &OidcOptions, cookie: &str) -> Result<Option<String>, u16> {
+        let mut req = Request::builder()
+            .uri("https://dash.example.com/")
+            .header("cookie", cookie)
+            .body(Body::empty())
+            .unwrap();
+        let client = HttpsClient::new(&crate::settings::Settings::default()).unwrap();
+        let response = tokio::runtime::Runtime::new().unwrap()
+            .block_on(authenticate(&mut req, options, &client, &TlsOptions::default()))
+            .unwrap();
+        match response {
+            Some(response) => Err(response.status().as_u16()),
+            None => Ok(req.headers().get(USER_HEADER).map(|v| v.to_str().unwrap().to_owned()))
+        }
+    }
+
+    #[test]
+    fn only_accepts_sessions_for_the_same_route() {
+        let alice = |options: &OidcOptions| Session {
+            sub: "alice".to_owned(),
+            email: None,
+            iss: options.issuer.as_ref().unwrap().as_str().to_owned(),
+            aud: options.client_id.clone().unwrap(),
+            exp: unix_now() + 60
+        };
+        let cookie = |options: &OidcOptions, session: &Session| {
+            format!("{}={}", options.cookie_name(SESSION_COOKIE), sign(&options.cookie_key(), session))
+        };
+        let dash = options("https://accounts.example.com", "dash");
+        let admin = options("https://accounts.example.com", "admin");
+        let other = options("https://other.example.com", "dash");
+        assert_ne!(dash.cookie_name(SESSION_COOKIE), admin.cookie_name(SESSION_COOKIE));
+        assert_ne!(dash.cookie_name(SESSION_COOKIE), other.cookie_name(SESSION_COOKIE));
+
+        let logged_in = cookie(&dash, &alice(&dash));
+        assert_eq!(user(&dash, &logged_in), Ok(Some("alice".to_owned())));
+        assert_eq!(user(&admin, &logged_in), Err(401));
+        assert_eq!(user(&other, &logged_in), Err(401));
+
+        // Even with the same name and secret, the keys differ:
+        let secret = |options: &OidcOptions| OidcOptions { cookie_secret: Some("s3cret".to_owned()), ..options.clone() };
+        let renamed = format!("{}={}", admin.cookie_name(SESSION_COOKIE), sign(&secret(&dash).cookie_key(), &alice(&dash)));
+        assert_eq!(user(&secret(&admin), &renamed), Err(401));
+        // And a session signed for another route's issuer and client is refused:
+        let forged = cookie(&secret(&admin), &alice(&dash));
+        assert_eq!(user(&secret(&admin), &forged), Err(401));
+        let expired = cookie(&dash, &Session { exp: unix_now() - 1, ..alice(&dash) });
+        assert_eq!(user(&dash, &expired), Err(401));
+    }
+
+    #[test]
+    fn only_sends_users_back_to_this_site() {
+        let url = |uri: &str| local_url(&uri.parse().unwrap()).to_owned();
+        assert_eq!(url("/dashboard?tab=1"), "/dashboard?tab=1");
+        assert_eq!(url("/"), "/");
+        assert_eq!(url("//evil.com/x"), "/");
+        assert_eq!(url("/\\evil.com/x"), "/");
+        assert_eq!(url("https://evil.com/x"), "/");
+        assert_eq!(url("*"), "/");
+    }
+}
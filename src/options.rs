@@ -0,0 +1,699 @@
+use hyper::header::HeaderName;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+use crate::api_keys::{ ApiKeys };
+use crate::auth::{ BasicAuth };
+use crate::cache::{ CacheOptions };
+use crate::chaos::{ Chaos, Fault };
+use crate::compress::{ CompressOptions };
+use crate::concurrency::{ DEFAULT_MAX_QUEUED };
+use crate::cors::{ CorsOptions };
+use crate::delay::{ Delay };
+use crate::diff::{ DiffOptions };
+use crate::error_pages::{ ErrorPage };
+use crate::errors::{ Error };
+use crate::forward_auth::{ ForwardAuth };
+use crate::graphql::{ GraphqlMatch, OperationType };
+use crate::headers::{ HeaderRules, HeaderRule, HeaderMatch };
+use crate::ip_filter::{ Cidr, IpFilter };
+use crate::jwt::{ self, JwtKey, JwtOptions };
+use crate::maintenance::{ Maintenance };
+use crate::mirror;
+use crate::oidc::{ self, OidcOptions };
+use crate::plugins::{ Plugin };
+use crate::rate_limit::{ Rate, RateLimit, RateLimitKey };
+use crate::replace::{ ReplaceOptions, Replacement };
+use crate::rewrite::{ CookieOptions };
+use crate::scripts::{ Script };
+use crate::security::{ SecurityHeaders };
+use crate::settings::{ parse_size };
+use crate::sticky::{ Sticky };
+use crate::transform::{ Transform, TransformOptions };
+
+/// How often we check the health of destinations, if not provided:
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+/// How long we wait for a health check to respond, if not provided:
+pub const DEFAULT_HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a tripped circuit breaker refuses requests for, if not provided:
+pub const DEFAULT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+/// How long we wait before the first retry, if not provided. This doubles
+/// with each subsequent retry:
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Options that can be set on individual routes, either on the command
+/// line (`SOURCE to DEST with KEY=VALUE ...`) or in a config file, as
+/// extra keys alongside `src` and `dest`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct RouteOptions {
+    /// A `Cache-Control` header to send back with files:
+    pub cache_control: Option<String>,
+    /// List the contents of directories without an index file?
+    /// If not set, we fall back to the `--dir-listing` flag.
+    pub dir_listing: Option<bool>,
+    /// Serve the index file at the root of a file destination for
+    /// paths that don't exist, so that client side routing works:
+    pub spa: bool,
+    /// Serve `foo.js.br`, `foo.js.zst` or `foo.js.gz` in place of `foo.js`,
+    /// if they exist and the client accepts them:
+    pub precompressed: bool,
+    /// A path to periodically request from each URL destination. Those
+    /// that fail to respond with a 2xx or 3xx are not routed to:
+    pub health_check: Option<String>,
+    /// How often to perform health checks:
+    pub health_interval: Duration,
+    /// How long to wait for a health check before it's considered failed:
+    pub health_timeout: Duration,
+    /// How many times to retry proxied requests that fail:
+    pub retries: u32,
+    /// How long to wait before the first retry:
+    pub retry_backoff: Duration,
+    /// Stop proxying to a destination after this many failures in a row
+    /// (0 means never):
+    pub breaker_failures: u32,
+    /// How long to stop proxying to a destination for once tripped:
+    pub breaker_cooldown: Duration,
+    /// How long to wait for a destination to start responding:
+    pub header_timeout: Option<Duration>,
+    /// How long to wait for a destination to finish responding, including
+    /// any retries and the response body (except for `h2c://` destinations,
+    /// whose trailers we'd otherwise lose):
+    pub timeout: Option<Duration>,
+    /// Forward the `Host` header we were given to URL destinations, rather
+    /// than replacing it with the destination's own host:
+    pub preserve_host: bool,
+    /// Point `Location` headers from URL destinations that refer to the
+    /// destination itself (like on redirects) back at us instead:
+    pub rewrite_location: bool,
+    /// Rewrite the cookies that URL destinations set:
+    pub cookies: CookieOptions,
+    /// Which cross-origin requests browsers can make to this route:
+    pub cors: CorsOptions,
+    /// Security headers to add to responses:
+    pub security: SecurityHeaders,
+    /// Respond with this instead of our own 404 and 5xx responses:
+    pub error_page: Option<Arc<ErrorPage>>,
+    /// What to do while we're down for maintenance:
+    pub maintenance: Maintenance,
+    /// Headers to add, change or remove on requests and responses:
+    pub headers: HeaderRules,
+    /// Only match requests with all of these headers:
+    pub when_headers: Vec<HeaderMatch>,
+    /// Only match GraphQL requests for these operations:
+    pub when_graphql: GraphqlMatch,
+    /// Only match gRPC calls, by whole service and method names (like
+    /// `50051/pkg.Service`), passing on their full paths:
+    pub grpc: bool,
+    /// Remove the query parameters that the source matched on (eg
+    /// `8080/search?engine=v2`) before passing the query on:
+    pub strip_matched_query: bool,
+    /// Which versions of HTTP to speak to clients on the listener this
+    /// route is served on. Routes sharing a listener must agree:
+    pub listener_protocol: Option<ListenerProtocol>,
+    /// How to make TLS connections to HTTPS destinations:
+    pub tls: TlsOptions,
+    /// How to accept TLS connections, for `https://` sources:
+    pub listener_tls: ListenerTlsOptions,
+    /// Users that must authenticate (using HTTP Basic auth) to use this
+    /// route. If there are none, anybody can:
+    pub auth: BasicAuth,
+    /// Require a valid JWT bearer token to use this route:
+    pub jwt: JwtOptions,
+    /// Ask an external service whether requests to this route are allowed:
+    pub forward_auth: ForwardAuth,
+    /// Make users log in with an OpenID Connect provider to use this route:
+    pub oidc: OidcOptions,
+    /// Require one of the API keys in a file to use this route:
+    pub api_keys: ApiKeys,
+    /// Which client networks may use this route:
+    pub ip_filter: IpFilter,
+    /// How fast each client may make requests to this route:
+    pub rate_limit: RateLimit,
+    /// How many requests to this route can be handled at once (by
+    /// default, there's no limit):
+    pub max_concurrent: Option<usize>,
+    /// How many requests to this route can wait for others to finish
+    /// before we start turning them away:
+    pub max_queued: usize,
+    /// Wait this long before handling each request to this route:
+    pub delay: Option<Delay>,
+    /// Fail some requests to this route on purpose:
+    pub chaos: Chaos,
+    /// Send a copy of each request proxied by this route here as well,
+    /// ignoring the response:
+    pub mirror: Option<Url>,
+    /// Compare responses with those of a shadow destination:
+    pub diff: DiffOptions,
+    /// Keep sending each client to the same destination of this route:
+    pub sticky: Option<Sticky>,
+    /// Answer GET requests to this route from a cache of earlier responses:
+    pub cache: CacheOptions,
+    /// Compress responses for clients that accept it:
+    pub compress: CompressOptions,
+    /// Ask destinations for compressed responses, but decompress them
+    /// before passing them on:
+    pub decompress: bool,
+    /// Find and replace text in the bodies of responses:
+    pub replace: ReplaceOptions,
+    /// Reshape the JSON bodies of requests and responses:
+    pub transform: TransformOptions,
+    /// WASM modules to pass requests and responses through, in order:
+    pub plugins: Vec<Arc<Plugin>>,
+    /// A Rhai script with hooks for requests and responses:
+    pub script: Option<Arc<Script>>,
+    /// Log the requests we proxy and the responses we get back?
+    /// If not set, we fall back to the `--capture` flag.
+    pub capture: Option<bool>,
+    /// The options as they were set, in order, so that they can be
+    /// written out to a config file again:
+    pub given: Vec<(String, String)>
+}
+
+/// How to make TLS connections to the destinations of a route. Routes with
+/// the same options share connections, and routes with none share the
+/// default ones.
+#[derive(Debug,Clone,PartialEq,Eq,Hash,Default)]
+pub struct TlsOptions {
+    /// A PEM certificate (and any intermediates) to authenticate ourselves
+    /// with, for destinations that require client certificates:
+    pub client_cert: Option<PathBuf>,
+    /// The PEM (PKCS#8) private key for `client_cert`:
+    pub client_key: Option<PathBuf>,
+    /// PEM certificates to trust when verifying destinations, on top of
+    /// the system's own (eg for dev servers with self-signed certificates):
+    pub ca_file: Option<PathBuf>,
+    /// Don't verify destinations' certificates at all:
+    pub insecure: bool
+}
+
+/// How to accept TLS connections from clients, for routes with `https://`
+/// sources.
+#[derive(Debug,Clone,PartialEq,Eq,Hash,Default)]
+pub struct ListenerTlsOptions {
+    /// A PEM certificate (and any intermediates) to present to clients
+    /// that ask for the route's virtual host with SNI (or to the rest, for
+    /// routes that aren't for one):
+    pub cert: Option<PathBuf>,
+    /// The PEM private key for `cert`:
+    pub key: Option<PathBuf>,
+    /// Ask clients for certificates signed by one of the PEM certificates
+    /// in this file:
+    pub client_ca: Option<PathBuf>,
+    /// Whether clients must present a certificate, if we ask for them (by
+    /// default, they must):
+    pub client_auth: Option<ClientAuth>
+}
+
+/// Whether clients must present a certificate to connect.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub enum ClientAuth {
+    /// Turn away clients without a valid certificate during the handshake:
+    Required,
+    /// Let clients without a certificate connect too, telling destinations
+    /// that they didn't present one. Invalid certificates are still turned away:
+    Optional
+}
+
+impl ClientAuth {
+    pub fn parse(input: &str) -> Result<ClientAuth, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "required" => Ok(ClientAuth::Required),
+            "optional" => Ok(ClientAuth::Optional),
+            _ => Err(err!("'{}' is not a client-auth mode; expecting 'required' or 'optional'", input))
+        }
+    }
+}
+
+/// Which versions of HTTP a listener speaks to clients:
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum ListenerProtocol {
+    /// HTTP/1.1, or HTTP/2 for clients that start with it (h2c with prior knowledge):
+    #[default]
+    Auto,
+    /// Only HTTP/1.1:
+    Http1,
+    /// Only HTTP/2:
+    Http2
+}
+
+impl ListenerProtocol {
+    pub fn parse(input: &str) -> Result<ListenerProtocol, Error> {
+        match input.trim().to_lowercase().as_str() {
+            "auto" => Ok(ListenerProtocol::Auto),
+            "http1" => Ok(ListenerProtocol::Http1),
+            "http2" => Ok(ListenerProtocol::Http2),
+            _ => Err(err!("'{}' is not a listener protocol; expecting 'auto', 'http1' or 'http2'", input))
+        }
+    }
+}
+
+impl Default for RouteOptions {
+    fn default() -> RouteOptions {
+        RouteOptions {
+            cache_control: None,
+            dir_listing: None,
+            spa: false,
+            precompressed: false,
+            health_check: None,
+            health_interval: DEFAULT_HEALTH_INTERVAL,
+            health_timeout: DEFAULT_HEALTH_TIMEOUT,
+            retries: 0,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            breaker_failures: 0,
+            breaker_cooldown: DEFAULT_BREAKER_COOLDOWN,
+            header_timeout: None,
+            timeout: None,
+            preserve_host: false,
+            rewrite_location: false,
+            cookies: CookieOptions::default(),
+            cors: CorsOptions::default(),
+            security: SecurityHeaders::default(),
+            error_page: None,
+            maintenance: Maintenance::default(),
+            headers: HeaderRules::default(),
+            when_headers: vec![],
+            when_graphql: GraphqlMatch::default(),
+            grpc: false,
+            strip_matched_query: false,
+            listener_protocol: None,
+            tls: TlsOptions::default(),
+            listener_tls: ListenerTlsOptions::default(),
+            auth: BasicAuth::default(),
+            jwt: JwtOptions::default(),
+            forward_auth: ForwardAuth::default(),
+            oidc: OidcOptions::default(),
+            api_keys: ApiKeys::default(),
+            ip_filter: IpFilter::default(),
+            rate_limit: RateLimit::default(),
+            max_concurrent: None,
+            max_queued: DEFAULT_MAX_QUEUED,
+            delay: None,
+            chaos: Chaos::default(),
+            mirror: None,
+            diff: DiffOptions::default(),
+            sticky: None,
+            cache: CacheOptions::default(),
+            compress: CompressOptions::default(),
+            decompress: false,
+            replace: ReplaceOptions::default(),
+            transform: TransformOptions::default(),
+            plugins: vec![],
+            script: None,
+            capture: None,
+            given: vec![]
+        }
+    }
+}
+
+impl RouteOptions {
+    /// Set an option given its name and value. Some options (like
+    /// header rules) can be given more than once:
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        match key {
+            "cache-control" => {
+                self.cache_control = Some(value.to_owned());
+            },
+            "dir-listing" => {
+                self.dir_listing = Some(parse_bool(value)?);
+            },
+            "spa" => {
+                self.spa = parse_bool(value)?;
+            },
+            "precompressed" => {
+                self.precompressed = parse_bool(value)?;
+            },
+            "health-check" => {
+                if !value.starts_with('/') {
+                    return Err(err!("Expecting a path starting with '/'"));
+                }
+                self.health_check = Some(value.to_owned());
+            },
+            "health-interval" => {
+                self.health_interval = parse_period(value)?;
+            },
+            "health-timeout" => {
+                self.health_timeout = parse_period(value)?;
+            },
+            "retries" => {
+                self.retries = value.parse().map_err(|_| err!("Expecting a number of retries but got '{}'", value))?;
+            },
+            "retry-backoff" => {
+                self.retry_backoff = parse_duration(value)?;
+            },
+            "breaker-failures" => {
+                self.breaker_failures = value.parse().map_err(|_| err!("Expecting a number of failures but got '{}'", value))?;
+            },
+            "breaker-cooldown" => {
+                self.breaker_cooldown = parse_duration(value)?;
+            },
+            "header-timeout" => {
+                self.header_timeout = Some(parse_period(value)?);
+            },
+            "timeout" => {
+                self.timeout = Some(parse_period(value)?);
+            },
+            "preserve-host" => {
+                self.preserve_host = parse_bool(value)?;
+            },
+            "rewrite-location" => {
+                self.rewrite_location = parse_bool(value)?;
+            },
+            "rewrite-cookies" => {
+                self.cookies.rewrite = parse_bool(value)?;
+            },
+            "cookie-domain" => {
+                self.cookies.domain = Some(value.trim().to_owned());
+            },
+            "cookie-secure" => {
+                self.cookies.secure = Some(parse_bool(value)?);
+            },
+            "cookie-samesite" => {
+                self.cookies.same_site = Some(CookieOptions::parse_same_site(value)?);
+            },
+            "cors" => {
+                self.cors.origins = CorsOptions::parse_origins(value)?;
+            },
+            "cors-methods" => {
+                self.cors.methods = CorsOptions::parse_methods(value)?;
+            },
+            "cors-headers" => {
+                self.cors.headers = CorsOptions::parse_headers(value)?;
+            },
+            "cors-expose" => {
+                self.cors.expose = CorsOptions::parse_headers(value)?;
+            },
+            "cors-credentials" => {
+                self.cors.credentials = parse_bool(value)?;
+            },
+            "cors-max-age" => {
+                self.cors.max_age = Some(parse_duration(value)?);
+            },
+            "security-headers" => {
+                self.security.preset = Some(parse_bool(value)?);
+            },
+            "hsts" | "content-type-options" | "frame-options" | "referrer-policy" | "csp" => {
+                self.security.set(key, value)?;
+            },
+            "error-page" => {
+                self.error_page = Some(ErrorPage::from_file(Path::new(value))?);
+            },
+            "maintenance" => {
+                self.maintenance.enabled = parse_bool(value)?;
+            },
+            "maintenance-page" => {
+                self.maintenance.enabled = true;
+                self.maintenance.page = Some(ErrorPage::from_file(Path::new(value))?);
+            },
+            "maintenance-retry-after" => {
+                self.maintenance.enabled = true;
+                self.maintenance.retry_after = parse_duration(value)?;
+            },
+            "when-header" => {
+                self.when_headers.push(HeaderMatch::parse(value)?);
+            },
+            "when-graphql-type" => {
+                self.when_graphql.kinds.push(OperationType::parse(value)?);
+            },
+            "when-graphql-name" => {
+                self.when_graphql.names.push(value.to_owned());
+            },
+            "grpc" => {
+                self.grpc = parse_bool(value)?;
+            },
+            "strip-matched-query" => {
+                self.strip_matched_query = parse_bool(value)?;
+            },
+            "listener-protocol" => {
+                self.listener_protocol = Some(ListenerProtocol::parse(value)?);
+            },
+            "client-cert" => {
+                self.tls.client_cert = Some(value.into());
+            },
+            "client-key" => {
+                self.tls.client_key = Some(value.into());
+            },
+            "ca-file" => {
+                self.tls.ca_file = Some(value.into());
+            },
+            "insecure" => {
+                self.tls.insecure = parse_bool(value)?;
+            },
+            "cert" => {
+                self.listener_tls.cert = Some(value.into());
+            },
+            "key" => {
+                self.listener_tls.key = Some(value.into());
+            },
+            "client-ca" => {
+                self.listener_tls.client_ca = Some(value.into());
+            },
+            "client-auth" => {
+                self.listener_tls.client_auth = Some(ClientAuth::parse(value)?);
+            },
+            "allow" => {
+                self.ip_filter.allow.extend(Cidr::parse_list(value)?);
+            },
+            "deny" => {
+                self.ip_filter.deny.extend(Cidr::parse_list(value)?);
+            },
+            "rate-limit" => {
+                self.rate_limit.rate = Some(Rate::parse(value)?);
+            },
+            "rate-limit-burst" => {
+                self.rate_limit.burst = Some(value.parse().map_err(|_| err!("Expecting a number of requests but got '{}'", value))?);
+            },
+            "rate-limit-key" => {
+                self.rate_limit.key = RateLimitKey::parse(value)?;
+            },
+            "max-concurrent" => {
+                self.max_concurrent = Some(parse_count(value)?);
+            },
+            "max-queued" => {
+                self.max_queued = value.parse().map_err(|_| err!("Expecting a number of requests but got '{}'", value))?;
+            },
+            "delay" => {
+                self.delay = Some(Delay::parse(value)?);
+            },
+            "chaos" => {
+                self.chaos.rate = Chaos::parse_rate(value)?;
+            },
+            "chaos-fault" => {
+                self.chaos.fault = Fault::parse(value)?;
+            },
+            "chaos-seed" => {
+                self.chaos.seed = Some(value.parse().map_err(|_| err!("Expecting a number to seed with but got '{}'", value))?);
+            },
+            "mirror" => {
+                self.mirror = Some(mirror::parse_url(value)?);
+            },
+            "diff" => {
+                self.diff.shadow = Some(mirror::parse_url(value)?);
+            },
+            "diff-ignore-headers" => {
+                self.diff.ignore_headers = DiffOptions::parse_headers(value)?;
+            },
+            "diff-ignore" => {
+                self.diff.ignore = Some(DiffOptions::parse_pattern(value)?);
+            },
+            "sticky" => {
+                self.sticky = Some(Sticky::parse(value)?);
+            },
+            "cache" => {
+                self.cache.enabled = parse_bool(value)?;
+            },
+            "cache-ttl" => {
+                self.cache.enabled = true;
+                self.cache.ttl = Some(parse_duration(value)?);
+            },
+            "compress" => {
+                self.compress.encodings = CompressOptions::parse_encodings(value)?;
+            },
+            "compress-min-size" => {
+                self.compress.min_size = parse_size(value)?;
+            },
+            "compress-types" => {
+                self.compress.types = CompressOptions::parse_types(value)?;
+            },
+            "decompress" => {
+                self.decompress = parse_bool(value)?;
+            },
+            "replace" => {
+                self.replace.rules.push(Replacement::text(value)?);
+            },
+            "replace-regex" => {
+                self.replace.rules.push(Replacement::regex(value)?);
+            },
+            "replace-types" => {
+                self.replace.types = CompressOptions::parse_types(value)?;
+            },
+            "json-request" => {
+                self.transform.request.push(Transform::parse(value)?);
+            },
+            "json-response" => {
+                self.transform.response.push(Transform::parse(value)?);
+            },
+            "plugin" => {
+                self.plugins.push(Plugin::load(Path::new(value))?);
+            },
+            "script" => {
+                self.script = Some(Script::load(Path::new(value))?);
+            },
+            "capture" => {
+                self.capture = Some(parse_bool(value)?);
+            },
+            "auth" => {
+                self.auth.add_user(value)?;
+            },
+            "auth-file" => {
+                self.auth.add_htpasswd_file(Path::new(value))?;
+            },
+            "api-keys" => {
+                self.api_keys = ApiKeys::from_file(Path::new(value))?;
+            },
+            "jwt-secret" => {
+                self.jwt.key = Some(JwtKey::Secret(value.to_owned()));
+            },
+            "jwt-jwks" => {
+                self.jwt.key = Some(JwtKey::jwks(value)?);
+            },
+            "jwt-claim" => {
+                self.jwt.required_claims.push(jwt::parse_required_claim(value)?);
+            },
+            "jwt-claim-header" => {
+                self.jwt.claim_headers.push(jwt::parse_claim_header(value)?);
+            },
+            "forward-auth" => {
+                self.forward_auth.url = Some(ForwardAuth::parse_url(value)?);
+            },
+            "forward-auth-header" => {
+                let name = HeaderName::from_bytes(value.as_bytes())
+                    .map_err(|_| err!("'{}' is not a valid header name", value))?;
+                self.forward_auth.copy_headers.push(name);
+            },
+            "oidc-issuer" => {
+                self.oidc.issuer = Some(oidc::parse_url(value)?);
+            },
+            "oidc-client-id" => {
+                self.oidc.client_id = Some(value.to_owned());
+            },
+            "oidc-client-secret" => {
+                self.oidc.client_secret = Some(value.to_owned());
+            },
+            "oidc-redirect-url" => {
+                self.oidc.redirect_url = Some(oidc::parse_url(value)?);
+            },
+            "oidc-cookie-secret" => {
+                self.oidc.cookie_secret = Some(value.to_owned());
+            },
+            "request-header" => {
+                self.headers.request.push(HeaderRule::set(value)?);
+            },
+            "add-request-header" => {
+                self.headers.request.push(HeaderRule::add(value)?);
+            },
+            "remove-request-header" => {
+                self.headers.request.push(HeaderRule::remove(value)?);
+            },
+            "response-header" => {
+                self.headers.response.push(HeaderRule::set(value)?);
+            },
+            "add-response-header" => {
+                self.headers.response.push(HeaderRule::add(value)?);
+            },
+            "remove-response-header" => {
+                self.headers.response.push(HeaderRule::remove(value)?);
+            },
+            _ => {
+                return Err(err!("Unknown route option '{}'", key));
+            }
+        }
+        self.given.push((key.to_owned(), value.to_owned()));
+        Ok(())
+    }
+
+    /// Set an option from something like `KEY=VALUE`. A lone `KEY` is
+    /// treated as `KEY=true`, which is handy for flags.
+    pub fn set_from_str(&mut self, input: &str) -> Result<(), Error> {
+        let (key, value) = match input.find('=') {
+            Some(idx) => (&input[..idx], &input[idx+1..]),
+            None => (input, "true")
+        };
+        self.set(key.trim(), value.trim())
+    }
+}
+
+/// Parse the value of an option that is on or off:
+fn parse_bool(value: &str) -> Result<bool, Error> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(err!("Expecting true or false but got '{}'", value))
+    }
+}
+
+/// Parse a count of something, which must be at least 1:
+pub fn parse_count(value: &str) -> Result<usize, Error> {
+    match value.trim().parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(err!("Expecting a number greater than 0 but got '{}'", value))
+    }
+}
+
+/// Parse a duration like `500ms`, `10s` or `2m`. Plain numbers are seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, Error> {
+    let value = value.trim();
+    let (num, to_millis) = if let Some(num) = value.strip_suffix("ms") {
+        (num, 1)
+    } else if let Some(num) = value.strip_suffix('s') {
+        (num, 1000)
+    } else if let Some(num) = value.strip_suffix('m') {
+        (num, 60 * 1000)
+    } else {
+        (value, 1000)
+    };
+
+    let num: u64 = num.trim().parse().map_err(|_| err!("Expecting a duration like 500ms, 10s or 2m but got '{}'", value))?;
+    let millis = num.checked_mul(to_millis).ok_or_else(|| err!("Duration '{}' is too long", value))?;
+    Ok(Duration::from_millis(millis))
+}
+
+/// Parse a duration that has to be longer than nothing, like how often to
+/// do something or how long to wait for it:
+pub fn parse_period(value: &str) -> Result<Duration, Error> {
+    let duration = parse_duration(value)?;
+    if duration == Duration::from_millis(0) {
+        return Err(err!("Expecting a duration greater than 0 but got '{}'", value));
+    }
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("3").unwrap(), Duration::from_secs(3));
+        assert!(parse_duration("wibble").is_err());
+        assert!(parse_duration("10h").is_err());
+        assert!(parse_duration("99999999999999999m").is_err());
+        assert_eq!(parse_duration("0s").unwrap(), Duration::from_millis(0));
+        assert!(parse_period("0s").is_err());
+        assert!(parse_period("0ms").is_err());
+        assert_eq!(parse_period("1ms").unwrap(), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn rejects_zero_periods() {
+        let mut options = RouteOptions::default();
+        assert!(options.set("health-interval", "0s").is_err());
+        assert!(options.set("health-timeout", "0").is_err());
+        assert!(options.set("timeout", "0ms").is_err());
+        assert!(options.set("retry-backoff", "0s").is_ok());
+    }
+}
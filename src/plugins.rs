@@ -0,0 +1,447 @@
+use hyper::{ HeaderMap, Method, Request, Response, StatusCode };
+use http_body::Body as _;
+use hyper::header::{ HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING };
+use serde::{ Deserialize, Serialize };
+use std::fmt;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::proxy;
+
+/// Bodies up to this size are handed to plugins, and can be rewritten by
+/// them. Bigger ones (and those of unknown size) are streamed past them.
+const MAX_PLUGIN_BODY: usize = 1024 * 1024;
+
+/// A WASM module that filters the requests and responses of a route, given
+/// with `plugin=PATH.wasm`. Modules export their `memory`, and a
+/// `weave_alloc(len: i32) -> i32` for us to write into. For each request,
+/// we call `on_request(ptr: i32, len: i32) -> i64` with a JSON message
+/// like:
+///
+/// ```json
+/// {"method": "GET", "uri": "/a?b=1", "headers": [["host", "x"]], "body": "..."}
+/// ```
+///
+/// and for each response `on_response`, with a `status` in place of the
+/// method and URI. Either export can be left out. They return where their
+/// reply is in memory, as `ptr << 32 | len`, or 0 to leave things be. A
+/// reply can replace the `headers` and the `body` (or `body_base64`, as
+/// bodies that aren't UTF-8 are given to plugins), and a `status` in a
+/// reply to a request is sent back as the response, without going any
+/// further. Each call gets a new instance of the module, which can't
+/// import anything.
+pub struct Plugin {
+    path: PathBuf,
+    module: runtime::Module,
+    on_request: bool,
+    on_response: bool
+}
+
+impl Plugin {
+    pub fn load(path: &Path) -> Result<Arc<Plugin>, Error> {
+        let module = runtime::compile(path)?;
+        Ok(Arc::new(Plugin {
+            path: path.to_owned(),
+            on_request: runtime::exports(&module, "on_request"),
+            on_response: runtime::exports(&module, "on_response"),
+            module
+        }))
+    }
+
+    fn call(&self, export: &str, message: &Message) -> Result<Option<Reply>, Error> {
+        let input = serde_json::to_vec(message).unwrap();
+        let output = runtime::call(&self.module, export, &input)
+            .map_err(|e| err!("Plugin '{}' failed in {}: {}", self.path.display(), export, e))?;
+        match output {
+            Some(output) => serde_json::from_slice(&output)
+                .map(Some)
+                .map_err(|e| err!("Plugin '{}' replied to {} with something we don't understand: {}", self.path.display(), export, e)),
+            None => Ok(None)
+        }
+    }
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Plugin({})", self.path.display())
+    }
+}
+
+impl PartialEq for Plugin {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+/// What we tell a plugin about a request or a response:
+#[derive(Debug,Clone,PartialEq,Default,Serialize)]
+struct Message {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    headers: Vec<(String, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_base64: Option<String>
+}
+
+/// What a plugin wants done about it:
+#[derive(Debug,Clone,PartialEq,Default,Deserialize)]
+struct Reply {
+    #[serde(default)]
+    status: Option<u16>,
+    #[serde(default)]
+    headers: Option<Vec<(String, String)>>,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    body_base64: Option<String>
+}
+
+impl Message {
+    fn new(headers: &HeaderMap, body: Option<&[u8]>) -> Message {
+        let mut message = Message {
+            headers: headers.iter()
+                .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+                .collect(),
+            ..Message::default()
+        };
+        match body.map(std::str::from_utf8) {
+            Some(Ok(text)) => message.body = Some(text.to_owned()),
+            Some(Err(_)) => message.body_base64 = body.map(base64::encode),
+            None => {}
+        }
+        message
+    }
+}
+
+impl Reply {
+    fn status(&self) -> Result<Option<StatusCode>, Error> {
+        match self.status {
+            Some(status) => StatusCode::from_u16(status)
+                .map(Some)
+                .map_err(|_| err!("{} is not a valid status", status)),
+            None => Ok(None)
+        }
+    }
+
+    /// Replace the headers we have, if the reply gives new ones:
+    fn apply_headers(&self, headers: &mut HeaderMap) -> Result<(), Error> {
+        let new_headers = match &self.headers {
+            Some(new_headers) => new_headers,
+            None => return Ok(())
+        };
+        headers.clear();
+        for (name, value) in new_headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| err!("'{}' is not a valid header name", name))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| err!("'{}' is not a valid value for {}", value, name))?;
+            headers.append(name, value);
+        }
+        Ok(())
+    }
+
+    /// The body to send in place of the one we have, if any:
+    fn body(&self) -> Result<Option<Vec<u8>>, Error> {
+        match (&self.body, &self.body_base64) {
+            (Some(text), _) => Ok(Some(text.clone().into_bytes())),
+            (None, Some(encoded)) => base64::decode(encoded)
+                .map(Some)
+                .map_err(|e| err!("body_base64 is not valid base64: {}", e)),
+            (None, None) => Ok(None)
+        }
+    }
+
+    /// Apply a reply to the headers and body of a request or response:
+    fn apply(&self, headers: &mut HeaderMap, body: &mut Option<Vec<u8>>) -> Result<(), Error> {
+        self.apply_headers(headers)?;
+        if let Some(new_body) = self.body()? {
+            *body = Some(new_body);
+        }
+        if let Some(body) = body {
+            headers.remove(TRANSFER_ENCODING);
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+        Ok(())
+    }
+}
+
+/// Read a body for plugins to see, if it's small enough. Otherwise, the
+/// body is handed back to be passed on as it is:
+async fn buffer(headers: &HeaderMap, body: Body) -> Result<Result<Vec<u8>, Body>, Error> {
+    let len = headers.get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    match len {
+        Some(len) if len <= MAX_PLUGIN_BODY => Ok(Ok(proxy::read_body(body).await?)),
+        _ if body.is_end_stream() => Ok(Ok(vec![])),
+        _ => Ok(Err(body))
+    }
+}
+
+/// Pass a request through the plugins of its route, in the order they
+/// were given. Hands back a response instead if one of them sends one:
+pub async fn filter_request(req: Request<Body>, plugins: &[Arc<Plugin>]) -> Result<Result<Request<Body>, Response<Body>>, Error> {
+    if !plugins.iter().any(|plugin| plugin.on_request) {
+        return Ok(Ok(req));
+    }
+    let (mut parts, body) = req.into_parts();
+    let (mut body, streamed) = match buffer(&parts.headers, body).await? {
+        Ok(body) => (Some(body), None),
+        Err(body) => (None, Some(body))
+    };
+
+    for plugin in plugins.iter().filter(|plugin| plugin.on_request) {
+        let message = Message {
+            method: Some(parts.method.to_string()),
+            uri: Some(parts.uri.to_string()),
+            ..Message::new(&parts.headers, body.as_deref())
+        };
+        let reply = match plugin.call("on_request", &message)? {
+            Some(reply) => reply,
+            None => continue
+        };
+        if let Some(status) = reply.status()? {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = status;
+            let mut resp_body = Some(vec![]);
+            reply.apply(resp.headers_mut(), &mut resp_body)?;
+            return Ok(Err(resp.map(|_| Body::from(resp_body.unwrap_or_default()))));
+        }
+        reply.apply(&mut parts.headers, &mut body)?;
+    }
+
+    let body = match (body, streamed) {
+        (Some(body), _) => Body::from(body),
+        (None, Some(streamed)) => streamed,
+        (None, None) => Body::empty()
+    };
+    Ok(Ok(Request::from_parts(parts, body)))
+}
+
+/// Pass a response through the plugins of its route, in the order they
+/// were given:
+pub async fn filter_response(resp: Response<Body>, method: &Method, plugins: &[Arc<Plugin>]) -> Result<Response<Body>, Error> {
+    if !plugins.iter().any(|plugin| plugin.on_response) {
+        return Ok(resp);
+    }
+    let (mut parts, body) = resp.into_parts();
+    let no_body = *method == Method::HEAD
+        || parts.status == StatusCode::NO_CONTENT
+        || parts.status == StatusCode::NOT_MODIFIED;
+    let (mut body, streamed) = if no_body {
+        (None, Some(body))
+    } else {
+        match buffer(&parts.headers, body).await? {
+            Ok(body) => (Some(body), None),
+            Err(body) => (None, Some(body))
+        }
+    };
+
+    for plugin in plugins.iter().filter(|plugin| plugin.on_response) {
+        let message = Message {
+            status: Some(parts.status.as_u16()),
+            ..Message::new(&parts.headers, body.as_deref())
+        };
+        let reply = match plugin.call("on_response", &message)? {
+            Some(reply) => reply,
+            None => continue
+        };
+        if let Some(status) = reply.status()? {
+            parts.status = status;
+        }
+        if no_body {
+            reply.apply_headers(&mut parts.headers)?;
+        } else {
+            reply.apply(&mut parts.headers, &mut body)?;
+        }
+    }
+
+    let body = match (body, streamed) {
+        (Some(body), _) => Body::from(body),
+        (None, Some(streamed)) => streamed,
+        (None, None) => Body::empty()
+    };
+    Ok(Response::from_parts(parts, body))
+}
+
+/// Running plugins with wasmtime, if weave was built with the
+/// `wasm-plugins` cargo feature:
+#[cfg(feature = "wasm-plugins")]
+mod runtime {
+    use lazy_static::lazy_static;
+    use std::path::Path;
+    use wasmtime::{ Config, Engine, Instance, Store };
+    use crate::errors::{ Error };
+
+    pub use wasmtime::Module;
+
+    /// How much work a plugin can do with each call, so that one stuck in
+    /// a loop can't hold up everything else:
+    const FUEL: u64 = 100_000_000;
+
+    lazy_static! {
+        static ref ENGINE: Engine = {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            Engine::new(&config).expect("the WASM engine is configured correctly")
+        };
+    }
+
+    pub fn compile(path: &Path) -> Result<Module, Error> {
+        Module::from_file(&ENGINE, path).map_err(|e| err!("Can't load plugin '{}': {}", path.display(), e))
+    }
+
+    pub fn exports(module: &Module, name: &str) -> bool {
+        module.get_export(name).is_some()
+    }
+
+    pub fn call(module: &Module, export: &str, input: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let mut store = Store::new(&ENGINE, ());
+        store.set_fuel(FUEL).map_err(|e| err!("{}", e))?;
+        let instance = Instance::new(&mut store, module, &[]).map_err(|e| err!("{}", e))?;
+        let memory = instance.get_memory(&mut store, "memory")
+            .ok_or_else(|| err!("the module doesn't export its memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "weave_alloc").map_err(|e| err!("{}", e))?;
+        let filter = instance.get_typed_func::<(i32, i32), i64>(&mut store, export).map_err(|e| err!("{}", e))?;
+
+        let ptr = alloc.call(&mut store, input.len() as i32).map_err(|e| err!("{}", e))?;
+        memory.write(&mut store, ptr as u32 as usize, input).map_err(|e| err!("{}", e))?;
+        let reply = filter.call(&mut store, (ptr, input.len() as i32)).map_err(|e| err!("{}", e))? as u64;
+        if reply == 0 {
+            return Ok(None);
+        }
+        let mut output = vec![0; (reply & 0xffff_ffff) as usize];
+        memory.read(&store, (reply >> 32) as usize, &mut output).map_err(|e| err!("{}", e))?;
+        Ok(Some(output))
+    }
+}
+
+#[cfg(not(feature = "wasm-plugins"))]
+mod runtime {
+    use std::path::Path;
+    use crate::errors::{ Error };
+
+    /// There are no modules without a runtime to compile them:
+    pub enum Module {}
+
+    pub fn compile(path: &Path) -> Result<Module, Error> {
+        Err(err!("Can't load plugin '{}', as weave was built without the wasm-plugins feature", path.display()))
+    }
+
+    pub fn exports(module: &Module, _name: &str) -> bool {
+        match *module {}
+    }
+
+    pub fn call(module: &Module, _export: &str, _input: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match *module {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn describes_bodies() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-a", "1".parse().unwrap());
+        let message = Message::new(&headers, Some(b"hello"));
+        assert_eq!(serde_json::to_string(&message).unwrap(), r#"{"headers":[["x-a","1"]],"body":"hello"}"#);
+        let message = Message::new(&headers, Some(&[0xff, 0x00]));
+        assert_eq!(message.body_base64.as_deref(), Some("/wA="));
+        assert_eq!(Message::new(&headers, None).body, None);
+    }
+
+    #[test]
+    fn applies_replies() {
+        let reply: Reply = serde_json::from_str(r#"{"headers": [["x-b", "2"], ["x-b", "3"]], "body_base64": "aGk="}"#).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-a", "1".parse().unwrap());
+        headers.insert(TRANSFER_ENCODING, "chunked".parse().unwrap());
+        let mut body = Some(b"hello".to_vec());
+        reply.apply(&mut headers, &mut body).unwrap();
+        assert_eq!(body.as_deref(), Some(&b"hi"[..]));
+        assert_eq!(headers.get_all("x-b").iter().count(), 2);
+        assert!(!headers.contains_key("x-a"));
+        assert_eq!(headers[CONTENT_LENGTH], "2");
+
+        let reply: Reply = serde_json::from_str(r#"{"status": 1000}"#).unwrap();
+        assert!(reply.status().is_err());
+        let reply: Reply = serde_json::from_str(r#"{"headers": [["bad header", "1"]]}"#).unwrap();
+        assert!(reply.apply_headers(&mut headers).is_err());
+    }
+
+    /// Turns requests away with a 403, and hands responses back as they are:
+    #[cfg(feature = "wasm-plugins")]
+    const FILTER: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "{\"status\":403,\"headers\":[[\"x-plugin\",\"1\"]],\"body\":\"nope\"}")
+          (func (export "weave_alloc") (param i32) (result i32)
+            i32.const 1024)
+          (func (export "on_request") (param i32 i32) (result i64)
+            i64.const 57)
+          (func (export "on_response") (param $ptr i32) (param $len i32) (result i64)
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+              (i64.extend_i32_u (local.get $len)))))
+    "#;
+
+    #[cfg(feature = "wasm-plugins")]
+    fn load(name: &str, wat: &str) -> Arc<Plugin> {
+        let path = std::env::temp_dir().join(format!("weave-plugin-test-{}-{}.wat", std::process::id(), name));
+        std::fs::write(&path, wat).unwrap();
+        let plugin = Plugin::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        plugin
+    }
+
+    #[test]
+    #[cfg(feature = "wasm-plugins")]
+    fn runs_plugins() {
+        use futures::executor::block_on;
+
+        let plugin = load("filter", FILTER);
+        let req = Request::get("/admin").body(Body::from("hello")).unwrap();
+        let resp = block_on(filter_request(req, &[Arc::clone(&plugin)])).unwrap().unwrap_err();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers()["x-plugin"], "1");
+        assert_eq!(block_on(proxy::read_body(resp.into_body())).unwrap(), b"nope");
+
+        let mut resp = Response::new(Body::from("hello"));
+        resp.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(5));
+        let resp = block_on(filter_response(resp, &Method::GET, &[plugin])).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(block_on(proxy::read_body(resp.into_body())).unwrap(), b"hello");
+    }
+
+    #[test]
+    #[cfg(feature = "wasm-plugins")]
+    fn stops_plugins_that_never_finish() {
+        let plugin = load("loop", r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "weave_alloc") (param i32) (result i32)
+                i32.const 0)
+              (func (export "on_request") (param i32 i32) (result i64)
+                (loop (br 0))
+                i64.const 0))
+        "#);
+        let err = plugin.call("on_request", &Message::default()).unwrap_err();
+        assert!(err.to_string().contains("failed in on_request"), "{}", err);
+    }
+
+    #[test]
+    #[cfg(not(feature = "wasm-plugins"))]
+    fn needs_the_feature() {
+        assert!(Plugin::load(Path::new("filter.wasm")).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use ansi_term::Color::{ Red };
+use hyper::{ Method, Request, Response, Version };
+use hyper::header::{ HeaderMap, HeaderValue };
+use hyper::http::request::Parts;
+use log::{ debug, warn };
+use std::io;
+use std::net::{ IpAddr, SocketAddr };
+use std::time::{ Duration, Instant };
+use tokio::time::{ self, sleep };
+use url::Url;
+use crate::acceptor::{ TlsConnection };
+use crate::body::{ Body };
+use crate::client::{ ClientError };
+use crate::errors::{ Error };
+use crate::error_pages;
+use crate::options::{ RouteOptions };
+use crate::HttpsClient;
+
+/// Added to the extensions of a response if we had to retry the
+/// request to get it, so that we can log how many retries it took.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Retries(pub u32);
+
+/// Added to the extensions of the 504 response we send back when the
+/// upstream takes too long, describing which timeout fired.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct TimedOut(pub String);
+
+/// Proxy a request through to the URL provided, retrying failed attempts
+/// if the route asks us to. We only retry requests that failed to connect,
+/// or whose method is idempotent, since otherwise the upstream may have
+/// acted on the request already. If the upstream takes too long, we
+/// respond with a 504.
+pub async fn proxy(mut req: Request<Body>, url: &Url, options: &RouteOptions, client: &HttpsClient) -> Result<Response<Body>, Error> {
+    // Set the request URI to our new destination:
+    *req.uri_mut() = format!("{}", url).parse().unwrap();
+    // Remove the host header (it's set according to URI if not present),
+    // unless the route wants the destination to see the original one:
+    if !options.preserve_host {
+        req.headers_mut().remove("host");
+    }
+    // Otherwise it'd be set to the encoded socket path for Unix sockets:
+    if url.scheme() == "unix" && !req.headers().contains_key("host") {
+        req.headers_mut().insert("host", HeaderValue::from_static("localhost"));
+    }
+
+    let timeout = match options.timeout {
+        Some(timeout) => timeout,
+        None => return proxy_with_retries(req, url, options, client).await
+    };
+
+    // The total timeout covers everything up to the last byte of the body:
+    let deadline = Instant::now() + timeout;
+    let resp = match time::timeout_at(deadline.into(), proxy_with_retries(req, url, options, client)).await {
+        Ok(resp) => resp?,
+        Err(_) => return Ok(timed_out(format!("timed out after {:#?}", timeout)))
+    };
+    // Passing the body on ourselves would drop any trailers, which gRPC
+    // needs, so for h2c the timeout only covers the response headers:
+    if url.scheme() == "h2c" {
+        return Ok(resp);
+    }
+    Ok(resp.map(|body| with_deadline(body, deadline, url.to_string(), timeout)))
+}
+
+async fn proxy_with_retries(req: Request<Body>, url: &Url, options: &RouteOptions, client: &HttpsClient) -> Result<Response<Body>, Error> {
+    if options.retries == 0 {
+        return match send(client, req, options).await {
+            Ok(resp) => Ok(resp),
+            Err(e) => failed(e, 0)
+        };
+    }
+
+    // We'll need the body again for each attempt, so buffer it:
+    let (parts, body) = req.into_parts();
+    let body = read_body(body).await?;
+    let idempotent = is_idempotent(&parts.method);
+
+    let mut backoff = options.retry_backoff;
+    let mut attempts = 0;
+    loop {
+        match send(client, clone_request(&parts, &body), options).await {
+            Ok(mut resp) => {
+                if attempts > 0 {
+                    resp.extensions_mut().insert(Retries(attempts));
+                }
+                return Ok(resp);
+            }
+            Err(e) => {
+                let can_retry = attempts < options.retries && (e.is_connect() || idempotent);
+                if !can_retry {
+                    return failed(e, attempts);
+                }
+                debug!("Retrying {} in {:#?}: {}", url, backoff, e);
+                sleep(backoff).await;
+                backoff *= 2;
+                attempts += 1;
+            }
+        }
+    }
+}
+
+/// Send a request, giving up if the response headers don't arrive in time:
+async fn send(client: &HttpsClient, req: Request<Body>, options: &RouteOptions) -> Result<Response<Body>, SendError> {
+    let timeout = match options.header_timeout {
+        Some(timeout) => timeout,
+        None => return Ok(as_http1(client.request(req, &options.tls).await?))
+    };
+    match time::timeout(timeout, client.request(req, &options.tls)).await {
+        Ok(res) => Ok(as_http1(res?)),
+        Err(_) => Err(SendError::TimedOut(timeout))
+    }
+}
+
+/// Responses from `h2c://` destinations are HTTP/2, but the client may not
+/// be, and hyper complains when it has to change the version itself. The
+/// version we answer with is decided by the client's connection anyway:
+fn as_http1(mut resp: Response<Body>) -> Response<Body> {
+    *resp.version_mut() = Version::HTTP_11;
+    resp
+}
+
+/// Turn a failure to get a response into the response or error to hand back:
+fn failed(e: SendError, attempts: u32) -> Result<Response<Body>, Error> {
+    let retries = if attempts > 0 { format!(" (after {} retries)", attempts) } else { String::new() };
+    match e {
+        SendError::TimedOut(timeout) => {
+            Ok(timed_out(format!("timed out after {:#?} waiting for response headers{}", timeout, retries)))
+        },
+        SendError::Hyper(e) if is_connect_timeout(&e) => {
+            Ok(timed_out(format!("{}{}", e, retries)))
+        },
+        SendError::Hyper(e) if attempts > 0 => {
+            Err(err!("{}{}", e, retries))
+        },
+        SendError::Hyper(e) => {
+            Err(e.into())
+        }
+    }
+}
+
+enum SendError {
+    Hyper(ClientError),
+    TimedOut(Duration)
+}
+
+impl SendError {
+    fn is_connect(&self) -> bool {
+        match self {
+            SendError::Hyper(e) => e.is_connect(),
+            SendError::TimedOut(_) => false
+        }
+    }
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SendError::Hyper(e) => e.fmt(f),
+            SendError::TimedOut(timeout) => write!(f, "timed out after {:#?}", timeout)
+        }
+    }
+}
+
+impl From<ClientError> for SendError {
+    fn from(e: ClientError) -> SendError {
+        SendError::Hyper(e)
+    }
+}
+
+/// Did we fail to connect in time (see `TimeoutConnector`)?
+fn is_connect_timeout(e: &ClientError) -> bool {
+    use std::error::Error;
+    e.is_connect() && e.source()
+        .and_then(|source| source.downcast_ref::<io::Error>())
+        .map(|e| e.kind() == io::ErrorKind::TimedOut)
+        .unwrap_or(false)
+}
+
+/// A 504 response, noting why we gave up waiting:
+pub fn timed_out(reason: String) -> Response<Body> {
+    let mut resp = error_pages::error(504, format!("Upstream {}", reason));
+    resp.extensions_mut().insert(TimedOut(reason));
+    resp
+}
+
+/// Pass the response body on until the deadline, and then abort it. By now
+/// we've sent the headers back, so this is all we can do.
+fn with_deadline(mut body: Body, deadline: Instant, url: String, timeout: Duration) -> Body {
+    let (mut sender, new_body) = Body::channel();
+
+    tokio::spawn(async move {
+        loop {
+            let chunk = match time::timeout_at(deadline.into(), body.next()).await {
+                Ok(Some(Ok(chunk))) => chunk,
+                Ok(Some(Err(e))) => {
+                    debug!("Error streaming response from {}: {}", url, e);
+                    sender.abort();
+                    return;
+                },
+                Ok(None) => return,
+                Err(_) => {
+                    let timeout_string = format!("[timeout] {} (response body not complete after {:#?})", url, timeout);
+                    warn!("{}", Red.paint(timeout_string));
+                    sender.abort();
+                    return;
+                }
+            };
+            // The client has gone away, so stop:
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    new_body
+}
+
+/// The start of a body we've passed on, and how big it was.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Teed {
+    pub start: Vec<u8>,
+    pub total: usize,
+    /// Whether the whole body was passed on, rather than failing or being
+    /// abandoned part way through:
+    pub complete: bool
+}
+
+/// Pass a body on, holding on to up to `limit` bytes of it, and hand them
+/// to `done` once it's all been passed on:
+pub fn tee<F>(mut body: Body, limit: usize, done: F) -> Body where F: FnOnce(Teed) + Send + 'static {
+    let (mut sender, new_body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut teed = Teed { start: vec![], total: 0, complete: false };
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    debug!("Error streaming body: {}", e);
+                    sender.abort();
+                    return done(teed);
+                }
+            };
+            let room = limit.saturating_sub(teed.start.len());
+            teed.start.extend_from_slice(&chunk[..room.min(chunk.len())]);
+            teed.total += chunk.len();
+            // The other end has gone away, so stop:
+            if sender.send_data(chunk).await.is_err() {
+                return done(teed);
+            }
+        }
+        teed.complete = true;
+        done(teed);
+    });
+
+    new_body
+}
+
+/// We speak HTTP/1.1 to destinations, so requests that arrived over HTTP/2
+/// need their version changing, and their host moving from the URI (where
+/// HTTP/2 puts it) into a `Host` header. This needs doing first, so that
+/// the forwarded headers and `preserve-host` see the host.
+pub fn use_http1(req: &mut Request<Body>) {
+    if req.version() != Version::HTTP_2 {
+        return;
+    }
+    *req.version_mut() = Version::HTTP_11;
+    if !req.headers().contains_key("host") {
+        let host = req.uri().authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok());
+        if let Some(host) = host {
+            req.headers_mut().insert("host", host);
+        }
+    }
+}
+
+/// Tell the destination who the request is really from, appending the client
+/// to any `X-Forwarded-For` and `Forwarded` headers that proxies in front of
+/// us have added. This needs doing before the host header is removed. Clients
+/// connected over a Unix socket have no address, so aren't added to
+/// `X-Forwarded-For`, and are `for=unknown` in `Forwarded` (RFC 7239).
+pub fn add_forwarded_headers(req: &mut Request<Body>, remote_addr: Option<SocketAddr>) {
+    let proto = if req.extensions().get::<TlsConnection>().is_some() { "https" } else { "http" };
+    let ip = remote_addr.map(|addr| addr.ip());
+    let host = req.headers().get("host").cloned();
+    let headers = req.headers_mut();
+
+    if let Some(ip) = ip {
+        append_to_list(headers, "x-forwarded-for", &ip.to_string());
+    }
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+    if let Some(host) = &host {
+        headers.insert("x-forwarded-host", host.clone());
+    }
+
+    // IPv6 addresses need quoting in a Forwarded header (RFC 7239), as do
+    // hosts with ports, so quote the host regardless:
+    let mut forwarded = match ip {
+        Some(IpAddr::V4(ip)) => format!("for={}", ip),
+        Some(IpAddr::V6(ip)) => format!("for=\"[{}]\"", ip),
+        None => "for=unknown".to_owned()
+    };
+    if let Some(host) = host.as_ref().and_then(|h| h.to_str().ok()) {
+        forwarded.push_str(&format!(";host=\"{}\"", host.replace('\\', "\\\\").replace('"', "\\\"")));
+    }
+    forwarded.push_str(&format!(";proto={}", proto));
+    append_to_list(headers, "forwarded", &forwarded);
+}
+
+/// Append an item to a comma separated header, merging any existing values:
+fn append_to_list(headers: &mut HeaderMap, name: &'static str, item: &str) {
+    let mut items: Vec<&str> = headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    items.push(item);
+    if let Ok(value) = HeaderValue::from_str(&items.join(", ")) {
+        headers.insert(name, value);
+    }
+}
+
+/// Methods which can be safely repeated:
+fn is_idempotent(method: &Method) -> bool {
+    [Method::GET, Method::HEAD, Method::OPTIONS, Method::TRACE, Method::PUT, Method::DELETE].contains(method)
+}
+
+/// Read a whole body into memory:
+pub async fn read_body(mut body: Body) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![];
+    while let Some(chunk) = body.next().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+    Ok(bytes)
+}
+
+/// Build a copy of a request from its parts and buffered body:
+pub fn clone_request(parts: &Parts, body: &[u8]) -> Request<Body> {
+    let mut req = Request::builder()
+        .method(parts.method.clone())
+        .uri(parts.uri.clone())
+        .version(parts.version)
+        .body(Body::from(body.to_vec()))
+        .unwrap();
+    *req.headers_mut() = parts.headers.clone();
+    req
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn adds_forwarded_headers() {
+        let mut req = Request::builder()
+            .header("host", "example.com:8080")
+            .header("x-forwarded-for", "10.0.0.1")
+            .header("forwarded", "for=10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        add_forwarded_headers(&mut req, Some("127.0.0.1:54321".parse().unwrap()));
+
+        let headers = req.headers();
+        assert_eq!(headers.get("x-forwarded-for").unwrap(), "10.0.0.1, 127.0.0.1");
+        assert_eq!(headers.get("x-forwarded-proto").unwrap(), "http");
+        assert_eq!(headers.get("x-forwarded-host").unwrap(), "example.com:8080");
+        assert_eq!(headers.get("forwarded").unwrap(), "for=10.0.0.1, for=127.0.0.1;host=\"example.com:8080\";proto=http");
+    }
+
+    #[test]
+    fn quotes_ipv6_addresses_in_forwarded_header() {
+        let mut req = Request::builder()
+            .body(Body::empty())
+            .unwrap();
+        add_forwarded_headers(&mut req, Some("[::1]:54321".parse().unwrap()));
+
+        let headers = req.headers();
+        assert_eq!(headers.get("x-forwarded-for").unwrap(), "::1");
+        assert!(headers.get("x-forwarded-host").is_none());
+        assert_eq!(headers.get("forwarded").unwrap(), "for=\"[::1]\";proto=http");
+    }
+
+    #[test]
+    fn forwards_https_for_tls_connections() {
+        let mut req = Request::builder()
+            .extension(TlsConnection::default())
+            .body(Body::empty())
+            .unwrap();
+        add_forwarded_headers(&mut req, Some("127.0.0.1:54321".parse().unwrap()));
+
+        let headers = req.headers();
+        assert_eq!(headers.get("x-forwarded-proto").unwrap(), "https");
+        assert_eq!(headers.get("forwarded").unwrap(), "for=127.0.0.1;proto=https");
+    }
+}
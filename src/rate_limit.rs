@@ -0,0 +1,188 @@
+use hyper::HeaderMap;
+use hyper::header::HeaderName;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+use crate::errors::{ Error };
+
+/// Once we're keeping track of this many clients for a route, we forget
+/// about those whose buckets have filled up again:
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// How fast clients of a route can make requests. Each client has a bucket
+/// of `burst` tokens, which refills at `rate`, and each request takes one.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct RateLimit {
+    /// If not set, requests aren't limited:
+    pub rate: Option<Rate>,
+    /// How many requests a client can make at once. Defaults to the
+    /// number of requests in the rate:
+    pub burst: Option<u32>,
+    /// How we tell clients apart:
+    pub key: RateLimitKey
+}
+
+/// A number of requests per some period, like `10/s` or `100/m`:
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Rate {
+    pub requests: u32,
+    pub per: Duration
+}
+
+#[derive(Debug,Clone,PartialEq,Default)]
+pub enum RateLimitKey {
+    /// Each client IP address has its own bucket:
+    #[default]
+    Ip,
+    /// Each value of this header has its own bucket. Requests without it
+    /// fall back to using their IP address:
+    Header(HeaderName)
+}
+
+impl Rate {
+    pub fn parse(input: &str) -> Result<Rate, Error> {
+        let invalid = || err!("Expecting a rate like 10/s, 100/m or 1000/h but got '{}'", input);
+        let idx = input.find('/').ok_or_else(invalid)?;
+        let requests: u32 = input[..idx].trim().parse().map_err(|_| invalid())?;
+        let per = match input[idx+1..].trim() {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            _ => return Err(invalid())
+        };
+        if requests == 0 {
+            return Err(invalid());
+        }
+        Ok(Rate { requests, per })
+    }
+
+    fn per_sec(&self) -> f64 {
+        f64::from(self.requests) / self.per.as_secs_f64()
+    }
+}
+
+impl RateLimitKey {
+    /// Parse `ip`, or `header:NAME`:
+    pub fn parse(input: &str) -> Result<RateLimitKey, Error> {
+        let input = input.trim();
+        if input.eq_ignore_ascii_case("ip") {
+            return Ok(RateLimitKey::Ip);
+        }
+        match input.find(':') {
+            Some(idx) if input[..idx].trim().eq_ignore_ascii_case("header") => {
+                let name = input[idx+1..].trim();
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| err!("'{}' is not a valid header name", name))?;
+                Ok(RateLimitKey::Header(name))
+            },
+            _ => Err(err!("Expecting 'ip' or 'header:NAME' but got '{}'", input))
+        }
+    }
+
+    fn client(&self, headers: &HeaderMap, remote_addr: Option<SocketAddr>) -> String {
+        if let RateLimitKey::Header(name) = self {
+            if let Some(value) = headers.get(name).and_then(|v| v.to_str().ok()) {
+                return format!("{}: {}", name, value);
+            }
+        }
+        match remote_addr {
+            Some(addr) => addr.ip().to_string(),
+            None => "unix".to_owned()
+        }
+    }
+}
+
+/// The buckets of each client of a route:
+#[derive(Debug,Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>
+}
+
+#[derive(Debug,Clone,Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter::default()
+    }
+
+    /// Let a request through if its client has a token to spare, else hand
+    /// back how long it should wait before trying again.
+    pub fn check(&self, limit: &RateLimit, headers: &HeaderMap, remote_addr: Option<SocketAddr>) -> Result<(), Duration> {
+        let rate = match &limit.rate {
+            Some(rate) => rate,
+            None => return Ok(())
+        };
+        let client = limit.key.client(headers, remote_addr);
+        let burst = f64::from(limit.burst.unwrap_or(rate.requests).max(1));
+        self.take(client, rate.per_sec(), burst, Instant::now())
+    }
+
+    fn take(&self, client: String, per_sec: f64, burst: f64, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| refill(bucket, per_sec, burst, now) < burst);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = refill(bucket, per_sec, burst, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+/// How many tokens a bucket has now:
+fn refill(bucket: &Bucket, per_sec: f64, burst: f64, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * per_sec).min(burst)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(Rate::parse("10/s").unwrap(), Rate { requests: 10, per: Duration::from_secs(1) });
+        assert_eq!(Rate::parse("100/m").unwrap(), Rate { requests: 100, per: Duration::from_secs(60) });
+        assert!(Rate::parse("0/s").is_err());
+        assert!(Rate::parse("10/d").is_err());
+        assert!(Rate::parse("10").is_err());
+
+        assert_eq!(RateLimitKey::parse("ip").unwrap(), RateLimitKey::Ip);
+        assert_eq!(RateLimitKey::parse("header:X-Api-Key").unwrap(), RateLimitKey::Header(HeaderName::from_static("x-api-key")));
+        assert!(RateLimitKey::parse("cookie:session").is_err());
+    }
+
+    #[test]
+    fn limits_each_client() {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        // 2 per second, in bursts of up to 3:
+        let take = |client: &str, secs: f64| limiter.take(client.to_owned(), 2.0, 3.0, start + Duration::from_secs_f64(secs));
+
+        assert!(take("a", 0.0).is_ok());
+        assert!(take("a", 0.0).is_ok());
+        assert!(take("a", 0.0).is_ok());
+        assert_eq!(take("a", 0.0), Err(Duration::from_millis(500)));
+        // Other clients have their own buckets:
+        assert!(take("b", 0.0).is_ok());
+        // Tokens come back over time:
+        assert!(take("a", 0.5).is_ok());
+        assert!(take("a", 0.5).is_err());
+        assert!(take("a", 10.0).is_ok());
+        assert!(take("a", 10.0).is_ok());
+        assert!(take("a", 10.0).is_ok());
+        assert!(take("a", 10.0).is_err());
+    }
+}
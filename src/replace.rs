@@ -0,0 +1,331 @@
+use hyper::{ HeaderMap, Method, Response, StatusCode };
+use hyper::header::{ HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE };
+use log::{ debug, warn };
+use regex::Regex;
+use std::borrow::Cow;
+use crate::body::{ Body };
+use crate::compress;
+use crate::errors::{ Error };
+use crate::headers;
+use crate::proxy;
+
+/// The content types we rewrite, if not provided:
+pub const DEFAULT_REPLACE_TYPES: &[&str] = &[
+    "text/*",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "application/xhtml+xml",
+    "image/svg+xml"
+];
+
+/// Responses up to this size are rewritten in one go, so that we can say
+/// how long they are afterwards. Bigger ones (and those of unknown size)
+/// are rewritten as they stream by.
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+/// When streaming, we rewrite a line at a time, holding on to the end of
+/// a line until the rest of it arrives. Lines longer than this are
+/// rewritten in pieces, so matches across the pieces are missed.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Find and replace text in the bodies of responses, like rewriting the
+/// absolute URLs of a destination to point at us instead.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ReplaceOptions {
+    /// Applied in the order they were given:
+    pub rules: Vec<Replacement>,
+    /// The content types to rewrite, like `text/*`:
+    pub types: Vec<String>
+}
+
+#[derive(Debug,Clone)]
+pub struct Replacement {
+    find: Find,
+    with: String
+}
+
+#[derive(Debug,Clone)]
+enum Find {
+    Text(String),
+    Regex(Regex)
+}
+
+/// How the text of a body is encoded, going by the `charset` of its
+/// `Content-Type`:
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Charset {
+    Utf8,
+    /// Each byte is a character, which also lets us rewrite the ASCII
+    /// parts of single byte encodings like Windows-1252 safely:
+    Latin1
+}
+
+impl Default for ReplaceOptions {
+    fn default() -> ReplaceOptions {
+        ReplaceOptions {
+            rules: vec![],
+            types: DEFAULT_REPLACE_TYPES.iter().map(|t| t.to_string()).collect()
+        }
+    }
+}
+
+impl Replacement {
+    /// Parse `FROM=>TO`, replacing the text `FROM`:
+    pub fn text(input: &str) -> Result<Replacement, Error> {
+        let (from, with) = split(input)?;
+        Ok(Replacement { find: Find::Text(from.to_owned()), with: with.to_owned() })
+    }
+
+    /// Parse `PATTERN=>TO`, replacing matches of a regular expression. `TO`
+    /// can refer to capture groups with `$1`, `$name` and so on.
+    pub fn regex(input: &str) -> Result<Replacement, Error> {
+        let (pattern, with) = split(input)?;
+        let re = Regex::new(pattern).map_err(|e| err!("'{}' is not a valid regular expression: {}", pattern, e))?;
+        Ok(Replacement { find: Find::Regex(re), with: with.to_owned() })
+    }
+
+    fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match &self.find {
+            Find::Text(from) if text.contains(from.as_str()) => Cow::Owned(text.replace(from.as_str(), &self.with)),
+            Find::Text(_) => Cow::Borrowed(text),
+            Find::Regex(re) => re.replace_all(text, self.with.as_str())
+        }
+    }
+}
+
+impl PartialEq for Replacement {
+    fn eq(&self, other: &Self) -> bool {
+        let same_find = match (&self.find, &other.find) {
+            (Find::Text(a), Find::Text(b)) => a == b,
+            (Find::Regex(a), Find::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false
+        };
+        same_find && self.with == other.with
+    }
+}
+
+fn split(input: &str) -> Result<(&str, &str), Error> {
+    let idx = input.find("=>").ok_or_else(|| err!("Expecting something like 'FROM=>TO' but got '{}'", input))?;
+    let from = &input[..idx];
+    if from.is_empty() {
+        return Err(err!("Expecting something to replace before '=>' in '{}'", input));
+    }
+    Ok((from, &input[idx+2..]))
+}
+
+/// Rewrite the body of a response, if it's one of the types we rewrite
+/// and we understand its charset. Compressed bodies are left alone (the
+/// `decompress` option can help with those).
+pub async fn replace(mut resp: Response<Body>, method: &Method, options: &ReplaceOptions) -> Result<Response<Body>, Error> {
+    if options.rules.is_empty()
+        || resp.status() == StatusCode::NO_CONTENT
+        || resp.status() == StatusCode::PARTIAL_CONTENT
+        || resp.status() == StatusCode::NOT_MODIFIED
+        || !headers::content_type_matches(resp.headers(), &options.types) {
+        return Ok(resp);
+    }
+    if resp.headers().contains_key(CONTENT_ENCODING) {
+        debug!("Not rewriting a compressed response");
+        return Ok(resp);
+    }
+    let charset = match charset(resp.headers()) {
+        Some(charset) => charset,
+        None => {
+            debug!("Not rewriting a response with an unknown charset");
+            return Ok(resp);
+        }
+    };
+
+    let len = resp.headers().get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    compress::remove_length(resp.headers_mut());
+    if *method == Method::HEAD {
+        return Ok(resp);
+    }
+
+    let rewriter = Rewriter { rules: options.rules.clone(), charset, pending: vec![] };
+    match len {
+        Some(len) if len <= MAX_BUFFERED => {
+            let (mut parts, body) = resp.into_parts();
+            let body = rewriter.finish(proxy::read_body(body).await?);
+            parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+            Ok(Response::from_parts(parts, Body::from(body)))
+        },
+        _ => Ok(resp.map(|body| stream(body, rewriter)))
+    }
+}
+
+fn stream(mut body: Body, mut rewriter: Rewriter) -> Body {
+    let (mut sender, new_body) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("Error rewriting response: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+            let rewritten = rewriter.push(&chunk);
+            // The client has gone away, so stop:
+            if !rewritten.is_empty() && sender.send_data(rewritten.into()).await.is_err() {
+                return;
+            }
+        }
+        let _ = sender.send_data(rewriter.finish(vec![]).into()).await;
+    });
+    new_body
+}
+
+fn charset(headers: &HeaderMap) -> Option<Charset> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let charset = content_type.split(';').skip(1)
+        .filter_map(|param| {
+            let idx = param.find('=')?;
+            if param[..idx].trim().eq_ignore_ascii_case("charset") {
+                Some(param[idx+1..].trim().trim_matches('"').to_lowercase())
+            } else {
+                None
+            }
+        })
+        .next();
+    match charset.as_deref() {
+        None | Some("utf-8") | Some("utf8") | Some("us-ascii") => Some(Charset::Utf8),
+        Some("iso-8859-1") | Some("latin1") | Some("windows-1252") => Some(Charset::Latin1),
+        _ => None
+    }
+}
+
+struct Rewriter {
+    rules: Vec<Replacement>,
+    charset: Charset,
+    /// The start of a line we've yet to see the end of:
+    pending: Vec<u8>
+}
+
+impl Rewriter {
+    /// Rewrite as much of the body as we can, having been handed the next
+    /// chunk of it.
+    fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        let end = match self.pending.iter().rposition(|&b| b == b'\n') {
+            Some(idx) => idx + 1,
+            None if self.pending.len() > MAX_LINE => self.boundary(),
+            None => return vec![]
+        };
+        let rest = self.pending.split_off(end);
+        let done = std::mem::replace(&mut self.pending, rest);
+        self.rewrite(done)
+    }
+
+    /// Rewrite whatever's left, and then `rest`:
+    fn finish(mut self, rest: Vec<u8>) -> Vec<u8> {
+        self.pending.extend_from_slice(&rest);
+        let done = std::mem::take(&mut self.pending);
+        self.rewrite(done)
+    }
+
+    fn rewrite(&self, bytes: Vec<u8>) -> Vec<u8> {
+        let text = match self.charset {
+            Charset::Utf8 => match String::from_utf8(bytes) {
+                Ok(text) => text,
+                // Leave what we can't read as it is:
+                Err(e) => return e.into_bytes()
+            },
+            Charset::Latin1 => bytes.iter().map(|&b| b as char).collect()
+        };
+        let mut text = Cow::Owned(text);
+        for rule in &self.rules {
+            if let Cow::Owned(replaced) = rule.apply(&text) {
+                text = Cow::Owned(replaced);
+            }
+        }
+        match self.charset {
+            Charset::Utf8 => text.into_owned().into_bytes(),
+            Charset::Latin1 => text.chars().map(|c| if (c as u32) < 256 { c as u8 } else { b'?' }).collect()
+        }
+    }
+
+    /// Where we can split the pending bytes without splitting a character:
+    fn boundary(&self) -> usize {
+        let bytes = &self.pending;
+        if self.charset == Charset::Latin1 {
+            return bytes.len();
+        }
+        // Step back over the bytes of a character that's been cut short:
+        for back in 1..=std::cmp::min(3, bytes.len()) {
+            let b = bytes[bytes.len() - back];
+            if b & 0xC0 == 0x80 {
+                continue;
+            }
+            let width = if b >= 0xF0 { 4 } else if b >= 0xE0 { 3 } else if b >= 0xC0 { 2 } else { 1 };
+            return if back < width { bytes.len() - back } else { bytes.len() };
+        }
+        bytes.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn rewriter(rules: &[Replacement], charset: Charset) -> Rewriter {
+        Rewriter { rules: rules.to_vec(), charset, pending: vec![] }
+    }
+
+    #[test]
+    fn parses_replacements() {
+        assert!(Replacement::text("http://internal:8080=>https://example.com").is_ok());
+        assert!(Replacement::regex(r"https?://internal(:\d+)?=>https://example.com").is_ok());
+        assert!(Replacement::text("no arrow").is_err());
+        assert!(Replacement::text("=>nothing").is_err());
+        assert!(Replacement::regex("(=>broken").is_err());
+    }
+
+    #[test]
+    fn rewrites_across_chunks() {
+        let rules = [
+            Replacement::text("http://internal:8080=>https://example.com").unwrap(),
+            Replacement::regex(r"v(\d)/=>api/v$1/").unwrap()
+        ];
+        let mut rewriter = rewriter(&rules, Charset::Utf8);
+        let mut out = rewriter.push(b"<a href=\"http://inter");
+        assert!(out.is_empty());
+        out.extend(rewriter.push(b"nal:8080/v2/users\">caf\xc3"));
+        out.extend(rewriter.push(b"\xa9</a>\nhttp://internal:8080"));
+        out.extend(rewriter.finish(vec![]));
+        assert_eq!(String::from_utf8(out).unwrap(), "<a href=\"https://example.com/api/v2/users\">caf\u{e9}</a>\nhttps://example.com");
+    }
+
+    #[test]
+    fn keeps_characters_whole_in_long_lines() {
+        let rules = [Replacement::text("é=>e").unwrap()];
+        let mut rewriter = rewriter(&rules, Charset::Utf8);
+        let mut line = vec![b'a'; MAX_LINE];
+        line.push(0xc3);
+        let mut out = rewriter.push(&line);
+        out.extend(rewriter.push(b"\xa9"));
+        out.extend(rewriter.finish(vec![]));
+        assert_eq!(&out[MAX_LINE..], b"e");
+    }
+
+    #[test]
+    fn understands_charsets() {
+        let headers = |content_type: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            headers
+        };
+        assert_eq!(charset(&headers("text/html")), Some(Charset::Utf8));
+        assert_eq!(charset(&headers("text/html; charset=\"UTF-8\"")), Some(Charset::Utf8));
+        assert_eq!(charset(&headers("text/html; charset=ISO-8859-1")), Some(Charset::Latin1));
+        assert_eq!(charset(&headers("text/html; charset=shift_jis")), None);
+
+        let rules = [Replacement::text("caf\u{e9}=>bar").unwrap()];
+        assert_eq!(rewriter(&rules, Charset::Latin1).finish(b"un caf\xe9\n".to_vec()), b"un bar\n");
+    }
+}
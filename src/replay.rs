@@ -0,0 +1,310 @@
+use ansi_term::Color::{ Green, Red, Yellow };
+use clap::{ App, AppSettings, Arg };
+use futures::future::join_all;
+use hyper::{ HeaderMap, Method, Request, Uri };
+use hyper::header::{ HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING };
+use lazy_static::lazy_static;
+use log::{ info, warn };
+use regex::Regex;
+use serde_json::Value;
+use std::path::Path;
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+use tokio::time::{ self, sleep_until };
+use url::Url;
+use crate::body::{ Body };
+use crate::client::HttpsClient;
+use crate::errors::{ Error };
+use crate::logging::{ self, LogFormat };
+use crate::options::{ TlsOptions };
+use crate::proxy;
+use crate::settings::Settings;
+use crate::syslog::{ LogTarget };
+
+/// How long we wait for each response before counting it as failed:
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+/// What redacted header values look like in the HAR files we write:
+const REDACTED: &str = "[redacted]";
+
+/// A request we've read from a HAR or access log file, to send again.
+#[derive(Debug,Clone,PartialEq)]
+struct Recorded {
+    time: SystemTime,
+    method: Method,
+    /// The path and query the request was made to:
+    path: String,
+    /// Access logs don't have headers or bodies:
+    headers: HeaderMap,
+    body: Vec<u8>,
+    /// The status it was responded to with, if we know it:
+    status: Option<u16>
+}
+
+/// How a replayed request went.
+enum Outcome {
+    Matched,
+    Mismatched,
+    Failed
+}
+
+/// `weave replay FILE --target URL`: send the requests in a HAR file, or a
+/// `json`, `common` or `combined` access log, to a target again, at their
+/// original pace or faster, and report any whose status doesn't match.
+pub async fn run(args: impl Iterator<Item=String>) -> Result<(), Error> {
+    let matches = App::new("weave replay")
+        .about("Send the requests recorded in a HAR file or access log again, reporting responses whose status differs.")
+        .usage("weave replay FILE --target URL [--speed FACTOR]")
+        .setting(AppSettings::NoBinaryName)
+        .arg(Arg::with_name("file")
+            .value_name("FILE")
+            .required(true)
+            .help("A HAR file, or an access log written with --log-format json, common or combined"))
+        .arg(Arg::with_name("target")
+            .long("target")
+            .value_name("URL")
+            .required(true)
+            .help("Where to send the requests (eg http://localhost:8080). Their paths and queries are kept"))
+        .arg(Arg::with_name("speed")
+            .long("speed")
+            .value_name("FACTOR")
+            .help("How much faster than they were made to send the requests (eg 2 for twice as fast), or 'max' to send them all at once. Defaults to 1"))
+        .arg(Arg::with_name("insecure")
+            .long("insecure")
+            .help("Don't verify the certificate of an HTTPS target"))
+        .get_matches_from(args);
+    logging::init(LogFormat::Text, &LogTarget::Stderr, None)?;
+
+    let target = matches.value_of("target").unwrap();
+    let target = Url::parse(target).map_err(|e| err!("Invalid --target '{}': {}", target, e))?;
+    if target.scheme() != "http" && target.scheme() != "https" {
+        return Err(err!("Invalid --target '{}': Expecting an http:// or https:// URL", target));
+    }
+    let speed = match matches.value_of("speed") {
+        Some(s) => parse_speed(s).map_err(|e| err!("Invalid --speed '{}': {}", s, e))?,
+        None => Some(1.0)
+    };
+    let path = Path::new(matches.value_of("file").unwrap());
+    let contents = std::fs::read_to_string(path).map_err(|e| err!("Cannot read '{}': {}", path.display(), e))?;
+    let recorded = parse_recorded(&contents)?;
+
+    let settings = Settings { insecure: matches.is_present("insecure"), ..Settings::default() };
+    let client = HttpsClient::new(&settings)?;
+
+    // Each request is sent at the same time after the first as it was
+    // originally, divided by the speed:
+    let first = recorded.iter().map(|r| r.time).min().unwrap_or(UNIX_EPOCH);
+    let started = Instant::now();
+    let sends = recorded.into_iter().map(|recorded| {
+        let offset = recorded.time.duration_since(first).unwrap_or_default();
+        let at = match speed {
+            Some(speed) => started + offset.div_f64(speed),
+            None => started
+        };
+        let (client, target) = (client.clone(), target.clone());
+        async move {
+            sleep_until(at.into()).await;
+            replay(recorded, &target, &client).await
+        }
+    });
+    let outcomes = join_all(sends).await;
+
+    let matched = outcomes.iter().filter(|o| matches!(o, Outcome::Matched)).count();
+    let mismatched = outcomes.iter().filter(|o| matches!(o, Outcome::Mismatched)).count();
+    let failed = outcomes.iter().filter(|o| matches!(o, Outcome::Failed)).count();
+    info!("Replayed {} requests in {:#?}: {} matched, {} mismatched, {} failed",
+          outcomes.len(), started.elapsed(), matched, mismatched, failed);
+    if mismatched > 0 || failed > 0 {
+        return Err(err!("{} of {} replayed requests failed or got a different status", mismatched + failed, outcomes.len()));
+    }
+    Ok(())
+}
+
+/// Send a request again, and compare the status we get back:
+async fn replay(recorded: Recorded, target: &Url, client: &HttpsClient) -> Outcome {
+    let uri: Uri = match format!("{}{}", target.as_str().trim_end_matches('/'), recorded.path).parse() {
+        Ok(uri) => uri,
+        Err(e) => {
+            warn!("{}", Red.paint(format!("[failed] {} {} (invalid path: {})", recorded.method, recorded.path, e)));
+            return Outcome::Failed;
+        }
+    };
+    let mut req = Request::new(Body::from(recorded.body));
+    *req.method_mut() = recorded.method.clone();
+    *req.uri_mut() = uri;
+    *req.headers_mut() = recorded.headers;
+
+    let before = Instant::now();
+    let sent = time::timeout(REPLAY_TIMEOUT, async {
+        let resp = client.request(req, &TlsOptions::default()).await?;
+        let status = resp.status().as_u16();
+        // Read the whole response, so that the connection can be reused:
+        proxy::read_body(resp.into_body()).await?;
+        Ok::<_, Error>(status)
+    });
+
+    let status = match sent.await {
+        Ok(Ok(status)) => status,
+        Ok(Err(e)) => {
+            warn!("{}", Red.paint(format!("[failed] {} {} ({})", recorded.method, recorded.path, e)));
+            return Outcome::Failed;
+        },
+        Err(_) => {
+            warn!("{}", Red.paint(format!("[failed] {} {} (timed out after {:#?})", recorded.method, recorded.path, REPLAY_TIMEOUT)));
+            return Outcome::Failed;
+        }
+    };
+    match recorded.status {
+        Some(expected) if expected != status => {
+            warn!("{}", Yellow.paint(format!("[{}] {} {} (expected {}) in {:#?}", status, recorded.method, recorded.path, expected, before.elapsed())));
+            Outcome::Mismatched
+        },
+        _ => {
+            info!("{}", Green.paint(format!("[{}] {} {} in {:#?}", status, recorded.method, recorded.path, before.elapsed())));
+            Outcome::Matched
+        }
+    }
+}
+
+/// Parse a speed up like `2` or `0.5`, or `max` for no waiting at all:
+fn parse_speed(input: &str) -> Result<Option<f64>, Error> {
+    if input.trim().eq_ignore_ascii_case("max") {
+        return Ok(None);
+    }
+    match input.trim().parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(Some(speed)),
+        _ => Err(err!("Expecting a number greater than 0, or 'max'"))
+    }
+}
+
+/// Read requests from a HAR file, or else from each line of an access log:
+fn parse_recorded(contents: &str) -> Result<Vec<Recorded>, Error> {
+    let recorded = match serde_json::from_str::<Value>(contents) {
+        Ok(har) if har.get("log").is_some() => parse_har(&har)?,
+        _ => contents.lines().filter_map(parse_log_line).collect()
+    };
+    if recorded.is_empty() {
+        return Err(err!("Expecting a HAR file, or an access log in the json, common or combined format, but found no requests"));
+    }
+    Ok(recorded)
+}
+
+fn parse_har(har: &Value) -> Result<Vec<Recorded>, Error> {
+    let entries = har["log"]["entries"].as_array().ok_or_else(|| err!("Expecting a list of entries in the HAR file"))?;
+    entries.iter().enumerate().map(|(n, entry)| {
+        parse_har_entry(entry).ok_or_else(|| err!("Cannot make sense of entry {} in the HAR file", n + 1))
+    }).collect()
+}
+
+fn parse_har_entry(entry: &Value) -> Option<Recorded> {
+    let request = &entry["request"];
+    let url = Url::parse(request["url"].as_str()?).ok()?;
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_owned()
+    };
+
+    // The body is sent as it is, and the target is a different host:
+    let mut headers = HeaderMap::new();
+    for header in request["headers"].as_array().map(|h| h.as_slice()).unwrap_or(&[]) {
+        let value = header["value"].as_str()?;
+        // Pseudo headers (like `:authority`) aren't real ones, and
+        // redacted ones would only be wrong:
+        let name = match HeaderName::from_bytes(header["name"].as_str()?.as_bytes()) {
+            Ok(name) if value != REDACTED => name,
+            _ => continue
+        };
+        if [CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING].contains(&name) {
+            continue;
+        }
+        headers.append(name, HeaderValue::from_str(value).ok()?);
+    }
+
+    Some(Recorded {
+        time: logging::parse_rfc3339(entry["startedDateTime"].as_str()?)?,
+        method: Method::from_bytes(request["method"].as_str()?.as_bytes()).ok()?,
+        path,
+        headers,
+        body: request["postData"]["text"].as_str().unwrap_or("").as_bytes().to_vec(),
+        // Browsers record requests that never got a response with status 0:
+        status: entry["response"]["status"].as_u64().filter(|s| *s > 0).map(|s| s as u16)
+    })
+}
+
+/// Read a request from a line of an access log, skipping anything else
+/// that might be logged alongside them:
+fn parse_log_line(line: &str) -> Option<Recorded> {
+    lazy_static! {
+        // `host ident user [time] "method path version" status bytes`:
+        static ref COMMON: Regex = Regex::new(r#"^\S+ \S+ \S+ \[([^\]]+)\] "(\S+) (\S+)[^"]*" (\d{3}) "#).unwrap();
+    }
+    let (time, method, path, status) = if line.starts_with('{') {
+        let entry: Value = serde_json::from_str(line).ok()?;
+        (logging::parse_rfc3339(entry["timestamp"].as_str()?)?,
+         entry["method"].as_str()?.to_owned(),
+         entry["path"].as_str()?.to_owned(),
+         entry["status"].as_u64()? as u16)
+    } else {
+        let caps = COMMON.captures(line)?;
+        (logging::parse_clf_time(&caps[1])?, caps[2].to_owned(), caps[3].to_owned(), caps[4].parse().ok()?)
+    };
+    Some(Recorded {
+        time,
+        method: Method::from_bytes(method.as_bytes()).ok()?,
+        path,
+        headers: HeaderMap::new(),
+        body: vec![],
+        status: Some(status)
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn reads_har_files() {
+        let har = r#"{"log":{"version":"1.2","entries":[{
+            "startedDateTime": "2000-02-29T12:34:56.789Z",
+            "request": {
+                "method": "POST",
+                "url": "http://localhost:8080/api?x=1",
+                "headers": [
+                    {"name": "host", "value": "localhost:8080"},
+                    {"name": ":authority", "value": "localhost:8080"},
+                    {"name": "authorization", "value": "[redacted]"},
+                    {"name": "content-type", "value": "application/json"}
+                ],
+                "postData": {"mimeType": "application/json", "text": "{}"}
+            },
+            "response": {"status": 201}
+        }]}}"#;
+        let recorded = parse_recorded(har).unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].method, Method::POST);
+        assert_eq!(recorded[0].path, "/api?x=1");
+        assert_eq!(recorded[0].headers.len(), 1);
+        assert_eq!(recorded[0].body, b"{}");
+        assert_eq!(recorded[0].status, Some(201));
+    }
+
+    #[test]
+    fn reads_access_logs() {
+        let log = concat!(
+            r#"{"timestamp":"2000-02-29T12:34:56.789Z","level":"INFO","target":"stargate","message":"Starting"}"#, "\n",
+            r#"{"timestamp":"2000-02-29T12:34:56.789Z","method":"GET","path":"/a?b=c","status":200}"#, "\n",
+            r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 +0000] "DELETE /x HTTP/1.1" 404 - "-" "curl/7.0""#, "\n"
+        );
+        let recorded = parse_recorded(log).unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!((recorded[0].method.as_str(), recorded[0].path.as_str(), recorded[0].status), ("GET", "/a?b=c", Some(200)));
+        assert_eq!((recorded[1].method.as_str(), recorded[1].path.as_str(), recorded[1].status), ("DELETE", "/x", Some(404)));
+        assert!(parse_recorded("nothing to see here").is_err());
+    }
+
+    #[test]
+    fn parses_speeds() {
+        assert_eq!(parse_speed("2").unwrap(), Some(2.0));
+        assert_eq!(parse_speed("MAX").unwrap(), None);
+        assert!(parse_speed("0").is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use hyper::{ HeaderMap, Request };
+use hyper::header::{ HeaderName, HeaderValue };
+use ring::rand::{ SecureRandom, SystemRandom };
+use std::fmt;
+
+/// The header we take request IDs from, and send them on and back in:
+pub const X_REQUEST_ID: &str = "x-request-id";
+/// The longest ID we'll take from a client:
+const MAX_LENGTH: usize = 200;
+
+/// Identifies a request in our logs, the destination's logs and whatever
+/// the client keeps, so that they can be tied together. Added to the
+/// extensions of each request, unless `--no-request-ids` is given.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    /// The ID the client gave a request, if it's one we can use, or a new
+    /// one if not:
+    pub fn for_request(headers: &HeaderMap) -> RequestId {
+        headers.get(X_REQUEST_ID)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| is_valid(id))
+            .map(|id| RequestId(id.to_owned()))
+            .unwrap_or_else(RequestId::generate)
+    }
+
+    fn generate() -> RequestId {
+        let mut bytes = [0; 16];
+        SystemRandom::new().fill(&mut bytes).expect("random numbers are available");
+        RequestId(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Set the ID in some headers, in place of any other:
+    pub fn insert(&self, headers: &mut HeaderMap) {
+        let value = HeaderValue::from_str(&self.0).expect("request IDs are valid header values");
+        headers.insert(HeaderName::from_static(X_REQUEST_ID), value);
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Where a request's ID goes in what we log about it, if it has one:
+pub fn suffix<T>(req: &Request<T>) -> String {
+    match req.extensions().get::<RequestId>() {
+        Some(id) => format!(" (id {})", id),
+        None => String::new()
+    }
+}
+
+/// IDs end up in logs, so we only take printable ones without spaces:
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn honors_usable_ids() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_REQUEST_ID, "client-1234".parse().unwrap());
+        assert_eq!(RequestId::for_request(&headers).as_str(), "client-1234");
+
+        for unusable in &["", "has spaces", &"a".repeat(MAX_LENGTH + 1)] {
+            headers.insert(X_REQUEST_ID, unusable.parse().unwrap());
+            let id = RequestId::for_request(&headers);
+            assert_eq!(id.as_str().len(), 32, "{}", unusable);
+        }
+    }
+
+    #[test]
+    fn generates_unique_ids() {
+        let id = RequestId::for_request(&HeaderMap::new());
+        assert!(id.as_str().bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(id, RequestId::for_request(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,271 @@
+use hyper::{ Request, Response };
+use hyper::header::{ HeaderValue, LOCATION, SET_COOKIE };
+use log::{ debug };
+use url::{ Origin, Url };
+use crate::body::{ Body };
+use crate::errors::{ Error };
+
+/// How to rewrite the cookies that destinations set, so that they still
+/// work behind us:
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct CookieOptions {
+    /// Drop `Domain` attributes naming the destination's host (so cookies
+    /// belong to whatever host clients used), and map `Path` attributes
+    /// onto the paths clients see:
+    pub rewrite: bool,
+    /// Set the `Domain` of every cookie to this instead:
+    pub domain: Option<String>,
+    /// Add (or remove) the `Secure` attribute of every cookie:
+    pub secure: Option<bool>,
+    /// Set the `SameSite` attribute of every cookie to this:
+    pub same_site: Option<String>
+}
+
+impl CookieOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.rewrite || self.domain.is_some() || self.secure.is_some() || self.same_site.is_some()
+    }
+
+    /// Parse `Strict`, `Lax` or `None`:
+    pub fn parse_same_site(input: &str) -> Result<String, Error> {
+        ["Strict", "Lax", "None"].iter()
+            .find(|s| s.eq_ignore_ascii_case(input.trim()))
+            .map(|s| s.to_string())
+            .ok_or_else(|| err!("Expecting a SameSite of Strict, Lax or None but got '{}'", input))
+    }
+}
+
+/// How the URLs of a destination map onto the URLs that clients see,
+/// worked out from a request and where we proxied it to. Destinations
+/// don't know they're behind us, so this lets us rewrite what they say
+/// about themselves (like where to redirect to) to keep clients on our
+/// side of the proxy.
+#[derive(Debug,Clone,PartialEq)]
+pub struct UrlMapping {
+    /// The destination's origin, like `http://localhost:9000`:
+    upstream_origin: Origin,
+    /// The destination's paths under here are ones we route to, like `/app`:
+    upstream_prefix: String,
+    /// The origin the client asked us for, like `http://example.com:8080`,
+    /// if it told us (with a `Host` header):
+    external_origin: Option<String>,
+    /// Where those paths are to clients, like `/api`:
+    external_prefix: String
+}
+
+impl UrlMapping {
+    /// Compare the path of a request with the path it was proxied to. What
+    /// they end with is the part of the path the route passed on, and what
+    /// comes before that is how the source and destination differ.
+    pub fn new<T>(req: &Request<T>, upstream: &Url) -> UrlMapping {
+        let external_path = req.uri().path();
+        let upstream_path = upstream.path();
+        let shared = shared_tail(external_path, upstream_path);
+
+        // We only listen on plain HTTP at the moment:
+        let external_origin = req.headers().get("host")
+            .and_then(|host| host.to_str().ok())
+            .map(|host| format!("http://{}", host));
+
+        UrlMapping {
+            upstream_origin: upstream.origin(),
+            upstream_prefix: upstream_path[..upstream_path.len() - shared].trim_end_matches('/').to_owned(),
+            external_origin,
+            external_prefix: external_path[..external_path.len() - shared].trim_end_matches('/').to_owned()
+        }
+    }
+
+    /// The URL a client should see in place of one the destination gave
+    /// (relative to `base`, the URL we proxied to), if it's one of the
+    /// destination's URLs that we route to:
+    pub fn map_url(&self, base: &Url, url: &str) -> Option<String> {
+        let url = base.join(url).ok()?;
+        if url.origin() != self.upstream_origin {
+            return None;
+        }
+        let mut mapped = self.external_origin.clone().unwrap_or_default();
+        mapped.push_str(&self.map_path(url.path())?);
+        if let Some(query) = url.query() {
+            mapped.push('?');
+            mapped.push_str(query);
+        }
+        if let Some(fragment) = url.fragment() {
+            mapped.push('#');
+            mapped.push_str(fragment);
+        }
+        Some(mapped)
+    }
+
+    /// The path a client should see in place of one of the destination's,
+    /// if it's under the path we route to:
+    pub fn map_path(&self, path: &str) -> Option<String> {
+        let rest = path.strip_prefix(self.upstream_prefix.as_str())?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        let mapped = format!("{}{}", self.external_prefix, rest);
+        Some(if mapped.is_empty() { "/".to_owned() } else { mapped })
+    }
+
+    /// Is this domain (from a cookie, say) the destination's host, or one
+    /// of its parents?
+    fn is_upstream_domain(&self, domain: &str) -> bool {
+        let host = match &self.upstream_origin {
+            Origin::Tuple(_, host, _) => host.to_string().to_lowercase(),
+            Origin::Opaque(_) => return false
+        };
+        let domain = domain.trim_start_matches('.').to_lowercase();
+        host == domain || host.ends_with(&format!(".{}", domain))
+    }
+}
+
+/// Point a `Location` header that refers to the destination (like on
+/// redirects) at the same place through us instead:
+pub fn rewrite_location(resp: &mut Response<Body>, base: &Url, mapping: &UrlMapping) {
+    let location = match resp.headers().get(LOCATION).and_then(|location| location.to_str().ok()) {
+        Some(location) => location.to_owned(),
+        None => return
+    };
+    let mapped = mapping.map_url(base, &location)
+        .and_then(|mapped| HeaderValue::from_str(&mapped).ok());
+    if let Some(mapped) = mapped {
+        debug!("Rewriting Location '{}' to '{}'", location, mapped.to_str().unwrap_or(""));
+        resp.headers_mut().insert(LOCATION, mapped);
+    }
+}
+
+/// Rewrite the attributes of the cookies a destination sets:
+pub fn rewrite_cookies(resp: &mut Response<Body>, mapping: &UrlMapping, options: &CookieOptions) {
+    let cookies: Vec<HeaderValue> = resp.headers().get_all(SET_COOKIE).iter()
+        .map(|cookie| {
+            cookie.to_str().ok()
+                .and_then(|c| HeaderValue::from_str(&rewrite_cookie(c, mapping, options)).ok())
+                .unwrap_or_else(|| cookie.clone())
+        })
+        .collect();
+    if cookies.is_empty() {
+        return;
+    }
+    resp.headers_mut().remove(SET_COOKIE);
+    for cookie in cookies {
+        resp.headers_mut().append(SET_COOKIE, cookie);
+    }
+}
+
+fn rewrite_cookie(cookie: &str, mapping: &UrlMapping, options: &CookieOptions) -> String {
+    let mut parts = cookie.split(';').map(|part| part.trim());
+    let mut rewritten = vec![parts.next().unwrap_or("").to_owned()];
+    for attr in parts.filter(|attr| !attr.is_empty()) {
+        let (name, value) = match attr.find('=') {
+            Some(idx) => (attr[..idx].trim(), Some(attr[idx+1..].trim())),
+            None => (attr, None)
+        };
+        let name_is = |n: &str| name.eq_ignore_ascii_case(n);
+        match value {
+            Some(_) if name_is("domain") && options.domain.is_some() => {},
+            Some(domain) if name_is("domain") && options.rewrite && mapping.is_upstream_domain(domain) => {},
+            Some(path) if name_is("path") && options.rewrite => {
+                let path = mapping.map_path(path).unwrap_or_else(|| path.to_owned());
+                rewritten.push(format!("Path={}", path));
+            },
+            _ if name_is("secure") && options.secure.is_some() => {},
+            _ if name_is("samesite") && options.same_site.is_some() => {},
+            _ => rewritten.push(attr.to_owned())
+        }
+    }
+    if let Some(domain) = &options.domain {
+        rewritten.push(format!("Domain={}", domain));
+    }
+    if options.secure == Some(true) {
+        rewritten.push("Secure".to_owned());
+    }
+    if let Some(same_site) = &options.same_site {
+        rewritten.push(format!("SameSite={}", same_site));
+    }
+    rewritten.join("; ")
+}
+
+/// How long the tail two paths share is, taking whole segments only:
+fn shared_tail(a: &str, b: &str) -> usize {
+    let shared = a.bytes().rev().zip(b.bytes().rev()).take_while(|(a, b)| a == b).count();
+    let tail = &a[a.len() - shared..];
+    match tail.find('/') {
+        // The whole of one path, or from a slash onwards:
+        _ if shared == a.len() || shared == b.len() => shared,
+        Some(idx) => shared - idx,
+        None => 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn mapping(path: &str, host: Option<&str>, upstream: &str) -> UrlMapping {
+        let mut req = Request::get(path);
+        if let Some(host) = host {
+            req = req.header("host", host);
+        }
+        UrlMapping::new(&req.body(()).unwrap(), &Url::parse(upstream).unwrap())
+    }
+
+    #[test]
+    fn maps_destination_urls_back_to_ours() {
+        let base = Url::parse("http://localhost:9000/app/users/1").unwrap();
+        let mapping = mapping("/api/users/1", Some("example.com:8080"), base.as_str());
+        let map = |url| mapping.map_url(&base, url);
+
+        assert_eq!(map("http://localhost:9000/app/login?next=%2F").as_deref(), Some("http://example.com:8080/api/login?next=%2F"));
+        assert_eq!(map("/app/users/2#top").as_deref(), Some("http://example.com:8080/api/users/2#top"));
+        assert_eq!(map("2").as_deref(), Some("http://example.com:8080/api/users/2"));
+        assert_eq!(map("http://localhost:9000/app").as_deref(), Some("http://example.com:8080/api"));
+        // Not paths we route to, or not the destination at all:
+        assert_eq!(map("http://localhost:9000/application"), None);
+        assert_eq!(map("/other"), None);
+        assert_eq!(map("http://localhost:9001/app/login"), None);
+        assert_eq!(map("https://example.org/"), None);
+    }
+
+    #[test]
+    fn works_out_prefixes_from_paths() {
+        let prefixes = |path, upstream| {
+            let mapping = mapping(path, None, upstream);
+            (mapping.external_prefix, mapping.upstream_prefix)
+        };
+        assert_eq!(prefixes("/api/users", "http://up/users"), ("/api".to_owned(), "".to_owned()));
+        assert_eq!(prefixes("/api", "http://up/"), ("/api".to_owned(), "".to_owned()));
+        assert_eq!(prefixes("/app/x", "http://up/myapp/x"), ("/app".to_owned(), "/myapp".to_owned()));
+        assert_eq!(prefixes("/articles/5", "http://up/a/5"), ("/articles".to_owned(), "/a".to_owned()));
+        assert_eq!(prefixes("/same", "http://up/same"), ("".to_owned(), "".to_owned()));
+
+        // Without a host, clients get a path on whatever host they asked for:
+        let mapping = mapping("/api/users", None, "http://up/users");
+        assert_eq!(mapping.map_url(&Url::parse("http://up/users").unwrap(), "/").as_deref(), Some("/api/"));
+    }
+
+    #[test]
+    fn rewrites_cookies() {
+        let mapping = mapping("/api/login", None, "http://app.internal:9000/app/login");
+        let rewrite = |cookie, options: &CookieOptions| rewrite_cookie(cookie, &mapping, options);
+
+        let options = CookieOptions { rewrite: true, ..CookieOptions::default() };
+        assert_eq!(rewrite("id=1; Domain=app.internal; Path=/app/account; HttpOnly", &options), "id=1; Path=/api/account; HttpOnly");
+        assert_eq!(rewrite("id=1; domain=.internal; path=/app", &options), "id=1; Path=/api");
+        // Paths and domains that aren't the destination's are left alone:
+        assert_eq!(rewrite("id=1; Domain=example.com; Path=/other", &options), "id=1; Domain=example.com; Path=/other");
+
+        let options = CookieOptions {
+            domain: Some("example.com".to_owned()),
+            secure: Some(true),
+            same_site: Some("Strict".to_owned()),
+            ..CookieOptions::default()
+        };
+        assert_eq!(rewrite("id=1; Domain=app.internal; Path=/app; SameSite=None; Secure", &options), "id=1; Path=/app; Domain=example.com; Secure; SameSite=Strict");
+        let options = CookieOptions { secure: Some(false), ..CookieOptions::default() };
+        assert_eq!(rewrite("id=1; Secure; HttpOnly", &options), "id=1; HttpOnly");
+
+        assert_eq!(CookieOptions::parse_same_site("lax").unwrap(), "Lax");
+        assert!(CookieOptions::parse_same_site("sometimes").is_err());
+    }
+}
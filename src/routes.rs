@@ -1,6 +1,10 @@
+use serde::{ Serialize, Serializer };
+use serde::ser::{ SerializeMap };
 use std::net::{ SocketAddr, ToSocketAddrs };
 use crate::errors::{ Error };
 use crate::location::{ SrcLocation, DestLocation };
+use crate::options::{ RouteOptions };
+use crate::listeners::{ ListenAddr };
 
 /// Take some args and hand back a vector of Routes we've parsed out of them,
 /// plus an Iterator of unused args:
@@ -13,7 +17,12 @@ pub fn from_args<I: IntoIterator<Item=String>>(args: I) -> Result<(Vec<Route>, i
     let mut args = args.into_iter().peekable();
     let mut expects_more = false;
     while let Some(peeked) = args.peek() {
-        let peeked = peeked.clone();
+        // Flags are left for whoever handles them to interpolate (or not):
+        let peeked = if peeked.starts_with('-') {
+            peeked.clone()
+        } else {
+            interpolate(peeked)?
+        };
         if let Ok(src) = SrcLocation::parse(&peeked) {
 
             // we've parsed more:
@@ -41,6 +50,7 @@ pub fn from_args<I: IntoIterator<Item=String>>(args: I) -> Result<(Vec<Route>, i
             // The arg following the 'to' should be another location
             // or something is wrong:
             let dest = if let Some(dest) = args.next() {
+                let dest = interpolate(&dest)?;
                 DestLocation::parse(&dest).map_err(|e| {
                     err!("Error parsing '{}': {}", dest, e)
                 })
@@ -48,10 +58,49 @@ pub fn from_args<I: IntoIterator<Item=String>>(args: I) -> Result<(Vec<Route>, i
                 Err(err!("Expecting a destination location to be provided after '{} to'", peeked))
             }?;
 
+            // Further destinations to balance requests across
+            // can follow, each after the word 'and-also':
+            let mut dests = vec![dest];
+            while args.peek().map(|a| a.trim() == "and-also").unwrap_or(false) {
+                args.next();
+                let dest = if let Some(dest) = args.next() {
+                    let dest = interpolate(&dest)?;
+                    DestLocation::parse(&dest).map_err(|e| {
+                        err!("Error parsing '{}': {}", dest, e)
+                    })
+                } else {
+                    Err(err!("Expecting a destination location to be provided after 'and-also'"))
+                }?;
+                dests.push(dest);
+            }
+
+            // The destination can be followed by 'with' and then some
+            // options for the route, up until the next 'and' or flag:
+            let mut options = RouteOptions::default();
+            if args.peek().map(|a| a.trim() == "with").unwrap_or(false) {
+                args.next();
+                let mut has_options = false;
+                while let Some(opt) = args.peek() {
+                    let opt = opt.trim().to_owned();
+                    if opt == "and" || opt.starts_with('-') {
+                        break
+                    }
+                    options.set_from_str(&opt).map_err(|e| {
+                        err!("Error parsing option '{}' for '{}': {}", opt, peeked, e)
+                    })?;
+                    has_options = true;
+                    args.next();
+                }
+                if !has_options {
+                    return Err(err!("Expecting options to be provided after '{} to {} with'", peeked, dests_to_string(&dests)));
+                }
+            }
+
             // If we've made it this far, we have a Route:
             routes.push(Route {
                 src,
-                dest
+                dests,
+                options
             });
 
             // Now, we either break or the next arg is 'and':
@@ -93,12 +142,38 @@ pub fn from_args<I: IntoIterator<Item=String>>(args: I) -> Result<(Vec<Route>, i
 #[derive(Debug,Clone,PartialEq)]
 pub struct Route {
     pub src: SrcLocation,
-    pub dest: DestLocation
+    /// Requests are balanced across these destinations. There is always at least one.
+    pub dests: Vec<DestLocation>,
+    pub options: RouteOptions
 }
 
 impl Route {
+    /// Describe the destinations of this route, for logging:
+    pub fn dests_to_string(&self) -> String {
+        dests_to_string(&self.dests)
+    }
+
+    /// Where to listen for requests on this route; either a Unix socket,
+    /// or else the socket address given by `src_socket_addr`:
+    pub fn listen_addr(&self) -> Result<ListenAddr, Error> {
+        match &self.src.socket {
+            Some(path) => Ok(ListenAddr::Unix(path.clone())),
+            None => Ok(ListenAddr::Tcp(self.src_socket_addr()?))
+        }
+    }
+
+    /// The TCP socket address to listen for requests on this route. Routes
+    /// for virtual hosts are listened for on every interface, unless they
+    /// were given an address (eg `api.local@127.0.0.1:8080`):
     pub fn src_socket_addr(&self) -> Result<SocketAddr, Error> {
-        let mut addrs = self.src.url.to_socket_addrs().map_err(|e| {
+        let url = &self.src.url;
+        let host = match url.host_str() {
+            _ if self.src.listens_anywhere() => "0.0.0.0",
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => "localhost"
+        };
+        let port = url.port_or_known_default().unwrap_or(80);
+        let mut addrs = (host, port).to_socket_addrs().map_err(|e| {
             err!("Cannot parse socket address to listen on: {}", e)
         })?;
 
@@ -108,4 +183,355 @@ impl Route {
             Err(err!("Cannot parse socket address to listen on"))
         }
     }
-}
\ No newline at end of file
+}
+
+/// Parse routes out of a string like `8080 to ./a and 8081 to 9000`, as
+/// given in the `WEAVE_ROUTES` environment variable. Args are split on
+/// whitespace, other than in single or double quotes, and there must be
+/// nothing besides routes.
+pub fn from_str(input: &str) -> Result<Vec<Route>, Error> {
+    let (routes, mut rest) = from_args(split_args(input)?)?;
+    match rest.next() {
+        Some(arg) => Err(err!("Expecting a route but got '{}'", arg)),
+        None => Ok(routes)
+    }
+}
+
+/// Parse the routes in the `WEAVE_ROUTES` environment variable, if it's
+/// set, which is handier than arguments in containers:
+pub fn from_env() -> Result<Vec<Route>, Error> {
+    match std::env::var("WEAVE_ROUTES") {
+        Ok(input) => from_str(&input).map_err(|e| {
+            err!("failed to parse routes in WEAVE_ROUTES: {}", e)
+        }),
+        Err(_) => Ok(vec![])
+    }
+}
+
+/// Read routes from stdin, one per line (see `from_lines`):
+pub fn from_stdin() -> Result<Vec<Route>, Error> {
+    let mut input = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input).map_err(|e| {
+        err!("failed to read routes from stdin: {}", e)
+    })?;
+    from_lines(&input).map_err(|e| {
+        err!("failed to parse routes from stdin: {}", e)
+    })
+}
+
+/// Parse routes given one per line, as they are with `--stdin`. Blank
+/// lines and lines starting with `#` are ignored.
+pub fn from_lines(input: &str) -> Result<Vec<Route>, Error> {
+    let mut routes = vec![];
+    for (idx, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        routes.extend(from_str(line).map_err(|e| {
+            err!("line {}: {}", idx + 1, e)
+        })?);
+    }
+    Ok(routes)
+}
+
+fn split_args(input: &str) -> Result<Vec<String>, Error> {
+    let mut args = vec![];
+    let mut arg = None;
+    let mut quote = None;
+    for c in input.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => arg.get_or_insert_with(String::new).push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            },
+            None if c.is_whitespace() => args.extend(arg.take()),
+            None => arg.get_or_insert_with(String::new).push(c)
+        }
+    }
+    if let Some(q) = quote {
+        return Err(err!("Expecting a closing {} in '{}'", q, input));
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// Expand `${VAR}` placeholders in a source or destination with the value
+/// of the environment variable named, so that routes like
+/// `0.0.0.0:${PORT} to ${BACKEND_URL}` can be given in quotes or config
+/// files. `$${` is left as a literal `${`.
+pub fn interpolate(input: &str) -> Result<String, Error> {
+    interpolate_with(input, |name| {
+        std::env::var(name).map_err(|e| match e {
+            std::env::VarError::NotPresent => err!("The environment variable '{}' used in '{}' is not set", name, input),
+            std::env::VarError::NotUnicode(_) => err!("The environment variable '{}' used in '{}' is not valid unicode", name, input)
+        })
+    })
+}
+
+fn interpolate_with<F: Fn(&str) -> Result<String, Error>>(input: &str, lookup: F) -> Result<String, Error> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(idx) = rest.find("${") {
+        if rest[..idx].ends_with('$') {
+            out.push_str(&rest[..idx]);
+            out.push('{');
+            rest = &rest[idx + 2..];
+            continue;
+        }
+        out.push_str(&rest[..idx]);
+        let end = rest[idx..].find('}').ok_or_else(|| {
+            err!("Expecting a '}}' to close the '${{' in '{}'", input)
+        })?;
+        let name = &rest[idx + 2..idx + end];
+        let valid = name.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic())
+            && name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric());
+        if !valid {
+            return Err(err!("'{}' is not a valid environment variable name in '{}'", name, input));
+        }
+        out.push_str(&lookup(name)?);
+        rest = &rest[idx + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Routes are written out as they'd appear in a config file: `src`, `dest`
+/// (a list if there are several) and then the options they were given.
+/// Options given more than once become lists.
+impl Serialize for Route {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut options: Vec<(&str, Vec<&str>)> = vec![];
+        for (key, value) in &self.options.given {
+            match options.iter_mut().find(|(k, _)| k == key) {
+                Some((_, values)) => values.push(value),
+                None => options.push((key, vec![value]))
+            }
+        }
+
+        let mut map = serializer.serialize_map(None)?;
+        // `${` would be taken for a placeholder when the config is loaded:
+        map.serialize_entry("src", &self.src.to_input().replace("${", "$${"))?;
+        let dests: Vec<String> = self.dests.iter().map(|d| d.to_input().replace("${", "$${")).collect();
+        if let [dest] = dests.as_slice() {
+            map.serialize_entry("dest", dest)?;
+        } else {
+            map.serialize_entry("dest", &dests)?;
+        }
+        for (key, values) in options {
+            let values: Vec<OptionValue> = values.into_iter().map(OptionValue).collect();
+            if let [value] = values.as_slice() {
+                map.serialize_entry(key, value)?;
+            } else {
+                map.serialize_entry(key, &values)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// Flags are written out as booleans, and everything else as strings:
+struct OptionValue<'a>(&'a str);
+
+impl<'a> Serialize for OptionValue<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            "true" => serializer.serialize_bool(true),
+            "false" => serializer.serialize_bool(false),
+            value => serializer.serialize_str(value)
+        }
+    }
+}
+
+fn dests_to_string(dests: &[DestLocation]) -> String {
+    dests.iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(" and-also ")
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn parses_route_options() {
+        let (routes, rest) = from_args(args("8080 to ./a with cache-control=no-cache preserve-host and 8081 to ./b --foo")).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].options.cache_control, Some("no-cache".to_owned()));
+        assert!(routes[0].options.preserve_host);
+        assert_eq!(routes[1].options, RouteOptions::default());
+        assert_eq!(rest.collect::<Vec<_>>(), vec!["--foo".to_owned()]);
+    }
+
+    #[test]
+    fn parses_multiple_destinations() {
+        let (routes, _) = from_args(args("8080 to 9000 and-also 9001 with spa and 8081 to ./b")).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].dests, vec![
+            DestLocation::parse("9000").unwrap(),
+            DestLocation::parse("9001").unwrap()
+        ]);
+        assert!(routes[0].options.spa);
+        assert_eq!(routes[1].dests.len(), 1);
+        assert!(from_args(args("8080 to 9000 and-also")).is_err());
+    }
+
+    #[test]
+    fn parses_status_destinations() {
+        let (routes, _) = from_args(args("8080/soon to status://503?body=Back%20soon&header=Retry-After:%203600 and 8081 to status://204")).unwrap();
+        let fixed = match &routes[0].dests[0] {
+            DestLocation::Status(fixed) => fixed,
+            other => panic!("expected a status destination but got {:?}", other)
+        };
+        assert_eq!(fixed.status, 503);
+        assert_eq!(fixed.body, "Back soon");
+        assert_eq!(fixed.headers.len(), 1);
+        assert_eq!(fixed.headers[0].0, "retry-after");
+        assert_eq!(fixed.headers[0].1, "3600");
+        assert_eq!(routes[1].dests[0].to_string(), "status://204");
+        assert!(from_args(args("8080 to status://999")).is_err());
+        assert!(from_args(args("8080 to status://200?wibble=1")).is_err());
+    }
+
+    #[test]
+    fn parses_upgrade_https_destinations() {
+        let (routes, _) = from_args(args("0.0.0.0:80 to upgrade-https and 8080 to upgrade-https:8443")).unwrap();
+        assert_eq!(routes[0].dests[0], DestLocation::UpgradeHttps { port: None });
+        assert_eq!(routes[1].dests[0], DestLocation::UpgradeHttps { port: Some(8443) });
+        assert_eq!(routes[1].dests[0].to_string(), "upgrade-https:8443");
+        assert!(from_args(args("8080 to upgrade-https:https")).is_err());
+    }
+
+    #[test]
+    fn parses_text_destinations() {
+        let text = |dest: &str| match DestLocation::parse(dest).unwrap() {
+            DestLocation::Text(fixed) => fixed,
+            other => panic!("expected a text destination but got {:?}", other)
+        };
+
+        let fixed = text(r#"text://{"ok":true}"#);
+        assert_eq!(fixed.status, 200);
+        assert_eq!(fixed.body, r#"{"ok":true}"#);
+        assert_eq!(fixed.headers[0].1, "application/json");
+
+        let fixed = text(r"text://hello\nworld \\n \d");
+        assert_eq!(fixed.body, "hello\nworld \\n \\d");
+        assert_eq!(fixed.headers[0].1, "text/plain; charset=utf-8");
+
+        let fixed = text("text:text/html://<b>hi</b>");
+        assert_eq!(fixed.body, "<b>hi</b>");
+        assert_eq!(fixed.headers[0].1, "text/html");
+    }
+
+    #[test]
+    fn parses_exec_destinations() {
+        let (routes, _) = from_args(args("8080/status to exec://./status.sh with timeout=5s")).unwrap();
+        assert_eq!(routes[0].dests[0], DestLocation::Exec("./status.sh".to_owned()));
+        assert_eq!(routes[0].dests[0].to_string(), "exec://./status.sh");
+        assert!(DestLocation::parse("exec://").is_err());
+    }
+
+    #[test]
+    fn parses_unix_socket_destinations() {
+        let (routes, _) = from_args(args("8080/api to unix:///var/run/app.sock and 8081 to unix:///var/run/app.sock/v1")).unwrap();
+        match &routes[0].dests[0] {
+            DestLocation::UnixSocket { socket, url } => {
+                assert_eq!(socket.to_str(), Some("/var/run/app.sock"));
+                assert_eq!(url.path(), "/");
+            },
+            other => panic!("expected a unix socket destination but got {:?}", other)
+        }
+        assert_eq!(routes[0].dests[0].to_string(), "unix:///var/run/app.sock");
+        assert_eq!(routes[1].dests[0].to_string(), "unix:///var/run/app.sock:/v1");
+        assert!(DestLocation::parse("unix://").is_err());
+    }
+
+    #[test]
+    fn parses_h2c_destinations() {
+        let (routes, _) = from_args(args("8080/grpc to h2c://backend:50051")).unwrap();
+        assert_eq!(routes[0].dests[0].to_string(), "h2c://backend:50051/");
+        assert_eq!(DestLocation::parse("h2c://50051").unwrap().to_string(), "h2c://localhost:50051/");
+    }
+
+    #[test]
+    fn listens_on_every_interface_for_virtual_hosts() {
+        let (routes, _) = from_args(args("api.local:8080 to 9000 and 8080 to ./site and web.local@127.0.0.1:8080/app to 9001")).unwrap();
+        assert_eq!(routes[0].src.host, Some("api.local".to_owned()));
+        assert_eq!(routes[0].src_socket_addr().unwrap(), "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(routes[1].src.host, None);
+        assert!(routes[1].src_socket_addr().unwrap().ip().is_loopback());
+        assert_eq!(routes[2].src.host, Some("web.local".to_owned()));
+        assert_eq!(routes[2].src_socket_addr().unwrap(), "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(routes[2].src.to_string(), "http://web.local@127.0.0.1:8080/app");
+        assert_eq!(routes[2].src, SrcLocation::parse(routes[2].src.to_string()).unwrap());
+        assert!(SrcLocation::parse("127.0.0.1@127.0.0.1:8080").is_err());
+    }
+
+    #[test]
+    fn parses_unix_socket_sources() {
+        let (routes, _) = from_args(args("unix:///tmp/weave.sock/api to 9000 and unix:///tmp/weave:/(\\d+) to 9001 and unix://./weave.sock to 9002")).unwrap();
+        assert_eq!(routes[0].listen_addr().unwrap(), ListenAddr::Unix("/tmp/weave.sock".into()));
+        assert_eq!(routes[0].src.url.path(), "/api");
+        assert_eq!(routes[0].src.to_string(), "unix:///tmp/weave.sock:/api");
+        assert_eq!(routes[1].listen_addr().unwrap(), ListenAddr::Unix("/tmp/weave".into()));
+        assert_eq!(routes[1].src.pattern, Some("/(\\d+)".to_owned()));
+        assert_eq!(routes[2].listen_addr().unwrap(), ListenAddr::Unix("./weave.sock".into()));
+        assert_eq!(routes[2].src.url.path(), "/");
+        assert!(SrcLocation::parse("unix://weave.sock").is_err());
+    }
+
+    #[test]
+    fn complains_about_bad_route_options() {
+        assert!(from_args(args("8080 to ./a with")).is_err());
+        assert!(from_args(args("8080 to ./a with and 8081 to ./b")).is_err());
+        assert!(from_args(args("8080 to ./a with wibble=1")).is_err());
+    }
+
+    #[test]
+    fn interpolates_environment_variables() {
+        std::env::set_var("WEAVE_TEST_PORT", "18123");
+        std::env::set_var("WEAVE_TEST_BACKEND", "http://localhost:9000/api");
+        let (routes, _) = from_args(args("0.0.0.0:${WEAVE_TEST_PORT} to ${WEAVE_TEST_BACKEND} and-also ./$${WEAVE_TEST_PORT}")).unwrap();
+        assert_eq!(routes[0].src, SrcLocation::parse("0.0.0.0:18123").unwrap());
+        assert_eq!(routes[0].dests, vec![
+            DestLocation::parse("http://localhost:9000/api").unwrap(),
+            DestLocation::parse("./${WEAVE_TEST_PORT}").unwrap()
+        ]);
+
+        let err = from_args(args("8080 to ${WEAVE_TEST_UNSET}")).err().unwrap();
+        assert!(err.to_string().contains("'WEAVE_TEST_UNSET'"));
+        assert!(from_args(args("${WEAVE_TEST_UNSET} to 9000")).is_err());
+        assert!(interpolate("8080 ${WEAVE_TEST_PORT").is_err());
+        assert!(interpolate("${1PORT}").is_err());
+    }
+
+    #[test]
+    fn parses_routes_from_strings() {
+        let routes = from_str("  8080 to http://app:3000 with 'cache-control=max-age=60, public'\n and 8081 to \"./static files\" ").unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].dests, vec![DestLocation::parse("http://app:3000").unwrap()]);
+        assert_eq!(routes[0].options.cache_control, Some("max-age=60, public".to_owned()));
+        assert_eq!(routes[1].dests, vec![DestLocation::parse("./static files").unwrap()]);
+        assert_eq!(from_str("").unwrap(), vec![]);
+        assert!(from_str("8080 to ./a --foo").is_err());
+        assert!(from_str("8080 to './a").is_err());
+    }
+
+    #[test]
+    fn parses_routes_one_per_line() {
+        let routes = from_lines("# generated\n8080 to ./a with spa\n\n8081/api to 9000 and-also 9001\n").unwrap();
+        assert_eq!(routes.len(), 2);
+        assert!(routes[0].options.spa);
+        assert_eq!(routes[1].dests.len(), 2);
+        let err = from_lines("8080 to ./a\n8081 to").err().unwrap();
+        assert!(err.to_string().starts_with("line 2:"));
+    }
+}
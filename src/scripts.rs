@@ -0,0 +1,364 @@
+use hyper::{ HeaderMap, Request, Response, StatusCode };
+use hyper::header::{ HeaderName, HeaderValue };
+use log::{ debug };
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use url::Url;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::location::{ ResolvedLocation };
+use crate::routes::{ Route };
+
+/// A Rhai script with hooks for the requests and responses of a route,
+/// given with `script=PATH.rhai`. Scripts can define either or both of:
+///
+/// ```text
+/// fn on_request(req) {
+///     if req.headers["x-api-key"] == () {
+///         return #{ status: 401, body: "Who are you?" };
+///     }
+///     req.headers["x-team"] = "blue";
+///     req.dest = "http://localhost:9001" + req.uri;
+///     req
+/// }
+///
+/// fn on_response(resp) {
+///     resp.headers.remove("server");
+///     resp
+/// }
+/// ```
+///
+/// Requests have a `method`, `uri`, `headers` and the `dest` they're going
+/// to; responses have a `status` and `headers`. Hooks hand back what they
+/// were given, changed as they like, or nothing to leave it be. A `status`
+/// handed back for a request is sent back as the response (with any
+/// `headers` and `body`), without going any further. Scripts are loaded
+/// again each time we're reloaded.
+pub struct Script {
+    path: PathBuf,
+    compiled: runtime::Compiled,
+    on_request: bool,
+    on_response: bool
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Arc<Script>, Error> {
+        let compiled = runtime::compile(path)?;
+        Ok(Arc::new(Script {
+            path: path.to_owned(),
+            on_request: runtime::defines(&compiled, "on_request"),
+            on_response: runtime::defines(&compiled, "on_response"),
+            compiled
+        }))
+    }
+
+    fn call(&self, hook: &str, hooked: Hooked) -> Result<Option<Hooked>, Error> {
+        runtime::call(&self.compiled, hook, hooked)
+            .map_err(|e| err!("Script '{}' failed in {}: {}", self.path.display(), hook, e))
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Script({})", self.path.display())
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+/// A request or response as scripts see it. Headers given more than once
+/// are joined with commas:
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Hooked {
+    pub method: Option<String>,
+    pub uri: Option<String>,
+    pub dest: Option<String>,
+    pub status: Option<u16>,
+    pub headers: BTreeMap<String, String>,
+    pub body: Option<String>
+}
+
+/// What to do with a request once its script has seen it:
+pub enum Outcome {
+    /// Send it on, to another URL if one is given:
+    Send(Request<Body>, Option<Url>),
+    /// Send this back instead:
+    Reject(Response<Body>)
+}
+
+impl Hooked {
+    fn headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+        let mut hooked: BTreeMap<String, String> = BTreeMap::new();
+        for (name, value) in headers {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            hooked.entry(name.to_string())
+                .and_modify(|joined| { joined.push_str(", "); joined.push_str(&value); })
+                .or_insert_with(|| value.into_owned());
+        }
+        hooked
+    }
+
+    /// Apply the changes a script made to the headers it was given, so
+    /// that those it left alone are left as they were (even when given
+    /// more than once, like `Set-Cookie`):
+    fn apply_headers(&self, given: &BTreeMap<String, String>, headers: &mut HeaderMap) -> Result<(), Error> {
+        for name in given.keys().filter(|name| !self.headers.contains_key(*name)) {
+            headers.remove(name.as_str());
+        }
+        for (name, value) in self.headers.iter().filter(|(name, value)| given.get(*name) != Some(*value)) {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| err!("'{}' is not a valid header name", name))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| err!("'{}' is not a valid value for {}", value, name))?;
+            headers.insert(name, value);
+        }
+        Ok(())
+    }
+
+    fn status(&self) -> Result<Option<StatusCode>, Error> {
+        match self.status {
+            Some(status) => StatusCode::from_u16(status)
+                .map(Some)
+                .map_err(|_| err!("{} is not a valid status", status)),
+            None => Ok(None)
+        }
+    }
+}
+
+/// Pass a request through the `on_request` hook of its route's script, if
+/// it has one, on its way to `location`:
+pub fn on_request(mut req: Request<Body>, location: &ResolvedLocation, script: &Option<Arc<Script>>) -> Result<Outcome, Error> {
+    let script = match script {
+        Some(script) if script.on_request => script,
+        _ => return Ok(Outcome::Send(req, None))
+    };
+    let given = Hooked {
+        method: Some(req.method().to_string()),
+        uri: Some(req.uri().to_string()),
+        dest: Some(location.to_string()),
+        headers: Hooked::headers(req.headers()),
+        ..Hooked::default()
+    };
+    let hooked = match script.call("on_request", given.clone())? {
+        Some(hooked) => hooked,
+        None => return Ok(Outcome::Send(req, None))
+    };
+
+    if let Some(status) = hooked.status()? {
+        let mut resp = Response::new(Body::from(hooked.body.clone().unwrap_or_default()));
+        *resp.status_mut() = status;
+        hooked.apply_headers(&BTreeMap::new(), resp.headers_mut())?;
+        return Ok(Outcome::Reject(resp));
+    }
+    hooked.apply_headers(&given.headers, req.headers_mut())?;
+    let new_dest = match &hooked.dest {
+        Some(new_dest) if hooked.dest != given.dest => Some(parse_dest(new_dest)?),
+        _ => None
+    };
+    if let Some(url) = &new_dest {
+        debug!("Script '{}' sent the request to {}", script.path.display(), url);
+    }
+    Ok(Outcome::Send(req, new_dest))
+}
+
+/// Pass a response through the `on_response` hook of its route's script,
+/// if it has one:
+pub fn on_response(mut resp: Response<Body>, script: &Option<Arc<Script>>) -> Result<Response<Body>, Error> {
+    let script = match script {
+        Some(script) if script.on_response => script,
+        _ => return Ok(resp)
+    };
+    let given = Hooked {
+        status: Some(resp.status().as_u16()),
+        headers: Hooked::headers(resp.headers()),
+        ..Hooked::default()
+    };
+    if let Some(hooked) = script.call("on_response", given.clone())? {
+        if let Some(status) = hooked.status()? {
+            *resp.status_mut() = status;
+        }
+        hooked.apply_headers(&given.headers, resp.headers_mut())?;
+    }
+    Ok(resp)
+}
+
+fn parse_dest(input: &str) -> Result<Url, Error> {
+    let url = Url::parse(input).map_err(|e| err!("'{}' is not a URL to send the request to: {}", input, e))?;
+    match url.scheme() {
+        "http" | "https" => Ok(url),
+        scheme => Err(err!("Scripts can only send requests to http and https URLs, not {}", scheme))
+    }
+}
+
+/// A copy of a route with its script loaded again, so that changes to it
+/// are picked up:
+pub fn reload(route: &Route) -> Result<Route, Error> {
+    let mut route = route.clone();
+    if let Some(script) = &route.options.script {
+        route.options.script = Some(Script::load(&script.path)?);
+    }
+    Ok(route)
+}
+
+/// Running scripts with Rhai, if weave was built with the `scripting`
+/// cargo feature:
+#[cfg(feature = "scripting")]
+mod runtime {
+    use lazy_static::lazy_static;
+    use rhai::{ Dynamic, Engine, Map, Scope, AST };
+    use std::convert::TryFrom;
+    use std::path::Path;
+    use crate::errors::{ Error };
+    use super::Hooked;
+
+    pub type Compiled = AST;
+
+    /// How much work a script can do with each call, so that one stuck in
+    /// a loop can't hold up everything else:
+    const MAX_OPERATIONS: u64 = 1_000_000;
+
+    lazy_static! {
+        static ref ENGINE: Engine = {
+            let mut engine = Engine::new();
+            engine.set_max_operations(MAX_OPERATIONS);
+            engine
+        };
+    }
+
+    pub fn compile(path: &Path) -> Result<AST, Error> {
+        ENGINE.compile_file(path.to_owned()).map_err(|e| err!("Can't load script '{}': {}", path.display(), e))
+    }
+
+    pub fn defines(ast: &AST, name: &str) -> bool {
+        ast.iter_functions().any(|f| f.name == name)
+    }
+
+    pub fn call(ast: &AST, hook: &str, hooked: Hooked) -> Result<Option<Hooked>, Error> {
+        let result: Dynamic = ENGINE.call_fn(&mut Scope::new(), ast, hook, (Dynamic::from_map(to_map(hooked)),))
+            .map_err(|e| err!("{}", e))?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        let map = result.try_cast::<Map>().ok_or_else(|| err!("expecting a map or nothing to be handed back"))?;
+        from_map(map).map(Some)
+    }
+
+    fn to_map(hooked: Hooked) -> Map {
+        let mut map = Map::new();
+        let strings = vec![("method", hooked.method), ("uri", hooked.uri), ("dest", hooked.dest), ("body", hooked.body)];
+        for (key, value) in strings {
+            if let Some(value) = value {
+                map.insert(key.into(), value.into());
+            }
+        }
+        if let Some(status) = hooked.status {
+            map.insert("status".into(), Dynamic::from_int(status.into()));
+        }
+        let headers: Map = hooked.headers.into_iter().map(|(name, value)| (name.into(), value.into())).collect();
+        map.insert("headers".into(), Dynamic::from_map(headers));
+        map
+    }
+
+    fn from_map(map: Map) -> Result<Hooked, Error> {
+        let string = |key: &str| -> Result<Option<String>, Error> {
+            match map.get(key) {
+                Some(value) if !value.is_unit() => value.clone().into_string()
+                    .map(Some)
+                    .map_err(|_| err!("expecting {} to be a string", key)),
+                _ => Ok(None)
+            }
+        };
+        let status = match map.get("status") {
+            Some(status) if !status.is_unit() => {
+                let status = status.as_int().map_err(|_| err!("expecting status to be a number"))?;
+                Some(u16::try_from(status).map_err(|_| err!("{} is not a valid status", status))?)
+            },
+            _ => None
+        };
+        let mut headers = std::collections::BTreeMap::new();
+        if let Some(given) = map.get("headers") {
+            let given = given.clone().try_cast::<Map>().ok_or_else(|| err!("expecting headers to be a map"))?;
+            for (name, value) in given {
+                let value = value.into_string().map_err(|_| err!("expecting the {} header to be a string", name))?;
+                headers.insert(name.to_string(), value);
+            }
+        }
+        Ok(Hooked {
+            method: string("method")?,
+            uri: string("uri")?,
+            dest: string("dest")?,
+            status,
+            headers,
+            body: string("body")?
+        })
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+mod runtime {
+    use std::path::Path;
+    use crate::errors::{ Error };
+    use super::Hooked;
+
+    /// There are no scripts without a runtime to compile them:
+    pub enum Compiled {}
+
+    pub fn compile(path: &Path) -> Result<Compiled, Error> {
+        Err(err!("Can't load script '{}', as weave was built without the scripting feature", path.display()))
+    }
+
+    pub fn defines(compiled: &Compiled, _name: &str) -> bool {
+        match *compiled {}
+    }
+
+    pub fn call(compiled: &Compiled, _hook: &str, _hooked: Hooked) -> Result<Option<Hooked>, Error> {
+        match *compiled {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn applies_header_changes() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", "a=1".parse().unwrap());
+        headers.append("set-cookie", "b=2".parse().unwrap());
+        headers.insert("server", "apache".parse().unwrap());
+        headers.insert("x-a", "1".parse().unwrap());
+        let given = Hooked::headers(&headers);
+        assert_eq!(given["set-cookie"], "a=1, b=2");
+
+        let mut hooked = Hooked { headers: given.clone(), ..Hooked::default() };
+        hooked.headers.remove("server");
+        hooked.headers.insert("x-a".to_owned(), "2".to_owned());
+        hooked.apply_headers(&given, &mut headers).unwrap();
+        assert_eq!(headers.get_all("set-cookie").iter().count(), 2);
+        assert!(!headers.contains_key("server"));
+        assert_eq!(headers["x-a"], "2");
+
+        hooked.headers.insert("bad header".to_owned(), "1".to_owned());
+        assert!(hooked.apply_headers(&given, &mut headers).is_err());
+    }
+
+    #[test]
+    fn parses_destinations() {
+        assert_eq!(parse_dest("http://localhost:9001/a").unwrap().as_str(), "http://localhost:9001/a");
+        assert!(parse_dest("file:///etc/passwd").is_err());
+        assert!(parse_dest("/a").is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "scripting"))]
+    fn needs_the_feature() {
+        assert!(Script::load(Path::new("hooks.rhai")).is_err());
+    }
+}
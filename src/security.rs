@@ -0,0 +1,111 @@
+use hyper::HeaderMap;
+use hyper::header::{
+    HeaderName,
+    HeaderValue,
+    CONTENT_SECURITY_POLICY,
+    REFERRER_POLICY,
+    STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS,
+    X_FRAME_OPTIONS
+};
+use crate::errors::{ Error };
+
+/// The security headers we add to responses when asked to. There's no
+/// `Content-Security-Policy` here, since no one policy suits every site;
+/// routes can provide their own with the `csp` option.
+const PRESET: &[(HeaderName, &str)] = &[
+    (STRICT_TRANSPORT_SECURITY, "max-age=31536000; includeSubDomains"),
+    (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+    (X_FRAME_OPTIONS, "SAMEORIGIN"),
+    (REFERRER_POLICY, "strict-origin-when-cross-origin")
+];
+
+/// Security headers to add to the responses of a route. The preset ones
+/// are only added if the response doesn't have them already, whereas
+/// ones we're given are always set.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct SecurityHeaders {
+    /// Add the preset headers? If not set, `--security-headers` decides:
+    pub preset: Option<bool>,
+    /// Headers to set in place of the preset ones, or to leave out if `None`:
+    pub overrides: Vec<(HeaderName, Option<HeaderValue>)>
+}
+
+impl SecurityHeaders {
+    /// Set one of the headers, given the option naming it (like `hsts`), or
+    /// leave it out if the value is `off`:
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), Error> {
+        let name = match option {
+            "hsts" => STRICT_TRANSPORT_SECURITY,
+            "content-type-options" => X_CONTENT_TYPE_OPTIONS,
+            "frame-options" => X_FRAME_OPTIONS,
+            "referrer-policy" => REFERRER_POLICY,
+            "csp" => CONTENT_SECURITY_POLICY,
+            _ => return Err(err!("'{}' is not a security header option", option))
+        };
+        let value = value.trim();
+        let value = if value.eq_ignore_ascii_case("off") {
+            None
+        } else {
+            Some(HeaderValue::from_str(value).map_err(|_| err!("'{}' is not a valid header value", value))?)
+        };
+        self.overrides.retain(|(n, _)| *n != name);
+        self.overrides.push((name, value));
+        Ok(())
+    }
+
+    /// Add the headers to a response, including the preset ones if this
+    /// route (or else `default_preset`) asks for them:
+    pub fn apply(&self, headers: &mut HeaderMap, default_preset: bool) {
+        if self.preset.unwrap_or(default_preset) {
+            for (name, value) in PRESET {
+                if !headers.contains_key(name) && !self.overrides.iter().any(|(n, _)| n == name) {
+                    headers.insert(name, HeaderValue::from_static(value));
+                }
+            }
+        }
+        for (name, value) in &self.overrides {
+            match value {
+                Some(value) => { headers.insert(name, value.clone()); },
+                None => { headers.remove(name); }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn adds_security_headers() {
+        let mut security = SecurityHeaders::default();
+        security.set("frame-options", "DENY").unwrap();
+        security.set("hsts", "off").unwrap();
+        security.set("csp", "default-src 'self'").unwrap();
+        assert!(security.set("x-powered-by", "weave").is_err());
+
+        // Without the preset, only the ones we were given:
+        let mut headers = HeaderMap::new();
+        security.apply(&mut headers, false);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.get(X_FRAME_OPTIONS).unwrap(), "DENY");
+        assert_eq!(headers.get(CONTENT_SECURITY_POLICY).unwrap(), "default-src 'self'");
+
+        // With it, the rest too, unless the response has its own:
+        let mut headers = HeaderMap::new();
+        headers.insert(REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+        headers.insert(STRICT_TRANSPORT_SECURITY, HeaderValue::from_static("max-age=60"));
+        security.apply(&mut headers, true);
+        assert_eq!(headers.get(X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert_eq!(headers.get(X_FRAME_OPTIONS).unwrap(), "DENY");
+        assert_eq!(headers.get(REFERRER_POLICY).unwrap(), "no-referrer");
+        assert!(headers.get(STRICT_TRANSPORT_SECURITY).is_none());
+
+        // Routes can opt out of the preset:
+        let mut headers = HeaderMap::new();
+        SecurityHeaders { preset: Some(false), ..SecurityHeaders::default() }.apply(&mut headers, true);
+        assert!(headers.is_empty());
+    }
+}
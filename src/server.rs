@@ -0,0 +1,297 @@
+use std::net::SocketAddr;
+use std::sync::{ Arc, Mutex };
+use crate::admin::{ self, AdminOptions };
+use crate::cache;
+use crate::control::{ Control };
+use crate::docker::{ self, DockerOptions };
+use crate::errors::{ Error };
+use crate::layers::{ Layer, Layers };
+use crate::listeners::{ ListenAddr, Listeners };
+use crate::routes::{ self, Route };
+use crate::settings::{ Settings };
+use crate::trace;
+use crate::HttpsClient;
+
+/// Routes being served, for programs that embed weave rather than running
+/// it. Routes can be added and removed while serving, as they can with the
+/// admin API. Dropping the server stops it too, unless it's serving the
+/// admin API, which keeps it going.
+pub struct WeaveServer {
+    control: Arc<Mutex<Control>>
+}
+
+/// A server started on free ports by `spawn`, for tests.
+pub struct Spawned {
+    /// Where the server is listening, in place of each TCP address its
+    /// routes ask for, in the order of the routes they're first for:
+    pub addrs: Vec<SocketAddr>,
+    /// Shut this down, or drop it, to stop the server:
+    pub server: WeaveServer
+}
+
+/// What to serve, and how. Call `start` to start serving it.
+#[derive(Debug,Clone,Default)]
+pub struct WeaveServerBuilder {
+    routes: Vec<Route>,
+    config_path: Option<String>,
+    settings: Settings,
+    layers: Layers,
+    admin: Option<AdminOptions>,
+    docker: Option<DockerOptions>
+}
+
+impl WeaveServerBuilder {
+    /// Serve a route, after any we have already:
+    pub fn route(mut self, route: Route) -> WeaveServerBuilder {
+        self.routes.push(route);
+        self
+    }
+
+    /// Serve routes written as they would be on the command line, like
+    /// `8080/api to 9000 and 8080 to ./public`:
+    pub fn parse_routes(mut self, input: &str) -> Result<WeaveServerBuilder, Error> {
+        let (parsed, mut rest) = routes::from_args(input.split_whitespace().map(|s| s.to_owned()))?;
+        if let Some(arg) = rest.next() {
+            return Err(err!("Expecting a route, but found '{}'", arg));
+        }
+        self.routes.extend(parsed);
+        Ok(self)
+    }
+
+    /// Serve the routes in a TOML config file too, as `--config` does.
+    /// It's read again each time the server is reloaded:
+    pub fn config(mut self, path: &str) -> WeaveServerBuilder {
+        self.config_path = Some(path.to_owned());
+        self
+    }
+
+    /// Settings which apply across all routes, in place of the defaults:
+    pub fn settings(mut self, settings: Settings) -> WeaveServerBuilder {
+        self.settings = settings;
+        self
+    }
+
+    /// Pass requests through a layer once they've matched a route (and
+    /// been authenticated, if it asks for that) on their way to its
+    /// destination. The first layer added sees requests first:
+    pub fn layer<L: Layer + 'static>(mut self, layer: L) -> WeaveServerBuilder {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    /// Serve the admin API too:
+    pub fn admin(mut self, options: AdminOptions) -> WeaveServerBuilder {
+        self.admin = Some(options);
+        self
+    }
+
+    /// Serve routes to Docker containers with `weave.*` labels too, as
+    /// `--docker` does, adding and removing them as containers start and
+    /// stop. There needn't be any other routes:
+    pub fn docker(mut self, options: DockerOptions) -> WeaveServerBuilder {
+        self.docker = Some(options);
+        self
+    }
+
+    /// Start listening for requests, which are handled on the tokio runtime
+    /// this is called from. Errors if there are no routes, or any of them
+    /// can't be listened for:
+    pub fn start(self) -> Result<WeaveServer, Error> {
+        self.start_listening(false)
+    }
+
+    /// Start listening for requests as `start` does, but on a free port in
+    /// place of each one the routes ask for, so that tests can each have a
+    /// server of their own and run at once:
+    pub fn spawn(self) -> Result<Spawned, Error> {
+        let server = self.start_listening(true)?;
+        let addrs = server.addrs().into_iter()
+            .filter_map(|addr| match addr {
+                ListenAddr::Tcp(addr) => Some(addr),
+                ListenAddr::Unix(_) => None
+            })
+            .collect();
+        Ok(Spawned { addrs, server })
+    }
+
+    fn start_listening(self, free_ports: bool) -> Result<WeaveServer, Error> {
+        let settings = Arc::new(Settings { layers: self.layers, ..self.settings });
+        if let Some(cache_dir) = &settings.cache_dir {
+            cache::use_disk(cache_dir, settings.cache_disk_size)?;
+        }
+
+        // Build a single client for all proxied requests:
+        let client = HttpsClient::new(&settings)?;
+        if let Some(tracer) = &settings.tracer {
+            trace::spawn_exporter(Arc::clone(tracer), client.clone());
+        }
+
+        let mut listeners = Listeners::new(client.clone(), settings);
+        if free_ports {
+            listeners.use_free_ports();
+        }
+        let mut control = Control::new(listeners, self.routes, self.config_path);
+        if self.docker.is_some() {
+            control.discovering();
+        }
+        control.reload()?;
+        let control = Arc::new(Mutex::new(control));
+
+        if let Some(admin) = self.admin {
+            admin::spawn(admin, Arc::clone(&control))?;
+        }
+        if let Some(options) = self.docker {
+            docker::spawn(options, Arc::clone(&control), client);
+        }
+        Ok(WeaveServer { control })
+    }
+}
+
+impl WeaveServer {
+    pub fn builder() -> WeaveServerBuilder {
+        WeaveServerBuilder::default()
+    }
+
+    /// Serve routes like `8080/api to 9000` on free ports, with the default
+    /// settings, for a test. See `WeaveServerBuilder::spawn`:
+    pub fn spawn(routes: &str) -> Result<Spawned, Error> {
+        WeaveServer::builder().parse_routes(routes)?.spawn()
+    }
+
+    /// The routes we're serving right now, in the order they're matched:
+    pub fn routes(&self) -> Vec<Route> {
+        self.control.lock().unwrap().routes().to_vec()
+    }
+
+    /// Serve some more routes, after those we're serving now:
+    pub fn add_routes(&self, routes: Vec<Route>) -> Result<(), Error> {
+        self.control.lock().unwrap().add(routes)
+    }
+
+    /// Stop serving a route, given its position in `routes`:
+    pub fn remove_route(&self, idx: usize) -> Result<Route, Error> {
+        self.control.lock().unwrap().remove(idx)
+    }
+
+    /// Go back to serving the routes we started with, along with those
+    /// in the config file as it is now. If something is wrong, we keep
+    /// serving the routes we have:
+    pub fn reload(&self) -> Result<(), Error> {
+        self.control.lock().unwrap().reload()
+    }
+
+    /// Where we're listening for requests, in the order of the routes
+    /// they're for:
+    pub fn addrs(&self) -> Vec<ListenAddr> {
+        self.control.lock().unwrap().addrs()
+    }
+
+    /// Stop listening for requests. Those being handled are finished:
+    pub fn shutdown(self) {
+        self.control.lock().unwrap().stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::io::{ Read, Write };
+    use std::time::Duration;
+    use tokio::time::sleep;
+    use tower_service::Service;
+    use crate::layers::{ self, Matched, RouteService };
+
+    #[test]
+    fn parses_routes() {
+        let builder = WeaveServer::builder()
+            .parse_routes("8080/api to 9000 and 8080 to ./public").unwrap()
+            .parse_routes("8081 to 9001").unwrap();
+        assert_eq!(builder.routes.len(), 3);
+        assert!(WeaveServer::builder().parse_routes("8080 to 9000 --insecure").is_err());
+    }
+
+    /// The response to a GET, over a connection of its own:
+    fn get(addr: SocketAddr, path: &str) -> std::io::Result<String> {
+        let mut stream = std::net::TcpStream::connect(addr)?;
+        write!(stream, "GET {} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n", path)?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp)?;
+        Ok(resp)
+    }
+
+    /// The status of a response to a GET:
+    fn status(addr: SocketAddr, path: &str) -> std::io::Result<String> {
+        Ok(get(addr, path)?.split(' ').nth(1).unwrap_or("").to_owned())
+    }
+
+    #[test]
+    fn spawns_on_free_ports() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let spawned = WeaveServer::spawn("8080/a to status://204 and 8081/b to status://204 and 8080/c to status://204").unwrap();
+            assert_eq!(spawned.addrs.len(), 2);
+            assert!(spawned.addrs.iter().all(|addr| addr.port() != 8080 && addr.port() != 8081));
+            assert_ne!(spawned.addrs[0], spawned.addrs[1]);
+
+            assert_eq!(status(spawned.addrs[0], "/c").unwrap(), "204");
+            assert_eq!(status(spawned.addrs[1], "/c").unwrap(), "404");
+
+            let addr = spawned.addrs[0];
+            spawned.server.shutdown();
+            sleep(Duration::from_millis(100)).await;
+            assert!(status(addr, "/a").is_err());
+        });
+    }
+
+    /// Turns away requests to routes other than the one given:
+    struct Only(&'static str);
+
+    impl Layer for Only {
+        fn layer<'a>(&self, mut inner: RouteService<'a>) -> RouteService<'a> {
+            let only = self.0;
+            layers::service_fn(move |req| {
+                if req.extensions().get::<Matched>().map(|m| m.route.as_str()) == Some(only) {
+                    return inner.call(req);
+                }
+                Box::pin(async { Ok(hyper::Response::builder().status(403).body(crate::Body::empty()).unwrap()) })
+            })
+        }
+    }
+
+    #[test]
+    fn passes_requests_through_layers() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let spawned = WeaveServer::builder()
+                .parse_routes("8080/a to status://204 and 8080/b to status://204").unwrap()
+                .layer(Only("http://localhost:8080/a to status://204"))
+                .spawn()
+                .unwrap();
+            assert_eq!(status(spawned.addrs[0], "/a").unwrap(), "204");
+            assert_eq!(status(spawned.addrs[0], "/b").unwrap(), "403");
+        });
+    }
+
+    #[test]
+    fn serves_single_page_apps_from_expanded_destinations() {
+        let dir = std::env::temp_dir().join(format!("weave-spa-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sites/a")).unwrap();
+        std::fs::write(dir.join("sites/a/index.html"), "site a").unwrap();
+        std::fs::write(dir.join("sites/a/app.js"), "app").unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let routes = format!("8080/(site) to {}/sites/(site)/ with spa=true", dir.display());
+            let spawned = WeaveServer::spawn(&routes).unwrap();
+            let addr = spawned.addrs[0];
+            let resp = get(addr, "/a/app.js").unwrap();
+            assert!(resp.ends_with("\r\n\r\napp"), "{}", resp);
+            let resp = get(addr, "/a/some/client/route").unwrap();
+            assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+            assert!(resp.ends_with("\r\n\r\nsite a"), "{}", resp);
+            assert_eq!(status(addr, "/b/some/client/route").unwrap(), "404");
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use clap::ArgMatches;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::Duration;
+use crate::acceptor;
+use crate::acme::{ AcmeOptions };
+use crate::cache::{ DEFAULT_CACHE_SIZE };
+use crate::capture::{ CaptureOptions };
+use crate::cassette::{ Cassette, CassetteMode };
+use crate::concurrency::{ DEFAULT_MAX_QUEUED };
+use crate::connector::{ Resolve };
+use crate::consul::{ ConsulOptions };
+use crate::curl;
+use crate::disk_cache::{ DEFAULT_CACHE_DISK_SIZE };
+use crate::error_pages::{ ErrorPage };
+use crate::har::{ Har, DEFAULT_HAR_BODY_LIMIT };
+use crate::layers::{ Layers };
+use crate::errors::{ Error };
+use crate::ip_filter::{ Cidr };
+use crate::options::{ parse_count, parse_period };
+use crate::statsd::{ Statsd, DEFAULT_STATSD_PREFIX };
+use crate::tls::{ TlsBackend };
+use crate::trace::{ Tracer, DEFAULT_SERVICE_NAME };
+
+/// How many bytes we read from disk at a time when streaming
+/// files back, if no chunk size is provided:
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// How many `exec://` commands can run at once, if not provided:
+pub const DEFAULT_MAX_EXEC: usize = 8;
+
+/// Settings which apply across all routes, provided as
+/// flags after the routes themselves.
+#[derive(Debug,Clone)]
+pub struct Settings {
+    /// The size of each chunk of a file that we stream back:
+    pub chunk_size: usize,
+    /// List the contents of directories without an index file
+    /// (routes can override this):
+    pub dir_listing: bool,
+    /// How long to wait when connecting to a destination before
+    /// giving up (by default, we wait as long as the OS does):
+    pub connect_timeout: Option<Duration>,
+    /// Tell destinations who the client is and what they asked for
+    /// with `X-Forwarded-*` and `Forwarded` headers:
+    pub forwarded_headers: bool,
+    /// Give each request an ID (or use the client's) to log, send on and
+    /// send back:
+    pub request_ids: bool,
+    /// How many `exec://` commands can run at once:
+    pub max_exec: usize,
+    /// How many requests can be handled at once, across all routes
+    /// (by default, there's no limit):
+    pub max_concurrent: Option<usize>,
+    /// How many requests can wait for others to finish before we start
+    /// turning them away:
+    pub max_queued: usize,
+    /// How much memory cached responses can take up:
+    pub cache_size: usize,
+    /// Where to cache responses on disk too, if anywhere:
+    pub cache_dir: Option<PathBuf>,
+    /// How much disk space cached responses can take up:
+    pub cache_disk_size: usize,
+    /// Which client networks can purge cached responses:
+    pub purge_from: Vec<Cidr>,
+    /// Allow cross-origin requests from anywhere to routes without their
+    /// own CORS options:
+    pub cors_permissive: bool,
+    /// Add security headers to the responses of routes that don't say
+    /// otherwise:
+    pub security_headers: bool,
+    /// Respond with this instead of our own 404 and 5xx responses, on
+    /// routes without their own:
+    pub error_page: Option<Arc<ErrorPage>>,
+    /// Where to send metrics about each request, if anywhere:
+    pub statsd: Option<Arc<Statsd>>,
+    /// Where to send a span for each request, if anywhere:
+    pub tracer: Option<Arc<Tracer>>,
+    /// Log the requests we proxy and the responses we get back, for
+    /// routes that don't say otherwise:
+    pub capture: bool,
+    /// How much of each body to log when capturing, and what to hide:
+    pub capture_options: CaptureOptions,
+    /// Where to record requests and responses as a HAR file, if anywhere:
+    pub har: Option<Arc<Har>>,
+    /// Where to save the responses of URL destinations, or answer from
+    /// saved ones, if anywhere:
+    pub cassette: Option<Arc<Cassette>>,
+    /// Where to save request bodies when logging the requests we proxy as
+    /// curl commands, if we're doing that:
+    pub curl: Option<PathBuf>,
+    /// Don't verify the certificates of HTTPS destinations:
+    pub insecure: bool,
+    /// Which TLS library to make HTTPS connections with:
+    pub tls_backend: TlsBackend,
+    /// Make self-signed certificates (kept in this directory) for
+    /// `https://` sources that aren't given one:
+    pub self_signed: Option<PathBuf>,
+    /// Get certificates for the virtual hosts of `https://` sources that
+    /// aren't given one from an ACME CA (like Let's Encrypt):
+    pub acme: Option<AcmeOptions>,
+    /// Where to connect to for some destination hosts, in place of where
+    /// DNS says:
+    pub resolve: Vec<Resolve>,
+    /// Where to look up the services of `consul://` destinations:
+    pub consul: ConsulOptions,
+    /// What to pass requests through between matching and dispatching
+    /// them, as given to `WeaveServerBuilder::layer`:
+    pub(crate) layers: Layers
+}
+
+impl Settings {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Settings, Error> {
+        let chunk_size = match matches.value_of("chunk-size") {
+            Some(s) => parse_size(s).map_err(|e| err!("Invalid --chunk-size '{}': {}", s, e))?,
+            None => DEFAULT_CHUNK_SIZE
+        };
+        let tls_backend = match matches.value_of("tls-backend") {
+            Some(s) => TlsBackend::parse(s).map_err(|e| err!("Invalid --tls-backend '{}': {}", s, e))?,
+            None => TlsBackend::default()
+        };
+        let max_exec = match matches.value_of("max-exec") {
+            Some(s) => parse_count(s).map_err(|e| err!("Invalid --max-exec '{}': {}", s, e))?,
+            None => DEFAULT_MAX_EXEC
+        };
+        let max_concurrent = match matches.value_of("max-concurrent") {
+            Some(s) => Some(parse_count(s).map_err(|e| err!("Invalid --max-concurrent '{}': {}", s, e))?),
+            None => None
+        };
+        let max_queued = match matches.value_of("max-queued") {
+            Some(s) => s.parse().map_err(|_| err!("Invalid --max-queued '{}': Not a valid number", s))?,
+            None => DEFAULT_MAX_QUEUED
+        };
+        let cache_size = match matches.value_of("cache-size") {
+            Some(s) => parse_size(s).map_err(|e| err!("Invalid --cache-size '{}': {}", s, e))?,
+            None => DEFAULT_CACHE_SIZE
+        };
+        let cache_disk_size = match matches.value_of("cache-disk-size") {
+            Some(s) => parse_size(s).map_err(|e| err!("Invalid --cache-disk-size '{}': {}", s, e))?,
+            None => DEFAULT_CACHE_DISK_SIZE
+        };
+        let purge_from = match matches.value_of("purge-from") {
+            Some(s) => Cidr::parse_list(s).map_err(|e| err!("Invalid --purge-from '{}': {}", s, e))?,
+            None => default_purge_from()
+        };
+        let connect_timeout = match matches.value_of("connect-timeout") {
+            Some(s) => Some(parse_period(s).map_err(|e| err!("Invalid --connect-timeout '{}': {}", s, e))?),
+            None => None
+        };
+        let error_page = match matches.value_of("error-page") {
+            Some(s) => Some(ErrorPage::from_file(Path::new(s))?),
+            None => None
+        };
+        let statsd = match matches.value_of("statsd") {
+            Some(s) => {
+                let tags = match matches.value_of("statsd-tags") {
+                    Some(t) => Statsd::parse_tags(t).map_err(|e| err!("Invalid --statsd-tags '{}': {}", t, e))?,
+                    None => vec![]
+                };
+                let prefix = matches.value_of("statsd-prefix").unwrap_or(DEFAULT_STATSD_PREFIX);
+                Some(Statsd::new(s, prefix, tags).map_err(|e| err!("Invalid --statsd '{}': {}", s, e))?)
+            },
+            None => None
+        };
+        let tracer = match matches.value_of("otlp-endpoint") {
+            Some(s) => {
+                let service_name = matches.value_of("otlp-service-name").unwrap_or(DEFAULT_SERVICE_NAME);
+                Some(Tracer::new(s, service_name).map_err(|e| err!("Invalid --otlp-endpoint '{}': {}", s, e))?)
+            },
+            None => None
+        };
+        let mut capture_options = CaptureOptions::default();
+        if let Some(s) = matches.value_of("capture-body-limit") {
+            capture_options.body_limit = parse_size(s).map_err(|e| err!("Invalid --capture-body-limit '{}': {}", s, e))?;
+        }
+        if let Some(s) = matches.value_of("capture-redact-headers") {
+            capture_options.redact_headers = CaptureOptions::parse_headers(s).map_err(|e| err!("Invalid --capture-redact-headers '{}': {}", s, e))?;
+        }
+        if let Some(s) = matches.value_of("capture-redact") {
+            capture_options.redact = Some(CaptureOptions::parse_pattern(s).map_err(|e| err!("Invalid --capture-redact '{}': {}", s, e))?);
+        }
+        let har = match matches.value_of("har") {
+            Some(s) => {
+                let body_limit = match matches.value_of("har-body-limit") {
+                    Some(l) => parse_size(l).map_err(|e| err!("Invalid --har-body-limit '{}': {}", l, e))?,
+                    None => DEFAULT_HAR_BODY_LIMIT
+                };
+                Some(Har::create(Path::new(s), body_limit, capture_options.clone())?)
+            },
+            None => None
+        };
+        let cassette = match matches.value_of("cassette") {
+            Some(s) => {
+                let mode = match matches.value_of("cassette-mode") {
+                    Some(m) => CassetteMode::parse(m).map_err(|e| err!("Invalid --cassette-mode '{}': {}", m, e))?,
+                    None => CassetteMode::Auto
+                };
+                Some(Cassette::open(Path::new(s), mode)?)
+            },
+            None => None
+        };
+        let resolve = matches.values_of("resolve").unwrap_or_default()
+            .map(|s| Resolve::parse(s).map_err(|e| err!("Invalid --resolve '{}': {}", s, e)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let curl = if matches.is_present("curl") {
+            let dir = matches.value_of("curl-bodies").map(PathBuf::from).unwrap_or_else(curl::default_bodies_dir);
+            std::fs::create_dir_all(&dir)
+                .map_err(|e| err!("Can't create --curl-bodies directory {}: {}", dir.display(), e))?;
+            Some(dir)
+        } else {
+            None
+        };
+
+        Ok(Settings {
+            chunk_size,
+            dir_listing: matches.is_present("dir-listing"),
+            connect_timeout,
+            forwarded_headers: !matches.is_present("no-forwarded-headers"),
+            request_ids: !matches.is_present("no-request-ids"),
+            max_exec,
+            max_concurrent,
+            max_queued,
+            cache_size,
+            cache_dir: matches.value_of("cache-dir").map(PathBuf::from),
+            cache_disk_size,
+            purge_from,
+            cors_permissive: matches.is_present("cors-permissive"),
+            security_headers: matches.is_present("security-headers"),
+            error_page,
+            statsd,
+            tracer,
+            capture: matches.is_present("capture"),
+            capture_options,
+            har,
+            cassette,
+            curl,
+            insecure: matches.is_present("insecure"),
+            tls_backend,
+            self_signed: if matches.is_present("self-signed") { Some(acceptor::default_self_signed_dir()) } else { None },
+            acme: AcmeOptions::from_matches(matches)?,
+            resolve,
+            consul: ConsulOptions::from_matches(matches)?,
+            layers: Layers::default()
+        })
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            dir_listing: false,
+            connect_timeout: None,
+            forwarded_headers: true,
+            request_ids: true,
+            max_exec: DEFAULT_MAX_EXEC,
+            max_concurrent: None,
+            max_queued: DEFAULT_MAX_QUEUED,
+            cache_size: DEFAULT_CACHE_SIZE,
+            cache_dir: None,
+            cache_disk_size: DEFAULT_CACHE_DISK_SIZE,
+            purge_from: default_purge_from(),
+            cors_permissive: false,
+            security_headers: false,
+            error_page: None,
+            statsd: None,
+            tracer: None,
+            capture: false,
+            capture_options: CaptureOptions::default(),
+            har: None,
+            cassette: None,
+            curl: None,
+            insecure: false,
+            tls_backend: TlsBackend::default(),
+            self_signed: None,
+            acme: None,
+            resolve: vec![],
+            consul: ConsulOptions::default(),
+            layers: Layers::default()
+        }
+    }
+}
+
+/// Only local clients can purge cached responses, if not provided:
+fn default_purge_from() -> Vec<Cidr> {
+    Cidr::parse_list("127.0.0.1, ::1").expect("loopback addresses are valid")
+}
+
+/// Parse a size in bytes, optionally suffixed with 'k', 'm' or 'g' (eg `64k`):
+pub fn parse_size(input: &str) -> Result<usize, Error> {
+    let s = input.trim().to_lowercase();
+    let (num, multiplier) = if let Some(num) = s.strip_suffix('k') {
+        (num, 1024)
+    } else if let Some(num) = s.strip_suffix('m') {
+        (num, 1024 * 1024)
+    } else if let Some(num) = s.strip_suffix('g') {
+        (num, 1024 * 1024 * 1024)
+    } else {
+        (&s[..], 1)
+    };
+
+    let size = num.parse::<usize>().map_err(|_| err!("Not a valid size"))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| err!("Size is too large"))?;
+    if size == 0 {
+        return Err(err!("Size must be greater than 0"));
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64k").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("10M").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("2g").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse_size("0k").is_err());
+        assert!(parse_size("lots").is_err());
+        assert_eq!(parse_size("99999999999g").unwrap_err().to_string(), "Size is too large");
+    }
+}
@@ -0,0 +1,368 @@
+use ansi_term::Color::{ Green, Red };
+use lazy_static::lazy_static;
+use log::{ info, warn };
+use std::collections::HashMap;
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+use std::sync::{ Arc, Mutex, RwLock, Weak };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::net::UdpSocket;
+use tokio::time::{ self, sleep };
+use url::Url;
+use crate::errors::{ Error };
+use crate::location::{ FixedResponse, ResolvedLocation };
+use crate::matcher::{ Matcher };
+
+/// How long we wait for the DNS server to answer:
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How soon we look a record up again after failing to, or finding none:
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Records are looked up again when they expire, but no more often than
+/// this, and no less often than `MAX_TTL`:
+const MIN_TTL: Duration = Duration::from_secs(1);
+const MAX_TTL: Duration = Duration::from_secs(300);
+/// The type and class of SRV records in DNS messages:
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+lazy_static! {
+    /// The targets of each SRV record that `srv://` destinations are for,
+    /// kept by name so that they carry on across reloads for as long as
+    /// some route still wants them:
+    static ref RECORDS: Mutex<HashMap<String, Weak<Targets>>> = Mutex::new(HashMap::new());
+}
+
+/// One target of an SRV record. Fields are in this order so that records
+/// sort by priority:
+#[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord)]
+struct Record {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String
+}
+
+/// The targets of an SRV record, as of the last time we looked it up,
+/// which requests to it are shared between.
+#[derive(Debug)]
+pub struct Targets {
+    name: String,
+    records: RwLock<Vec<Record>>,
+    next: AtomicUsize,
+    /// Is something keeping these up to date?
+    refreshing: AtomicBool
+}
+
+impl Targets {
+    /// The targets of an SRV record, shared with any other routes to it:
+    pub fn of(name: &str) -> Arc<Targets> {
+        let mut records = RECORDS.lock().unwrap();
+        if let Some(targets) = records.get(name).and_then(Weak::upgrade) {
+            return targets;
+        }
+        records.retain(|_, targets| targets.strong_count() > 0);
+        let targets = Arc::new(Targets {
+            name: name.to_owned(),
+            records: RwLock::new(vec![]),
+            next: AtomicUsize::new(0),
+            refreshing: AtomicBool::new(false)
+        });
+        records.insert(name.to_owned(), Arc::downgrade(&targets));
+        targets
+    }
+
+    /// Where a request for an `srv://` destination would go for each of
+    /// the record's targets:
+    pub fn each(&self, location: ResolvedLocation) -> Vec<ResolvedLocation> {
+        let url = match location {
+            ResolvedLocation::Url(url) => url,
+            location => return vec![location]
+        };
+        self.records.read().unwrap().iter()
+            .map(|record| ResolvedLocation::Url(with_target(url.clone(), record)))
+            .collect()
+    }
+
+    /// Send a request for an `srv://` destination to one of the record's
+    /// targets, or answer it with a 503 if there are none:
+    pub fn pick(&self, location: ResolvedLocation) -> ResolvedLocation {
+        let url = match location {
+            ResolvedLocation::Url(url) => url,
+            location => return location
+        };
+        let records = self.records.read().unwrap();
+        match choose(&records, self.next.fetch_add(1, Ordering::Relaxed)) {
+            Some(record) => ResolvedLocation::Url(with_target(url, record)),
+            None => ResolvedLocation::Fixed(FixedResponse {
+                status: 503,
+                headers: vec![],
+                body: format!("Weave: No targets for the SRV record '{}'", self.name)
+            })
+        }
+    }
+}
+
+/// Pick the nth target to send a request to, as RFC 2782 asks: only those
+/// with the lowest priority are used, and they're sent requests in
+/// proportion to their weights. Targets with a weight of 0 are only sent
+/// requests if they all have one:
+fn choose(records: &[Record], n: usize) -> Option<&Record> {
+    let priority = records.iter().map(|record| record.priority).min()?;
+    let candidates: Vec<&Record> = records.iter().filter(|record| record.priority == priority).collect();
+    let total: usize = candidates.iter().map(|record| record.weight as usize).sum();
+    if total == 0 {
+        return Some(candidates[n % candidates.len()]);
+    }
+    let mut n = n % total;
+    for record in candidates {
+        if n < record.weight as usize {
+            return Some(record);
+        }
+        n -= record.weight as usize;
+    }
+    None
+}
+
+fn with_target(mut url: Url, record: &Record) -> Url {
+    let _ = url.set_host(Some(&record.target));
+    let _ = url.set_port(Some(record.port));
+    url
+}
+
+/// Start looking up each SRV record that routes have `srv://`
+/// destinations for, unless we already are. Lookups for a record stop
+/// once no routes want it.
+pub fn spawn_lookups(matcher: &Matcher) {
+    for targets in matcher.srv_targets() {
+        if targets.refreshing.swap(true, Ordering::Relaxed) {
+            continue;
+        }
+        tokio::spawn(look_up_periodically(targets.name.clone(), Arc::downgrade(targets)));
+    }
+}
+
+async fn look_up_periodically(name: String, targets: Weak<Targets>) {
+    let mut failing = None;
+    loop {
+        let res = look_up(&name).await;
+
+        // The routes we were looking up for are gone, so stop:
+        let targets = match targets.upgrade() {
+            Some(targets) => targets,
+            None => return
+        };
+
+        let wait = match res {
+            Ok((records, ttl)) => {
+                failing = None;
+                let wait = if records.is_empty() { RETRY_INTERVAL } else { ttl.max(MIN_TTL).min(MAX_TTL) };
+                let mut current = targets.records.write().unwrap();
+                if *current != records {
+                    if records.is_empty() {
+                        warn!("{}", Red.paint(format!("[srv] {} has no targets", name)));
+                    } else {
+                        let listed: Vec<String> = records.iter()
+                            .map(|r| format!("{}:{} (priority {}, weight {})", r.target, r.port, r.priority, r.weight))
+                            .collect();
+                        info!("{}", Green.paint(format!("[srv] {} has {} targets: {}", name, records.len(), listed.join(", "))));
+                    }
+                    *current = records;
+                }
+                wait
+            },
+            // We keep sending requests to the targets we knew of, and
+            // only say so when the lookups start failing (or fail differently):
+            Err(e) => {
+                let e = e.to_string();
+                if failing.as_ref() != Some(&e) {
+                    warn!("{}", Red.paint(format!("[srv] Can't look up {} ({})", name, e)));
+                    failing = Some(e);
+                }
+                RETRY_INTERVAL
+            }
+        };
+
+        drop(targets);
+        sleep(wait).await;
+    }
+}
+
+/// Ask the DNS server for the targets of an SRV record, and how long
+/// until the answer expires:
+async fn look_up(name: &str) -> Result<(Vec<Record>, Duration), Error> {
+    let server = nameserver();
+    let local = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+
+    // Answers are only read from the server we asked, so the ID needn't
+    // be hard to guess:
+    let id = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos() as u16).unwrap_or(0);
+    let query = query(id, name)?;
+    let answered = time::timeout(LOOKUP_TIMEOUT, async {
+        socket.send(&query).await?;
+        let mut buf = vec![0; 4096];
+        let len = socket.recv(&mut buf).await?;
+        parse_response(id, &buf[..len])
+    });
+    answered.await.map_err(|_| err!("{} didn't answer within {:#?}", server, LOOKUP_TIMEOUT))?
+}
+
+/// The first DNS server in `/etc/resolv.conf`, which is read each time so
+/// that changes to it are picked up:
+fn nameserver() -> SocketAddr {
+    let ip = std::fs::read_to_string("/etc/resolv.conf").ok()
+        .and_then(|conf| {
+            conf.lines()
+                .filter_map(|line| line.trim().strip_prefix("nameserver"))
+                .find_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        })
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    SocketAddr::new(ip, 53)
+}
+
+/// A DNS message asking for the SRV records with a name:
+fn query(id: u16, name: &str) -> Result<Vec<u8>, Error> {
+    let mut packet = id.to_be_bytes().to_vec();
+    // Recursion desired, and one question:
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(err!("'{}' is not a valid DNS name", name));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+/// The SRV records in the answer to a query, sorted, and the shortest
+/// time any of them can be kept for. A name that doesn't exist has no
+/// records:
+fn parse_response(id: u16, packet: &[u8]) -> Result<(Vec<Record>, Duration), Error> {
+    if packet.len() < 12 || read_u16(packet, 0)? != id || packet[2] & 0x80 == 0 {
+        return Err(err!("Got a DNS message that isn't an answer to our query"));
+    }
+    if packet[2] & 0x02 != 0 {
+        return Err(err!("The answer was too long to be sent over UDP"));
+    }
+    match packet[3] & 0x0f {
+        0 => {},
+        3 => return Ok((vec![], MAX_TTL)),
+        code => return Err(err!("The DNS server answered with error code {}", code))
+    }
+
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(packet, pos)?.1 + 4;
+    }
+    let mut records = vec![];
+    let mut ttl = MAX_TTL;
+    for _ in 0..answers {
+        pos = read_name(packet, pos)?.1;
+        let kind = read_u16(packet, pos)?;
+        let record_ttl = read_u32(packet, pos + 4)?;
+        let len = read_u16(packet, pos + 8)? as usize;
+        let data = pos + 10;
+        pos = data + len;
+        if kind != TYPE_SRV {
+            continue;
+        }
+        let (target, _) = read_name(packet, data + 6)?;
+        // A target of "." means the service isn't offered at this name:
+        if target.is_empty() {
+            continue;
+        }
+        records.push(Record {
+            priority: read_u16(packet, data)?,
+            weight: read_u16(packet, data + 2)?,
+            port: read_u16(packet, data + 4)?,
+            target
+        });
+        ttl = ttl.min(Duration::from_secs(record_ttl as u64));
+    }
+    records.sort();
+    records.dedup();
+    Ok((records, ttl))
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Result<u16, Error> {
+    match packet.get(pos..pos + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(err!("The DNS message ended early"))
+    }
+}
+
+fn read_u32(packet: &[u8], pos: usize) -> Result<u32, Error> {
+    Ok((read_u16(packet, pos)? as u32) << 16 | read_u16(packet, pos + 2)? as u32)
+}
+
+/// Read a name (without a trailing '.'), following pointers to names
+/// earlier in the message. Hands back where the name ends, too:
+fn read_name(packet: &[u8], mut pos: usize) -> Result<(String, usize), Error> {
+    let mut labels = vec![];
+    let mut end = None;
+    // Enough to read any valid name, without looping forever on bad ones:
+    for _ in 0..128 {
+        let len = *packet.get(pos).ok_or_else(|| err!("The DNS message ended early"))? as usize;
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(pos + 2);
+            pos = (read_u16(packet, pos)? & 0x3fff) as usize;
+            continue;
+        }
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        let label = packet.get(pos + 1..pos + 1 + len).ok_or_else(|| err!("The DNS message ended early"))?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+    Err(err!("Found a name in the DNS message that's too long"))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_srv_answers() {
+        let mut packet = query(0x1234, "_http._tcp.api.example.com").unwrap();
+        // Answer with no errors, and two answers:
+        packet[2] |= 0x80;
+        packet[7] = 2;
+        // The first target's name is written out, and the second points to
+        // the end of the first's (`example.com`):
+        packet.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60, 0, 22, 0, 10, 0, 1, 0x1f, 0x90]);
+        let example_com = packet.len() as u8 + 3;
+        packet.extend_from_slice(b"\x02b1\x07example\x03com\x00");
+        packet.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 30, 0, 11, 0, 5, 0, 3, 0x1f, 0x91]);
+        packet.extend_from_slice(&[0x02, b'b', b'2', 0xc0, example_com]);
+
+        let (records, ttl) = parse_response(0x1234, &packet).unwrap();
+        assert_eq!(ttl, Duration::from_secs(30));
+        assert_eq!(records, vec![
+            Record { priority: 5, weight: 3, port: 8081, target: "b2.example.com".to_owned() },
+            Record { priority: 10, weight: 1, port: 8080, target: "b1.example.com".to_owned() }
+        ]);
+        assert!(parse_response(0x4321, &packet).is_err());
+        assert!(parse_response(0x1234, &packet[..packet.len() - 3]).is_err());
+    }
+
+    #[test]
+    fn picks_targets_by_priority_and_weight() {
+        let record = |priority, weight, target: &str| Record { priority, weight, port: 80, target: target.to_owned() };
+        let records = vec![record(10, 3, "a"), record(10, 1, "b"), record(10, 0, "c"), record(20, 5, "d")];
+        let picked: Vec<&str> = (0..8).map(|n| choose(&records, n).unwrap().target.as_str()).collect();
+        assert_eq!(picked, vec!["a", "a", "a", "b", "a", "a", "a", "b"]);
+
+        let records = vec![record(10, 0, "a"), record(10, 0, "b")];
+        assert_eq!(choose(&records, 1).unwrap().target, "b");
+        assert!(choose(&[], 0).is_none());
+    }
+}
@@ -0,0 +1,168 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::{ BTreeMap, HashMap, VecDeque };
+use std::sync::{ Arc, Mutex, RwLock };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+use crate::routes::{ Route };
+
+/// How many of the most recent errors we keep hold of:
+const MAX_RECENT_ERRORS: usize = 50;
+
+lazy_static! {
+    /// Statistics for each route, kept by name so that they carry on
+    /// across reloads for routes that are still there:
+    static ref STATS: RwLock<HashMap<String, Arc<RouteStats>>> = RwLock::new(HashMap::new());
+    /// The most recent requests we responded to with an error, oldest first:
+    static ref RECENT_ERRORS: Mutex<VecDeque<RecentError>> = Mutex::new(VecDeque::new());
+}
+
+/// Counts of the requests a route has handled.
+#[derive(Debug,Default)]
+pub struct RouteStats {
+    requests: AtomicU64,
+    in_flight: AtomicU64,
+    /// Responses by class of status (`1xx` to `5xx`):
+    statuses: [AtomicU64; 5],
+    /// How long all responses took to be ready, added up:
+    total_micros: AtomicU64
+}
+
+/// A copy of the statistics for a route at some point in time:
+#[derive(Debug,Clone,PartialEq,Serialize)]
+pub struct Snapshot {
+    pub requests: u64,
+    pub in_flight: u64,
+    pub statuses: BTreeMap<&'static str, u64>,
+    /// How long responses took to be ready, on average:
+    pub mean_ms: f64
+}
+
+/// A request we responded to with a `4xx` or `5xx` status.
+#[derive(Debug,Clone,PartialEq,Serialize)]
+pub struct RecentError {
+    /// When we responded, in milliseconds since the Unix epoch:
+    pub time: u64,
+    /// The route the request was for, if it matched one:
+    pub route: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// What went wrong, if it was us that responded with the error:
+    pub message: Option<String>
+}
+
+/// A request being handled, which counts as in flight until finished with:
+pub struct InFlight {
+    stats: Arc<RouteStats>,
+    started: Instant
+}
+
+/// The name a route's statistics are kept under:
+pub fn route_name(route: &Route) -> String {
+    format!("{} to {}", route.src, route.dests_to_string())
+}
+
+/// The statistics for a route, creating them the first time we see it:
+pub fn for_route(route: &Route) -> Arc<RouteStats> {
+    let name = route_name(route);
+    if let Some(stats) = STATS.read().unwrap().get(&name) {
+        return Arc::clone(stats);
+    }
+    Arc::clone(STATS.write().unwrap().entry(name).or_default())
+}
+
+/// Keep hold of an error we've just responded with, forgetting the oldest
+/// if we have too many:
+pub fn record_error(route: Option<String>, method: String, path: String, status: u16, message: Option<String>) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    let error = RecentError { time, route, method, path, status, message };
+    let mut errors = RECENT_ERRORS.lock().unwrap();
+    if errors.len() >= MAX_RECENT_ERRORS {
+        errors.pop_front();
+    }
+    errors.push_back(error);
+}
+
+/// The most recent errors, newest first:
+pub fn recent_errors() -> Vec<RecentError> {
+    RECENT_ERRORS.lock().unwrap().iter().rev().cloned().collect()
+}
+
+impl RouteStats {
+    /// Count a request that's just arrived:
+    pub fn start(self: &Arc<Self>) -> InFlight {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight { stats: Arc::clone(self), started: Instant::now() }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let classes = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+        let statuses = classes.iter().zip(&self.statuses)
+            .map(|(class, count)| (*class, count.load(Ordering::Relaxed)))
+            .collect();
+        let finished: u64 = self.statuses.iter().map(|count| count.load(Ordering::Relaxed)).sum();
+        let total_micros = self.total_micros.load(Ordering::Relaxed);
+        Snapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            statuses,
+            mean_ms: if finished == 0 { 0.0 } else { total_micros as f64 / finished as f64 / 1000.0 }
+        }
+    }
+}
+
+impl InFlight {
+    /// Count the response to the request:
+    pub fn finish(self, status: u16) {
+        let class = (status / 100).clamp(1, 5) as usize - 1;
+        self.stats.statuses[class].fetch_add(1, Ordering::Relaxed);
+        self.stats.total_micros.fetch_add(self.started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::routes;
+
+    #[test]
+    fn counts_requests() {
+        let route = routes::from_args(vec!["8080/stats-test".to_owned(), "to".to_owned(), "9000".to_owned()]).unwrap().0.remove(0);
+        let stats = for_route(&route);
+        let first = stats.start();
+        let second = stats.start();
+        assert_eq!(stats.snapshot().in_flight, 2);
+        first.finish(200);
+        second.finish(503);
+
+        // The same route (after a reload, say) has the same stats:
+        let snapshot = for_route(&route.clone()).snapshot();
+        assert_eq!(snapshot.requests, 2);
+        assert_eq!(snapshot.in_flight, 0);
+        assert_eq!(snapshot.statuses["2xx"], 1);
+        assert_eq!(snapshot.statuses["5xx"], 1);
+        assert_eq!(snapshot.statuses["4xx"], 0);
+    }
+
+    #[test]
+    fn keeps_recent_errors() {
+        let error = |status| record_error(None, "GET".to_owned(), "/".to_owned(), status, None);
+        for _ in 0..MAX_RECENT_ERRORS {
+            error(404);
+        }
+        error(502);
+        let errors = recent_errors();
+        assert_eq!(errors.len(), MAX_RECENT_ERRORS);
+        assert_eq!(errors[0].status, 502);
+        assert!(errors[0].time > 0);
+    }
+}
@@ -0,0 +1,99 @@
+use log::{ debug };
+use std::net::{ ToSocketAddrs, UdpSocket };
+use std::sync::Arc;
+use std::time::Duration;
+use crate::errors::{ Error };
+
+/// What metric names start with, if not provided:
+pub const DEFAULT_STATSD_PREFIX: &str = "weave.";
+
+/// Sends metrics about each request to a StatsD server over UDP. Metrics
+/// are tagged DogStatsD style (`|#key:value,...`) with the route,
+/// destination and class of status, along with any tags we're given.
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<String>
+}
+
+impl Statsd {
+    pub fn new(addr: &str, prefix: &str, tags: Vec<String>) -> Result<Arc<Statsd>, Error> {
+        let addr = addr.to_socket_addrs()
+            .map_err(|e| err!("Cannot parse StatsD address: {}", e))?
+            .next()
+            .ok_or_else(|| err!("Cannot parse StatsD address"))?;
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        // Metrics are best effort; we'd rather drop them than wait:
+        socket.set_nonblocking(true)?;
+        Ok(Arc::new(Statsd { socket, prefix: prefix.to_owned(), tags }))
+    }
+
+    /// Parse a comma separated list of tags like `env:dev,team:web`:
+    pub fn parse_tags(input: &str) -> Result<Vec<String>, Error> {
+        let tags: Vec<String> = input.split(',')
+            .map(|tag| tag.trim().to_owned())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        if let Some(tag) = tags.iter().find(|tag| tag.contains(&['|', '#'][..])) {
+            return Err(err!("'{}' is not a valid tag", tag));
+        }
+        Ok(tags)
+    }
+
+    /// Count a request and how long it took to respond to. Requests that
+    /// don't match a route or aren't sent to a destination are tagged with
+    /// `none` for those:
+    pub fn record(&self, route: Option<&str>, dest: Option<&str>, status: u16, elapsed: Duration) {
+        let mut tags = self.tags.clone();
+        tags.push(format!("route:{}", tag_value(route.unwrap_or("none"))));
+        tags.push(format!("destination:{}", tag_value(dest.unwrap_or("none"))));
+        tags.push(format!("status:{}xx", (status / 100).clamp(1, 5)));
+        let tags = tags.join(",");
+        let millis = elapsed.as_secs_f64() * 1000.0;
+        let packet = format!(
+            "{prefix}requests:1|c|#{tags}\n{prefix}response_time:{millis:.3}|ms|#{tags}",
+            prefix = self.prefix,
+            tags = tags,
+            millis = millis
+        );
+        if let Err(e) = self.socket.send(packet.as_bytes()) {
+            debug!("Failed to send metrics to StatsD: {}", e);
+        }
+    }
+}
+
+/// Tag values can't contain the characters that separate tags and fields:
+fn tag_value(value: &str) -> String {
+    value.replace(&[',', '|', '#', '\n'][..], "_")
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn sends_tagged_metrics() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let statsd = Statsd::new(&addr, "weave.", Statsd::parse_tags("env:dev, team:web").unwrap()).unwrap();
+        statsd.record(Some("8080/a to 9000,9001"), None, 503, Duration::from_millis(12));
+
+        let mut buf = [0; 1024];
+        let len = server.recv(&mut buf).unwrap();
+        let tags = "env:dev,team:web,route:8080/a to 9000_9001,destination:none,status:5xx";
+        assert_eq!(
+            std::str::from_utf8(&buf[..len]).unwrap(),
+            format!("weave.requests:1|c|#{}\nweave.response_time:12.000|ms|#{}", tags, tags)
+        );
+    }
+
+    #[test]
+    fn parses_tags() {
+        assert_eq!(Statsd::parse_tags("a:b,,c").unwrap(), vec!["a:b", "c"]);
+        assert!(Statsd::parse_tags("a|b").is_err());
+    }
+}
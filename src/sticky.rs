@@ -0,0 +1,158 @@
+use hyper::HeaderMap;
+use hyper::header::HeaderName;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{ Hash, Hasher };
+use crate::errors::{ Error };
+use crate::headers::{ cookie };
+use crate::routes::{ Route };
+
+/// How to keep sending a client to the same destination of a route, so
+/// that stateful destinations behave behind the balancer.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Sticky {
+    /// Hand the client a cookie naming its destination:
+    Cookie,
+    /// Pick a destination from the value of an existing cookie:
+    HashCookie(String),
+    /// Pick a destination from the value of a header:
+    HashHeader(HeaderName)
+}
+
+impl Sticky {
+    /// Parse `cookie`, `cookie:NAME` or `header:NAME`:
+    pub fn parse(input: &str) -> Result<Sticky, Error> {
+        let input = input.trim();
+        if input.eq_ignore_ascii_case("cookie") {
+            return Ok(Sticky::Cookie);
+        }
+        let invalid = || err!("Expecting 'cookie', 'cookie:NAME' or 'header:NAME' but got '{}'", input);
+        let idx = input.find(':').ok_or_else(invalid)?;
+        let (kind, name) = (input[..idx].trim(), input[idx+1..].trim());
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        if kind.eq_ignore_ascii_case("cookie") {
+            Ok(Sticky::HashCookie(name.to_owned()))
+        } else if kind.eq_ignore_ascii_case("header") {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| err!("'{}' is not a valid header name", name))?;
+            Ok(Sticky::HashHeader(name))
+        } else {
+            Err(invalid())
+        }
+    }
+
+    /// Pick the destination (by index) this client should stick to, if we
+    /// know it and it's healthy. Hashed values are spread across destinations
+    /// with rendezvous hashing, so that when one becomes unhealthy, only its
+    /// clients move elsewhere.
+    pub fn pick(&self, route: &Route, headers: &HeaderMap, is_healthy: impl Fn(usize) -> bool) -> Option<usize> {
+        let dest_ids = route.dests.iter().map(|dest| hash(&dest.to_string()));
+        match self {
+            Sticky::Cookie => {
+                let id = cookie(headers, &cookie_name(route))?;
+                dest_ids.enumerate()
+                    .find(|&(idx, dest_id)| format!("{:016x}", dest_id) == id && is_healthy(idx))
+                    .map(|(idx, _)| idx)
+            },
+            Sticky::HashCookie(name) => {
+                let value = cookie(headers, name)?;
+                rendezvous(value, dest_ids, is_healthy)
+            },
+            Sticky::HashHeader(name) => {
+                let value = headers.get(name)?.to_str().ok()?;
+                rendezvous(value, dest_ids, is_healthy)
+            }
+        }
+    }
+}
+
+/// The `Set-Cookie` header that sticks a client to one of the destinations
+/// of a route:
+pub fn set_cookie(route: &Route, idx: usize) -> String {
+    let dest_id = hash(&route.dests[idx].to_string());
+    format!("{}={:016x}; Path=/; HttpOnly; SameSite=Lax", cookie_name(route), dest_id)
+}
+
+/// Each route has its own cookie, so that clients can stick to a
+/// destination of each:
+fn cookie_name(route: &Route) -> String {
+    format!("weave_sticky_{:08x}", hash(&route.src.to_string()) as u32)
+}
+
+fn rendezvous(value: &str, dest_ids: impl Iterator<Item=u64>, is_healthy: impl Fn(usize) -> bool) -> Option<usize> {
+    dest_ids.enumerate()
+        .filter(|&(idx, _)| is_healthy(idx))
+        .max_by_key(|&(_, dest_id)| hash(&(value, dest_id)))
+        .map(|(idx, _)| idx)
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use hyper::header::{ HeaderValue, COOKIE };
+    use crate::routes;
+
+    fn route(s: &str) -> Route {
+        routes::from_args(s.split_whitespace().map(|s| s.to_owned())).unwrap().0.remove(0)
+    }
+
+    #[test]
+    fn parses_sticky_options() {
+        assert_eq!(Sticky::parse("cookie").unwrap(), Sticky::Cookie);
+        assert_eq!(Sticky::parse("cookie:session").unwrap(), Sticky::HashCookie("session".to_owned()));
+        assert_eq!(Sticky::parse("header:X-User").unwrap(), Sticky::HashHeader(HeaderName::from_static("x-user")));
+        assert!(Sticky::parse("cookie:").is_err());
+        assert!(Sticky::parse("ip").is_err());
+    }
+
+    #[test]
+    fn sticks_to_the_destination_in_our_cookie() {
+        let route = route("8080 to 9001 and-also 9002");
+        let set_cookie = set_cookie(&route, 1);
+        let cookie = set_cookie.split(';').next().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(cookie).unwrap());
+
+        assert_eq!(Sticky::Cookie.pick(&route, &headers, |_| true), Some(1));
+        // Unless that destination is unhealthy:
+        assert_eq!(Sticky::Cookie.pick(&route, &headers, |idx| idx != 1), None);
+        assert_eq!(Sticky::Cookie.pick(&route, &HeaderMap::new(), |_| true), None);
+    }
+
+    #[test]
+    fn hashes_values_to_destinations() {
+        let route = route("8080 to 9001 and-also 9002 and-also 9003");
+        let sticky = Sticky::HashHeader(HeaderName::from_static("x-user"));
+        let headers = |user: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-user", HeaderValue::from_str(user).unwrap());
+            headers
+        };
+
+        let users: Vec<String> = (0..100).map(|n| format!("user{}", n)).collect();
+        let picks: Vec<usize> = users.iter().map(|u| sticky.pick(&route, &headers(u), |_| true).unwrap()).collect();
+        // The same users get the same destinations each time, spread across them all:
+        let again: Vec<usize> = users.iter().map(|u| sticky.pick(&route, &headers(u), |_| true).unwrap()).collect();
+        assert_eq!(picks, again);
+        assert!((0..3).all(|idx| picks.contains(&idx)));
+
+        // Only the users of an unhealthy destination move:
+        for (user, &pick) in users.iter().zip(&picks) {
+            let new_pick = sticky.pick(&route, &headers(user), |idx| idx != 0).unwrap();
+            if pick == 0 {
+                assert_ne!(new_pick, 0);
+            } else {
+                assert_eq!(new_pick, pick);
+            }
+        }
+        assert_eq!(sticky.pick(&route, &HeaderMap::new(), |_| true), None);
+    }
+}
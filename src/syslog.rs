@@ -0,0 +1,224 @@
+use env_logger::filter::{ Filter };
+use log::{ Level, Log, Metadata, Record };
+use std::io;
+use std::net::{ ToSocketAddrs, UdpSocket };
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::time::SystemTime;
+use crate::errors::{ Error };
+use crate::logging;
+
+/// What we call ourselves in syslog and the journal:
+const IDENTIFIER: &str = "weave";
+/// Where local syslog daemons listen:
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Where journald listens for its native protocol:
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// We log as a system daemon:
+const FACILITY_DAEMON: u8 = 3;
+
+/// Where log messages go.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum LogTarget {
+    Stderr,
+    /// The local syslog daemon, over its Unix socket:
+    Syslog,
+    /// A syslog server over UDP, as `host:port`:
+    SyslogUdp(String),
+    Journald
+}
+
+impl LogTarget {
+    /// Parse `stderr`, `syslog`, `syslog://host:port` or `journald`:
+    pub fn parse(input: &str) -> Result<LogTarget, Error> {
+        let input = input.trim();
+        match input.to_lowercase().as_str() {
+            "stderr" => return Ok(LogTarget::Stderr),
+            "syslog" => return Ok(LogTarget::Syslog),
+            "journald" => return Ok(LogTarget::Journald),
+            _ => {}
+        }
+        match input.get(..9) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("syslog://") => {
+                let addr = &input[9..];
+                let addr = if addr.contains(':') { addr.to_owned() } else { format!("{}:514", addr) };
+                Ok(LogTarget::SyslogUdp(addr))
+            },
+            _ => Err(err!("Expecting 'stderr', 'syslog', 'syslog://host:port' or 'journald'"))
+        }
+    }
+}
+
+/// Sends log messages to syslog or journald, with priorities to match
+/// their levels.
+pub struct SystemLogger {
+    filter: Filter,
+    sink: Sink
+}
+
+enum Sink {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+    #[cfg(unix)]
+    Journald(UnixDatagram)
+}
+
+impl SystemLogger {
+    pub fn new(target: &LogTarget, filter: Filter) -> Result<SystemLogger, Error> {
+        let sink = match target {
+            LogTarget::Stderr => return Err(err!("Messages to stderr are logged as usual")),
+            LogTarget::SyslogUdp(addr) => {
+                let addr = addr.to_socket_addrs()
+                    .map_err(|e| err!("Cannot parse syslog address '{}': {}", addr, e))?
+                    .next()
+                    .ok_or_else(|| err!("Cannot parse syslog address '{}'", addr))?;
+                let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+                socket.connect(addr)?;
+                Sink::Udp(socket)
+            },
+            #[cfg(unix)]
+            LogTarget::Syslog => Sink::Syslog(connect_unix(SYSLOG_SOCKET)?),
+            #[cfg(unix)]
+            LogTarget::Journald => Sink::Journald(connect_unix(JOURNALD_SOCKET)?),
+            #[cfg(not(unix))]
+            LogTarget::Syslog | LogTarget::Journald => {
+                return Err(err!("Logging to syslog and journald over Unix sockets is not supported on this platform"));
+            }
+        };
+        Ok(SystemLogger { filter, sink })
+    }
+
+    fn send(&self, record: &Record) -> io::Result<()> {
+        let message = strip_colors(&record.args().to_string());
+        let severity = severity(record.level());
+        match &self.sink {
+            // Remote servers get RFC 5424 messages:
+            Sink::Udp(socket) => {
+                let line = format!("<{}>1 {} - {} {} - - {}",
+                                   FACILITY_DAEMON * 8 + severity,
+                                   logging::rfc3339(SystemTime::now()),
+                                   IDENTIFIER,
+                                   std::process::id(),
+                                   message);
+                socket.send(line.as_bytes()).map(|_| ())
+            },
+            // The local daemon fills in the time and host itself:
+            #[cfg(unix)]
+            Sink::Syslog(socket) => {
+                let line = format!("<{}>{}[{}]: {}", FACILITY_DAEMON * 8 + severity, IDENTIFIER, std::process::id(), message);
+                socket.send(line.as_bytes()).map(|_| ())
+            },
+            #[cfg(unix)]
+            Sink::Journald(socket) => {
+                let target = record.module_path().unwrap_or_else(|| record.target());
+                let fields = journald_fields(&[
+                    ("MESSAGE", &message),
+                    ("PRIORITY", &severity.to_string()),
+                    ("SYSLOG_IDENTIFIER", IDENTIFIER),
+                    ("SYSLOG_PID", &std::process::id().to_string()),
+                    ("TARGET", target)
+                ]);
+                socket.send(&fields).map(|_| ())
+            }
+        }
+    }
+}
+
+impl Log for SystemLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        // There's nowhere to complain to if this fails:
+        if self.filter.matches(record) {
+            let _ = self.send(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> Result<UnixDatagram, Error> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path).map_err(|e| err!("Cannot connect to '{}': {}", path, e))?;
+    Ok(socket)
+}
+
+/// Syslog severities, from `3` (error) to `7` (debug):
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7
+    }
+}
+
+/// Fields in journald's native protocol. Values with newlines in are sent
+/// with their length up front, rather than as `KEY=value` lines:
+fn journald_fields(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = vec![];
+    for (key, value) in fields {
+        out.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            out.push(b'\n');
+            out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            out.push(b'=');
+        }
+        out.extend_from_slice(value.as_bytes());
+        out.push(b'\n');
+    }
+    out
+}
+
+/// Colored lines meant for terminals just have escape codes in the way
+/// anywhere else:
+fn strip_colors(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn parses_log_targets() {
+        assert_eq!(LogTarget::parse("journald").unwrap(), LogTarget::Journald);
+        assert_eq!(LogTarget::parse("Syslog").unwrap(), LogTarget::Syslog);
+        assert_eq!(LogTarget::parse("syslog://logs.internal").unwrap(), LogTarget::SyslogUdp("logs.internal:514".to_owned()));
+        assert_eq!(LogTarget::parse("syslog://10.0.0.1:1514").unwrap(), LogTarget::SyslogUdp("10.0.0.1:1514".to_owned()));
+        assert!(LogTarget::parse("kafka").is_err());
+    }
+
+    #[test]
+    fn writes_journald_fields() {
+        let fields = journald_fields(&[("MESSAGE", "a\nb"), ("PRIORITY", "6")]);
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\nPRIORITY=6\n");
+        assert_eq!(fields, expected);
+    }
+
+    #[test]
+    fn strips_colors() {
+        assert_eq!(strip_colors("\x1b[32m[200] / to /\x1b[0m"), "[200] / to /");
+    }
+}
@@ -0,0 +1,440 @@
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{ Connected, Connection, HttpConnector };
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use tokio::io::{ AsyncRead, AsyncWrite, ReadBuf };
+use tokio::net::TcpStream;
+use tower_service::Service;
+use crate::connector::{ self, Resolve };
+use crate::errors::{ Error };
+use crate::options::{ TlsOptions };
+
+/// The TLS libraries we can make HTTPS connections with. Which of these are
+/// available depends on the `native-tls-backend` (the default) and
+/// `rustls-backend` cargo features; building with only rustls avoids
+/// depending on native libraries like OpenSSL. Built with neither, we can
+/// still proxy to `http` URLs, but not to `https` ones.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum TlsBackend {
+    Native,
+    Rustls
+}
+
+impl Default for TlsBackend {
+    fn default() -> TlsBackend {
+        if cfg!(feature = "native-tls-backend") { TlsBackend::Native } else { TlsBackend::Rustls }
+    }
+}
+
+impl TlsBackend {
+    pub fn parse(input: &str) -> Result<TlsBackend, Error> {
+        let backend = match input.trim().to_lowercase().as_str() {
+            "native" | "native-tls" => TlsBackend::Native,
+            "rustls" => TlsBackend::Rustls,
+            _ => return Err(err!("'{}' is not a TLS backend; expecting 'native' or 'rustls'", input))
+        };
+        let available = match backend {
+            TlsBackend::Native => cfg!(feature = "native-tls-backend"),
+            TlsBackend::Rustls => cfg!(feature = "rustls-backend")
+        };
+        if !available {
+            return Err(err!("The {:?} TLS backend was not compiled in", backend));
+        }
+        Ok(backend)
+    }
+}
+
+/// Connects over plain TCP for `http` URLs, and over TLS for anything else,
+/// using whichever TLS backend it was built with.
+#[derive(Clone)]
+pub struct HttpsConnector {
+    http: HttpConnector,
+    tls: Tls,
+    /// Where to connect to for some hosts, in place of where DNS says:
+    resolve: Vec<Resolve>
+}
+
+#[derive(Clone)]
+enum Tls {
+    #[cfg(feature = "native-tls-backend")]
+    Native(tokio_native_tls::TlsConnector),
+    #[cfg(feature = "rustls-backend")]
+    Rustls(tokio_rustls::TlsConnector),
+    #[cfg(not(any(feature = "native-tls-backend", feature = "rustls-backend")))]
+    Unavailable
+}
+
+impl HttpsConnector {
+    pub fn new(backend: TlsBackend, options: &TlsOptions, resolve: &[Resolve]) -> Result<HttpsConnector, Error> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let tls = match backend {
+            #[cfg(feature = "native-tls-backend")]
+            TlsBackend::Native => Tls::Native(native::connector(options)?.into()),
+            #[cfg(feature = "rustls-backend")]
+            TlsBackend::Rustls => Tls::Rustls(std::sync::Arc::new(rustls::config(options)?).into()),
+            #[cfg(not(any(feature = "native-tls-backend", feature = "rustls-backend")))]
+            _ => {
+                let _ = options;
+                Tls::Unavailable
+            },
+            #[cfg(any(feature = "native-tls-backend", feature = "rustls-backend"))]
+            #[allow(unreachable_patterns)]
+            _ => return Err(err!("The {:?} TLS backend was not compiled in", backend))
+        };
+        Ok(HttpsConnector { http, tls, resolve: resolve.to_vec() })
+    }
+}
+
+impl Service<Uri> for HttpsConnector {
+    type Response = HttpsStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output=Result<HttpsStream, io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.http.poll_ready(cx).map_err(io::Error::other)
+    }
+
+    fn call(&mut self, mut dst: Uri) -> Self::Future {
+        let is_https = dst.scheme_str() != Some("http");
+        // We may connect somewhere else, but TLS is for the host's name:
+        let host = match connector::resolve(&self.resolve, &mut dst) {
+            Ok(host) => host,
+            Err(e) => return Box::pin(async { Err(e) })
+        };
+        let connecting = self.http.call(dst);
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let tcp = connecting.await.map_err(io::Error::other)?.into_inner();
+            if !is_https {
+                return Ok(HttpsStream::Http(tcp));
+            }
+            match tls {
+                #[cfg(feature = "native-tls-backend")]
+                Tls::Native(tls) => {
+                    let stream = tls.connect(&host, tcp).await.map_err(io::Error::other)?;
+                    Ok(HttpsStream::Native(stream))
+                },
+                #[cfg(feature = "rustls-backend")]
+                Tls::Rustls(tls) => {
+                    let name: ::rustls::pki_types::ServerName = std::convert::TryFrom::try_from(host.clone()).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a valid DNS name", host))
+                    })?;
+                    Ok(HttpsStream::Rustls(Box::new(tls.connect(name, tcp).await?)))
+                },
+                #[cfg(not(any(feature = "native-tls-backend", feature = "rustls-backend")))]
+                Tls::Unavailable => {
+                    Err(io::Error::other(format!("Can't connect to '{}' over TLS, as weave was built without a TLS backend", host)))
+                }
+            }
+        })
+    }
+}
+
+/// A connection made by `HttpsConnector`:
+pub enum HttpsStream {
+    Http(TcpStream),
+    #[cfg(feature = "native-tls-backend")]
+    Native(tokio_native_tls::TlsStream<TcpStream>),
+    #[cfg(feature = "rustls-backend")]
+    Rustls(Box<tokio_rustls::client::TlsStream<TcpStream>>)
+}
+
+impl Connection for HttpsStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for HttpsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HttpsStream::Http(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "native-tls-backend")]
+            HttpsStream::Native(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "rustls-backend")]
+            HttpsStream::Rustls(s) => Pin::new(s).poll_read(cx, buf)
+        }
+    }
+}
+
+impl AsyncWrite for HttpsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            HttpsStream::Http(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "native-tls-backend")]
+            HttpsStream::Native(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "rustls-backend")]
+            HttpsStream::Rustls(s) => Pin::new(s).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HttpsStream::Http(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "native-tls-backend")]
+            HttpsStream::Native(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "rustls-backend")]
+            HttpsStream::Rustls(s) => Pin::new(s).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HttpsStream::Http(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "native-tls-backend")]
+            HttpsStream::Native(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "rustls-backend")]
+            HttpsStream::Rustls(s) => Pin::new(s).poll_shutdown(cx)
+        }
+    }
+}
+
+pub(crate) fn read(path: &std::path::Path) -> Result<Vec<u8>, Error> {
+    std::fs::read(path).map_err(|e| err!("Cannot read '{}': {}", path.display(), e))
+}
+
+#[cfg(feature = "native-tls-backend")]
+mod native {
+    use native_tls::{ Certificate, Identity, TlsConnector };
+    use crate::errors::{ Error };
+    use crate::options::{ TlsOptions };
+    use super::read;
+
+    /// Build a TLS connector according to the options given for a route:
+    pub fn connector(tls: &TlsOptions) -> Result<TlsConnector, Error> {
+        let mut builder = TlsConnector::builder();
+        match (&tls.client_cert, &tls.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?).map_err(|e| {
+                    err!("Cannot use client certificate '{}' with key '{}': {}", cert.display(), key.display(), e)
+                })?;
+                builder.identity(identity);
+            },
+            (None, None) => {},
+            _ => return Err(err!("Both client-cert and client-key must be given to use a client certificate"))
+        }
+        if let Some(ca_file) = &tls.ca_file {
+            let certs = pem_certificates(&read(ca_file)?).map_err(|e| {
+                err!("Cannot use CA file '{}': {}", ca_file.display(), e)
+            })?;
+            for cert in certs {
+                builder.add_root_certificate(cert);
+            }
+        }
+        if tls.insecure {
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Parse each of the certificates in a PEM bundle:
+    fn pem_certificates(pem: &[u8]) -> Result<Vec<Certificate>, Error> {
+        const END: &str = "-----END CERTIFICATE-----";
+        let pem = String::from_utf8_lossy(pem);
+        let certs = pem.split_inclusive(END)
+            .filter(|block| block.contains(END))
+            .map(|block| Certificate::from_pem(block.trim().as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(err!("No PEM certificates found"));
+        }
+        Ok(certs)
+    }
+
+    #[cfg(test)]
+    mod test {
+
+        use super::*;
+
+        #[test]
+        fn requires_both_client_cert_and_key() {
+            let tls = TlsOptions { client_cert: Some("client.pem".into()), ..TlsOptions::default() };
+            assert!(connector(&tls).is_err());
+            assert!(connector(&TlsOptions::default()).is_ok());
+        }
+
+        #[test]
+        fn complains_about_empty_ca_files() {
+            assert!(pem_certificates(b"").is_err());
+            assert!(pem_certificates(b"-----BEGIN CERTIFICATE-----\nwibble\n-----END CERTIFICATE-----\n").is_err());
+        }
+    }
+}
+
+#[cfg(feature = "rustls-backend")]
+mod rustls {
+    use ::rustls::{ ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme };
+    use ::rustls::client::danger::{ HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier };
+    use ::rustls::crypto::{ self, CryptoProvider };
+    use ::rustls::pki_types::{ CertificateDer, PrivateKeyDer, ServerName, UnixTime };
+    use std::sync::Arc;
+    use crate::errors::{ Error };
+    use crate::options::{ TlsOptions };
+    use super::read;
+
+    /// Build a rustls config according to the options given for a route,
+    /// trusting the same system certificates as the native backend does:
+    pub fn config(tls: &TlsOptions) -> Result<ClientConfig, Error> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider)).with_safe_default_protocol_versions()?;
+        let builder = if tls.insecure {
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        } else {
+            builder.with_root_certificates(root_store(tls)?)
+        };
+        let config = match (&tls.client_cert, &tls.client_key) {
+            (Some(cert), Some(key)) => {
+                let certs = pem_certificates(&read(cert)?);
+                let private_key = match rustls_pemfile::pkcs8_private_keys(&mut &*read(key)?).next() {
+                    Some(Ok(private_key)) if !certs.is_empty() => PrivateKeyDer::Pkcs8(private_key),
+                    _ => return Err(err!("Cannot use client certificate '{}' with key '{}': Expecting PEM certificates and a PKCS#8 key", cert.display(), key.display()))
+                };
+                builder.with_client_auth_cert(certs, private_key)?
+            },
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(err!("Both client-cert and client-key must be given to use a client certificate"))
+        };
+        Ok(config)
+    }
+
+    /// The system's root certificates, and those in the route's CA file:
+    fn root_store(tls: &TlsOptions) -> Result<RootCertStore, Error> {
+        let mut roots = RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs();
+        if let (true, Some(e)) = (native.certs.is_empty(), native.errors.first()) {
+            return Err(err!("Cannot load the system's root certificates: {}", e));
+        }
+        roots.add_parsable_certificates(native.certs);
+        if let Some(ca_file) = &tls.ca_file {
+            let (added, _) = roots.add_parsable_certificates(pem_certificates(&read(ca_file)?));
+            if added == 0 {
+                return Err(err!("Cannot use CA file '{}': No PEM certificates found", ca_file.display()));
+            }
+        }
+        Ok(roots)
+    }
+
+    fn pem_certificates(pem: &[u8]) -> Vec<CertificateDer<'static>> {
+        rustls_pemfile::certs(&mut &*pem).filter_map(Result::ok).collect()
+    }
+
+    /// Accepts any certificate, for the `insecure` option. Handshakes are
+    /// still checked against the certificate they're made with:
+    #[derive(Debug)]
+    struct NoVerification(Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for NoVerification {
+        fn verify_server_cert(&self, _end_entity: &CertificateDer, _intermediates: &[CertificateDer], _name: &ServerName, _ocsp: &[u8], _now: UnixTime) -> Result<ServerCertVerified, ::rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, ::rustls::Error> {
+            crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, ::rustls::Error> {
+            crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+
+        use super::*;
+        use ::rustls::ServerConfig;
+        use hyper::Uri;
+        use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+        use tokio::net::TcpListener;
+        use tokio_rustls::TlsAcceptor;
+        use tower_service::Service;
+        use crate::connector::{ Resolve };
+        use crate::tls::{ HttpsConnector, TlsBackend };
+
+        /// Say hello over TLS to whoever connects, with a certificate for
+        /// `weave.test`, handing back the port and the certificate's PEM:
+        async fn serve_hello() -> (u16, String) {
+            let cert = rcgen::generate_simple_self_signed(vec!["weave.test".to_owned()]).unwrap();
+            let key = PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
+            let config = ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions().unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.cert.der().clone()], key)
+                .unwrap();
+            let acceptor = TlsAcceptor::from(Arc::new(config));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                loop {
+                    let (tcp, _) = listener.accept().await.unwrap();
+                    if let Ok(mut stream) = acceptor.accept(tcp).await {
+                        let _ = stream.write_all(b"hello").await;
+                        let _ = stream.shutdown().await;
+                    }
+                }
+            });
+            (port, cert.cert.pem())
+        }
+
+        async fn say_hello(port: u16, tls: &TlsOptions) -> Result<String, String> {
+            let resolve = [Resolve::parse("weave.test:*:127.0.0.1").unwrap()];
+            let mut connector = HttpsConnector::new(TlsBackend::Rustls, tls, &resolve).map_err(|e| e.to_string())?;
+            let uri: Uri = format!("https://weave.test:{}/", port).parse().unwrap();
+            let mut stream = connector.call(uri).await.map_err(|e| e.to_string())?;
+            let mut hello = String::new();
+            stream.read_to_string(&mut hello).await.map_err(|e| e.to_string())?;
+            Ok(hello)
+        }
+
+        #[test]
+        fn verifies_destinations_with_rustls() {
+            let ca_file = std::env::temp_dir().join(format!("weave-rustls-test-{}.pem", std::process::id()));
+            tokio::runtime::Runtime::new().unwrap().block_on(async {
+                let (port, pem) = serve_hello().await;
+                std::fs::write(&ca_file, pem).unwrap();
+
+                // Trusting the certificate's CA (itself, here):
+                let trusted = TlsOptions { ca_file: Some(ca_file.clone()), ..TlsOptions::default() };
+                assert_eq!(say_hello(port, &trusted).await, Ok("hello".to_owned()));
+
+                // Not trusting it:
+                let err = say_hello(port, &TlsOptions::default()).await.unwrap_err();
+                assert!(err.contains("UnknownIssuer"), "unexpected error: {}", err);
+
+                // Not checking:
+                let insecure = TlsOptions { insecure: true, ..TlsOptions::default() };
+                assert_eq!(say_hello(port, &insecure).await, Ok("hello".to_owned()));
+            });
+            let _ = std::fs::remove_file(&ca_file);
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(any(feature = "native-tls-backend", feature = "rustls-backend")))]
+mod test {
+
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn needs_a_backend_for_https() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let mut connector = HttpsConnector::new(TlsBackend::default(), &TlsOptions::default(), &[]).unwrap();
+            let uri = |scheme: &str| format!("{}://127.0.0.1:{}/", scheme, port).parse().unwrap();
+            assert!(connector.call(uri("http")).await.is_ok());
+            let err = connector.call(uri("https")).await.err().unwrap();
+            assert!(err.to_string().contains("without a TLS backend"), "{}", err);
+        });
+    }
+}
@@ -0,0 +1,322 @@
+use ansi_term::Color::{ Red };
+use hyper::{ HeaderMap, Request };
+use hyper::header::{ HeaderValue, CONTENT_TYPE };
+use log::{ debug, warn };
+use ring::rand::{ SecureRandom, SystemRandom };
+use serde_json::{ json, Value };
+use std::mem;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::time::{ self, sleep };
+use url::Url;
+use crate::body::{ Body };
+use crate::errors::{ Error };
+use crate::options::{ TlsOptions };
+use crate::proxy;
+use crate::HttpsClient;
+
+/// The header W3C trace context is passed along in:
+pub const TRACEPARENT: &str = "traceparent";
+/// What spans say they're from, if not provided:
+pub const DEFAULT_SERVICE_NAME: &str = "weave";
+/// How often we send the spans we've collected to the collector:
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long we wait for the collector before giving up on a batch:
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many spans we'll hold on to between exports. Beyond this, spans are
+/// dropped rather than letting memory grow while a collector is down:
+const MAX_PENDING_SPANS: usize = 2048;
+
+/// Collects a span for each request, and sends them to an OpenTelemetry
+/// collector every so often over OTLP/HTTP (as JSON).
+#[derive(Debug)]
+pub struct Tracer {
+    /// Where to send spans; the collector's `/v1/traces` endpoint:
+    endpoint: Url,
+    service_name: String,
+    pending: Mutex<Vec<Value>>
+}
+
+/// Where a request sits in a trace. Requests that come with a valid
+/// `traceparent` header carry on with that trace, and other requests start
+/// a new one.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// The span of whoever made the request to us, if it was traced:
+    pub parent_span_id: Option<[u8; 8]>,
+    /// The span for our handling of the request:
+    pub span_id: [u8; 8],
+    /// The trace flags we were given (`01` if sampled):
+    pub flags: u8
+}
+
+/// The details of a request worth recording on its span.
+#[derive(Debug,Clone,PartialEq)]
+pub struct SpanInfo<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub route: Option<&'a str>,
+    pub dest: Option<&'a str>,
+    pub status: u16,
+    pub start: SystemTime,
+    pub end: SystemTime
+}
+
+impl Tracer {
+    /// Send spans to the collector at this URL (eg `http://localhost:4318`):
+    pub fn new(endpoint: &str, service_name: &str) -> Result<Arc<Tracer>, Error> {
+        let url = Url::parse(endpoint).map_err(|e| err!("'{}' is not a valid URL: {}", endpoint, e))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(err!("Expecting an http:// or https:// URL but got '{}'", endpoint));
+        }
+        // Like other OTLP exporters, we're given the base URL of the collector:
+        let endpoint = match url.path() {
+            "/" => url.join("v1/traces").expect("a relative path can be joined"),
+            _ => url
+        };
+        Ok(Arc::new(Tracer {
+            endpoint,
+            service_name: service_name.to_owned(),
+            pending: Mutex::new(vec![])
+        }))
+    }
+
+    /// Hold on to a span for a request until we next send spans off,
+    /// unless whoever made the request isn't sampling its trace:
+    pub fn record(&self, context: &TraceContext, info: &SpanInfo) {
+        if context.flags & 1 == 0 {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING_SPANS {
+            debug!("Dropping a span; too many are waiting to be sent");
+            return;
+        }
+        pending.push(span_json(context, info));
+    }
+
+    /// Send the spans we've collected so far, if there are any:
+    async fn export(&self, client: &HttpsClient) -> Result<(), Error> {
+        let spans = mem::take(&mut *self.pending.lock().unwrap());
+        if spans.is_empty() {
+            return Ok(());
+        }
+        let count = spans.len();
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [string_attr("service.name", &self.service_name)]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "weave" },
+                    "spans": spans
+                }]
+            }]
+        });
+        let req = Request::post(self.endpoint.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))?;
+        let resp = time::timeout(EXPORT_TIMEOUT, client.request(req, &TlsOptions::default())).await
+            .map_err(|_| err!("Timed out"))??;
+        let status = resp.status();
+        proxy::read_body(resp.into_body()).await?;
+        if !status.is_success() {
+            return Err(err!("The collector responded with {}", status));
+        }
+        debug!("Sent {} spans to {}", count, self.endpoint);
+        Ok(())
+    }
+}
+
+/// Send the spans we collect to the collector every so often, until we exit:
+pub fn spawn_exporter(tracer: Arc<Tracer>, client: HttpsClient) {
+    tokio::spawn(async move {
+        loop {
+            sleep(EXPORT_INTERVAL).await;
+            if let Err(e) = tracer.export(&client).await {
+                warn!("{}", Red.paint(format!("Failed to send spans to {}: {}", tracer.endpoint, e)));
+            }
+        }
+    });
+}
+
+impl TraceContext {
+    /// Carry on with the trace a request is part of, or start a new one:
+    pub fn for_request(headers: &HeaderMap) -> TraceContext {
+        let span_id = random_bytes();
+        let parent = headers.get(TRACEPARENT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_traceparent);
+        match parent {
+            Some((trace_id, parent_span_id, flags)) => TraceContext {
+                trace_id,
+                parent_span_id: Some(parent_span_id),
+                span_id,
+                flags
+            },
+            None => TraceContext {
+                trace_id: random_bytes(),
+                parent_span_id: None,
+                span_id,
+                flags: 1
+            }
+        }
+    }
+
+    /// The `traceparent` to send on to destinations, which makes our span
+    /// the parent of theirs:
+    pub fn traceparent(&self) -> HeaderValue {
+        let value = format!("00-{}-{}-{:02x}", hex(&self.trace_id), hex(&self.span_id), self.flags);
+        HeaderValue::from_str(&value).expect("hex is a valid header value")
+    }
+}
+
+/// Parse `00-<trace id>-<parent span id>-<flags>`. Later versions may add
+/// more fields, which we ignore:
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], u8)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    if version.len() != 2 || version == "ff" {
+        return None;
+    }
+    let trace_id = unhex(parts.next()?)?;
+    let span_id = unhex(parts.next()?)?;
+    let flags = unhex::<[u8; 1]>(parts.next()?)?[0];
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+    // All zeros isn't a valid ID:
+    if trace_id == [0; 16] || span_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, span_id, flags))
+}
+
+fn span_json(context: &TraceContext, info: &SpanInfo) -> Value {
+    let mut attributes = vec![
+        string_attr("http.request.method", info.method),
+        string_attr("url.path", info.path),
+        json!({ "key": "http.response.status_code", "value": { "intValue": info.status.to_string() } })
+    ];
+    if let Some(route) = info.route {
+        attributes.push(string_attr("weave.route", route));
+    }
+    if let Some(dest) = info.dest {
+        attributes.push(string_attr("weave.destination", dest));
+    }
+    let mut span = json!({
+        "traceId": hex(&context.trace_id),
+        "spanId": hex(&context.span_id),
+        "name": format!("{} {}", info.method, info.route.unwrap_or("(no route)")),
+        // A server span:
+        "kind": 2,
+        "startTimeUnixNano": unix_nanos(info.start).to_string(),
+        "endTimeUnixNano": unix_nanos(info.end).to_string(),
+        "attributes": attributes,
+        // Only server errors count as errors for the server's span:
+        "status": { "code": if info.status >= 500 { 2 } else { 0 } }
+    });
+    if let Some(parent) = &context.parent_span_id {
+        span["parentSpanId"] = Value::from(hex(parent));
+    }
+    span
+}
+
+fn string_attr(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0)
+}
+
+fn random_bytes<T: AsMut<[u8]> + Default>() -> T {
+    let mut bytes = T::default();
+    SystemRandom::new().fill(bytes.as_mut()).expect("random numbers are available");
+    bytes
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse lowercase hex of exactly the right length:
+fn unhex<T: AsMut<[u8]> + Default>(input: &str) -> Option<T> {
+    let mut bytes = T::default();
+    let out = bytes.as_mut();
+    if input.len() != out.len() * 2 || input.bytes().any(|b| b.is_ascii_uppercase()) {
+        return None;
+    }
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(input.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn headers(traceparent: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_str(traceparent).unwrap());
+        headers
+    }
+
+    #[test]
+    fn carries_on_with_traces() {
+        let context = TraceContext::for_request(&headers("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        assert_eq!(hex(&context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&context.parent_span_id.unwrap()), "00f067aa0ba902b7");
+        let traceparent = context.traceparent();
+        let traceparent = traceparent.to_str().unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        assert_ne!(&traceparent[36..52], "00f067aa0ba902b7");
+    }
+
+    #[test]
+    fn starts_new_traces() {
+        for invalid in &[
+            "",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        ] {
+            let context = TraceContext::for_request(&headers(invalid));
+            assert!(context.parent_span_id.is_none(), "{}", invalid);
+            assert_ne!(context.trace_id, [0; 16]);
+        }
+    }
+
+    #[test]
+    fn describes_spans() {
+        let context = TraceContext::for_request(&headers("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let span = span_json(&context, &SpanInfo {
+            method: "GET",
+            path: "/api/users",
+            route: Some("8080/api to 9000"),
+            dest: Some("http://localhost:9000/"),
+            status: 502,
+            start,
+            end: start + Duration::from_millis(5)
+        });
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["startTimeUnixNano"], "1000000000");
+        assert_eq!(span["endTimeUnixNano"], "1005000000");
+        assert_eq!(span["status"]["code"], 2);
+        assert_eq!(span["attributes"][3]["value"]["stringValue"], "8080/api to 9000");
+    }
+
+    #[test]
+    fn finds_the_traces_endpoint() {
+        assert_eq!(Tracer::new("http://localhost:4318", "weave").unwrap().endpoint.as_str(), "http://localhost:4318/v1/traces");
+        assert_eq!(Tracer::new("http://localhost:4318/otlp/traces", "weave").unwrap().endpoint.as_str(), "http://localhost:4318/otlp/traces");
+        assert!(Tracer::new("localhost:4318", "weave").is_err());
+    }
+}
@@ -0,0 +1,395 @@
+use hyper::{ HeaderMap, Method, Request, Response, StatusCode };
+use hyper::header::{ HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING };
+use log::{ debug };
+use serde_json::{ Map, Value as Json };
+use std::fmt;
+use crate::body::{ Body };
+use crate::compress;
+use crate::errors::{ Error };
+use crate::proxy;
+
+/// JSON bodies up to this size are transformed. Bigger ones (and those of
+/// unknown size) are passed on as they are.
+const MAX_TRANSFORMED: usize = 1024 * 1024;
+
+/// Reshape the JSON bodies of requests and responses to a route, given
+/// with `json-request=RULE` and `json-response=RULE`. Rules are applied in
+/// the order they were given.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct TransformOptions {
+    pub request: Vec<Transform>,
+    pub response: Vec<Transform>
+}
+
+/// A change to make to a JSON body, written something like jq:
+///
+/// ```text
+/// del(.user.password)              remove a field
+/// rename(.items[].userName, name)  rename a field, keeping its value
+/// .version = 2                     set a field to some JSON
+/// .user.id = .id                   set a field to another field's value
+/// .tags //= []                     set a field, if it's missing or null
+/// ```
+///
+/// Paths start at the top of the body with `.`, and go into fields with
+/// `.name`, items of arrays with `[0]` and every item (or field) with `[]`.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Transform {
+    input: String,
+    op: Op
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Op {
+    Delete(Path),
+    Rename(Path, String),
+    Set(Path, Source),
+    Default(Path, Source)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Source {
+    Json(Json),
+    Path(Path)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+struct Path(Vec<Step>);
+
+#[derive(Debug,Clone,PartialEq)]
+enum Step {
+    Key(String),
+    Index(usize),
+    Each
+}
+
+impl Transform {
+    pub fn parse(input: &str) -> Result<Transform, Error> {
+        let trimmed = input.trim();
+        let op = if let Some(path) = trimmed.strip_prefix("del(").and_then(|rest| rest.strip_suffix(')')) {
+            Op::Delete(Path::parse_field(path)?)
+        } else if let Some(args) = trimmed.strip_prefix("rename(").and_then(|rest| rest.strip_suffix(')')) {
+            let idx = args.rfind(',').ok_or_else(|| err!("Expecting something like 'rename(.from, to)' but got '{}'", input))?;
+            let to = args[idx+1..].trim();
+            if to.is_empty() || to.contains(['.', '[']) {
+                return Err(err!("Expecting a field name to rename to, like 'rename(.from, to)', but got '{}'", to));
+            }
+            Op::Rename(Path::parse_field(&args[..idx])?, to.to_owned())
+        } else if let Some(idx) = trimmed.find("//=") {
+            Op::Default(Path::parse_field(&trimmed[..idx])?, Source::parse(&trimmed[idx+3..])?)
+        } else if let Some(idx) = trimmed.find('=') {
+            Op::Set(Path::parse_field(&trimmed[..idx])?, Source::parse(&trimmed[idx+1..])?)
+        } else {
+            return Err(err!("Expecting something like 'del(.a)', 'rename(.a, b)', '.a = 1' or '.a //= 1' but got '{}'", input));
+        };
+        Ok(Transform { input: trimmed.to_owned(), op })
+    }
+
+    fn apply(&self, json: &mut Json) {
+        match &self.op {
+            Op::Delete(path) => path.each_parent(json, false, &mut |parent, key| {
+                parent.remove(key);
+            }),
+            Op::Rename(path, to) => path.each_parent(json, false, &mut |parent, key| {
+                if let Some(value) = parent.remove(key) {
+                    parent.insert(to.clone(), value);
+                }
+            }),
+            Op::Set(path, source) => {
+                let value = source.value(json);
+                path.each_parent(json, true, &mut |parent, key| {
+                    parent.insert(key.to_owned(), value.clone());
+                })
+            },
+            Op::Default(path, source) => {
+                let value = source.value(json);
+                path.each_parent(json, true, &mut |parent, key| {
+                    let current = parent.entry(key.to_owned()).or_insert(Json::Null);
+                    if current.is_null() {
+                        *current = value.clone();
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.input)
+    }
+}
+
+impl Source {
+    fn parse(input: &str) -> Result<Source, Error> {
+        let input = input.trim();
+        if input.starts_with('.') {
+            let path = Path::parse(input)?;
+            if path.0.contains(&Step::Each) {
+                return Err(err!("Can't take a value from more than one place, as '{}' would", input));
+            }
+            return Ok(Source::Path(path));
+        }
+        serde_json::from_str(input)
+            .map(Source::Json)
+            .map_err(|e| err!("Expecting a path or some JSON but got '{}': {}", input, e))
+    }
+
+    /// The value to set, taken from the body as it was before we set it.
+    /// Missing fields are null:
+    fn value(&self, json: &Json) -> Json {
+        match self {
+            Source::Json(value) => value.clone(),
+            Source::Path(path) => path.get(json).cloned().unwrap_or(Json::Null)
+        }
+    }
+}
+
+impl Path {
+    fn parse(input: &str) -> Result<Path, Error> {
+        let input = input.trim();
+        if !input.starts_with('.') {
+            return Err(err!("Expecting a path starting with '.' but got '{}'", input));
+        }
+        // `.` is the whole body, and `.[]` each item of it:
+        let mut rest = match &input[1..] {
+            rest if rest.is_empty() || rest.starts_with('[') => rest,
+            _ => input
+        };
+        let mut steps = vec![];
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(|| err!("Expecting a ']' in '{}'", input))?;
+                let index = &after[..end];
+                steps.push(if index.is_empty() {
+                    Step::Each
+                } else {
+                    Step::Index(index.parse().map_err(|_| err!("'{}' is not an index in '{}'", index, input))?)
+                });
+                rest = &after[end+1..];
+            } else if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                if end == 0 {
+                    return Err(err!("Expecting a field name after '.' in '{}'", input));
+                }
+                steps.push(Step::Key(after[..end].to_owned()));
+                rest = &after[end..];
+            } else {
+                return Err(err!("Expecting a '.' or '[' in '{}'", input));
+            }
+        }
+        Ok(Path(steps))
+    }
+
+    /// A path to a field of an object, which is what we change:
+    fn parse_field(input: &str) -> Result<Path, Error> {
+        let path = Path::parse(input)?;
+        match path.0.last() {
+            Some(Step::Key(_)) => Ok(path),
+            _ => Err(err!("Expecting a path to a field, like '.a.b', but got '{}'", input.trim()))
+        }
+    }
+
+    fn get<'a>(&self, mut json: &'a Json) -> Option<&'a Json> {
+        for step in &self.0 {
+            json = match step {
+                Step::Key(key) => json.get(key)?,
+                Step::Index(idx) => json.get(idx)?,
+                Step::Each => return None
+            };
+        }
+        Some(json)
+    }
+
+    /// Call `f` with each object the path's field is in, along with the
+    /// name of the field. If `create` is set, missing objects on the way
+    /// there are created, as they are in jq:
+    fn each_parent(&self, json: &mut Json, create: bool, f: &mut dyn FnMut(&mut Map<String, Json>, &str)) {
+        let (last, steps) = match self.0.split_last() {
+            Some((Step::Key(last), steps)) => (last, steps),
+            _ => return
+        };
+        visit(json, steps, create, &mut |parent| {
+            if let Json::Object(parent) = parent {
+                f(parent, last);
+            }
+        });
+    }
+}
+
+fn visit(json: &mut Json, steps: &[Step], create: bool, f: &mut dyn FnMut(&mut Json)) {
+    let (step, rest) = match steps.split_first() {
+        Some(split) => split,
+        None => return f(json)
+    };
+    match (step, json) {
+        (Step::Key(key), Json::Object(map)) => {
+            if create {
+                let child = map.entry(key.clone()).or_insert(Json::Null);
+                if child.is_null() {
+                    *child = Json::Object(Map::new());
+                }
+            }
+            if let Some(child) = map.get_mut(key) {
+                visit(child, rest, create, f);
+            }
+        },
+        (Step::Index(idx), Json::Array(items)) => {
+            if let Some(child) = items.get_mut(*idx) {
+                visit(child, rest, create, f);
+            }
+        },
+        (Step::Each, Json::Array(items)) => {
+            for child in items {
+                visit(child, rest, create, f);
+            }
+        },
+        (Step::Each, Json::Object(map)) => {
+            for child in map.values_mut() {
+                visit(child, rest, create, f);
+            }
+        },
+        _ => {}
+    }
+}
+
+/// Is this a JSON body we can transform?
+fn is_json(headers: &HeaderMap) -> bool {
+    if headers.contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+    let content_type = match headers.get(CONTENT_TYPE).and_then(|t| t.to_str().ok()) {
+        Some(content_type) => content_type.split(';').next().unwrap_or("").trim().to_lowercase(),
+        None => return false
+    };
+    content_type == "application/json" || content_type.ends_with("+json")
+}
+
+/// Apply some transforms to a JSON body, handing back the body to send in
+/// its place. Bodies that aren't JSON after all are left as they were:
+async fn transform_body(headers: &mut HeaderMap, body: Body, transforms: &[Transform]) -> Result<Body, Error> {
+    let len = headers.get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    match len {
+        Some(len) if len <= MAX_TRANSFORMED => {},
+        _ => {
+            debug!("Not transforming a JSON body of unknown or excessive length");
+            return Ok(body);
+        }
+    }
+
+    let bytes = proxy::read_body(body).await?;
+    let mut json: Json = match serde_json::from_slice(&bytes) {
+        Ok(json) => json,
+        Err(e) => {
+            debug!("Not transforming a body that isn't valid JSON: {}", e);
+            return Ok(Body::from(bytes));
+        }
+    };
+    for transform in transforms {
+        transform.apply(&mut json);
+    }
+    let bytes = serde_json::to_vec(&json).unwrap();
+    compress::remove_length(headers);
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+    Ok(Body::from(bytes))
+}
+
+/// Transform the body of a request to a route, if it's JSON:
+pub async fn request(req: Request<Body>, options: &TransformOptions) -> Result<Request<Body>, Error> {
+    if options.request.is_empty() || !is_json(req.headers()) {
+        return Ok(req);
+    }
+    let (mut parts, body) = req.into_parts();
+    let body = transform_body(&mut parts.headers, body, &options.request).await?;
+    Ok(Request::from_parts(parts, body))
+}
+
+/// Transform the body of a response from a route, if it's JSON:
+pub async fn response(resp: Response<Body>, method: &Method, options: &TransformOptions) -> Result<Response<Body>, Error> {
+    if options.response.is_empty()
+        || *method == Method::HEAD
+        || resp.status() == StatusCode::NO_CONTENT
+        || resp.status() == StatusCode::PARTIAL_CONTENT
+        || resp.status() == StatusCode::NOT_MODIFIED
+        || !is_json(resp.headers()) {
+        return Ok(resp);
+    }
+    let (mut parts, body) = resp.into_parts();
+    let body = transform_body(&mut parts.headers, body, &options.response).await?;
+    Ok(Response::from_parts(parts, body))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use serde_json::json;
+
+    fn transformed(json: Json, rules: &[&str]) -> Json {
+        let mut json = json;
+        for rule in rules {
+            Transform::parse(rule).unwrap().apply(&mut json);
+        }
+        json
+    }
+
+    #[test]
+    fn parses_transforms() {
+        assert_eq!(Transform::parse("del(.a.b[0].c)").unwrap().op, Op::Delete(Path(vec![
+            Step::Key("a".to_owned()), Step::Key("b".to_owned()), Step::Index(0), Step::Key("c".to_owned())
+        ])));
+        assert_eq!(Transform::parse(".a //= .b").unwrap().op, Op::Default(
+            Path(vec![Step::Key("a".to_owned())]),
+            Source::Path(Path(vec![Step::Key("b".to_owned())]))
+        ));
+        assert_eq!(Transform::parse(".[].a = {\"x\": 1}").unwrap().op, Op::Set(
+            Path(vec![Step::Each, Step::Key("a".to_owned())]),
+            Source::Json(json!({"x": 1}))
+        ));
+        for bad in &["del(.a[0])", "del(a)", "rename(.a, b.c)", ".a = nope", ".a = .b[]", ".a..b = 1", "keep(.a)"] {
+            assert!(Transform::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn transforms_json() {
+        let json = json!({
+            "id": 7,
+            "user": {"userName": "ann", "password": "secret"},
+            "items": [{"userName": "bob"}, {"other": 1}],
+            "tags": null
+        });
+        assert_eq!(transformed(json, &[
+            "del(.user.password)",
+            "rename(.user.userName, name)",
+            "rename(.items[].userName, name)",
+            ".user.id = .id",
+            ".meta.version = 2",
+            ".tags //= []",
+            ".id //= 8"
+        ]), json!({
+            "id": 7,
+            "user": {"name": "ann", "id": 7},
+            "items": [{"name": "bob"}, {"other": 1}],
+            "tags": [],
+            "meta": {"version": 2}
+        }));
+    }
+
+    #[test]
+    fn transforms_json_bodies() {
+        let options = TransformOptions { request: vec![], response: vec![Transform::parse("del(.a)").unwrap()] };
+        let resp = Response::builder()
+            .header(CONTENT_TYPE, "application/vnd.api+json; charset=utf-8")
+            .header(CONTENT_LENGTH, "13")
+            .body(Body::from(r#"{"a":1,"b":2}"#))
+            .unwrap();
+        let resp = futures::executor::block_on(response(resp, &Method::GET, &options)).unwrap();
+        assert_eq!(resp.headers()[CONTENT_LENGTH], "7");
+        let body = futures::executor::block_on(proxy::read_body(resp.into_body())).unwrap();
+        assert_eq!(body, br#"{"b":2}"#);
+    }
+}